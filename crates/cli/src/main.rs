@@ -1,8 +1,16 @@
 use std::fs;
-use std::io::{self, BufReader, Read, Seek};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use dicom2tiff::{
+    AssociatedImages, CancellationToken, ChannelSelection, ChunkCompression, ConversionOptions,
+    Converter, DirectoryStore, FocalPlaneSelection, IccProfilePolicy, ImageInfo, LevelSelection,
+    MetadataPolicy, NativeCompression, OutputFlavor, OutputStore, Severity, Slide, SlideInfo,
+    Strictness, TileLayout, ZipStore,
+};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
 use tempfile::NamedTempFile;
 use zip::ZipArchive;
 
@@ -10,16 +18,381 @@ use zip::ZipArchive;
 #[derive(Parser)]
 #[command(name = "dicom2tiff")]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input path (directory, .dcm file, or .zip file). When using --single, this must be a .dcm file.
-    input: PathBuf,
+    #[arg(required = true)]
+    input: Option<PathBuf>,
 
-    /// Output .tiff file
-    output: PathBuf,
+    /// Output .tiff file (.zarr directory, .dzi file or IIIF directory with --format, or .zip
+    /// file for any of them), or output directory when using --all
+    #[arg(required_unless_present = "dry_run")]
+    output: Option<PathBuf>,
 
     /// Process only the specified file (do not scan parent directory)
     #[arg(short, long)]
     single: bool,
+
+    /// Convert every slide found in the input to its own .tiff file (or output of --format) in
+    /// the output directory, named after the slide's Pyramid UID (or Series Instance UID)
+    #[arg(long, conflicts_with = "slide")]
+    all: bool,
+
+    /// Convert only the slide with this Pyramid UID or Series Instance UID
+    #[arg(long, value_name = "UID")]
+    slide: Option<String>,
+
+    /// Compression of tiles created from uncompressed (native) or RLE compressed pixel data
+    #[arg(long, value_enum, default_value_t = NativeCompressionArg::Deflate)]
+    native_compression: NativeCompressionArg,
+
+    /// Write a classic TIFF instead of a BigTIFF (limited to 4 GiB)
+    #[arg(long)]
+    classic_tiff: bool,
+
+    /// Write only these pyramid levels, as comma-separated indices from level 0 (the largest)
+    #[arg(long, value_delimiter = ',', value_name = "LEVELS")]
+    levels: Option<Vec<usize>>,
+
+    /// Add reduced-resolution levels below the smallest level of a TIFF, each half the size of
+    /// the one before, until a level fits in a single tile. Their tiles are averaged from the
+    /// pixels of the level before and encoded as JPEG
+    #[arg(long)]
+    synthesize_levels: bool,
+
+    /// The format of the output
+    #[arg(long, value_enum, default_value_t = FormatArg::Tiff)]
+    format: FormatArg,
+
+    /// The layout of the output TIFF
+    #[arg(long, value_enum, default_value_t = FlavorArg::Aperio)]
+    flavor: FlavorArg,
+
+    /// Compression of the chunks of an OME-Zarr
+    #[arg(long, value_enum, default_value_t = ChunkCompressionArg::Zlib)]
+    chunk_compression: ChunkCompressionArg,
+
+    /// Width and height of DZI and IIIF tiles [default: the DICOM tile width]
+    #[arg(long, value_name = "PIXELS")]
+    tile_size: Option<u32>,
+
+    /// JPEG quality of DZI and IIIF tiles which are not copied from DICOM frames
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    tile_quality: u8,
+
+    /// URI at which the IIIF tiles are served, the id of the image in info.json [default: the
+    /// name of the output directory]
+    #[arg(long, value_name = "URI")]
+    iiif_id: Option<String>,
+
+    /// Leave out the thumbnail image
+    #[arg(long)]
+    no_thumbnail: bool,
+
+    /// Leave out the label image
+    #[arg(long)]
+    no_label: bool,
+
+    /// Leave out the overview (macro) image
+    #[arg(long)]
+    no_overview: bool,
+
+    /// Leave out ICC profiles
+    #[arg(long)]
+    no_icc_profile: bool,
+
+    /// Which DICOM metadata to write to the TIFF [default: full with --flavor ome, minimal
+    /// otherwise]
+    #[arg(long, value_enum)]
+    metadata: Option<MetadataArg>,
+
+    /// Which focal plane of a Z-stack to write: "nominal" (nearest to Z offset 0), the index of
+    /// a plane from the lowest up, "edf" (the sharpest plane of every tile, an extended depth of
+    /// field) or "all" (one Z page per plane, with --flavor ome only)
+    #[arg(long, default_value = "nominal", value_parser = parse_focal_plane, value_name = "PLANE")]
+    focal_plane: FocalPlaneSelection,
+
+    /// Which channel (optical path) of a fluorescence slide to write: "first", the Optical Path
+    /// Identifier of a channel, "all" (one C page per channel, with --flavor ome only) or
+    /// "split" (every channel to its own output, named after the output with the identifier
+    /// of the channel appended)
+    #[arg(long, default_value = "first", value_parser = parse_channel, value_name = "CHANNEL")]
+    channel: ChannelArg,
+
+    /// Skip pyramid levels and associated images which cannot be converted, and leave out
+    /// invalid metadata, instead of failing
+    #[arg(long)]
+    lenient: bool,
+
+    /// Prepare tiles on this many threads, or one per CPU core if 0. The output is the same as
+    /// with a single thread
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    threads: usize,
+
+    /// Check the input as the conversion to a TIFF with these options would, and report every
+    /// problem with the file it is in, without writing any output
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert a tiled pyramidal TIFF or Aperio SVS file to DICOM WSI instances, one per
+    /// pyramid level, copying its JPEG and JPEG 2000 tiles without recompression
+    ToDicom(ToDicomArgs),
+    /// Describe the slides of DICOM files from their headers, without converting them, and
+    /// report what would fail their conversion
+    Info(InfoArgs),
+}
+
+#[derive(clap::Args)]
+struct InfoArgs {
+    /// Input path (directory, .dcm file, or .zip file)
+    input: PathBuf,
+
+    /// Read only the specified file (do not scan parent directory)
+    #[arg(short, long)]
+    single: bool,
+
+    /// Print a JSON array with an object per slide
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args)]
+struct ToDicomArgs {
+    /// Input .tiff or .svs file
+    input: PathBuf,
+
+    /// Output directory, to which the instances are written as level-<N>.dcm
+    output: PathBuf,
+
+    /// Convert only these pyramid levels, as comma-separated indices from level 0 (the largest)
+    #[arg(long, value_delimiter = ',', value_name = "LEVELS")]
+    levels: Option<Vec<usize>>,
+
+    /// Leave out the ICC profile
+    #[arg(long)]
+    no_icc_profile: bool,
+
+    /// Which TIFF metadata to write to the instances
+    #[arg(long, value_enum, default_value_t = MetadataArg::Minimal)]
+    metadata: MetadataArg,
+
+    /// Skip pyramid levels which cannot be converted instead of failing
+    #[arg(long)]
+    lenient: bool,
+
+    /// Study Instance UID of the instances [default: a new UID]
+    #[arg(long, value_name = "UID")]
+    study_uid: Option<String>,
+
+    /// Series Instance UID of the instances [default: a new UID]
+    #[arg(long, value_name = "UID")]
+    series_uid: Option<String>,
+
+    /// Container (slide) identifier of the instances [default: the name of the input file]
+    #[arg(long, value_name = "ID")]
+    container_id: Option<String>,
+}
+
+impl ToDicomArgs {
+    fn conversion_options(&self) -> ConversionOptions {
+        let mut options = ConversionOptions::new()
+            .levels(match &self.levels {
+                Some(levels) => LevelSelection::Only(levels.clone()),
+                None => LevelSelection::All,
+            })
+            .icc_profile(if self.no_icc_profile {
+                IccProfilePolicy::Omit
+            } else {
+                IccProfilePolicy::Preserve
+            })
+            .metadata(self.metadata.into())
+            .strictness(if self.lenient {
+                Strictness::Lenient
+            } else {
+                Strictness::Strict
+            });
+        if let Some(uid) = &self.study_uid {
+            options = options.study_instance_uid(uid.as_str());
+        }
+        if let Some(uid) = &self.series_uid {
+            options = options.series_instance_uid(uid.as_str());
+        }
+        let container_id = self.container_id.clone().or_else(|| {
+            let stem = self.input.file_stem()?;
+            Some(stem.to_string_lossy().into_owned())
+        });
+        if let Some(container_id) = container_id {
+            options = options.container_identifier(container_id);
+        }
+        options
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MetadataArg {
+    /// Only the resolution (MPP)
+    Minimal,
+    /// Also the acquisition date and time, the scanner and the objective lens power
+    Full,
+}
+
+impl From<MetadataArg> for MetadataPolicy {
+    fn from(arg: MetadataArg) -> Self {
+        match arg {
+            MetadataArg::Minimal => MetadataPolicy::Minimal,
+            MetadataArg::Full => MetadataPolicy::Full,
+        }
+    }
+}
+
+impl Args {
+    fn conversion_options(&self) -> ConversionOptions {
+        let options = ConversionOptions::new()
+            .flavor(match self.flavor {
+                FlavorArg::Aperio => OutputFlavor::Aperio,
+                FlavorArg::Generic => OutputFlavor::Generic,
+                FlavorArg::Ome => OutputFlavor::Ome,
+            })
+            .bigtiff(!self.classic_tiff)
+            .levels(match &self.levels {
+                Some(levels) => LevelSelection::Only(levels.clone()),
+                None => LevelSelection::All,
+            })
+            .associated_images(AssociatedImages {
+                thumbnail: !self.no_thumbnail,
+                label: !self.no_label,
+                overview: !self.no_overview,
+            })
+            .icc_profile(if self.no_icc_profile {
+                IccProfilePolicy::Omit
+            } else {
+                IccProfilePolicy::Preserve
+            })
+            .focal_planes(self.focal_plane)
+            .channels(match &self.channel {
+                ChannelArg::Selection(channel) => channel.clone(),
+                // Every channel is selected by its identifier in turn
+                ChannelArg::Split => ChannelSelection::First,
+            })
+            .strictness(if self.lenient {
+                Strictness::Lenient
+            } else {
+                Strictness::Strict
+            })
+            .native_compression(NativeCompression::from(self.native_compression))
+            .synthesize_levels(self.synthesize_levels)
+            .chunk_compression(match self.chunk_compression {
+                ChunkCompressionArg::None => ChunkCompression::None,
+                ChunkCompressionArg::Zlib => ChunkCompression::Zlib,
+                ChunkCompressionArg::Zstd => ChunkCompression::Zstd,
+            });
+        let options = match self.metadata {
+            Some(metadata) => options.metadata(metadata.into()),
+            None => options,
+        };
+        let options = match self.tile_size {
+            Some(tile_size) => options.static_tile_size(tile_size),
+            None => options,
+        };
+        options.static_tile_quality(self.tile_quality)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FormatArg {
+    /// Pyramidal TIFF, in the layout given by --flavor
+    Tiff,
+    /// OME-Zarr (NGFF 0.4) multiscale image, as a directory, or as a zip store if the output
+    /// ends with .zip
+    Zarr,
+    /// Deep Zoom image: the .dzi file and JPEG tiles in the _files directory next to it
+    Dzi,
+    /// IIIF Image API 3.0 level 0 static JPEG tiles and info.json, in the output directory
+    Iiif,
+}
+
+impl FormatArg {
+    /// The name of the output of a slide converted with --all.
+    fn output_name(self, uid: &str) -> String {
+        match self {
+            FormatArg::Tiff => format!("{}.tiff", uid),
+            FormatArg::Zarr => format!("{}.zarr", uid),
+            FormatArg::Dzi => format!("{}.dzi", uid),
+            FormatArg::Iiif => uid.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ChunkCompressionArg {
+    None,
+    Zlib,
+    Zstd,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FlavorArg {
+    /// Aperio SVS layout, readable by OpenSlide
+    Aperio,
+    /// Plain pyramidal TIFF without associated images or JPEG 2000 tiles
+    Generic,
+    /// OME-TIFF with OME-XML metadata and the reduced levels in SubIFDs
+    Ome,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NativeCompressionArg {
+    None,
+    Deflate,
+    Lzw,
+    Zstd,
+}
+
+fn parse_focal_plane(value: &str) -> Result<FocalPlaneSelection, String> {
+    match value {
+        "nominal" => Ok(FocalPlaneSelection::Nominal),
+        "edf" => Ok(FocalPlaneSelection::ExtendedDepthOfField),
+        "all" => Ok(FocalPlaneSelection::All),
+        _ => value
+            .parse()
+            .map(FocalPlaneSelection::Index)
+            .map_err(|_| "expected nominal, edf, all or the index of a plane".to_string()),
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum ChannelArg {
+    Selection(ChannelSelection),
+    Split,
+}
+
+fn parse_channel(value: &str) -> Result<ChannelArg, String> {
+    match value {
+        "first" => Ok(ChannelArg::Selection(ChannelSelection::First)),
+        "all" => Ok(ChannelArg::Selection(ChannelSelection::All)),
+        "split" => Ok(ChannelArg::Split),
+        "" => Err("expected first, all, split or the identifier of a channel".to_string()),
+        _ => Ok(ChannelArg::Selection(ChannelSelection::Identifier(
+            value.to_string(),
+        ))),
+    }
+}
+
+impl From<NativeCompressionArg> for NativeCompression {
+    fn from(arg: NativeCompressionArg) -> Self {
+        match arg {
+            NativeCompressionArg::None => NativeCompression::None,
+            NativeCompressionArg::Deflate => NativeCompression::Deflate,
+            NativeCompressionArg::Lzw => NativeCompression::Lzw,
+            NativeCompressionArg::Zstd => NativeCompression::Zstd,
+        }
+    }
 }
 
 fn is_dicom_file(path: &Path) -> bool {
@@ -52,9 +425,11 @@ fn is_zip_file(path: &Path) -> bool {
     false
 }
 
+/// Extracts the DICOM files of a zip archive to temporary files, with the paths of their entries
+/// in the archive.
 fn get_dicom_files_from_zip(
     zip_path: &Path,
-) -> Result<Vec<NamedTempFile>, Box<dyn std::error::Error>> {
+) -> Result<Vec<(PathBuf, NamedTempFile)>, Box<dyn std::error::Error>> {
     let file = fs::File::open(zip_path)?;
     let reader = BufReader::new(file);
     let mut archive = ZipArchive::new(reader)?;
@@ -69,6 +444,8 @@ fn get_dicom_files_from_zip(
             continue;
         }
 
+        let entry_path = zip_path.join(zip_file.name());
+
         // Create a temporary file (will be auto-deleted when dropped)
         let mut temp_file = NamedTempFile::new()?;
 
@@ -79,7 +456,7 @@ fn get_dicom_files_from_zip(
         temp_file.rewind()?;
         if is_dicom_data(&mut temp_file).unwrap_or(false) {
             // Seek back to the beginning for processing
-            dicom_files.push(temp_file);
+            dicom_files.push((entry_path, temp_file));
         }
         // If not a DICOM file, temp_file is dropped and auto-deleted
     }
@@ -109,13 +486,338 @@ fn get_dicom_files(path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Erro
     Ok(paths)
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn convert<R: Read + Seek + Send>(
+    dicom_sources: Vec<R>,
+    paths: &[PathBuf],
+    output_path: &Path,
+    args: &Args,
+    cancellation: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let converter = new_converter(args.conversion_options(), args, cancellation);
+    let discovery = converter.discover_slides(dicom_sources)?;
+    for error in &discovery.skipped {
+        match error {
+            dicom2tiff::Error::Instance { index, .. } if *index < paths.len() => {
+                eprintln!("Warning: skipped {}: {}", paths[*index].display(), error)
+            }
+            _ => eprintln!("Warning: skipped {}", error),
+        }
+    }
+    let slides = discovery.slides;
+    if slides.is_empty() {
+        return Err(dicom2tiff::Error::NoPyramidLevels.into());
+    }
+
+    if args.all {
+        fs::create_dir_all(output_path)?;
+        for slide in &slides {
+            let output_path = output_path.join(args.format.output_name(slide.uid()));
+            if args.channel != ChannelArg::Split {
+                println!("{}", output_path.display());
+            }
+            convert_channels(&converter, slide, &output_path, args, cancellation)?;
+        }
+        return Ok(());
+    }
+
+    let slide = match &args.slide {
+        Some(uid) => slides
+            .iter()
+            .find(|slide| slide.matches_uid(uid))
+            .ok_or_else(|| format!("No slide with UID {}", uid))?,
+        None if slides.len() == 1 => &slides[0],
+        None => {
+            let ids = slides.iter().map(|slide| slide.id().clone()).collect();
+            return Err(dicom2tiff::Error::MultipleSlides(ids).into());
+        }
+    };
+    convert_channels(&converter, slide, output_path, args, cancellation)?;
+
+    Ok(())
+}
+
+fn new_converter(
+    options: ConversionOptions,
+    args: &Args,
+    cancellation: &CancellationToken,
+) -> Converter {
+    let converter = Converter::new(options).cancellation_token(cancellation.clone());
+    if args.threads != 1 {
+        converter.threads(args.threads)
+    } else {
+        converter
+    }
+}
+
+/// Converts a slide, or with --channel split every channel of it to its own output, named after
+/// the output with the identifier of the channel appended. A slide without identified channels
+/// is converted as a whole.
+fn convert_channels(
+    converter: &Converter,
+    slide: &Slide,
+    output_path: &Path,
+    args: &Args,
+    cancellation: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let identifiers = slide.optical_paths();
+    if args.channel != ChannelArg::Split || identifiers.is_empty() {
+        return convert_slide(converter, slide, output_path, args);
+    }
+    for identifier in identifiers {
+        let options = converter
+            .options()
+            .clone()
+            .channels(ChannelSelection::Identifier(identifier.clone()));
+        let channel_converter = new_converter(options, args, cancellation);
+        let channel_output_path = channel_output_path(output_path, &identifier);
+        println!("{}", channel_output_path.display());
+        convert_slide(&channel_converter, slide, &channel_output_path, args)?;
+    }
+    Ok(())
+}
+
+/// The output of a channel: the output with the identifier of the channel appended to its name,
+/// with the characters which are not safe in file names replaced.
+fn channel_output_path(output_path: &Path, identifier: &str) -> PathBuf {
+    let identifier: String = identifier
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let stem = output_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match output_path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, identifier, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, identifier),
+    };
+    output_path.with_file_name(name)
+}
+
+/// Converts a slide while showing a progress bar of the written tiles on stderr, if it is a
+/// terminal. The partial output is removed if the conversion is cancelled, unless it is a
+/// directory which existed before.
+fn convert_slide(
+    converter: &Converter,
+    slide: &Slide,
+    output_path: &Path,
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (converter, progress_bar) = with_progress_bar(converter)?;
+
+    // Outputs of many files go to a zip store if the output ends with .zip, or else to a
+    // directory: the output itself, or the directory of the .dzi file
+    let is_zip = output_path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+    let name = output_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (directory, partial_output) = match args.format {
+        FormatArg::Dzi if !is_zip => (
+            output_path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            output_path.with_file_name(format!("{}_files", name)),
+        ),
+        _ => (output_path.to_path_buf(), output_path.to_path_buf()),
+    };
+    let remove_partial_output =
+        args.format == FormatArg::Tiff || is_zip || !partial_output.exists();
+    let convert_to_store = |store: &mut dyn OutputStore| match args.format {
+        FormatArg::Tiff => unreachable!("TIFF files are not written to a store"),
+        FormatArg::Zarr => converter.convert_slide_to_zarr(slide, store),
+        FormatArg::Dzi => {
+            converter.convert_slide_to_tiles(slide, &TileLayout::Dzi { name: name.clone() }, store)
+        }
+        FormatArg::Iiif => {
+            let id = args.iiif_id.clone().unwrap_or_else(|| name.clone());
+            converter.convert_slide_to_tiles(slide, &TileLayout::Iiif { id }, store)
+        }
+    };
+
+    let result = match args.format {
+        FormatArg::Tiff => converter.convert_slide(slide, fs::File::create(output_path)?),
+        _ if is_zip => {
+            let mut store = ZipStore::new(BufWriter::new(fs::File::create(output_path)?));
+            convert_to_store(&mut store).and_then(|()| Ok(store.finish()?.flush()?))
+        }
+        _ => convert_to_store(&mut DirectoryStore::new(directory)),
+    };
+    match &result {
+        Ok(()) => progress_bar.finish(),
+        Err(dicom2tiff::Error::Cancelled) => {
+            progress_bar.abandon();
+            if remove_partial_output && partial_output.is_dir() {
+                fs::remove_dir_all(&partial_output)?;
+            } else if remove_partial_output && partial_output.exists() {
+                fs::remove_file(&partial_output)?;
+            }
+        }
+        Err(_) => progress_bar.abandon(),
+    }
+    Ok(result?)
+}
+
+/// Converts a pyramidal TIFF to DICOM instances in the output directory while showing a progress
+/// bar. The instances written so far are removed if the conversion is cancelled.
+fn convert_tiff_to_dicom(
+    args: &ToDicomArgs,
+    cancellation: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let converter =
+        Converter::new(args.conversion_options()).cancellation_token(cancellation.clone());
+    let (converter, progress_bar) = with_progress_bar(&converter)?;
+    let tiff_source = BufReader::new(fs::File::open(&args.input)?);
+    fs::create_dir_all(&args.output)?;
+
+    let mut output_paths = Vec::new();
+    let result = converter.convert_tiff_to_dicom(tiff_source, |level| {
+        let output_path = args.output.join(format!("level-{}.dcm", level));
+        let output = fs::File::create(&output_path)?;
+        output_paths.push(output_path);
+        Ok(BufWriter::new(output))
+    });
+    match &result {
+        Ok(()) => {
+            progress_bar.finish();
+            for output_path in &output_paths {
+                println!("{}", output_path.display());
+            }
+        }
+        Err(dicom2tiff::Error::Cancelled) => {
+            progress_bar.abandon();
+            for output_path in &output_paths {
+                fs::remove_file(output_path)?;
+            }
+        }
+        Err(_) => progress_bar.abandon(),
+    }
+    Ok(result?)
+}
+
+/// Reports the progress of the conversions of `converter` on a progress bar of the written
+/// tiles on stderr, if it is a terminal.
+fn with_progress_bar(
+    converter: &Converter,
+) -> Result<(Converter, ProgressBar), Box<dyn std::error::Error>> {
+    let progress_bar = ProgressBar::new(0).with_style(
+        ProgressStyle::with_template("[{elapsed_precise}] [{bar:40}] {pos}/{len} tiles, {msg}")?
+            .progress_chars("=> "),
+    );
+    let converter = converter.clone().on_progress({
+        let progress_bar = progress_bar.clone();
+        move |progress| {
+            progress_bar.set_length(progress.tiles_total);
+            progress_bar.set_position(progress.tiles_written);
+            progress_bar.set_message(format!(
+                "level {} ({}/{}), {}",
+                progress.level,
+                progress.levels_written,
+                progress.levels_total,
+                HumanBytes(progress.bytes_written)
+            ));
+        }
+    });
+    Ok((converter, progress_bar))
+}
+
+/// Maps errors to the exit codes of sysexits.h, so scripts can tell bad input from unsupported
+/// input and I/O failures.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> u8 {
+    const EX_USAGE: u8 = 64;
+    const EX_DATAERR: u8 = 65;
+    const EX_UNAVAILABLE: u8 = 69;
+    const EX_SOFTWARE: u8 = 70;
+    const EX_IOERR: u8 = 74;
+    // Like shells report processes terminated by SIGINT
+    const INTERRUPTED: u8 = 130;
+
+    if let Some(error) = error.downcast_ref::<dicom2tiff::Error>() {
+        match error.root() {
+            dicom2tiff::Error::MissingAttribute { .. }
+            | dicom2tiff::Error::InvalidAttribute { .. }
+            | dicom2tiff::Error::InvalidPixelData(_)
+            | dicom2tiff::Error::NoPyramidLevels
+            | dicom2tiff::Error::InvalidTiff(_)
+            | dicom2tiff::Error::Dicom(_) => EX_DATAERR,
+            dicom2tiff::Error::UnsupportedPhotometricInterpretation(_)
+            | dicom2tiff::Error::UnsupportedTransferSyntax(_)
+            | dicom2tiff::Error::UnsupportedPixelData(_) => EX_UNAVAILABLE,
+            dicom2tiff::Error::MultipleSlides(_) => EX_USAGE,
+            dicom2tiff::Error::Cancelled => INTERRUPTED,
+            dicom2tiff::Error::Io(_) | dicom2tiff::Error::DicomWrite(_) => EX_IOERR,
+            dicom2tiff::Error::Tiff(_) => EX_SOFTWARE,
+            _ => 1,
+        }
+    } else if error.is::<io::Error>() {
+        EX_IOERR
+    } else {
+        1
+    }
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
 
-    let input_path = &args.input;
-    let output = fs::File::create(&args.output)?;
+    let result = match &args.command {
+        Some(Command::ToDicom(to_dicom_args)) => {
+            convert_tiff_to_dicom(to_dicom_args, &cancel_on_ctrl_c())
+        }
+        Some(Command::Info(info_args)) => print_info(info_args),
+        None => run(&args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            if let Some(dicom2tiff::Error::MultipleSlides(_)) = error.downcast_ref() {
+                eprintln!("Use --slide <UID> to convert one of them, or --all to convert each");
+            }
+            ExitCode::from(exit_code(error.as_ref()))
+        }
+    }
+}
+
+/// Handles Ctrl-C during a conversion: the first one cancels it, which then removes the partial
+/// output, and a second one exits immediately. Commands which write no output keep the default
+/// handling, which exits on the first.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancellation = CancellationToken::new();
+    let handler_cancellation = cancellation.clone();
+    let handler_result = ctrlc::set_handler(move || {
+        if handler_cancellation.is_cancelled() {
+            std::process::exit(130);
+        }
+        handler_cancellation.cancel();
+    });
+    if let Err(error) = handler_result {
+        eprintln!("Warning: failed to handle Ctrl-C: {}", error);
+    }
+    cancellation
+}
+
+/// A DICOM source: a file, or a file extracted from a zip archive.
+trait DicomSource: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> DicomSource for T {}
 
-    if args.single {
+/// The DICOM sources of the input, with the paths of their files.
+type DicomSources = Vec<(PathBuf, Box<dyn DicomSource>)>;
+
+/// Opens the DICOM files of the input, with their paths: the files of a zip archive, the given
+/// file only with `single`, or else all DICOM files in the directory (or the directory of the
+/// given file).
+fn open_dicom_sources(
+    input_path: &Path,
+    single: bool,
+) -> Result<DicomSources, Box<dyn std::error::Error>> {
+    if single {
         // Single file mode: only process the specified file
         if !input_path.is_file() {
             eprintln!("Error: --single requires a file path, not a directory");
@@ -126,25 +828,212 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             std::process::exit(1);
         }
         let file = fs::File::open(input_path)?;
-        let dicom_sources = vec![BufReader::new(file)];
-        dicom2tiff::convert_dicom_sources(dicom_sources, output)?;
+        Ok(vec![(
+            input_path.to_path_buf(),
+            Box::new(BufReader::new(file)),
+        )])
     // Check if the input is a ZIP file
     } else if input_path.is_file() && is_zip_file(input_path) {
         let dicom_files = get_dicom_files_from_zip(input_path)?;
-        let dicom_sources: Vec<BufReader<_>> =
-            dicom_files.into_iter().map(BufReader::new).collect();
-        dicom2tiff::convert_dicom_sources(dicom_sources, output)?;
+        Ok(dicom_files
+            .into_iter()
+            .map(|(path, file)| {
+                let source = Box::new(BufReader::new(file)) as Box<dyn DicomSource>;
+                (path, source)
+            })
+            .collect())
     } else {
         let dicom_paths = get_dicom_files(input_path)?;
-        let dicom_sources: Vec<BufReader<_>> = dicom_paths
-            .into_iter()
-            .map(fs::File::open)
-            .collect::<Result<Vec<_>, _>>()?
+        dicom_paths
             .into_iter()
-            .map(BufReader::new)
-            .collect();
-        dicom2tiff::convert_dicom_sources(dicom_sources, output)?;
+            .map(|path| {
+                let file = fs::File::open(&path)?;
+                Ok((path, Box::new(BufReader::new(file)) as Box<dyn DicomSource>))
+            })
+            .collect()
     }
+}
 
-    Ok(())
+fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(input_path) = &args.input else {
+        unreachable!("clap requires the input without a subcommand");
+    };
+    let (paths, dicom_sources): (Vec<_>, Vec<_>) = open_dicom_sources(input_path, args.single)?
+        .into_iter()
+        .unzip();
+    if args.dry_run {
+        return validate(dicom_sources, &paths, args);
+    }
+    let Some(output_path) = &args.output else {
+        unreachable!("clap requires the output without --dry-run");
+    };
+    convert(
+        dicom_sources,
+        &paths,
+        output_path,
+        args,
+        &cancel_on_ctrl_c(),
+    )
+}
+
+/// Prints every problem the conversion of the input would run into, with the file it is in.
+/// Fails with the first error, after printing everything.
+fn validate<R: Read + Seek + Send>(
+    dicom_sources: Vec<R>,
+    paths: &[PathBuf],
+    args: &Args,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let report = Converter::new(args.conversion_options()).validate(dicom_sources)?;
+    // Each slide is converted on its own with --all or --slide
+    let issues = report.issues.into_iter().filter(|issue| {
+        !(matches!(issue.error, dicom2tiff::Error::MultipleSlides(_))
+            && (args.all || args.slide.is_some()))
+    });
+
+    let mut first_error = None;
+    let (mut errors, mut warnings) = (0, 0);
+    for issue in issues {
+        match issue.instance_index().and_then(|index| paths.get(index)) {
+            Some(path) => println!("{}: {}: {}", issue.severity, path.display(), issue.error),
+            None => println!("{}", issue),
+        }
+        if issue.severity == Severity::Error {
+            errors += 1;
+            first_error.get_or_insert(issue.error);
+        } else {
+            warnings += 1;
+        }
+    }
+    println!(
+        "{} slide(s), {} error(s), {} warning(s)",
+        report.slides.len(),
+        errors,
+        warnings
+    );
+
+    match first_error {
+        Some(error) => Err(error.into()),
+        None => Ok(()),
+    }
+}
+
+/// Prints what the headers tell about the slides of the input. Fails with the first problem
+/// which would fail a conversion, after printing everything.
+fn print_info(args: &InfoArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let dicom_sources = open_dicom_sources(&args.input, args.single)?
+        .into_iter()
+        .map(|(_, source)| source)
+        .collect();
+    let slides = dicom2tiff::probe_dicom_sources(dicom_sources)?;
+    if slides.is_empty() {
+        return Err(dicom2tiff::Error::NoPyramidLevels.into());
+    }
+
+    if args.json {
+        let slides = slides.iter().map(SlideInfo::to_json).collect::<Vec<_>>();
+        println!("[{}]", slides.join(","));
+    } else {
+        for (index, slide) in slides.iter().enumerate() {
+            if index > 0 {
+                println!();
+            }
+            print_slide_info(slide);
+        }
+    }
+
+    match slides
+        .into_iter()
+        .find_map(|slide| slide.problems.into_iter().next())
+    {
+        Some(problem) => Err(problem.into()),
+        None => Ok(()),
+    }
+}
+
+fn print_slide_info(slide: &SlideInfo) {
+    let unknown = || "?".to_string();
+    let size = |width: Option<u32>, height: Option<u32>| {
+        format!(
+            "{}x{}",
+            width.map_or_else(unknown, |width| width.to_string()),
+            height.map_or_else(unknown, |height| height.to_string())
+        )
+    };
+    let describe_image = |image: &ImageInfo| {
+        let mut description = format!(
+            "{}, {} tiles, {} frames, {}, transfer syntax {}",
+            size(image.width, image.height),
+            size(
+                image.tile_width.map(u32::from),
+                image.tile_height.map(u32::from)
+            ),
+            image
+                .number_of_frames
+                .map_or_else(unknown, |frames| frames.to_string()),
+            image
+                .photometric_interpretation
+                .clone()
+                .unwrap_or_else(unknown),
+            image.transfer_syntax_uid
+        );
+        if let Some(dimension_organization_type) = &image.dimension_organization_type {
+            description.push_str(&format!(", {}", dimension_organization_type));
+        }
+        if let Some((mpp_x, mpp_y)) = image.mpp {
+            description.push_str(&format!(", {} x {} µm/pixel", mpp_x, mpp_y));
+        }
+        description
+    };
+
+    println!("Slide {}", slide.id);
+    for (level, image) in slide.levels.iter().enumerate() {
+        println!("  Level {}: {}", level, describe_image(image));
+    }
+    for (name, image) in [
+        ("Thumbnail", &slide.thumbnail),
+        ("Label", &slide.label),
+        ("Overview", &slide.overview),
+    ] {
+        if let Some(image) = image {
+            println!("  {}: {}", name, describe_image(image));
+        }
+    }
+    for optical_path in &slide.optical_paths {
+        let mut details = Vec::new();
+        details.extend(optical_path.description.clone());
+        details.extend(optical_path.illumination_color.clone());
+        if let Some(wavelength) = optical_path.illumination_wavelength {
+            details.push(format!("{} nm", wavelength));
+        }
+        if let Some(wavelength) = optical_path.emission_wavelength {
+            details.push(format!("{} nm emission", wavelength));
+        }
+        if let Some(power) = optical_path.objective_lens_power {
+            details.push(format!("{}x objective", power));
+        }
+        if optical_path.has_icc_profile {
+            details.push("ICC profile".to_string());
+        }
+        println!(
+            "  Optical path {}: {}",
+            optical_path.identifier.clone().unwrap_or_else(unknown),
+            details.join(", ")
+        );
+    }
+    if slide.focal_planes.len() > 1 {
+        let z_offsets: Vec<String> = slide
+            .focal_planes
+            .iter()
+            .map(|z_offset| z_offset.to_string())
+            .collect();
+        println!("  Focal planes: {} µm", z_offsets.join(", "));
+    }
+    if slide.is_convertible() {
+        println!("  Convertible: yes");
+    } else {
+        println!("  Convertible: no");
+        for problem in &slide.problems {
+            println!("    {}", problem);
+        }
+    }
 }
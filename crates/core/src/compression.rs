@@ -0,0 +1,378 @@
+use tiff::encoder::compression::{CompressionAlgorithm, Deflate, DeflateLevel, Lzw};
+use tiff::tags::{CompressionMethod, PhotometricInterpretation as TiffPhotometricInterpretation};
+
+use crate::error::{Error, Result};
+use crate::frames::NativeLayout;
+use crate::metadata::trim_padding;
+
+// Aperio specific TIFF compression codes for JPEG 2000 tiles
+pub const APERIO_COMPRESSION_JP2K_YCBCR: u16 = 33003;
+pub const APERIO_COMPRESSION_JP2K_RGB: u16 = 33005;
+
+/// The encoding of the pixel data of a DICOM instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelEncoding {
+    /// Native (uncompressed) pixel data.
+    Native,
+    /// Encapsulated pixel data, one or more fragments per frame.
+    Encapsulated(Codec),
+}
+
+/// The codec of encapsulated pixel data frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// JPEG baseline or extended (ISO/IEC 10918-1)
+    Jpeg,
+    /// JPEG lossless (ISO/IEC 10918-1 process 14)
+    JpegLossless,
+    /// JPEG 2000 (ISO/IEC 15444-1), lossless or lossy
+    Jpeg2000,
+    /// High-Throughput JPEG 2000 (ISO/IEC 15444-15)
+    HighThroughputJpeg2000,
+    /// JPEG-LS (ISO/IEC 14495-1)
+    JpegLs,
+    /// DICOM RLE Lossless
+    Rle,
+}
+
+impl Codec {
+    fn from_lossy_image_compression_method(method: &str) -> Option<Self> {
+        match method {
+            "ISO_10918_1" => Some(Codec::Jpeg),
+            "ISO_15444_1" => Some(Codec::Jpeg2000),
+            "ISO_15444_15" => Some(Codec::HighThroughputJpeg2000),
+            "ISO_14495_1" => Some(Codec::JpegLs),
+            _ => None,
+        }
+    }
+}
+
+/// Determines the pixel data encoding from the transfer syntax UID. The lossy image compression
+/// method is only used as a hint, for transfer syntaxes which are not known.
+pub fn get_pixel_encoding(
+    transfer_syntax_uid: &str,
+    lossy_image_compression_method: Option<&str>,
+) -> Result<PixelEncoding> {
+    // UIDs may be padded with a trailing null character
+    let transfer_syntax_uid = trim_padding(transfer_syntax_uid).unwrap_or_default();
+    let encoding = match transfer_syntax_uid.as_str() {
+        // Implicit VR Little Endian, Explicit VR Little Endian,
+        // Deflated Explicit VR Little Endian and Explicit VR Big Endian
+        "1.2.840.10008.1.2"
+        | "1.2.840.10008.1.2.1"
+        | "1.2.840.10008.1.2.1.99"
+        | "1.2.840.10008.1.2.2" => PixelEncoding::Native,
+        // JPEG Baseline and JPEG Extended
+        "1.2.840.10008.1.2.4.50" | "1.2.840.10008.1.2.4.51" => {
+            PixelEncoding::Encapsulated(Codec::Jpeg)
+        }
+        // JPEG Lossless and JPEG Lossless SV1
+        "1.2.840.10008.1.2.4.57" | "1.2.840.10008.1.2.4.70" => {
+            PixelEncoding::Encapsulated(Codec::JpegLossless)
+        }
+        // JPEG-LS Lossless and JPEG-LS Near-Lossless
+        "1.2.840.10008.1.2.4.80" | "1.2.840.10008.1.2.4.81" => {
+            PixelEncoding::Encapsulated(Codec::JpegLs)
+        }
+        // JPEG 2000 Lossless and JPEG 2000
+        "1.2.840.10008.1.2.4.90" | "1.2.840.10008.1.2.4.91" => {
+            PixelEncoding::Encapsulated(Codec::Jpeg2000)
+        }
+        // HTJ2K Lossless, HTJ2K Lossless RPCL and HTJ2K
+        "1.2.840.10008.1.2.4.201" | "1.2.840.10008.1.2.4.202" | "1.2.840.10008.1.2.4.203" => {
+            PixelEncoding::Encapsulated(Codec::HighThroughputJpeg2000)
+        }
+        // RLE Lossless
+        "1.2.840.10008.1.2.5" => PixelEncoding::Encapsulated(Codec::Rle),
+        _ => {
+            return lossy_image_compression_method
+                .and_then(Codec::from_lossy_image_compression_method)
+                .map(PixelEncoding::Encapsulated)
+                .ok_or_else(|| Error::UnsupportedTransferSyntax(transfer_syntax_uid.to_string()));
+        }
+    };
+    Ok(encoding)
+}
+
+/// Determines the TIFF compression for tiles copied as-is from frames of the given codec. JPEG
+/// tiles must have 8 bit samples, since TIFF readers decode them with baseline JPEG decoders.
+pub fn get_tiff_compression(
+    codec: Codec,
+    tiff_photometric_interpretation: TiffPhotometricInterpretation,
+    bits_stored: u16,
+) -> Result<CompressionMethod> {
+    match (codec, tiff_photometric_interpretation) {
+        (Codec::Jpeg, _) if bits_stored > 8 => Err(Error::UnsupportedPixelData(format!(
+            "JPEG compressed pixel data with {} bits stored cannot be stored as TIFF tiles",
+            bits_stored
+        ))),
+        (Codec::Jpeg, _) => Ok(CompressionMethod::ModernJPEG),
+        // HTJ2K code streams are decodable by JPEG 2000 decoders with Part 15 support
+        // (e.g. OpenJPEG 2.5+), so both use the Aperio JPEG 2000 compression codes.
+        (Codec::Jpeg2000 | Codec::HighThroughputJpeg2000, TiffPhotometricInterpretation::RGB) => {
+            Ok(CompressionMethod::Unknown(APERIO_COMPRESSION_JP2K_RGB))
+        }
+        (Codec::Jpeg2000 | Codec::HighThroughputJpeg2000, TiffPhotometricInterpretation::YCbCr) => {
+            Ok(CompressionMethod::Unknown(APERIO_COMPRESSION_JP2K_YCBCR))
+        }
+        (Codec::Jpeg2000 | Codec::HighThroughputJpeg2000, _) => {
+            Err(Error::UnsupportedPhotometricInterpretation(format!(
+                "{:?} with JPEG 2000",
+                tiff_photometric_interpretation
+            )))
+        }
+        (Codec::JpegLossless, _) => Err(Error::UnsupportedPixelData(
+            "JPEG Lossless compressed pixel data cannot be stored as TIFF tiles".to_string(),
+        )),
+        (Codec::JpegLs, _) => Err(Error::UnsupportedPixelData(
+            "JPEG-LS compressed pixel data cannot be stored as TIFF tiles".to_string(),
+        )),
+        (Codec::Rle, _) => Err(Error::UnsupportedPixelData(
+            "RLE frames must be decoded before being stored as tiles".to_string(),
+        )),
+    }
+}
+
+/// The compression of TIFF tiles created from native (uncompressed) or RLE pixel data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NativeCompression {
+    /// Store the tiles uncompressed.
+    None,
+    /// Deflate (zlib) compression with a horizontal predictor.
+    #[default]
+    Deflate,
+    /// LZW compression with a horizontal predictor.
+    Lzw,
+    /// Zstandard compression with a horizontal predictor.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl NativeCompression {
+    pub(crate) fn tiff_compression(&self) -> CompressionMethod {
+        match self {
+            NativeCompression::None => CompressionMethod::None,
+            NativeCompression::Deflate => CompressionMethod::Deflate,
+            NativeCompression::Lzw => CompressionMethod::LZW,
+            #[cfg(feature = "zstd")]
+            NativeCompression::Zstd => CompressionMethod::ZSTD,
+        }
+    }
+
+    /// Whether tiles are stored with TIFF horizontal differencing (Predictor 2) applied.
+    pub(crate) fn uses_predictor(&self, layout: &NativeLayout) -> bool {
+        // Horizontal differencing is not defined for subsampled YCbCr data
+        *self != NativeCompression::None && !layout.subsampled_422
+    }
+
+    /// Compresses a color-by-pixel native tile.
+    pub(crate) fn compress(&self, tile: &[u8], layout: &NativeLayout) -> Result<Vec<u8>> {
+        let mut tile = tile.to_vec();
+        if self.uses_predictor(layout) {
+            apply_horizontal_predictor(&mut tile, layout);
+        }
+        let mut compressed = Vec::new();
+        match self {
+            NativeCompression::None => return Ok(tile),
+            NativeCompression::Deflate => {
+                Deflate::with_level(DeflateLevel::Balanced).write_to(&mut compressed, &tile)?;
+            }
+            NativeCompression::Lzw => {
+                Lzw.write_to(&mut compressed, &tile)?;
+            }
+            #[cfg(feature = "zstd")]
+            NativeCompression::Zstd => {
+                compressed = zstd::bulk::compress(&tile, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            }
+        }
+        Ok(compressed)
+    }
+}
+
+/// The compression of the chunks of an OME-Zarr, as a codec of numcodecs, which Zarr readers use
+/// to decode them.
+#[cfg(feature = "zarr")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkCompression {
+    /// Store the chunks uncompressed.
+    None,
+    /// zlib compression (the `zlib` codec).
+    #[default]
+    Zlib,
+    /// Zstandard compression (the `zstd` codec).
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+#[cfg(feature = "zarr")]
+impl ChunkCompression {
+    /// The `compressor` of the array metadata.
+    pub(crate) fn compressor_json(&self) -> &'static str {
+        match self {
+            ChunkCompression::None => "null",
+            // The levels of DeflateLevel::Balanced and zstd::DEFAULT_COMPRESSION_LEVEL
+            ChunkCompression::Zlib => r#"{"id":"zlib","level":6}"#,
+            #[cfg(feature = "zstd")]
+            ChunkCompression::Zstd => r#"{"id":"zstd","level":3}"#,
+        }
+    }
+
+    pub(crate) fn compress(&self, chunk: Vec<u8>) -> Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        match self {
+            ChunkCompression::None => return Ok(chunk),
+            ChunkCompression::Zlib => {
+                Deflate::with_level(DeflateLevel::Balanced).write_to(&mut compressed, &chunk)?;
+            }
+            #[cfg(feature = "zstd")]
+            ChunkCompression::Zstd => {
+                compressed = zstd::bulk::compress(&chunk, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            }
+        }
+        Ok(compressed)
+    }
+}
+
+/// Replaces every sample by its difference to the same sample of the previous pixel in the row.
+fn apply_horizontal_predictor(tile: &mut [u8], layout: &NativeLayout) {
+    let samples = usize::from(layout.samples_per_pixel);
+    for row in tile.chunks_exact_mut(layout.row_len()) {
+        if layout.bits_allocated == 16 {
+            for i in (samples..row.len() / 2).rev() {
+                let current = u16::from_ne_bytes([row[i * 2], row[i * 2 + 1]]);
+                let previous =
+                    u16::from_ne_bytes([row[(i - samples) * 2], row[(i - samples) * 2 + 1]]);
+                row[i * 2..i * 2 + 2]
+                    .copy_from_slice(&current.wrapping_sub(previous).to_ne_bytes());
+            }
+        } else {
+            for i in (samples..row.len()).rev() {
+                row[i] = row[i].wrapping_sub(row[i - samples]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_encoding_of_every_transfer_syntax() {
+        let encodings = [
+            ("1.2.840.10008.1.2", PixelEncoding::Native),
+            ("1.2.840.10008.1.2.1", PixelEncoding::Native),
+            ("1.2.840.10008.1.2.1.99", PixelEncoding::Native),
+            ("1.2.840.10008.1.2.2", PixelEncoding::Native),
+            (
+                "1.2.840.10008.1.2.4.50",
+                PixelEncoding::Encapsulated(Codec::Jpeg),
+            ),
+            (
+                "1.2.840.10008.1.2.4.51",
+                PixelEncoding::Encapsulated(Codec::Jpeg),
+            ),
+            (
+                "1.2.840.10008.1.2.4.57",
+                PixelEncoding::Encapsulated(Codec::JpegLossless),
+            ),
+            (
+                "1.2.840.10008.1.2.4.70",
+                PixelEncoding::Encapsulated(Codec::JpegLossless),
+            ),
+            (
+                "1.2.840.10008.1.2.4.80",
+                PixelEncoding::Encapsulated(Codec::JpegLs),
+            ),
+            (
+                "1.2.840.10008.1.2.4.81",
+                PixelEncoding::Encapsulated(Codec::JpegLs),
+            ),
+            (
+                "1.2.840.10008.1.2.4.90",
+                PixelEncoding::Encapsulated(Codec::Jpeg2000),
+            ),
+            (
+                "1.2.840.10008.1.2.4.91",
+                PixelEncoding::Encapsulated(Codec::Jpeg2000),
+            ),
+            (
+                "1.2.840.10008.1.2.4.201",
+                PixelEncoding::Encapsulated(Codec::HighThroughputJpeg2000),
+            ),
+            (
+                "1.2.840.10008.1.2.4.202",
+                PixelEncoding::Encapsulated(Codec::HighThroughputJpeg2000),
+            ),
+            (
+                "1.2.840.10008.1.2.4.203",
+                PixelEncoding::Encapsulated(Codec::HighThroughputJpeg2000),
+            ),
+            (
+                "1.2.840.10008.1.2.5",
+                PixelEncoding::Encapsulated(Codec::Rle),
+            ),
+        ];
+        for (uid, encoding) in encodings {
+            assert_eq!(get_pixel_encoding(uid, None).unwrap(), encoding, "{}", uid);
+            // A known transfer syntax takes precedence over the lossy image compression method
+            assert_eq!(
+                get_pixel_encoding(uid, Some("ISO_15444_1")).unwrap(),
+                encoding,
+                "{}",
+                uid
+            );
+        }
+    }
+
+    #[test]
+    fn transfer_syntax_padding_is_ignored() {
+        assert_eq!(
+            get_pixel_encoding("1.2.840.10008.1.2.4.50\0", None).unwrap(),
+            PixelEncoding::Encapsulated(Codec::Jpeg)
+        );
+        assert_eq!(
+            get_pixel_encoding("1.2.840.10008.1.2.1 ", None).unwrap(),
+            PixelEncoding::Native
+        );
+    }
+
+    #[test]
+    fn unknown_transfer_syntaxes_fall_back_to_the_lossy_image_compression_method() {
+        let uid = "1.2.3.4.5\0";
+        for (method, codec) in [
+            ("ISO_10918_1", Codec::Jpeg),
+            ("ISO_15444_1", Codec::Jpeg2000),
+            ("ISO_15444_15", Codec::HighThroughputJpeg2000),
+            ("ISO_14495_1", Codec::JpegLs),
+        ] {
+            assert_eq!(
+                get_pixel_encoding(uid, Some(method)).unwrap(),
+                PixelEncoding::Encapsulated(codec)
+            );
+        }
+        for method in [None, Some("ISO_99999")] {
+            match get_pixel_encoding(uid, method) {
+                Err(Error::UnsupportedTransferSyntax(uid)) => assert_eq!(uid, "1.2.3.4.5"),
+                result => panic!("unexpected {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn only_8_bit_jpeg_frames_are_copied_into_tiles() {
+        let rgb = TiffPhotometricInterpretation::RGB;
+        assert_eq!(
+            get_tiff_compression(Codec::Jpeg, rgb, 8).unwrap(),
+            CompressionMethod::ModernJPEG
+        );
+        assert!(matches!(
+            get_tiff_compression(Codec::Jpeg, rgb, 12),
+            Err(Error::UnsupportedPixelData(_))
+        ));
+        assert!(matches!(
+            get_tiff_compression(Codec::JpegLossless, rgb, 8),
+            Err(Error::UnsupportedPixelData(_))
+        ));
+    }
+}
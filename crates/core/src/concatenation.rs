@@ -0,0 +1,400 @@
+use dicom_core::value::DataSetSequence;
+use dicom_core::{DataElement, PrimitiveValue, VR};
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::{DefaultDicomObject, InMemDicomObject};
+
+use crate::error::{Error, Result, get_element_opt, get_number_of_frames};
+use crate::metadata::trim_padding;
+use crate::shared_read_seek::SharedReadSeek;
+use crate::slide::{DicomInstance, get_uid};
+use crate::tiff_writer::read_dicom_header;
+use crate::validation::{Severity, ValidationIssue};
+
+/// The place of an instance in a concatenation, a large image split into several instances
+/// whose frames follow each other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ConcatenationPart {
+    pub uid: String,
+    /// The In-concatenation Number, from 1
+    pub number: u32,
+    /// The In-concatenation Total Number, if given
+    pub total: Option<u32>,
+}
+
+impl ConcatenationPart {
+    /// Reads the place of an instance in its concatenation, `None` if it is not part of one.
+    pub fn read(obj: &InMemDicomObject) -> Result<Option<Self>> {
+        let Some(uid) = get_uid(obj, dicom_tags::CONCATENATION_UID)? else {
+            return Ok(None);
+        };
+        let get_number = |tag| {
+            get_element_opt(obj, tag)?
+                .map(|e| e.to_int::<u32>())
+                .transpose()
+                .map_err(|e| Error::invalid_attribute(tag, e))
+        };
+        let number = get_number(dicom_tags::IN_CONCATENATION_NUMBER)?
+            .filter(|number| *number > 0)
+            .ok_or_else(|| {
+                Error::invalid_attribute(
+                    dicom_tags::IN_CONCATENATION_NUMBER,
+                    "missing or 0 in an instance of a concatenation",
+                )
+            })?;
+        Ok(Some(Self {
+            uid,
+            number,
+            total: get_number(dicom_tags::IN_CONCATENATION_TOTAL_NUMBER)?,
+        }))
+    }
+}
+
+/// Joins the instances of every concatenation into one: the first instance, followed by the
+/// sources of the others in the order of their In-concatenation Number. Incomplete
+/// concatenations are passed to `report` as errors, and left out if it returns `Ok`.
+pub(crate) fn join_concatenations<'a, K>(
+    mut parts: Vec<(K, ConcatenationPart, DicomInstance<'a>)>,
+    mut report: impl FnMut(ValidationIssue) -> Result<()>,
+) -> Result<Vec<(K, DicomInstance<'a>)>> {
+    parts.sort_by(|(_, a, _), (_, b, _)| (&a.uid, a.number).cmp(&(&b.uid, b.number)));
+    let mut concatenations: Vec<(K, ConcatenationPart, DicomInstance<'a>, Vec<u32>)> = Vec::new();
+    for (key, part, instance) in parts {
+        match concatenations.last_mut() {
+            Some((_, first, first_instance, numbers)) if first.uid == part.uid => {
+                first_instance.join(instance);
+                numbers.push(part.number);
+            }
+            _ => {
+                let number = part.number;
+                concatenations.push((key, part, instance, vec![number]));
+            }
+        }
+    }
+
+    let mut instances = Vec::new();
+    for (key, first, instance, numbers) in concatenations {
+        let total = first.total.unwrap_or(numbers.len() as u32);
+        let is_complete = numbers.len() as u32 == total
+            && numbers
+                .iter()
+                .enumerate()
+                .all(|(index, number)| *number == index as u32 + 1);
+        if is_complete {
+            instances.push((key, instance));
+            continue;
+        }
+        let numbers: Vec<String> = numbers.iter().map(u32::to_string).collect();
+        let error = Error::invalid_attribute(
+            dicom_tags::IN_CONCATENATION_NUMBER,
+            format!(
+                "the concatenation {} has the instances {} of {}",
+                first.uid,
+                numbers.join(", "),
+                total
+            ),
+        );
+        report(ValidationIssue::new(
+            Severity::Error,
+            Error::Instance {
+                index: instance.index,
+                sop_instance_uid: instance.sop_instance_uid.clone(),
+                image: None,
+                source: Box::new(error),
+            },
+        ))?;
+    }
+    Ok(instances)
+}
+
+/// Reads the attributes before the pixel data of an instance, and merges those of the further
+/// instances of its concatenation, if any: the frames of all instances are counted, and their
+/// per-frame functional groups joined, as if they were a single instance.
+pub(crate) fn read_header(
+    source: SharedReadSeek,
+    concatenation: &[SharedReadSeek],
+) -> Result<DefaultDicomObject> {
+    let mut header = read_dicom_header(source)?;
+    if concatenation.is_empty() {
+        return Ok(header);
+    }
+
+    let transfer_syntax =
+        |obj: &DefaultDicomObject| trim_padding(obj.meta().transfer_syntax()).unwrap_or_default();
+    let header_transfer_syntax = transfer_syntax(&header);
+    let mut num_frames = get_number_of_frames(&header)?;
+    let mut per_frame_items = get_per_frame_items(&header, num_frames)?;
+    for source in concatenation {
+        let part = read_dicom_header(source.clone())?;
+        let part_transfer_syntax = transfer_syntax(&part);
+        if part_transfer_syntax != header_transfer_syntax {
+            return Err(Error::UnsupportedTransferSyntax(format!(
+                "{} in an instance of a concatenation of {}",
+                part_transfer_syntax, header_transfer_syntax
+            )));
+        }
+        let frame_offset = get_element_opt(&part, dicom_tags::CONCATENATION_FRAME_OFFSET_NUMBER)?
+            .map(|e| e.to_int::<usize>())
+            .transpose()
+            .map_err(|e| {
+                Error::invalid_attribute(dicom_tags::CONCATENATION_FRAME_OFFSET_NUMBER, e)
+            })?;
+        if frame_offset.is_some_and(|offset| offset != num_frames) {
+            return Err(Error::invalid_attribute(
+                dicom_tags::CONCATENATION_FRAME_OFFSET_NUMBER,
+                format!(
+                    "{} where the frames of the instances before end at {}",
+                    frame_offset.unwrap_or_default(),
+                    num_frames
+                ),
+            ));
+        }
+        let part_frames = get_number_of_frames(&part)?;
+        match (
+            &mut per_frame_items,
+            get_per_frame_items(&part, part_frames)?,
+        ) {
+            (Some(items), Some(part_items)) => items.extend(part_items),
+            (None, None) => {}
+            _ => {
+                return Err(Error::invalid_attribute(
+                    dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+                    "only some instances of the concatenation have per-frame functional groups",
+                ));
+            }
+        }
+        num_frames += part_frames;
+    }
+
+    header.put(DataElement::new(
+        dicom_tags::NUMBER_OF_FRAMES,
+        VR::IS,
+        PrimitiveValue::from(num_frames.to_string()),
+    ));
+    if let Some(items) = per_frame_items {
+        header.put(DataElement::new(
+            dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            VR::SQ,
+            DataSetSequence::from(items),
+        ));
+    }
+    // The offset tables are those of the pixel data of the first instance only
+    header.remove_element(dicom_tags::EXTENDED_OFFSET_TABLE);
+    header.remove_element(dicom_tags::EXTENDED_OFFSET_TABLE_LENGTHS);
+    Ok(header)
+}
+
+/// The per-frame functional groups of an instance, which must be one item per frame.
+fn get_per_frame_items(
+    header: &InMemDicomObject,
+    num_frames: usize,
+) -> Result<Option<Vec<InMemDicomObject>>> {
+    let Some(items) = get_element_opt(header, dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)?
+        .and_then(|e| e.items())
+    else {
+        return Ok(None);
+    };
+    if items.len() != num_frames {
+        return Err(Error::invalid_attribute(
+            dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            format!("{} items for {} frames", items.len(), num_frames),
+        ));
+    }
+    Ok(Some(items.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_dictionary_std::uids;
+
+    use super::*;
+    use crate::testing::{file, plane_position, sequence, strings};
+
+    const UID: &str = "1.2.3.4";
+
+    /// The instance `number` of a concatenation of `total`, whose frames follow `frame_offset`
+    /// others, each with its index in the concatenation as its column position.
+    fn part_object(
+        (number, total): (u16, Option<u16>),
+        frame_offset: usize,
+        num_frames: usize,
+    ) -> InMemDicomObject {
+        let mut obj = InMemDicomObject::from_element_iter([
+            strings(dicom_tags::CONCATENATION_UID, VR::UI, &[UID]),
+            DataElement::new(
+                dicom_tags::IN_CONCATENATION_NUMBER,
+                VR::US,
+                PrimitiveValue::from(number),
+            ),
+            DataElement::new(
+                dicom_tags::CONCATENATION_FRAME_OFFSET_NUMBER,
+                VR::UL,
+                PrimitiveValue::from(frame_offset as u32),
+            ),
+            strings(
+                dicom_tags::NUMBER_OF_FRAMES,
+                VR::IS,
+                &[&num_frames.to_string()],
+            ),
+            sequence(
+                dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+                (frame_offset..frame_offset + num_frames)
+                    .map(|frame| plane_position(frame as i32 + 1, 1, None))
+                    .collect(),
+            ),
+        ]);
+        if let Some(total) = total {
+            obj.put(DataElement::new(
+                dicom_tags::IN_CONCATENATION_TOTAL_NUMBER,
+                VR::US,
+                PrimitiveValue::from(total),
+            ));
+        }
+        obj
+    }
+
+    fn instance<'a>(
+        index: usize,
+        obj: InMemDicomObject,
+        transfer_syntax: &str,
+    ) -> DicomInstance<'a> {
+        DicomInstance {
+            index,
+            sop_instance_uid: None,
+            tiles: 0,
+            z_offsets: vec![0.0],
+            optical_paths: Vec::new(),
+            source: SharedReadSeek::from_read_seek(file(obj, transfer_syntax)),
+            concatenation: Vec::new(),
+        }
+    }
+
+    /// The parts of a concatenation of instances of the given numbers of frames, in the given
+    /// order, keyed by their index in the sources.
+    fn parts<'a>(
+        frames: &[usize],
+        order: &[usize],
+        total: Option<u16>,
+    ) -> Vec<(usize, ConcatenationPart, DicomInstance<'a>)> {
+        order
+            .iter()
+            .map(|&index| {
+                let frame_offset = frames[..index].iter().sum();
+                let number = index as u16 + 1;
+                let obj = part_object((number, total), frame_offset, frames[index]);
+                let part = ConcatenationPart::read(&obj).unwrap().unwrap();
+                (index, part, instance(index, obj, uids::JPEG_BASELINE8_BIT))
+            })
+            .collect()
+    }
+
+    fn frame_columns(header: &InMemDicomObject) -> Vec<i64> {
+        let items = header
+            .element(dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap();
+        items
+            .iter()
+            .map(|item| {
+                item.element(dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE)
+                    .unwrap()
+                    .items()
+                    .unwrap()[0]
+                    .element(dicom_tags::COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX)
+                    .unwrap()
+                    .to_int()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parts_are_joined_in_the_order_of_their_numbers() {
+        let mut issues = Vec::new();
+        let joined = join_concatenations(parts(&[2, 1, 3], &[2, 0, 1], Some(3)), |issue| {
+            issues.push(issue);
+            Ok(())
+        })
+        .unwrap();
+        assert!(issues.is_empty());
+        assert_eq!(joined.len(), 1);
+        let (key, instance) = &joined[0];
+        assert_eq!(
+            (*key, instance.index, instance.concatenation.len()),
+            (0, 0, 2)
+        );
+
+        let header = instance.read_header().unwrap();
+        assert_eq!(get_number_of_frames(&header).unwrap(), 6);
+        assert_eq!(frame_columns(&header), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn incomplete_concatenations_are_reported() {
+        // Without the instance 2 of 3, and of an unknown total
+        for (order, total) in [([0, 2], Some(3)), ([2, 0], None)] {
+            let mut issues = Vec::new();
+            let joined = join_concatenations(parts(&[1, 1, 1], &order, total), |issue| {
+                issues.push(issue);
+                Ok(())
+            })
+            .unwrap();
+            assert!(joined.is_empty());
+            assert_eq!(issues.len(), 1);
+            assert_eq!(issues[0].severity, Severity::Error);
+            assert!(matches!(
+                &issues[0].error,
+                Error::Instance { index: 0, source, .. }
+                    if matches!(**source, Error::InvalidAttribute { tag, .. }
+                        if tag == dicom_tags::IN_CONCATENATION_NUMBER)
+            ));
+        }
+
+        let result = join_concatenations(parts(&[1, 1], &[1], None), |issue| Err(issue.error));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn frame_offsets_must_follow_the_frames_before() {
+        // An overlap and a gap after the 2 frames of the first instance
+        for frame_offset in [1, 3] {
+            let first = instance(0, part_object((1, Some(2)), 0, 2), uids::JPEG_BASELINE8_BIT);
+            let second = instance(
+                1,
+                part_object((2, Some(2)), frame_offset, 1),
+                uids::JPEG_BASELINE8_BIT,
+            );
+            let result = read_header(first.source, &[second.source]);
+            assert!(
+                matches!(
+                    result,
+                    Err(Error::InvalidAttribute { tag, .. })
+                        if tag == dicom_tags::CONCATENATION_FRAME_OFFSET_NUMBER
+                ),
+                "offset {}",
+                frame_offset
+            );
+        }
+    }
+
+    #[test]
+    fn transfer_syntaxes_must_match() {
+        let read = |first_transfer_syntax, second_transfer_syntax| {
+            let first = instance(0, part_object((1, Some(2)), 0, 1), first_transfer_syntax);
+            let second = instance(1, part_object((2, Some(2)), 1, 1), second_transfer_syntax);
+            read_header(first.source, &[second.source])
+        };
+
+        assert!(matches!(
+            read(uids::JPEG_BASELINE8_BIT, uids::JPEG2000),
+            Err(Error::UnsupportedTransferSyntax(_))
+        ));
+        // Padded to an even length
+        let header = read(
+            uids::EXPLICIT_VR_LITTLE_ENDIAN,
+            uids::EXPLICIT_VR_LITTLE_ENDIAN,
+        )
+        .unwrap();
+        assert_eq!(get_number_of_frames(&header).unwrap(), 2);
+    }
+}
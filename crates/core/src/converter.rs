@@ -0,0 +1,589 @@
+use std::fmt;
+use std::io::{self, Read, Seek, Write};
+use std::sync::Arc;
+
+#[cfg(any(feature = "tiles", feature = "zarr"))]
+use crate::OutputStore;
+use crate::cancellation::CancellationToken;
+use crate::error::{Error, Result};
+use crate::info::{self, SlideInfo};
+use crate::progress::{Progress, ProgressCallback};
+use crate::slide::{self, InstanceType, Slide, SlideDiscovery};
+#[cfg(feature = "tiles")]
+use crate::tiles_writer;
+use crate::validation::{self, ValidationReport};
+use crate::workers::Parallelism;
+#[cfg(feature = "zarr")]
+use crate::{ChunkCompression, zarr_writer};
+use crate::{NativeCompression, dicom_writer, tiff_writer};
+
+/// The layout and metadata conventions of the output TIFF.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OutputFlavor {
+    /// A TIFF which OpenSlide and other readers recognize as an Aperio SVS file. This allows
+    /// JPEG 2000 tiles and associated images.
+    #[default]
+    Aperio,
+    /// A plain tiled pyramidal TIFF without an Aperio ImageDescription, in which the levels
+    /// after the first are flagged as reduced-resolution images (NewSubfileType), as read by
+    /// libvips, GDAL and Bio-Formats. It has no associated images, and levels with JPEG 2000
+    /// tiles cannot be written, since TIFF has no standard JPEG 2000 compression.
+    Generic,
+    /// An OME-TIFF, as read by Bio-Formats, QuPath and napari, with the reduced levels in
+    /// SubIFDs of level 0 and OME-XML in its ImageDescription. Like the generic flavor, it has
+    /// no associated images or JPEG 2000 tiles. Unless the metadata policy is set, the OME-XML
+    /// has the acquisition date and the instrument, as with [`MetadataPolicy::Full`].
+    Ome,
+}
+
+/// The layout of a static tile tree written by [`Converter::convert_slide_to_tiles`].
+#[cfg(feature = "tiles")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TileLayout {
+    /// A Deep Zoom image, as read by OpenSeadragon: `{name}.dzi` and the tiles in
+    /// `{name}_files/{level}/{column}_{row}.jpeg`, from level 0 of a single pixel up.
+    Dzi { name: String },
+    /// IIIF Image API 3.0 level 0 static tiles: `info.json`, with `id` as the URI of the image
+    /// service (where the tiles are served), and the tiles in `{region}/{size}/0/default.jpg`.
+    Iiif { id: String },
+}
+
+/// Which pyramid levels are written, by their index from level 0 (the largest) up.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum LevelSelection {
+    #[default]
+    All,
+    /// Only the levels with the given indices
+    Only(Vec<usize>),
+    /// Only the given number of levels, starting from level 0
+    First(usize),
+}
+
+impl LevelSelection {
+    pub(crate) fn includes(&self, level: usize) -> bool {
+        match self {
+            LevelSelection::All => true,
+            LevelSelection::Only(levels) => levels.contains(&level),
+            LevelSelection::First(count) => level < *count,
+        }
+    }
+}
+
+/// Which focal planes of a slide scanned at several depths (a Z-stack) are written. Planes are
+/// told apart by the Z offsets of their plane positions, whether they are in one instance or in
+/// an instance each.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FocalPlaneSelection {
+    /// The plane nearest to Z offset 0, where the scanner focused
+    #[default]
+    Nominal,
+    /// The plane at this index, from the lowest Z offset of level 0 up. Other levels use their
+    /// plane nearest to it.
+    Index(usize),
+    /// The plane nearest to this Z offset in the slide coordinate system, in millimeters
+    ZOffset(f64),
+    /// An extended depth of field image: every tile from the plane whose frame is the largest,
+    /// which is usually the sharpest. The planes of every level must be in a single instance.
+    /// Native frames all have the same size, so they are taken from the nominal plane.
+    ExtendedDepthOfField,
+    /// Every plane, as the Z planes of an OME-TIFF, each with its pyramid in SubIFDs. Only
+    /// supported with [`OutputFlavor::Ome`].
+    All,
+}
+
+/// Which channels of a slide with several optical paths, e.g. of fluorescence, are written.
+/// Channels are told apart by the identifiers of the optical paths of their frames, whether
+/// they are in one instance or in an instance each.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChannelSelection {
+    /// The first optical path of level 0
+    #[default]
+    First,
+    /// The optical path with this Optical Path Identifier
+    Identifier(String),
+    /// Every optical path, as the channels of an OME-TIFF, each with its pyramid in SubIFDs.
+    /// Only supported with [`OutputFlavor::Ome`].
+    All,
+}
+
+/// Which associated images are written, if the input has them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AssociatedImages {
+    pub thumbnail: bool,
+    pub label: bool,
+    pub overview: bool,
+}
+
+impl AssociatedImages {
+    pub fn all() -> Self {
+        Self {
+            thumbnail: true,
+            label: true,
+            overview: true,
+        }
+    }
+
+    pub fn none() -> Self {
+        Self {
+            thumbnail: false,
+            label: false,
+            overview: false,
+        }
+    }
+}
+
+impl Default for AssociatedImages {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// What happens to the ICC profiles of the optical paths.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IccProfilePolicy {
+    /// Write the ICC profile in the IccProfile tag of every image
+    #[default]
+    Preserve,
+    /// Leave out ICC profiles
+    Omit,
+}
+
+/// Which metadata of the DICOM instances is written to the TIFF. The default is
+/// [`Full`](MetadataPolicy::Full) for [`OutputFlavor::Ome`] and
+/// [`Minimal`](MetadataPolicy::Minimal) otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataPolicy {
+    /// Only the resolution (MPP) of every image
+    Minimal,
+    /// Also the acquisition date and time, the scanner and the objective lens power, in the
+    /// ImageDescription of level 0 and the DateTime, Make, Model and Software tags.
+    Full,
+}
+
+/// How invalid or unsupported input is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Fail the conversion
+    #[default]
+    Strict,
+    /// Leave out invalid resolution and ICC profile metadata and skip pyramid levels and
+    /// associated images which cannot be converted. The conversion still fails if no pyramid
+    /// level can be converted, or if reading or writing fails.
+    Lenient,
+}
+
+/// A predicate which tells which instances are pyramid levels, instead of
+/// [`InstanceType::is_pyramid_level`].
+#[derive(Clone)]
+pub(crate) struct LevelPredicate(Arc<dyn Fn(&InstanceType) -> bool + Send + Sync>);
+
+impl fmt::Debug for LevelPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LevelPredicate")
+    }
+}
+
+/// Options of a conversion, built with chained setters starting from the defaults, which match
+/// [`convert_dicom_sources`](crate::convert_dicom_sources).
+#[derive(Clone, Debug)]
+pub struct ConversionOptions {
+    pub(crate) flavor: OutputFlavor,
+    pub(crate) bigtiff: bool,
+    pub(crate) levels: LevelSelection,
+    pub(crate) associated_images: AssociatedImages,
+    pub(crate) icc_profile: IccProfilePolicy,
+    pub(crate) metadata: Option<MetadataPolicy>,
+    pub(crate) strictness: Strictness,
+    pub(crate) native_compression: NativeCompression,
+    pub(crate) focal_planes: FocalPlaneSelection,
+    pub(crate) channels: ChannelSelection,
+    pub(crate) level_predicate: Option<LevelPredicate>,
+    #[cfg(feature = "downsample")]
+    pub(crate) synthesize_levels: bool,
+    #[cfg(feature = "zarr")]
+    pub(crate) chunk_compression: ChunkCompression,
+    #[cfg(feature = "tiles")]
+    pub(crate) static_tile_size: Option<u32>,
+    #[cfg(feature = "tiles")]
+    pub(crate) static_tile_quality: u8,
+    pub(crate) study_instance_uid: Option<String>,
+    pub(crate) series_instance_uid: Option<String>,
+    pub(crate) container_identifier: Option<String>,
+}
+
+impl Default for ConversionOptions {
+    fn default() -> Self {
+        Self {
+            flavor: OutputFlavor::default(),
+            bigtiff: true,
+            levels: LevelSelection::default(),
+            associated_images: AssociatedImages::default(),
+            icc_profile: IccProfilePolicy::default(),
+            metadata: None,
+            strictness: Strictness::default(),
+            native_compression: NativeCompression::default(),
+            focal_planes: FocalPlaneSelection::default(),
+            channels: ChannelSelection::default(),
+            level_predicate: None,
+            #[cfg(feature = "downsample")]
+            synthesize_levels: false,
+            #[cfg(feature = "zarr")]
+            chunk_compression: ChunkCompression::default(),
+            #[cfg(feature = "tiles")]
+            static_tile_size: None,
+            #[cfg(feature = "tiles")]
+            static_tile_quality: 90,
+            study_instance_uid: None,
+            series_instance_uid: None,
+            container_identifier: None,
+        }
+    }
+}
+
+impl ConversionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flavor(mut self, flavor: OutputFlavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Whether to write a BigTIFF (the default) or a classic TIFF, which is limited to 4 GiB.
+    pub fn bigtiff(mut self, bigtiff: bool) -> Self {
+        self.bigtiff = bigtiff;
+        self
+    }
+
+    pub fn levels(mut self, levels: LevelSelection) -> Self {
+        self.levels = levels;
+        self
+    }
+
+    pub fn associated_images(mut self, associated_images: AssociatedImages) -> Self {
+        self.associated_images = associated_images;
+        self
+    }
+
+    pub fn icc_profile(mut self, icc_profile: IccProfilePolicy) -> Self {
+        self.icc_profile = icc_profile;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: MetadataPolicy) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn strictness(mut self, strictness: Strictness) -> Self {
+        self.strictness = strictness;
+        self
+    }
+
+    /// The compression of tiles created from native (uncompressed) or RLE compressed pixel data.
+    pub fn native_compression(mut self, native_compression: NativeCompression) -> Self {
+        self.native_compression = native_compression;
+        self
+    }
+
+    pub fn focal_planes(mut self, focal_planes: FocalPlaneSelection) -> Self {
+        self.focal_planes = focal_planes;
+        self
+    }
+
+    pub fn channels(mut self, channels: ChannelSelection) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    /// Tells which instances are pyramid levels, instead of
+    /// [`InstanceType::is_pyramid_level`]. Instances which are neither pyramid levels nor
+    /// associated images are left out of the slides, as reported by
+    /// [`Converter::discover_slides`].
+    pub fn pyramid_levels_by(
+        mut self,
+        predicate: impl Fn(&InstanceType) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.level_predicate = Some(LevelPredicate(Arc::new(predicate)));
+        self
+    }
+
+    /// Whether to synthesize reduced-resolution levels below the smallest written level of a
+    /// TIFF, until a level fits in a single tile, for slides without them. Each level is half the
+    /// size of the one before, and its tiles average the pixels of the level before and are
+    /// encoded as 8 bit JPEG, so the smallest level must be of frames which can be decoded
+    /// (JPEG baseline, RLE or native). Off by default.
+    #[cfg(feature = "downsample")]
+    pub fn synthesize_levels(mut self, synthesize_levels: bool) -> Self {
+        self.synthesize_levels = synthesize_levels;
+        self
+    }
+
+    /// The compression of the chunks of OME-Zarr outputs.
+    #[cfg(feature = "zarr")]
+    pub fn chunk_compression(mut self, chunk_compression: ChunkCompression) -> Self {
+        self.chunk_compression = chunk_compression;
+        self
+    }
+
+    /// The width and height of static tiles, by default the tile width of the largest level, so
+    /// that its JPEG frames can be copied as tiles.
+    #[cfg(feature = "tiles")]
+    pub fn static_tile_size(mut self, tile_size: u32) -> Self {
+        self.static_tile_size = Some(tile_size);
+        self
+    }
+
+    /// The JPEG quality (1 to 100) of static tiles which are not copied from frames, 90 by
+    /// default.
+    #[cfg(feature = "tiles")]
+    pub fn static_tile_quality(mut self, quality: u8) -> Self {
+        self.static_tile_quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// The Study Instance UID of DICOM outputs, by default a new UID.
+    pub fn study_instance_uid(mut self, uid: impl Into<String>) -> Self {
+        self.study_instance_uid = Some(uid.into());
+        self
+    }
+
+    /// The Series Instance UID of DICOM outputs, by default a new UID.
+    pub fn series_instance_uid(mut self, uid: impl Into<String>) -> Self {
+        self.series_instance_uid = Some(uid.into());
+        self
+    }
+
+    /// The identifier of the slide (Container Identifier and Specimen Identifier) in DICOM
+    /// outputs, by default "Unknown".
+    pub fn container_identifier(mut self, identifier: impl Into<String>) -> Self {
+        self.container_identifier = Some(identifier.into());
+        self
+    }
+
+    /// The metadata policy, or the default of the flavor if none is set.
+    pub(crate) fn metadata_policy(&self) -> MetadataPolicy {
+        match (self.metadata, self.flavor) {
+            (Some(metadata), _) => metadata,
+            (None, OutputFlavor::Ome) => MetadataPolicy::Full,
+            (None, _) => MetadataPolicy::Minimal,
+        }
+    }
+
+    pub(crate) fn is_pyramid_level(&self, instance_type: &InstanceType) -> bool {
+        match &self.level_predicate {
+            Some(predicate) => (predicate.0)(instance_type),
+            None => instance_type.is_pyramid_level(),
+        }
+    }
+
+    /// Turns the error of an optional part of the output into `None` in lenient mode. Reading
+    /// and writing errors and cancellation are never ignored.
+    pub(crate) fn skip_error<T>(&self, result: Result<T>) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e @ (Error::Io(_) | Error::Cancelled)) => Err(e),
+            Err(_) if self.strictness == Strictness::Lenient => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Converts DICOM WSI instances to pyramidal TIFFs with the given options, and pyramidal TIFFs
+/// back to DICOM WSI instances.
+#[derive(Clone, Debug)]
+pub struct Converter {
+    options: ConversionOptions,
+    progress: Option<ProgressCallback>,
+    progress_interval: u64,
+    parallelism: Parallelism,
+    cancellation: CancellationToken,
+}
+
+impl Default for Converter {
+    fn default() -> Self {
+        Self::new(ConversionOptions::default())
+    }
+}
+
+impl Converter {
+    pub fn new(options: ConversionOptions) -> Self {
+        Self {
+            options,
+            progress: None,
+            progress_interval: 64,
+            parallelism: Parallelism::default(),
+            cancellation: CancellationToken::new(),
+        }
+    }
+
+    pub fn options(&self) -> &ConversionOptions {
+        &self.options
+    }
+
+    /// Calls `callback` with the progress of every conversion: when a pyramid level starts and
+    /// finishes, every [`progress_interval`](Converter::progress_interval) tiles in between,
+    /// and when the conversion finishes.
+    pub fn on_progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(ProgressCallback::new(callback));
+        self
+    }
+
+    /// How many tiles are written between progress reports, 64 by default.
+    pub fn progress_interval(mut self, tiles: u64) -> Self {
+        self.progress_interval = tiles;
+        self
+    }
+
+    /// Reads and prepares the tiles of every conversion (decoding RLE, compressing native
+    /// frames, stripping shared JPEG tables) on a pool of `threads` threads, or on the global
+    /// rayon pool if `threads` is 0. Levels are parsed in parallel too. A single writer still
+    /// writes everything in order, so the output is the same as without threads, which is the
+    /// default.
+    #[cfg(feature = "parallel")]
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.parallelism = Parallelism::Threads(threads);
+        self
+    }
+
+    /// Makes conversions fail with [`Error::Cancelled`] once `token` is cancelled.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Converts the slide of the given sources, which must contain a single slide. Use
+    /// [`Converter::discover_slides`] and [`Converter::convert_slide`] to convert inputs with
+    /// several slides.
+    pub fn convert<R: Read + Seek + Send, W: Write + Seek>(
+        &self,
+        dicom_sources: Vec<R>,
+        output: W,
+    ) -> Result<()> {
+        let slides = self.discover_slides(dicom_sources)?.slides;
+        match &slides[..] {
+            [] => Err(Error::NoPyramidLevels),
+            [slide] => self.convert_slide(slide, output),
+            _ => Err(Error::MultipleSlides(
+                slides.iter().map(|slide| slide.id().clone()).collect(),
+            )),
+        }
+    }
+
+    /// Reads the headers of the given sources and groups them into slides like
+    /// [`discover_slides`](crate::discover_slides), with the pyramid levels of the options, and
+    /// reports why every other instance is left out.
+    pub fn discover_slides<'a, R: Read + Seek + Send + 'a>(
+        &self,
+        dicom_sources: Vec<R>,
+    ) -> Result<SlideDiscovery<'a>> {
+        slide::discover(dicom_sources, &self.options)
+    }
+
+    /// Walks the given sources as [`Converter::convert`] would, reading the headers of all
+    /// instances and locating the frames of the images it converts without decoding them, and
+    /// reports every problem instead of stopping at the first: the image types, the image
+    /// attributes, the mapping of the photometric interpretation and compression to the TIFF,
+    /// the functional groups of sparse tiles, the optical paths and the number of frames
+    /// against the tile grid. Errors are the problems which would fail the conversion, or
+    /// which the TIFF cannot be read with; warnings are those which the conversion skips in
+    /// lenient mode, and instances, images or frames which it leaves out. Nothing is written,
+    /// and only errors reading the sources are returned as errors.
+    pub fn validate<R: Read + Seek + Send>(
+        &self,
+        dicom_sources: Vec<R>,
+    ) -> Result<ValidationReport> {
+        validation::validate(dicom_sources, &self.options)
+    }
+
+    pub fn convert_slide<W: Write + Seek>(&self, slide: &Slide, output: W) -> Result<()> {
+        tiff_writer::write_pyramid(
+            slide.sources(),
+            output,
+            &self.options,
+            self.progress.as_ref(),
+            self.progress_interval,
+            &self.parallelism.workers()?,
+            &self.cancellation,
+        )
+    }
+
+    /// Reads the headers of the instances of a slide, up to their pixel data, and describes its
+    /// levels, associated images and optical paths, with the problems which would fail its
+    /// conversion to a TIFF with the options of the converter. Only errors reading the sources
+    /// are returned as errors.
+    pub fn probe_slide(&self, slide: &Slide) -> Result<SlideInfo> {
+        info::probe_slide(slide.id(), slide.sources(), &self.options)
+    }
+
+    /// Converts a slide to an OME-Zarr (NGFF 0.4) multiscale image in `store`, with an array
+    /// of shape (c, y, x) per pyramid level, chunked by the tiles of the DICOM instance, and
+    /// the pixel spacing of the levels as their scale. JPEG and RLE frames are decoded, and
+    /// all chunks are stored with the [`chunk_compression`](ConversionOptions::chunk_compression)
+    /// of the options. The flavor, associated images, ICC profile and metadata options only
+    /// apply to TIFFs.
+    #[cfg(feature = "zarr")]
+    pub fn convert_slide_to_zarr<S: OutputStore>(&self, slide: &Slide, mut store: S) -> Result<()> {
+        zarr_writer::write_multiscales(
+            slide.sources(),
+            &mut store,
+            &self.options,
+            self.progress.as_ref(),
+            self.progress_interval,
+            &self.parallelism.workers()?,
+            &self.cancellation,
+        )
+    }
+
+    /// Converts a slide to static JPEG tiles in `store`, in a Deep Zoom or IIIF layout, at every
+    /// power of two downsampling of the largest level. Tiles of output levels which have the
+    /// size of a pyramid level with browser-compatible JPEG frames of the tile size are copied
+    /// from the frames without re-encoding. Other tiles are resampled from the smallest pyramid
+    /// level which is at least as large, and encoded with the
+    /// [`static_tile_quality`](ConversionOptions::static_tile_quality) of the options. Tiles
+    /// without pixel data in sparsely tiled images are not written.
+    #[cfg(feature = "tiles")]
+    pub fn convert_slide_to_tiles<S: OutputStore>(
+        &self,
+        slide: &Slide,
+        layout: &TileLayout,
+        mut store: S,
+    ) -> Result<()> {
+        tiles_writer::write_tiles(
+            slide.sources(),
+            &mut store,
+            layout,
+            &self.options,
+            self.progress.as_ref(),
+            self.progress_interval,
+            &self.parallelism.workers()?,
+            &self.cancellation,
+        )
+    }
+
+    /// Converts a tiled pyramidal TIFF (an Aperio SVS file, a generic pyramidal TIFF or an
+    /// OME-TIFF) to VL Whole Slide Microscopy Image instances, one per pyramid level, in the
+    /// outputs which `create_output` returns for the level indices. JPEG and JPEG 2000 tiles are
+    /// copied into encapsulated frames without recompression; other compressions are not
+    /// supported. The instances share a study, series, frame of reference and pyramid. The
+    /// levels, ICC profile, metadata and strictness options apply, and
+    /// [`MetadataPolicy::Full`] carries over the acquisition date, scanner and objective power
+    /// of Aperio files. Patient and study attributes are left empty for the archive to fill in.
+    pub fn convert_tiff_to_dicom<R: Read + Seek, W: Write>(
+        &self,
+        tiff_source: R,
+        create_output: impl FnMut(usize) -> io::Result<W>,
+    ) -> Result<()> {
+        dicom_writer::write_instances(
+            tiff_source,
+            create_output,
+            &self.options,
+            self.progress.as_ref(),
+            self.progress_interval,
+            &self.cancellation,
+        )
+    }
+}
@@ -0,0 +1,128 @@
+use jpeg_decoder::ColorTransform;
+use tiff::tags::PhotometricInterpretation as TiffPhotometricInterpretation;
+
+use crate::compression::{Codec, PixelEncoding};
+use crate::error::{Error, Result};
+use crate::frames::Frames;
+use crate::image::{DicomImage, TileData};
+
+/// The value of the 8 bit samples of pixels without pixel data (missing tiles of sparse images),
+/// which is white, like the background of a slide.
+#[cfg(feature = "downsample")]
+pub(crate) const BACKGROUND: u8 = 0xFF;
+
+/// Fails if the frames of an image cannot be decoded into RGB or monochrome samples.
+pub(crate) fn check_decodable(image: &DicomImage) -> Result<()> {
+    match image.pixel_encoding {
+        PixelEncoding::Encapsulated(Codec::Jpeg) => Ok(()),
+        PixelEncoding::Native | PixelEncoding::Encapsulated(Codec::Rle) => {
+            // Native YCbCr samples would have to be converted to RGB
+            if image.tiff_photometric_interpretation == TiffPhotometricInterpretation::YCbCr {
+                return Err(Error::UnsupportedPixelData(
+                    "native YBR pixel data cannot be decoded to RGB".to_string(),
+                ));
+            }
+            Ok(())
+        }
+        PixelEncoding::Encapsulated(codec) => Err(Error::UnsupportedPixelData(format!(
+            "{:?} compressed frames cannot be decoded",
+            codec
+        ))),
+    }
+}
+
+/// Fails if the frames of an image cannot be decoded into the samples of JPEG tiles.
+#[cfg(feature = "downsample")]
+pub(crate) fn check_jpeg_encodable(image: &DicomImage) -> Result<()> {
+    check_decodable(image)?;
+    if image.samples_per_pixel != 1 && image.samples_per_pixel != 3 {
+        return Err(Error::UnsupportedPixelData(format!(
+            "{} samples per pixel cannot be stored in JPEG tiles",
+            image.samples_per_pixel
+        )));
+    }
+    if image.is_signed {
+        return Err(Error::UnsupportedPixelData(
+            "signed samples cannot be stored in JPEG tiles".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The bytes of each decoded sample, 1 or 2.
+pub(crate) fn bytes_per_sample(image: &DicomImage, tile_data: &TileData) -> usize {
+    usize::from(image.bits_per_sample(tile_data)[0].div_ceil(8))
+}
+
+/// Decodes a frame into color-by-pixel RGB or monochrome samples of the whole tile, with 16 bit
+/// samples in native byte order.
+pub(crate) fn decode_frame(
+    image: &DicomImage,
+    tile_data: &TileData,
+    frame_index: usize,
+) -> Result<Vec<u8>> {
+    let frame = tile_data.frames.get(frame_index)?;
+    let tile = match &tile_data.frames {
+        Frames::Encapsulated(_) => decode_jpeg(&frame, image)?,
+        Frames::Native { .. } | Frames::Rle { .. } => frame.into_owned(),
+    };
+    let expected_len = usize::from(image.tile_width)
+        * usize::from(image.tile_height)
+        * usize::from(image.samples_per_pixel)
+        * bytes_per_sample(image, tile_data);
+    if tile.len() != expected_len {
+        return Err(Error::InvalidPixelData(format!(
+            "frame {} has {} bytes of samples instead of {}",
+            frame_index + 1,
+            tile.len(),
+            expected_len
+        )));
+    }
+    Ok(tile)
+}
+
+/// Decodes a frame into 8 bit color-by-pixel samples of the whole tile, where black is 0.
+/// Samples with more than 8 bits keep their most significant bits.
+#[cfg(feature = "downsample")]
+pub(crate) fn decode_frame_8_bit(
+    image: &DicomImage,
+    tile_data: &TileData,
+    frame_index: usize,
+) -> Result<Vec<u8>> {
+    let tile = decode_frame(image, tile_data, frame_index)?;
+    let mut samples = if bytes_per_sample(image, tile_data) == 2 {
+        let shift = image.bits_stored.saturating_sub(8).min(8);
+        tile.chunks_exact(2)
+            .map(|bytes| (u16::from_ne_bytes([bytes[0], bytes[1]]) >> shift).min(0xFF) as u8)
+            .collect()
+    } else {
+        tile
+    };
+    if image.tiff_photometric_interpretation == TiffPhotometricInterpretation::WhiteIsZero {
+        for value in &mut samples {
+            *value = 0xFF - *value;
+        }
+    }
+    Ok(samples)
+}
+
+/// Decodes a JPEG frame into color-by-pixel RGB or monochrome samples, 16 bit samples in native
+/// byte order.
+fn decode_jpeg(frame: &[u8], image: &DicomImage) -> Result<Vec<u8>> {
+    let mut decoder = jpeg_decoder::Decoder::new(frame);
+    // The photometric interpretation tells whether the components are YCbCr, which the decoder
+    // would otherwise guess from the markers of the frame
+    if image.samples_per_pixel == 3 {
+        decoder.set_color_transform(match image.tiff_photometric_interpretation {
+            TiffPhotometricInterpretation::YCbCr => ColorTransform::YCbCr,
+            _ => ColorTransform::RGB,
+        });
+    }
+    decoder.decode().map_err(|e| match e {
+        jpeg_decoder::Error::Unsupported(feature) => {
+            Error::UnsupportedPixelData(format!("JPEG frame uses {:?}", feature))
+        }
+        jpeg_decoder::Error::Io(e) => Error::Io(e),
+        e => Error::InvalidPixelData(format!("invalid JPEG frame: {}", e)),
+    })
+}
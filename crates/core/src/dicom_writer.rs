@@ -0,0 +1,1349 @@
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dicom_core::value::DataSetSequence;
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::{tags as dicom_tags, uids};
+use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+use tiff::tags::{CompressionMethod, PhotometricInterpretation, Tag as TiffTag};
+
+use crate::cancellation::CancellationToken;
+use crate::compression::{APERIO_COMPRESSION_JP2K_RGB, APERIO_COMPRESSION_JP2K_YCBCR};
+use crate::converter::{ConversionOptions, IccProfilePolicy, MetadataPolicy};
+use crate::error::{Error, ImageKind, Result};
+use crate::jpeg;
+use crate::progress::{ProgressCallback, ProgressTracker};
+use crate::tiff_reader::{Ifd, TiffReader};
+
+/// The NewSubfileType bit of transparency masks.
+const SUBFILE_MASK: u64 = 4;
+
+/// Converts the tiled images of a pyramidal TIFF (an Aperio SVS file, a generic pyramidal TIFF
+/// or an OME-TIFF with its levels in SubIFDs) to VL Whole Slide Microscopy Image instances, one
+/// per pyramid level. The tiles are copied into encapsulated frames without decoding them, and
+/// each instance is written to the output which `create_output` returns for its level.
+pub(crate) fn write_instances<R: Read + Seek, W: Write>(
+    tiff_source: R,
+    mut create_output: impl FnMut(usize) -> io::Result<W>,
+    options: &ConversionOptions,
+    progress_callback: Option<&ProgressCallback>,
+    progress_interval: u64,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    cancellation.check()?;
+    let mut tiff = TiffReader::new(tiff_source)?;
+    let levels = read_pyramid(&mut tiff)?;
+    let series = SeriesAttributes::new(&levels[0].ifd, options);
+
+    let selected_levels = levels
+        .iter()
+        .enumerate()
+        .filter(|(level, _)| options.levels.includes(*level))
+        .collect::<Vec<_>>();
+    let mut progress = ProgressTracker::new(
+        progress_callback,
+        progress_interval,
+        selected_levels.len(),
+        selected_levels
+            .iter()
+            .map(|(_, image)| image.tile_count() as u64)
+            .sum(),
+    );
+
+    for (level, image) in selected_levels {
+        cancellation.check()?;
+        progress.start_level(level, image.tile_count() as u64);
+        let sop_instance_uid = generate_uid();
+        let level_error = |e| Error::Instance {
+            index: level,
+            sop_instance_uid: Some(sop_instance_uid.clone()),
+            image: Some(ImageKind::Level(level)),
+            source: Box::new(e),
+        };
+        let Some(encoding) = options
+            .skip_error(get_frame_encoding(&mut tiff, image))
+            .map_err(level_error)?
+        else {
+            progress.finish_level();
+            continue;
+        };
+
+        let dataset = instance_dataset(&series, image, level, &encoding, &sop_instance_uid);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid(sop_instance_uid.as_str())
+            .transfer_syntax(encoding.transfer_syntax_uid)
+            .build()
+            .expect("the required file meta attributes are set");
+        let mut output = create_output(level)?;
+        dataset
+            .with_exact_meta(meta)
+            .write_all(&mut output)
+            .map_err(|e| level_error(e.into()))?;
+        write_pixel_data(&mut tiff, &mut output, image, &mut progress, cancellation)
+            .map_err(level_error)?;
+        output.flush()?;
+        progress.finish_level();
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+/// A tiled image of the TIFF, which is written as a pyramid level.
+struct TiffImage {
+    ifd: Ifd,
+    width: u32,
+    height: u32,
+    tile_width: u16,
+    tile_height: u16,
+    compression: CompressionMethod,
+    photometric_interpretation: Option<PhotometricInterpretation>,
+    samples_per_pixel: u16,
+    bits_per_sample: u16,
+    tile_offsets: Vec<u64>,
+    /// The byte counts of the tiles, 0 for tiles which are missing
+    tile_byte_counts: Vec<u64>,
+    /// (x, y) in millimeters
+    pixel_spacing: Option<(f64, f64)>,
+}
+
+impl TiffImage {
+    /// Reads a tiled image from its IFD, or returns `None` if it is stripped (like the
+    /// associated images of Aperio SVS files) or a transparency mask.
+    fn from_ifd(ifd: Ifd) -> Result<Option<Self>> {
+        let (Some(tile_width), Some(tile_height)) = (
+            ifd.unsigned(TiffTag::TileWidth),
+            ifd.unsigned(TiffTag::TileLength),
+        ) else {
+            return Ok(None);
+        };
+        if ifd.unsigned(TiffTag::NewSubfileType).unwrap_or(0) & SUBFILE_MASK != 0 {
+            return Ok(None);
+        }
+        let required = |tag: TiffTag| {
+            ifd.unsigned(tag)
+                .ok_or_else(|| Error::InvalidTiff(format!("a tiled image has no {:?} tag", tag)))
+        };
+        let width = u32::try_from(required(TiffTag::ImageWidth)?)
+            .map_err(|_| Error::InvalidTiff("the image width is too large".to_string()))?;
+        let height = u32::try_from(required(TiffTag::ImageLength)?)
+            .map_err(|_| Error::InvalidTiff("the image height is too large".to_string()))?;
+        let (Ok(tile_width), Ok(tile_height)) =
+            (u16::try_from(tile_width), u16::try_from(tile_height))
+        else {
+            return Err(Error::InvalidTiff(format!(
+                "{}x{} tiles are larger than DICOM frames can be",
+                tile_width, tile_height
+            )));
+        };
+        if width == 0 || height == 0 || tile_width == 0 || tile_height == 0 {
+            return Err(Error::InvalidTiff(format!(
+                "a {}x{} image has {}x{} tiles",
+                width, height, tile_width, tile_height
+            )));
+        }
+
+        let tile_offsets = ifd
+            .unsigned_vec(TiffTag::TileOffsets)
+            .unwrap_or_default()
+            .to_vec();
+        let tile_byte_counts = ifd
+            .unsigned_vec(TiffTag::TileByteCounts)
+            .unwrap_or_default()
+            .to_vec();
+        let tile_count = width.div_ceil(u32::from(tile_width)) as usize
+            * height.div_ceil(u32::from(tile_height)) as usize;
+        if tile_offsets.len() != tile_count || tile_byte_counts.len() != tile_count {
+            return Err(Error::InvalidTiff(format!(
+                "a {}x{} image with {}x{} tiles has {} tile offsets and {} byte counts",
+                width,
+                height,
+                tile_width,
+                tile_height,
+                tile_offsets.len(),
+                tile_byte_counts.len()
+            )));
+        }
+
+        let image = Self {
+            width,
+            height,
+            tile_width,
+            tile_height,
+            compression: CompressionMethod::from_u16_exhaustive(
+                ifd.unsigned(TiffTag::Compression).unwrap_or(1) as u16,
+            ),
+            photometric_interpretation: ifd
+                .unsigned(TiffTag::PhotometricInterpretation)
+                .and_then(|value| PhotometricInterpretation::from_u16(value as u16)),
+            samples_per_pixel: ifd.unsigned(TiffTag::SamplesPerPixel).unwrap_or(1) as u16,
+            bits_per_sample: ifd.unsigned(TiffTag::BitsPerSample).unwrap_or(1) as u16,
+            tile_offsets,
+            tile_byte_counts,
+            pixel_spacing: get_pixel_spacing(&ifd),
+            ifd,
+        };
+        Ok(Some(image))
+    }
+
+    fn tiles_across(&self) -> u32 {
+        self.width.div_ceil(u32::from(self.tile_width))
+    }
+
+    /// The number of tiles which are not missing, which become the frames of the instance.
+    fn tile_count(&self) -> usize {
+        self.tile_byte_counts
+            .iter()
+            .filter(|&&byte_count| byte_count > 0)
+            .count()
+    }
+
+    fn is_sparse(&self) -> bool {
+        self.tile_count() < self.tile_byte_counts.len()
+    }
+}
+
+/// Reads the IFDs of the TIFF, including SubIFDs, and returns the tiled images which make up
+/// the pyramid, from the largest level down.
+fn read_pyramid<R: Read + Seek>(tiff: &mut TiffReader<R>) -> Result<Vec<TiffImage>> {
+    let mut images = Vec::new();
+    let mut seen_ifds = HashSet::new();
+    let mut next_ifd = tiff.first_ifd;
+    while next_ifd != 0 {
+        if !seen_ifds.insert(next_ifd) {
+            return Err(Error::InvalidTiff("the IFDs form a cycle".to_string()));
+        }
+        let ifd = tiff.read_ifd(next_ifd)?;
+        next_ifd = ifd.next;
+        // The reduced levels of an OME-TIFF are in SubIFDs of its first level
+        let sub_ifds = ifd
+            .unsigned_vec(TiffTag::SubIfd)
+            .unwrap_or_default()
+            .to_vec();
+        images.extend(TiffImage::from_ifd(ifd)?);
+        for sub_ifd in sub_ifds {
+            if seen_ifds.insert(sub_ifd) {
+                images.extend(TiffImage::from_ifd(tiff.read_ifd(sub_ifd)?)?);
+            }
+        }
+    }
+
+    // Further images which are not smaller than the previous level, like other planes or
+    // channels, are not part of the pyramid
+    let mut levels: Vec<TiffImage> = Vec::new();
+    for image in images {
+        if levels.last().is_none_or(|level| image.width < level.width) {
+            levels.push(image);
+        }
+    }
+    if levels.is_empty() {
+        return Err(Error::InvalidTiff(
+            "the TIFF has no tiled images".to_string(),
+        ));
+    }
+
+    // Levels without a resolution of their own are scaled from a level with one
+    let Some((reference_size, (spacing_x, spacing_y))) = levels.iter().find_map(|level| {
+        level
+            .pixel_spacing
+            .map(|spacing| ((level.width, level.height), spacing))
+    }) else {
+        return Err(Error::InvalidTiff(
+            "the TIFF has no resolution or Aperio MPP, which DICOM instances require".to_string(),
+        ));
+    };
+    for level in &mut levels {
+        level.pixel_spacing.get_or_insert((
+            spacing_x * f64::from(reference_size.0) / f64::from(level.width),
+            spacing_y * f64::from(reference_size.1) / f64::from(level.height),
+        ));
+    }
+
+    Ok(levels)
+}
+
+/// The pixel spacing (x, y) in millimeters, from the resolution tags in inches or centimeters,
+/// or else from the MPP of an Aperio ImageDescription.
+fn get_pixel_spacing(ifd: &Ifd) -> Option<(f64, f64)> {
+    let millimeters_per_unit = match ifd.unsigned(TiffTag::ResolutionUnit).unwrap_or(2) {
+        2 => Some(25.4),
+        3 => Some(10.0),
+        _ => None,
+    };
+    let is_valid = |spacing: f64| spacing.is_finite() && spacing > 0.0;
+    let resolution_spacing = millimeters_per_unit.and_then(|millimeters_per_unit| {
+        let x = millimeters_per_unit / ifd.float(TiffTag::XResolution)?;
+        let y = millimeters_per_unit / ifd.float(TiffTag::YResolution)?;
+        (is_valid(x) && is_valid(y)).then_some((x, y))
+    });
+    resolution_spacing.or_else(|| {
+        let mpp = aperio_metadata(ifd)
+            .into_iter()
+            .find(|(key, _)| key == "MPP")?
+            .1
+            .parse::<f64>()
+            .ok()?;
+        is_valid(mpp).then_some((mpp / 1000.0, mpp / 1000.0))
+    })
+}
+
+/// The key-value pairs of an Aperio ImageDescription, empty for other descriptions.
+fn aperio_metadata(ifd: &Ifd) -> Vec<(String, String)> {
+    let Some(description) = ifd.ascii(TiffTag::ImageDescription) else {
+        return Vec::new();
+    };
+    if !description.starts_with("Aperio") {
+        return Vec::new();
+    }
+    description
+        .split('|')
+        .skip(1)
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// How the tiles of a level are stored as DICOM frames.
+struct FrameEncoding {
+    transfer_syntax_uid: &'static str,
+    photometric_interpretation: &'static str,
+    bits_stored: u16,
+    /// The lossy image compression method, `None` if the compression is lossless
+    lossy_image_compression_method: Option<&'static str>,
+}
+
+/// Determines the transfer syntax and photometric interpretation of the frames of a level, or
+/// fails if its tiles cannot be copied into DICOM frames as they are.
+fn get_frame_encoding<R: Read + Seek>(
+    tiff: &mut TiffReader<R>,
+    image: &TiffImage,
+) -> Result<FrameEncoding> {
+    if image
+        .ifd
+        .unsigned(TiffTag::PlanarConfiguration)
+        .unwrap_or(1)
+        != 1
+    {
+        return Err(Error::UnsupportedPixelData(
+            "tiles with separate color planes".to_string(),
+        ));
+    }
+    let photometric_interpretation =
+        match (image.samples_per_pixel, image.photometric_interpretation) {
+            (1, Some(PhotometricInterpretation::WhiteIsZero)) => "MONOCHROME1",
+            (1, Some(PhotometricInterpretation::BlackIsZero)) => "MONOCHROME2",
+            (3, Some(PhotometricInterpretation::RGB)) => "RGB",
+            (3, Some(PhotometricInterpretation::YCbCr)) => "YBR_FULL_422",
+            (samples_per_pixel, photometric_interpretation) => {
+                return Err(Error::UnsupportedPhotometricInterpretation(format!(
+                    "{:?} with {} samples per pixel",
+                    photometric_interpretation, samples_per_pixel
+                )));
+            }
+        };
+
+    match image.compression {
+        CompressionMethod::ModernJPEG => {
+            // The coding process is only known from the frame header of a tile
+            let Some(tile) = image.tile_byte_counts.iter().position(|&count| count > 0) else {
+                return Err(Error::InvalidTiff("all tiles are missing".to_string()));
+            };
+            let frame = read_frame(tiff, image, tile)?;
+            let Some((marker, precision)) =
+                jpeg::SplitJpeg::parse(&frame).and_then(|jpeg| jpeg.start_of_frame())
+            else {
+                return Err(Error::InvalidPixelData(format!(
+                    "tile {} is not a JPEG stream",
+                    tile + 1
+                )));
+            };
+            let (transfer_syntax_uid, lossy_image_compression_method) = match (marker, precision) {
+                (jpeg::MARKER_SOF0, 8) => (uids::JPEG_BASELINE8_BIT, Some("ISO_10918_1")),
+                (jpeg::MARKER_SOF1, 8 | 12) => (uids::JPEG_EXTENDED12_BIT, Some("ISO_10918_1")),
+                (jpeg::MARKER_SOF3, _) => (uids::JPEG_LOSSLESS, None),
+                _ => {
+                    return Err(Error::UnsupportedPixelData(format!(
+                        "JPEG tiles with SOF{} frames of {} bit samples have no DICOM transfer \
+                         syntax",
+                        marker - jpeg::MARKER_SOF0,
+                        precision
+                    )));
+                }
+            };
+            // Subsampled YCbCr is YBR_FULL_422 in DICOM WSI, regardless of the subsampling
+            let photometric_interpretation = match image.ifd.unsigned_vec(TiffTag::Unknown(530)) {
+                Some([1, 1]) if photometric_interpretation == "YBR_FULL_422" => "YBR_FULL",
+                _ => photometric_interpretation,
+            };
+            Ok(FrameEncoding {
+                transfer_syntax_uid,
+                photometric_interpretation,
+                bits_stored: u16::from(precision),
+                lossy_image_compression_method,
+            })
+        }
+        CompressionMethod::Unknown(
+            compression @ (APERIO_COMPRESSION_JP2K_YCBCR | APERIO_COMPRESSION_JP2K_RGB),
+        ) => Ok(FrameEncoding {
+            transfer_syntax_uid: uids::JPEG2000,
+            photometric_interpretation: match (image.samples_per_pixel, compression) {
+                (1, _) => photometric_interpretation,
+                (_, APERIO_COMPRESSION_JP2K_YCBCR) => "YBR_ICT",
+                _ => "RGB",
+            },
+            bits_stored: image.bits_per_sample,
+            lossy_image_compression_method: Some("ISO_15444_1"),
+        }),
+        compression => Err(Error::UnsupportedPixelData(format!(
+            "{:?} compressed tiles cannot be copied to DICOM frames",
+            compression
+        ))),
+    }
+}
+
+/// Reads a tile, with the shared JPEG tables of the image inserted, so it is a complete frame.
+fn read_frame<R: Read + Seek>(
+    tiff: &mut TiffReader<R>,
+    image: &TiffImage,
+    tile: usize,
+) -> Result<Vec<u8>> {
+    let byte_count = image.tile_byte_counts[tile];
+    let reader = tiff.get_mut();
+    reader.seek(SeekFrom::Start(image.tile_offsets[tile]))?;
+    let mut data = Vec::new();
+    reader.take(byte_count).read_to_end(&mut data)?;
+    if data.len() as u64 != byte_count {
+        return Err(Error::InvalidPixelData(format!(
+            "tile {} is truncated",
+            tile + 1
+        )));
+    }
+    match image.ifd.bytes(TiffTag::JPEGTables) {
+        Some(tables) if image.compression == CompressionMethod::ModernJPEG => {
+            jpeg::with_tables(tables, &data).ok_or_else(|| {
+                Error::InvalidPixelData(format!("tile {} is not a JPEG stream", tile + 1))
+            })
+        }
+        _ => Ok(data),
+    }
+}
+
+/// Writes the tiles of an image as encapsulated Pixel Data, in explicit VR little endian: an
+/// empty basic offset table followed by one fragment per frame. Missing tiles are left out.
+fn write_pixel_data<R: Read + Seek, W: Write>(
+    tiff: &mut TiffReader<R>,
+    output: &mut W,
+    image: &TiffImage,
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    // Pixel Data (7FE0,0010), OB of undefined length
+    output.write_all(&[
+        0xE0, 0x7F, 0x10, 0x00, b'O', b'B', 0, 0, 0xFF, 0xFF, 0xFF, 0xFF,
+    ])?;
+    write_item(output, &[])?;
+    for (tile, &byte_count) in image.tile_byte_counts.iter().enumerate() {
+        if byte_count == 0 {
+            continue;
+        }
+        cancellation.check()?;
+        let mut frame = read_frame(tiff, image, tile)?;
+        // Items have an even length
+        if frame.len() % 2 == 1 {
+            frame.push(0);
+        }
+        write_item(output, &frame).map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidInput => {
+                Error::UnsupportedPixelData(format!("tile {} is larger than 4 GiB", tile + 1))
+            }
+            _ => Error::Io(e),
+        })?;
+        progress.tile_written(frame.len() as u64);
+    }
+    // Sequence Delimitation Item
+    output.write_all(&[0xFE, 0xFF, 0xDD, 0xE0, 0, 0, 0, 0])?;
+    Ok(())
+}
+
+fn write_item<W: Write>(output: &mut W, data: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // Item (FFFE,E000)
+    output.write_all(&[0xFE, 0xFF, 0x00, 0xE0])?;
+    output.write_all(&len.to_le_bytes())?;
+    output.write_all(data)
+}
+
+/// The attributes shared by the instances of all levels of the slide.
+struct SeriesAttributes {
+    study_instance_uid: String,
+    series_instance_uid: String,
+    frame_of_reference_uid: String,
+    pyramid_uid: String,
+    dimension_organization_uid: String,
+    specimen_uid: String,
+    container_identifier: String,
+    /// (YYYYMMDD, HHMMSS)
+    date_time: (String, String),
+    manufacturer: String,
+    model: String,
+    serial_number: String,
+    software_versions: Vec<String>,
+    objective_lens_power: Option<String>,
+    icc_profile: Option<Vec<u8>>,
+}
+
+impl SeriesAttributes {
+    /// Generates the UIDs of the slide and, with [`MetadataPolicy::Full`], reads the acquisition
+    /// date and time, the scanner and the objective lens power from the first level.
+    fn new(ifd: &Ifd, options: &ConversionOptions) -> Self {
+        let aperio_metadata = aperio_metadata(ifd);
+        let is_full = options.metadata_policy() == MetadataPolicy::Full;
+        let aperio = |aperio_key: &str| {
+            if !is_full {
+                return None;
+            }
+            aperio_metadata
+                .iter()
+                .find(|(key, _)| key == aperio_key)
+                .map(|(_, value)| value.clone())
+                .filter(|value| !value.is_empty())
+        };
+        let tiff = |tiff_tag: TiffTag| {
+            if !is_full {
+                return None;
+            }
+            ifd.ascii(tiff_tag).filter(|value| !value.is_empty())
+        };
+        let unknown = || "Unknown".to_string();
+
+        let date_time = tiff(TiffTag::DateTime)
+            .and_then(|date_time| tiff_date_time(&date_time))
+            .or_else(|| aperio_date_time(&aperio("Date")?, &aperio("Time")?))
+            .unwrap_or_else(|| utc_date_time(SystemTime::now()));
+        let mut software_versions = tiff(TiffTag::Software).into_iter().collect::<Vec<_>>();
+        software_versions.push(format!("dicom2tiff {}", env!("CARGO_PKG_VERSION")));
+
+        Self {
+            study_instance_uid: options
+                .study_instance_uid
+                .clone()
+                .unwrap_or_else(generate_uid),
+            series_instance_uid: options
+                .series_instance_uid
+                .clone()
+                .unwrap_or_else(generate_uid),
+            frame_of_reference_uid: generate_uid(),
+            pyramid_uid: generate_uid(),
+            dimension_organization_uid: generate_uid(),
+            specimen_uid: generate_uid(),
+            container_identifier: options.container_identifier.clone().unwrap_or_else(unknown),
+            date_time,
+            manufacturer: aperio("Manufacturer")
+                .or_else(|| tiff(TiffTag::Make))
+                .unwrap_or_else(unknown),
+            model: aperio("Model")
+                .or_else(|| tiff(TiffTag::Model))
+                .unwrap_or_else(unknown),
+            serial_number: aperio("ScanScope ID").unwrap_or_else(unknown),
+            software_versions,
+            objective_lens_power: aperio("AppMag").filter(|power| power.parse::<f64>().is_ok()),
+            icc_profile: match options.icc_profile {
+                IccProfilePolicy::Preserve => ifd.bytes(TiffTag::IccProfile).map(<[u8]>::to_vec),
+                IccProfilePolicy::Omit => None,
+            },
+        }
+    }
+}
+
+/// The attributes of the instance of a pyramid level, except its Pixel Data.
+fn instance_dataset(
+    series: &SeriesAttributes,
+    image: &TiffImage,
+    level: usize,
+    encoding: &FrameEncoding,
+    sop_instance_uid: &str,
+) -> InMemDicomObject {
+    let (pixel_spacing_x, pixel_spacing_y) = image.pixel_spacing.unwrap_or((1.0, 1.0));
+    // The thickness of the section is not known
+    let imaged_volume_depth = 0.001;
+    let image_type: &[&str] = if level == 0 {
+        &["ORIGINAL", "PRIMARY", "VOLUME", "NONE"]
+    } else {
+        &["DERIVED", "PRIMARY", "VOLUME", "RESAMPLED"]
+    };
+    let (date, time) = &series.date_time;
+    let mut obj = InMemDicomObject::new_empty();
+
+    // SOP Common
+    put_str(
+        &mut obj,
+        dicom_tags::SPECIFIC_CHARACTER_SET,
+        VR::CS,
+        "ISO_IR 192",
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::SOP_CLASS_UID,
+        VR::UI,
+        uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE,
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::SOP_INSTANCE_UID,
+        VR::UI,
+        sop_instance_uid,
+    );
+    // Patient and study, to be filled in by the archive
+    put_empty(&mut obj, dicom_tags::PATIENT_NAME, VR::PN);
+    put_empty(&mut obj, dicom_tags::PATIENT_ID, VR::LO);
+    put_empty(&mut obj, dicom_tags::PATIENT_BIRTH_DATE, VR::DA);
+    put_empty(&mut obj, dicom_tags::PATIENT_SEX, VR::CS);
+    put_str(
+        &mut obj,
+        dicom_tags::STUDY_INSTANCE_UID,
+        VR::UI,
+        &series.study_instance_uid,
+    );
+    put_str(&mut obj, dicom_tags::STUDY_DATE, VR::DA, date);
+    put_str(&mut obj, dicom_tags::STUDY_TIME, VR::TM, time);
+    put_empty(&mut obj, dicom_tags::REFERRING_PHYSICIAN_NAME, VR::PN);
+    put_empty(&mut obj, dicom_tags::STUDY_ID, VR::SH);
+    put_empty(&mut obj, dicom_tags::ACCESSION_NUMBER, VR::SH);
+    // Series and frame of reference
+    put_str(&mut obj, dicom_tags::MODALITY, VR::CS, "SM");
+    put_str(
+        &mut obj,
+        dicom_tags::SERIES_INSTANCE_UID,
+        VR::UI,
+        &series.series_instance_uid,
+    );
+    put_str(&mut obj, dicom_tags::SERIES_NUMBER, VR::IS, "1");
+    put_str(
+        &mut obj,
+        dicom_tags::FRAME_OF_REFERENCE_UID,
+        VR::UI,
+        &series.frame_of_reference_uid,
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::POSITION_REFERENCE_INDICATOR,
+        VR::LO,
+        "SLIDE_CORNER",
+    );
+    // Equipment
+    put_str(
+        &mut obj,
+        dicom_tags::MANUFACTURER,
+        VR::LO,
+        &series.manufacturer,
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::MANUFACTURER_MODEL_NAME,
+        VR::LO,
+        &series.model,
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::DEVICE_SERIAL_NUMBER,
+        VR::LO,
+        &series.serial_number,
+    );
+    put_strs(
+        &mut obj,
+        dicom_tags::SOFTWARE_VERSIONS,
+        VR::LO,
+        &series.software_versions,
+    );
+
+    // Whole slide microscopy image
+    put_strs(&mut obj, dicom_tags::IMAGE_TYPE, VR::CS, image_type);
+    put_str(
+        &mut obj,
+        dicom_tags::INSTANCE_NUMBER,
+        VR::IS,
+        &(level + 1).to_string(),
+    );
+    put_str(&mut obj, dicom_tags::CONTENT_DATE, VR::DA, date);
+    put_str(&mut obj, dicom_tags::CONTENT_TIME, VR::TM, time);
+    put_str(
+        &mut obj,
+        dicom_tags::ACQUISITION_DATE_TIME,
+        VR::DT,
+        &format!("{}{}", date, time),
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::PYRAMID_UID,
+        VR::UI,
+        &series.pyramid_uid,
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::VOLUMETRIC_PROPERTIES,
+        VR::CS,
+        "VOLUME",
+    );
+    put_str(&mut obj, dicom_tags::BURNED_IN_ANNOTATION, VR::CS, "NO");
+    put_str(&mut obj, dicom_tags::SPECIMEN_LABEL_IN_IMAGE, VR::CS, "NO");
+    put_str(&mut obj, dicom_tags::FOCUS_METHOD, VR::CS, "AUTO");
+    put_str(&mut obj, dicom_tags::EXTENDED_DEPTH_OF_FIELD, VR::CS, "NO");
+    match encoding.lossy_image_compression_method {
+        Some(method) => {
+            put_str(&mut obj, dicom_tags::LOSSY_IMAGE_COMPRESSION, VR::CS, "01");
+            put_str(
+                &mut obj,
+                dicom_tags::LOSSY_IMAGE_COMPRESSION_METHOD,
+                VR::CS,
+                method,
+            );
+        }
+        None => put_str(&mut obj, dicom_tags::LOSSY_IMAGE_COMPRESSION, VR::CS, "00"),
+    }
+    put_value(
+        &mut obj,
+        dicom_tags::IMAGED_VOLUME_WIDTH,
+        VR::FL,
+        PrimitiveValue::from((f64::from(image.width) * pixel_spacing_x) as f32),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::IMAGED_VOLUME_HEIGHT,
+        VR::FL,
+        PrimitiveValue::from((f64::from(image.height) * pixel_spacing_y) as f32),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::IMAGED_VOLUME_DEPTH,
+        VR::FL,
+        PrimitiveValue::from(imaged_volume_depth as f32),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS,
+        VR::UL,
+        PrimitiveValue::from(image.width),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::TOTAL_PIXEL_MATRIX_ROWS,
+        VR::UL,
+        PrimitiveValue::from(image.height),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::TOTAL_PIXEL_MATRIX_FOCAL_PLANES,
+        VR::UL,
+        PrimitiveValue::from(1_u32),
+    );
+    let mut origin = InMemDicomObject::new_empty();
+    put_str(
+        &mut origin,
+        dicom_tags::X_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+        VR::DS,
+        "0",
+    );
+    put_str(
+        &mut origin,
+        dicom_tags::Y_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+        VR::DS,
+        "0",
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::TOTAL_PIXEL_MATRIX_ORIGIN_SEQUENCE,
+        vec![origin],
+    );
+    // Rows run along the X axis of the slide and columns along its Y axis
+    put_strs(
+        &mut obj,
+        dicom_tags::IMAGE_ORIENTATION_SLIDE,
+        VR::DS,
+        &["0", "1", "0", "1", "0", "0"],
+    );
+
+    // Image pixel
+    let bits_allocated = if encoding.bits_stored > 8 { 16 } else { 8 };
+    put_value(
+        &mut obj,
+        dicom_tags::SAMPLES_PER_PIXEL,
+        VR::US,
+        PrimitiveValue::from(image.samples_per_pixel),
+    );
+    put_str(
+        &mut obj,
+        dicom_tags::PHOTOMETRIC_INTERPRETATION,
+        VR::CS,
+        encoding.photometric_interpretation,
+    );
+    if image.samples_per_pixel > 1 {
+        put_value(
+            &mut obj,
+            dicom_tags::PLANAR_CONFIGURATION,
+            VR::US,
+            PrimitiveValue::from(0_u16),
+        );
+    } else {
+        put_str(
+            &mut obj,
+            dicom_tags::PRESENTATION_LUT_SHAPE,
+            VR::CS,
+            "IDENTITY",
+        );
+    }
+    put_str(
+        &mut obj,
+        dicom_tags::NUMBER_OF_FRAMES,
+        VR::IS,
+        &image.tile_count().to_string(),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::ROWS,
+        VR::US,
+        PrimitiveValue::from(image.tile_height),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::COLUMNS,
+        VR::US,
+        PrimitiveValue::from(image.tile_width),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::BITS_ALLOCATED,
+        VR::US,
+        PrimitiveValue::from(bits_allocated),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::BITS_STORED,
+        VR::US,
+        PrimitiveValue::from(encoding.bits_stored),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::HIGH_BIT,
+        VR::US,
+        PrimitiveValue::from(encoding.bits_stored - 1),
+    );
+    put_value(
+        &mut obj,
+        dicom_tags::PIXEL_REPRESENTATION,
+        VR::US,
+        PrimitiveValue::from(0_u16),
+    );
+
+    // Multi-frame functional groups and dimensions
+    let mut pixel_measures = InMemDicomObject::new_empty();
+    // Row spacing first, then column spacing
+    put_strs(
+        &mut pixel_measures,
+        dicom_tags::PIXEL_SPACING,
+        VR::DS,
+        &[
+            decimal_string(pixel_spacing_y),
+            decimal_string(pixel_spacing_x),
+        ],
+    );
+    put_str(
+        &mut pixel_measures,
+        dicom_tags::SLICE_THICKNESS,
+        VR::DS,
+        &decimal_string(imaged_volume_depth),
+    );
+    let mut frame_type = InMemDicomObject::new_empty();
+    put_strs(&mut frame_type, dicom_tags::FRAME_TYPE, VR::CS, image_type);
+    let mut optical_path_identification = InMemDicomObject::new_empty();
+    put_str(
+        &mut optical_path_identification,
+        dicom_tags::OPTICAL_PATH_IDENTIFIER,
+        VR::SH,
+        "1",
+    );
+    let mut shared_functional_groups = InMemDicomObject::new_empty();
+    put_items(
+        &mut shared_functional_groups,
+        dicom_tags::PIXEL_MEASURES_SEQUENCE,
+        vec![pixel_measures],
+    );
+    put_items(
+        &mut shared_functional_groups,
+        dicom_tags::WHOLE_SLIDE_MICROSCOPY_IMAGE_FRAME_TYPE_SEQUENCE,
+        vec![frame_type],
+    );
+    put_items(
+        &mut shared_functional_groups,
+        dicom_tags::OPTICAL_PATH_IDENTIFICATION_SEQUENCE,
+        vec![optical_path_identification],
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+        vec![shared_functional_groups],
+    );
+    if image.is_sparse() {
+        put_str(
+            &mut obj,
+            dicom_tags::DIMENSION_ORGANIZATION_TYPE,
+            VR::CS,
+            "TILED_SPARSE",
+        );
+        put_items(
+            &mut obj,
+            dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            per_frame_functional_groups(image),
+        );
+    } else {
+        put_str(
+            &mut obj,
+            dicom_tags::DIMENSION_ORGANIZATION_TYPE,
+            VR::CS,
+            "TILED_FULL",
+        );
+    }
+    let mut dimension_organization = InMemDicomObject::new_empty();
+    put_str(
+        &mut dimension_organization,
+        dicom_tags::DIMENSION_ORGANIZATION_UID,
+        VR::UI,
+        &series.dimension_organization_uid,
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::DIMENSION_ORGANIZATION_SEQUENCE,
+        vec![dimension_organization],
+    );
+    let dimension_indices = [
+        dicom_tags::COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+        dicom_tags::ROW_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+    ]
+    .into_iter()
+    .map(|dimension_index_pointer| {
+        let mut dimension_index = InMemDicomObject::new_empty();
+        put_str(
+            &mut dimension_index,
+            dicom_tags::DIMENSION_ORGANIZATION_UID,
+            VR::UI,
+            &series.dimension_organization_uid,
+        );
+        put_value(
+            &mut dimension_index,
+            dicom_tags::DIMENSION_INDEX_POINTER,
+            VR::AT,
+            PrimitiveValue::Tags(std::iter::once(dimension_index_pointer).collect()),
+        );
+        put_value(
+            &mut dimension_index,
+            dicom_tags::FUNCTIONAL_GROUP_POINTER,
+            VR::AT,
+            PrimitiveValue::Tags(
+                std::iter::once(dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE).collect(),
+            ),
+        );
+        dimension_index
+    })
+    .collect();
+    put_items(
+        &mut obj,
+        dicom_tags::DIMENSION_INDEX_SEQUENCE,
+        dimension_indices,
+    );
+
+    // Specimen
+    put_str(
+        &mut obj,
+        dicom_tags::CONTAINER_IDENTIFIER,
+        VR::LO,
+        &series.container_identifier,
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::ISSUER_OF_THE_CONTAINER_IDENTIFIER_SEQUENCE,
+        Vec::new(),
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::CONTAINER_TYPE_CODE_SEQUENCE,
+        vec![code_item("433466003", "SCT", "Microscope slide")],
+    );
+    let mut specimen_description = InMemDicomObject::new_empty();
+    put_str(
+        &mut specimen_description,
+        dicom_tags::SPECIMEN_IDENTIFIER,
+        VR::LO,
+        &series.container_identifier,
+    );
+    put_str(
+        &mut specimen_description,
+        dicom_tags::SPECIMEN_UID,
+        VR::UI,
+        &series.specimen_uid,
+    );
+    put_items(
+        &mut specimen_description,
+        dicom_tags::ISSUER_OF_THE_SPECIMEN_IDENTIFIER_SEQUENCE,
+        Vec::new(),
+    );
+    put_items(
+        &mut specimen_description,
+        dicom_tags::SPECIMEN_PREPARATION_SEQUENCE,
+        Vec::new(),
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::SPECIMEN_DESCRIPTION_SEQUENCE,
+        vec![specimen_description],
+    );
+
+    // Optical path and acquisition context
+    let mut optical_path = InMemDicomObject::new_empty();
+    put_str(
+        &mut optical_path,
+        dicom_tags::OPTICAL_PATH_IDENTIFIER,
+        VR::SH,
+        "1",
+    );
+    put_items(
+        &mut optical_path,
+        dicom_tags::ILLUMINATION_TYPE_CODE_SEQUENCE,
+        vec![code_item("111744", "DCM", "Brightfield illumination")],
+    );
+    put_items(
+        &mut optical_path,
+        dicom_tags::ILLUMINATION_COLOR_CODE_SEQUENCE,
+        vec![code_item("414298005", "SCT", "Full Spectrum")],
+    );
+    if let Some(objective_lens_power) = &series.objective_lens_power {
+        put_str(
+            &mut optical_path,
+            dicom_tags::OBJECTIVE_LENS_POWER,
+            VR::DS,
+            objective_lens_power,
+        );
+    }
+    if let Some(icc_profile) = &series.icc_profile {
+        put_value(
+            &mut optical_path,
+            dicom_tags::ICC_PROFILE,
+            VR::OB,
+            PrimitiveValue::U8(icc_profile.iter().copied().collect()),
+        );
+    }
+    put_value(
+        &mut obj,
+        dicom_tags::NUMBER_OF_OPTICAL_PATHS,
+        VR::UL,
+        PrimitiveValue::from(1_u32),
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::OPTICAL_PATH_SEQUENCE,
+        vec![optical_path],
+    );
+    put_items(
+        &mut obj,
+        dicom_tags::ACQUISITION_CONTEXT_SEQUENCE,
+        Vec::new(),
+    );
+
+    obj
+}
+
+/// The per-frame functional groups of a sparsely tiled image, with the position of every frame
+/// in the total pixel matrix and on the slide.
+fn per_frame_functional_groups(image: &TiffImage) -> Vec<InMemDicomObject> {
+    let (pixel_spacing_x, pixel_spacing_y) = image.pixel_spacing.unwrap_or((1.0, 1.0));
+    let tiles_across = image.tiles_across() as usize;
+    image
+        .tile_byte_counts
+        .iter()
+        .enumerate()
+        .filter(|&(_, &byte_count)| byte_count > 0)
+        .map(|(tile, _)| {
+            let column = (tile % tiles_across) as u32 * u32::from(image.tile_width);
+            let row = (tile / tiles_across) as u32 * u32::from(image.tile_height);
+            let mut plane_position = InMemDicomObject::new_empty();
+            // Positions are 1-based
+            put_value(
+                &mut plane_position,
+                dicom_tags::COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+                VR::SL,
+                PrimitiveValue::from(column as i32 + 1),
+            );
+            put_value(
+                &mut plane_position,
+                dicom_tags::ROW_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+                VR::SL,
+                PrimitiveValue::from(row as i32 + 1),
+            );
+            put_str(
+                &mut plane_position,
+                dicom_tags::X_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+                VR::DS,
+                &decimal_string(f64::from(row) * pixel_spacing_y),
+            );
+            put_str(
+                &mut plane_position,
+                dicom_tags::Y_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+                VR::DS,
+                &decimal_string(f64::from(column) * pixel_spacing_x),
+            );
+            put_str(
+                &mut plane_position,
+                dicom_tags::Z_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+                VR::DS,
+                "0",
+            );
+            let mut frame = InMemDicomObject::new_empty();
+            put_items(
+                &mut frame,
+                dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE,
+                vec![plane_position],
+            );
+            frame
+        })
+        .collect()
+}
+
+fn put_value(obj: &mut InMemDicomObject, tag: Tag, vr: VR, value: PrimitiveValue) {
+    obj.put(DataElement::new(tag, vr, value));
+}
+
+fn put_str(obj: &mut InMemDicomObject, tag: Tag, vr: VR, value: &str) {
+    put_value(obj, tag, vr, PrimitiveValue::from(value));
+}
+
+fn put_strs(obj: &mut InMemDicomObject, tag: Tag, vr: VR, values: &[impl AsRef<str>]) {
+    let values = values.iter().map(|v| v.as_ref().to_string()).collect();
+    put_value(obj, tag, vr, PrimitiveValue::Strs(values));
+}
+
+fn put_empty(obj: &mut InMemDicomObject, tag: Tag, vr: VR) {
+    put_value(obj, tag, vr, PrimitiveValue::Empty);
+}
+
+fn put_items(obj: &mut InMemDicomObject, tag: Tag, items: Vec<InMemDicomObject>) {
+    obj.put(DataElement::new(tag, VR::SQ, DataSetSequence::from(items)));
+}
+
+fn code_item(value: &str, scheme: &str, meaning: &str) -> InMemDicomObject {
+    let mut item = InMemDicomObject::new_empty();
+    put_str(&mut item, dicom_tags::CODE_VALUE, VR::SH, value);
+    put_str(
+        &mut item,
+        dicom_tags::CODING_SCHEME_DESIGNATOR,
+        VR::SH,
+        scheme,
+    );
+    put_str(&mut item, dicom_tags::CODE_MEANING, VR::LO, meaning);
+    item
+}
+
+/// Formats a decimal string, which holds at most 16 characters.
+fn decimal_string(value: f64) -> String {
+    let value_string = value.to_string();
+    if value_string.len() <= 16 {
+        value_string
+    } else {
+        format!("{:.8e}", value)
+    }
+}
+
+/// A new UID under the UUID root 2.25, from 128 random bits.
+fn generate_uid() -> String {
+    let random_u64 = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_nanos()),
+        );
+        hasher.finish()
+    };
+    let random = (u128::from(random_u64()) << 64) | u128::from(random_u64());
+    format!("2.25.{}", random)
+}
+
+/// The date (YYYYMMDD) and time (HHMMSS) of a TIFF DateTime ("YYYY:MM:DD HH:MM:SS").
+fn tiff_date_time(date_time: &str) -> Option<(String, String)> {
+    let digits = date_time
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    (digits.len() == 14).then(|| (digits[..8].to_string(), digits[8..].to_string()))
+}
+
+/// The date (YYYYMMDD) and time (HHMMSS) of an Aperio Date (MM/DD/YY) and Time (HH:MM:SS).
+fn aperio_date_time(date: &str, time: &str) -> Option<(String, String)> {
+    let date = date.split('/').collect::<Vec<_>>();
+    let time = time.split(':').collect::<Vec<_>>();
+    let is_number = |value: &&str| value.len() == 2 && value.bytes().all(|b| b.is_ascii_digit());
+    if date.len() != 3 || time.len() != 3 || !date.iter().chain(&time).all(is_number) {
+        return None;
+    }
+    Some((
+        format!("20{}{}{}", date[2], date[0], date[1]),
+        time.concat(),
+    ))
+}
+
+/// The UTC date (YYYYMMDD) and time (HHMMSS) of a point in time.
+fn utc_date_time(time: SystemTime) -> (String, String) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, seconds) = ((seconds / 86400) as i64, seconds % 86400);
+    // The civil date of a day since the epoch, as computed by Howard Hinnant's civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (
+        format!("{:04}{:02}{:02}", year, month, day),
+        format!(
+            "{:02}{:02}{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use tiff::encoder::{TiffEncoder, colortype};
+
+    use super::*;
+
+    fn utc(seconds: u64) -> (String, String) {
+        utc_date_time(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+
+    fn date_time(date: &str, time: &str) -> (String, String) {
+        (date.to_string(), time.to_string())
+    }
+
+    #[test]
+    fn utc_date_time_of_known_dates() {
+        assert_eq!(utc(0), date_time("19700101", "000000"));
+        // Leap days of a year divisible by 400, and by 4
+        assert_eq!(utc(951_782_400), date_time("20000229", "000000"));
+        assert_eq!(utc(1_709_251_199), date_time("20240229", "235959"));
+        // 2100 is not a leap year
+        assert_eq!(utc(4_107_542_399), date_time("21000228", "235959"));
+        assert_eq!(utc(4_107_542_400), date_time("21000301", "000000"));
+        // Times before the epoch are taken as the epoch
+        assert_eq!(
+            utc_date_time(UNIX_EPOCH - Duration::from_secs(1)),
+            date_time("19700101", "000000")
+        );
+    }
+
+    #[test]
+    fn aperio_date_time_reorders_the_date() {
+        assert_eq!(
+            aperio_date_time("12/31/23", "08:05:09"),
+            Some(date_time("20231231", "080509"))
+        );
+        assert_eq!(aperio_date_time("12/31/2023", "08:05:09"), None);
+        assert_eq!(aperio_date_time("12-31-23", "08:05:09"), None);
+        assert_eq!(aperio_date_time("12/31/23", "8:05:09"), None);
+        assert_eq!(aperio_date_time("1a/31/23", "08:05:09"), None);
+    }
+
+    #[test]
+    fn tiff_date_time_takes_the_digits() {
+        assert_eq!(
+            tiff_date_time("2024:01:02 03:04:05"),
+            Some(date_time("20240102", "030405"))
+        );
+        assert_eq!(tiff_date_time("2024:01:02"), None);
+        assert_eq!(tiff_date_time("2024:01:02 03:04:05:06"), None);
+    }
+
+    #[test]
+    fn decimal_string_fits_16_characters() {
+        assert_eq!(decimal_string(0.00025), "0.00025");
+        assert_eq!(decimal_string(40.0), "40");
+        assert_eq!(decimal_string(1.0 / 3.0), "3.33333333e-1");
+        assert!(decimal_string(-1.0 / 3.0).len() <= 16);
+    }
+
+    /// The first IFD of a TIFF with the given ASCII tags.
+    fn tiff_ifd(tags: &[(TiffTag, &str)]) -> Ifd {
+        let mut tiff = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut tiff).unwrap();
+        let mut image = encoder.new_image::<colortype::Gray8>(1, 1).unwrap();
+        for &(tag, value) in tags {
+            image.encoder().write_tag(tag, value).unwrap();
+        }
+        image.write_data(&[0]).unwrap();
+        let mut reader = TiffReader::new(tiff).unwrap();
+        reader.read_ifd(reader.first_ifd).unwrap()
+    }
+
+    #[test]
+    fn series_attributes_read_aperio_keys_before_tiff_tags() {
+        let ifd = tiff_ifd(&[
+            (
+                TiffTag::ImageDescription,
+                "Aperio Image Library v12\n1000x800 |AppMag = 20|Date = 12/31/23\
+                 |Time = 08:05:09|ScanScope ID = SS1234|Model = |Manufacturer = Leica",
+            ),
+            (TiffTag::Make, "Aperio"),
+            (TiffTag::Model, "GT 450"),
+            (TiffTag::Software, "Scanner 1.0"),
+        ]);
+        let options = ConversionOptions::default().metadata(MetadataPolicy::Full);
+        let series = SeriesAttributes::new(&ifd, &options);
+        assert_eq!(series.manufacturer, "Leica");
+        // An empty Aperio value falls back to the TIFF tag
+        assert_eq!(series.model, "GT 450");
+        assert_eq!(series.serial_number, "SS1234");
+        assert_eq!(series.objective_lens_power.as_deref(), Some("20"));
+        assert_eq!(series.date_time, date_time("20231231", "080509"));
+        assert_eq!(series.software_versions[0], "Scanner 1.0");
+
+        let ifd = tiff_ifd(&[(TiffTag::DateTime, "2024:01:02 03:04:05")]);
+        let series = SeriesAttributes::new(&ifd, &options);
+        assert_eq!(series.date_time, date_time("20240102", "030405"));
+        assert_eq!(series.manufacturer, "Unknown");
+    }
+
+    #[test]
+    fn series_attributes_leave_out_metadata_unless_it_is_full() {
+        let ifd = tiff_ifd(&[
+            (
+                TiffTag::ImageDescription,
+                "Aperio Image Library|AppMag = 20",
+            ),
+            (TiffTag::Make, "Aperio"),
+            (TiffTag::Software, "Scanner 1.0"),
+            (TiffTag::DateTime, "2024:01:02 03:04:05"),
+        ]);
+        let options = ConversionOptions::default().metadata(MetadataPolicy::Minimal);
+        let series = SeriesAttributes::new(&ifd, &options);
+        assert_eq!(series.manufacturer, "Unknown");
+        assert_eq!(series.objective_lens_power, None);
+        assert_ne!(series.date_time, date_time("20240102", "030405"));
+        assert_eq!(series.software_versions.len(), 1);
+    }
+}
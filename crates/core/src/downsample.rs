@@ -0,0 +1,399 @@
+use std::io;
+
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+
+use crate::cancellation::CancellationToken;
+use crate::decode::{self, BACKGROUND};
+use crate::error::Result;
+use crate::image::{DicomImage, TileData};
+use crate::workers::Workers;
+
+/// The size and JPEG encoding of the tiles of reduced levels.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TileFormat {
+    /// (width, height)
+    pub(crate) tile_size: (u32, u32),
+    /// Whether the tiles at the right and bottom edges are cropped to the level, rather than
+    /// padded to the tile size with white
+    pub(crate) crop: bool,
+    pub(crate) quality: u8,
+    /// Whether color tiles have 2x2 chroma subsampling, as given in the YCbCrSubSampling tag of
+    /// a TIFF, rather than the default of the encoder for the quality
+    pub(crate) subsample_chroma: bool,
+}
+
+/// The (width, height) of the reduced-resolution levels synthesized below an image of `size`
+/// with tiles of `tile_size`: each level half the size of the one before, rounded up, until a
+/// level fits in a single tile.
+pub(crate) fn reduced_sizes(
+    (mut width, mut height): (u32, u32),
+    (tile_width, tile_height): (u32, u32),
+) -> Vec<(u32, u32)> {
+    let mut sizes = Vec::new();
+    while width > tile_width || height > tile_height {
+        width = width.div_ceil(2);
+        height = height.div_ceil(2);
+        sizes.push((width, height));
+    }
+    sizes
+}
+
+/// The number of tiles of a level of `size` with tiles of `tile_size`.
+pub(crate) fn count_tiles(
+    (width, height): (u32, u32),
+    (tile_width, tile_height): (u32, u32),
+) -> u64 {
+    u64::from(width.div_ceil(tile_width)) * u64::from(height.div_ceil(tile_height))
+}
+
+/// Makes the reduced-resolution levels of `sizes` below an image, each half the size of the one
+/// before, rounded up, with tiles of `format`. The image is decoded one row of tiles at a time,
+/// and every level averages 2x2 pixels of the level before. Each row of JPEG tiles of a level is
+/// passed to `write_tiles` with the index of the level in `sizes` as soon as it is complete, so
+/// rows of the levels are interleaved, but the rows of each level come from the top down. Tiles
+/// without any pixel of a frame (only possible when the image is sparsely tiled) are `None`.
+pub(crate) fn downsample_image(
+    (image, tile_data, tile_frames): (&DicomImage, &TileData, &[Option<usize>]),
+    sizes: &[(u32, u32)],
+    format: &TileFormat,
+    workers: &Workers,
+    cancellation: &CancellationToken,
+    mut write_tiles: impl FnMut(usize, Vec<Option<Vec<u8>>>) -> Result<()>,
+) -> Result<()> {
+    let samples = usize::from(image.samples_per_pixel);
+    let mut levels: Vec<ReducedLevel> = sizes
+        .iter()
+        .map(|&size| ReducedLevel::new(size, *format, samples))
+        .collect();
+
+    let tiles_across = image.tiles_across();
+    let (frame_width, frame_height) = (usize::from(image.tile_width), u32::from(image.tile_height));
+    let frame_row_len = frame_width * samples;
+    let mut row = Row {
+        samples: vec![BACKGROUND; image.image_width as usize * samples],
+        covered: vec![false; image.image_width as usize],
+    };
+    for tile_y in 0..image.tiles_down() {
+        cancellation.check()?;
+        let slots: Vec<usize> = (0..tiles_across)
+            .map(|tile_x| (tile_y * tiles_across + tile_x) as usize)
+            .collect();
+        let frames = workers
+            .map(&slots, |&slot| {
+                tile_frames[slot]
+                    .map(|frame_index| decode::decode_frame_8_bit(image, tile_data, frame_index))
+                    .transpose()
+            })
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        for (tile_x, frame) in frames.iter().enumerate() {
+            let start = tile_x * frame_width;
+            let len = frame_width.min(row.covered.len() - start);
+            row.covered[start..start + len].fill(frame.is_some());
+        }
+        let rows = (image.image_height - tile_y * frame_height).min(frame_height) as usize;
+        for y in 0..rows {
+            for (tile_x, frame) in frames.iter().enumerate() {
+                let start = tile_x * frame_row_len;
+                let len = frame_row_len.min(row.samples.len() - start);
+                let samples = &mut row.samples[start..start + len];
+                match frame {
+                    Some(frame) => samples.copy_from_slice(&frame[y * frame_row_len..][..len]),
+                    None => samples.fill(BACKGROUND),
+                }
+            }
+            push_row(&mut levels, 0, &row, workers, &mut write_tiles)?;
+        }
+    }
+
+    // The last rows of levels of an odd height, and the last rows of tiles
+    for index in 0..levels.len() {
+        let (level, further) = levels[index..]
+            .split_first_mut()
+            .expect("the index is in range");
+        if let Some(previous) = level.pending.take() {
+            let row = level.reduce(&previous, None);
+            if let Some(tiles) = level.push_row(&row, workers)? {
+                write_tiles(index, tiles)?;
+            }
+            push_row(further, index + 1, &row, workers, &mut write_tiles)?;
+        }
+        if let Some(tiles) = level.flush(workers)? {
+            write_tiles(index, tiles)?;
+        }
+    }
+    Ok(())
+}
+
+/// Passes a row of pixels of the level above `levels` down to them.
+fn push_row(
+    levels: &mut [ReducedLevel],
+    index: usize,
+    input: &Row,
+    workers: &Workers,
+    write_tiles: &mut impl FnMut(usize, Vec<Option<Vec<u8>>>) -> Result<()>,
+) -> Result<()> {
+    let Some((level, further)) = levels.split_first_mut() else {
+        return Ok(());
+    };
+    let Some(previous) = level.pending.take() else {
+        level.pending = Some(input.clone());
+        return Ok(());
+    };
+    let row = level.reduce(&previous, Some(input));
+    if let Some(tiles) = level.push_row(&row, workers)? {
+        write_tiles(index, tiles)?;
+    }
+    push_row(further, index + 1, &row, workers, write_tiles)
+}
+
+/// A row of 8 bit color-by-pixel samples, with whether each pixel comes from a frame.
+#[derive(Clone)]
+struct Row {
+    samples: Vec<u8>,
+    covered: Vec<bool>,
+}
+
+/// A reduced level being made from the rows of the level above it.
+struct ReducedLevel {
+    size: (u32, u32),
+    format: TileFormat,
+    samples: usize,
+    /// The first of the two rows of the level above which make the next row
+    pending: Option<Row>,
+    /// The rows of the current row of tiles, padded to a whole number of tiles across
+    band: Vec<u8>,
+    band_rows: u32,
+    /// Whether any pixel of each tile of the current row of tiles comes from a frame
+    band_covered: Vec<bool>,
+}
+
+impl ReducedLevel {
+    fn new(size: (u32, u32), format: TileFormat, samples: usize) -> Self {
+        let (tile_width, tile_height) = format.tile_size;
+        let tiles_across = size.0.div_ceil(tile_width);
+        let band_len = (tiles_across * tile_width * tile_height) as usize * samples;
+        Self {
+            size,
+            format,
+            samples,
+            pending: None,
+            band: vec![BACKGROUND; band_len],
+            band_rows: 0,
+            band_covered: vec![false; tiles_across as usize],
+        }
+    }
+
+    /// Averages two rows of the level above, or the last one of an odd height, into a row.
+    fn reduce(&self, first: &Row, second: Option<&Row>) -> Row {
+        let samples = self.samples;
+        let input_width = first.covered.len();
+        let inputs = [Some(first), second];
+        let width = self.size.0 as usize;
+        let mut row = Row {
+            samples: Vec::with_capacity(width * samples),
+            covered: Vec::with_capacity(width),
+        };
+        for x in 0..width {
+            let columns = 2 * x..(2 * x + 2).min(input_width);
+            for sample in 0..samples {
+                let (mut sum, mut count) = (0u32, 0u32);
+                for input in inputs.iter().flatten() {
+                    for column in columns.clone() {
+                        sum += u32::from(input.samples[column * samples + sample]);
+                        count += 1;
+                    }
+                }
+                row.samples.push(((sum + count / 2) / count) as u8);
+            }
+            let covered = inputs
+                .iter()
+                .flatten()
+                .any(|input| input.covered[columns.clone()].contains(&true));
+            row.covered.push(covered);
+        }
+        row
+    }
+
+    /// Adds a row to the current row of tiles, and returns its tiles once it is complete.
+    fn push_row(&mut self, row: &Row, workers: &Workers) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+        let band_row_len = self.band.len() / self.format.tile_size.1 as usize;
+        let start = self.band_rows as usize * band_row_len;
+        self.band[start..start + row.samples.len()].copy_from_slice(&row.samples);
+        let tile_width = self.format.tile_size.0 as usize;
+        for (covered, columns) in self
+            .band_covered
+            .iter_mut()
+            .zip(row.covered.chunks(tile_width))
+        {
+            *covered |= columns.contains(&true);
+        }
+        self.band_rows += 1;
+        if self.band_rows < self.format.tile_size.1 {
+            return Ok(None);
+        }
+        self.flush(workers)
+    }
+
+    /// Encodes the tiles of the current row of tiles, if it has any rows, with the rows below
+    /// the level left white unless the tiles are cropped.
+    fn flush(&mut self, workers: &Workers) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+        if self.band_rows == 0 {
+            return Ok(None);
+        }
+        let (tile_width, tile_height) = self.format.tile_size;
+        let band_row_len = self.band.len() / tile_height as usize;
+        let columns: Vec<usize> = (0..self.band_covered.len()).collect();
+        let tiles = workers.map(&columns, |&tile_x| {
+            if !self.band_covered[tile_x] {
+                return Ok(None);
+            }
+            let x = tile_x as u32 * tile_width;
+            let size = if self.format.crop {
+                (tile_width.min(self.size.0 - x), self.band_rows)
+            } else {
+                (tile_width, tile_height)
+            };
+            let row_len = size.0 as usize * self.samples;
+            let mut pixels = Vec::with_capacity(row_len * size.1 as usize);
+            for y in 0..size.1 as usize {
+                let start = y * band_row_len + x as usize * self.samples;
+                pixels.extend_from_slice(&self.band[start..start + row_len]);
+            }
+            encode_tile(&pixels, size, self.samples, &self.format).map(Some)
+        });
+        self.band.fill(BACKGROUND);
+        self.band_rows = 0;
+        self.band_covered.fill(false);
+        tiles.into_iter().collect::<Result<Vec<_>>>().map(Some)
+    }
+}
+
+/// Encodes the 8 bit color-by-pixel samples of a tile of `size` as a JPEG image, YCbCr if it
+/// is in color.
+pub(crate) fn encode_tile(
+    pixels: &[u8],
+    (width, height): (u32, u32),
+    samples: usize,
+    format: &TileFormat,
+) -> Result<Vec<u8>> {
+    let mut tile = Vec::new();
+    let mut encoder = Encoder::new(&mut tile, format.quality);
+    let color_type = if samples == 1 {
+        ColorType::Luma
+    } else {
+        if format.subsample_chroma {
+            encoder.set_sampling_factor(SamplingFactor::F_2_2);
+        }
+        ColorType::Rgb
+    };
+    encoder
+        .encode(pixels, width as u16, height as u16, color_type)
+        .map_err(io::Error::other)?;
+    Ok(tile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(tile_size: (u32, u32), crop: bool) -> TileFormat {
+        TileFormat {
+            tile_size,
+            crop,
+            quality: 100,
+            subsample_chroma: false,
+        }
+    }
+
+    fn row(samples: &[u8]) -> Row {
+        Row {
+            samples: samples.to_vec(),
+            covered: vec![true; samples.len()],
+        }
+    }
+
+    /// The (width, height) and samples of a JPEG tile.
+    fn decode(tile: &[u8]) -> ((u16, u16), Vec<u8>) {
+        let mut decoder = jpeg_decoder::Decoder::new(tile);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        ((info.width, info.height), pixels)
+    }
+
+    #[test]
+    fn levels_are_halved_until_one_fits_in_a_single_tile() {
+        assert_eq!(
+            reduced_sizes((1000, 600), (256, 256)),
+            [(500, 300), (250, 150)]
+        );
+        assert_eq!(reduced_sizes((257, 3), (256, 256)), [(129, 2)]);
+        assert_eq!(reduced_sizes((256, 256), (256, 256)), []);
+        assert_eq!(count_tiles((500, 300), (256, 256)), 4);
+        assert_eq!(count_tiles((250, 150), (256, 256)), 1);
+    }
+
+    #[test]
+    fn pixels_are_averaged_2x2() {
+        let level = ReducedLevel::new((2, 1), format((256, 256), true), 1);
+        let reduced = level.reduce(&row(&[0, 10, 20, 30]), Some(&row(&[40, 50, 60, 71])));
+        // (0 + 10 + 40 + 50) / 4 and (20 + 30 + 60 + 71) / 4 rounded
+        assert_eq!(reduced.samples, [25, 45]);
+        assert_eq!(reduced.covered, [true, true]);
+
+        let level = ReducedLevel::new((1, 1), format((256, 256), true), 3);
+        let reduced = level.reduce(
+            &row(&[0, 4, 8, 2, 6, 10]),
+            Some(&row(&[4, 8, 12, 6, 10, 14])),
+        );
+        assert_eq!(reduced.samples, [3, 7, 11]);
+    }
+
+    #[test]
+    fn odd_edges_are_averaged_from_the_pixels_there_are() {
+        // The last column of an odd width, and the last row of an odd height
+        let level = ReducedLevel::new((2, 2), format((256, 256), true), 1);
+        let reduced = level.reduce(&row(&[0, 10, 20]), Some(&row(&[40, 50, 61])));
+        assert_eq!(reduced.samples, [25, 41]);
+        let reduced = level.reduce(&row(&[0, 10, 21]), None);
+        assert_eq!(reduced.samples, [5, 21]);
+
+        let mut first = row(&[0, 10, 20]);
+        first.covered = vec![false, false, true];
+        let reduced = level.reduce(&first, None);
+        assert_eq!(reduced.covered, [false, true]);
+    }
+
+    #[test]
+    fn the_last_row_of_tiles_of_an_odd_level_is_flushed() {
+        let workers = Workers::Sequential;
+        for crop in [true, false] {
+            let mut level = ReducedLevel::new((3, 3), format((2, 2), crop), 1);
+            assert!(level.push_row(&row(&[100; 3]), &workers).unwrap().is_none());
+            let tiles = level.push_row(&row(&[100; 3]), &workers).unwrap().unwrap();
+            assert_eq!(tiles.len(), 2);
+            let mut uncovered = row(&[100; 3]);
+            uncovered.covered = vec![true, false, false];
+            assert!(level.push_row(&uncovered, &workers).unwrap().is_none());
+            let last = level.flush(&workers).unwrap().unwrap();
+            assert!(level.flush(&workers).unwrap().is_none());
+
+            let (size, pixels) = decode(tiles[1].as_ref().unwrap());
+            assert_eq!(size, if crop { (1, 2) } else { (2, 2) });
+            assert!(pixels[0].abs_diff(100) <= 2);
+            // The right tile of the last row has no pixel of a frame
+            assert!(last[1].is_none());
+            let (size, pixels) = decode(last[0].as_ref().unwrap());
+            assert_eq!(size, if crop { (2, 1) } else { (2, 2) });
+            assert!(pixels[..2].iter().all(|&sample| sample.abs_diff(100) <= 2));
+            if !crop {
+                // Padded below the level with white
+                assert!(
+                    pixels[2..]
+                        .iter()
+                        .all(|&sample| sample.abs_diff(BACKGROUND) <= 2)
+                );
+            }
+        }
+    }
+}
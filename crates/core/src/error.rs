@@ -0,0 +1,297 @@
+use std::fmt;
+
+use dicom_core::Tag;
+use dicom_core::dictionary::{DataDictionary, DataDictionaryEntry};
+use dicom_dictionary_std::StandardDataDictionary;
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::InMemDicomObject;
+use dicom_object::mem::InMemElement;
+
+use crate::SlideId;
+use crate::metadata::trim_padding;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The errors of converting DICOM WSI files.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A required attribute is missing.
+    MissingAttribute {
+        tag: Tag,
+    },
+    /// An attribute has an unexpected type, multiplicity or value.
+    InvalidAttribute {
+        tag: Tag,
+        reason: String,
+    },
+    /// The photometric interpretation cannot be stored in a TIFF.
+    UnsupportedPhotometricInterpretation(String),
+    /// The transfer syntax (given by its UID) is not supported.
+    UnsupportedTransferSyntax(String),
+    /// A feature of the pixel data, like its bit depth or compression, is not supported.
+    UnsupportedPixelData(String),
+    /// The pixel data is corrupt.
+    InvalidPixelData(String),
+    /// None of the sources is a pyramid level of a slide.
+    NoPyramidLevels,
+    /// The sources contain several slides, which must be converted separately.
+    MultipleSlides(Vec<SlideId>),
+    /// The conversion was cancelled with a [`CancellationToken`](crate::CancellationToken).
+    Cancelled,
+    /// A source could not be parsed as a DICOM file.
+    Dicom(Box<dicom_object::ReadError>),
+    Io(std::io::Error),
+    /// The output TIFF could not be written.
+    Tiff(tiff::TiffError),
+    /// The input TIFF is malformed, or has no tiled images to convert to DICOM.
+    InvalidTiff(String),
+    /// An output DICOM file could not be written.
+    DicomWrite(Box<dicom_object::WriteError>),
+    /// An error of one instance of the sources.
+    Instance {
+        /// The index of the instance in the sources
+        index: usize,
+        sop_instance_uid: Option<String>,
+        /// The image of the slide the instance is, if known
+        image: Option<ImageKind>,
+        source: Box<Error>,
+    },
+}
+
+/// The images of a slide in the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    /// A pyramid level, 0 being the largest
+    Level(usize),
+    Thumbnail,
+    Label,
+    Overview,
+}
+
+impl Error {
+    /// The error without the context of the instance it occurred in.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Instance { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+
+    pub(crate) fn invalid_attribute(tag: Tag, reason: impl fmt::Display) -> Self {
+        Error::InvalidAttribute {
+            tag,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingAttribute { tag } => {
+                write!(f, "Missing attribute {}", AttributeName(*tag))
+            }
+            Error::InvalidAttribute { tag, reason } => {
+                write!(f, "Invalid attribute {}: {}", AttributeName(*tag), reason)
+            }
+            Error::UnsupportedPhotometricInterpretation(photometric_interpretation) => write!(
+                f,
+                "Unsupported photometric interpretation: {}",
+                photometric_interpretation
+            ),
+            Error::UnsupportedTransferSyntax(uid) => {
+                write!(f, "Unsupported transfer syntax: {}", uid)
+            }
+            Error::UnsupportedPixelData(reason) => write!(f, "Unsupported pixel data: {}", reason),
+            Error::InvalidPixelData(reason) => write!(f, "Invalid pixel data: {}", reason),
+            Error::NoPyramidLevels => write!(f, "No pyramid levels found"),
+            Error::MultipleSlides(ids) => {
+                write!(
+                    f,
+                    "Found {} slides, which must be converted separately:",
+                    ids.len()
+                )?;
+                for id in ids {
+                    write!(f, "\n  {}", id)?;
+                }
+                Ok(())
+            }
+            Error::Cancelled => write!(f, "The conversion was cancelled"),
+            Error::Dicom(e) => write!(f, "Failed to read DICOM file: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Tiff(e) => write!(f, "Failed to write TIFF: {}", e),
+            Error::InvalidTiff(reason) => write!(f, "Invalid TIFF: {}", reason),
+            Error::DicomWrite(e) => write!(f, "Failed to write DICOM file: {}", e),
+            Error::Instance {
+                index,
+                sop_instance_uid,
+                image,
+                source,
+            } => {
+                if let Some(image) = image {
+                    write!(f, "{} ", image)?;
+                }
+                write!(f, "(instance {}", index)?;
+                if let Some(sop_instance_uid) = sop_instance_uid {
+                    write!(f, ", SOP Instance UID {}", sop_instance_uid)?;
+                }
+                write!(f, "): {}", source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Dicom(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Tiff(e) => Some(e),
+            Error::DicomWrite(e) => Some(e),
+            Error::Instance { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageKind::Level(level) => write!(f, "Level {}", level),
+            ImageKind::Thumbnail => write!(f, "Thumbnail"),
+            ImageKind::Label => write!(f, "Label"),
+            ImageKind::Overview => write!(f, "Overview"),
+        }
+    }
+}
+
+impl From<dicom_object::ReadError> for Error {
+    fn from(e: dicom_object::ReadError) -> Self {
+        Error::Dicom(Box::new(e))
+    }
+}
+
+impl From<dicom_object::WriteError> for Error {
+    fn from(e: dicom_object::WriteError) -> Self {
+        Error::DicomWrite(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<tiff::TiffError> for Error {
+    fn from(e: tiff::TiffError) -> Self {
+        match e {
+            tiff::TiffError::IoError(e) => Error::Io(e),
+            e => Error::Tiff(e),
+        }
+    }
+}
+
+/// A tag with its keyword, if it is a standard attribute.
+struct AttributeName(Tag);
+
+impl fmt::Display for AttributeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match StandardDataDictionary.by_tag(self.0) {
+            Some(entry) => write!(f, "{} {}", entry.alias(), self.0),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// The element of a required attribute.
+pub(crate) fn get_element(obj: &InMemDicomObject, tag: Tag) -> Result<&InMemElement> {
+    get_element_opt(obj, tag)?.ok_or(Error::MissingAttribute { tag })
+}
+
+/// The element of an optional attribute.
+pub(crate) fn get_element_opt(obj: &InMemDicomObject, tag: Tag) -> Result<Option<&InMemElement>> {
+    obj.element_opt(tag)
+        .map_err(|e| Error::invalid_attribute(tag, e))
+}
+
+pub(crate) fn get_u16(obj: &InMemDicomObject, tag: Tag) -> Result<u16> {
+    get_element(obj, tag)?
+        .uint16()
+        .map_err(|e| Error::invalid_attribute(tag, e))
+}
+
+pub(crate) fn get_u32(obj: &InMemDicomObject, tag: Tag) -> Result<u32> {
+    get_element(obj, tag)?
+        .uint32()
+        .map_err(|e| Error::invalid_attribute(tag, e))
+}
+
+pub(crate) fn get_i64(obj: &InMemDicomObject, tag: Tag) -> Result<i64> {
+    get_element(obj, tag)?
+        .to_int()
+        .map_err(|e| Error::invalid_attribute(tag, e))
+}
+
+pub(crate) fn get_opt_u16(obj: &InMemDicomObject, tag: Tag) -> Result<Option<u16>> {
+    get_element_opt(obj, tag)?
+        .map(|e| e.uint16().map_err(|e| Error::invalid_attribute(tag, e)))
+        .transpose()
+}
+
+/// The value of a string attribute, without padding, which must not be empty.
+pub(crate) fn get_str(obj: &InMemDicomObject, tag: Tag) -> Result<String> {
+    let value = get_element(obj, tag)?
+        .to_str()
+        .map_err(|e| Error::invalid_attribute(tag, e))?;
+    trim_padding(&value).ok_or_else(|| Error::invalid_attribute(tag, "the value is empty"))
+}
+
+/// The NumberOfFrames of an image, 1 if it is absent.
+pub(crate) fn get_number_of_frames(obj: &InMemDicomObject) -> Result<usize> {
+    Ok(get_element_opt(obj, dicom_tags::NUMBER_OF_FRAMES)?
+        .map(|e| e.to_int::<usize>())
+        .transpose()
+        .map_err(|e| Error::invalid_attribute(dicom_tags::NUMBER_OF_FRAMES, e))?
+        .unwrap_or(1))
+}
+
+/// The items of a sequence attribute.
+pub(crate) fn get_items(obj: &InMemDicomObject, tag: Tag) -> Result<&[InMemDicomObject]> {
+    get_element(obj, tag)?
+        .items()
+        .ok_or_else(|| Error::invalid_attribute(tag, "expected a sequence"))
+}
+
+/// The first item of a sequence attribute.
+pub(crate) fn get_first_item(obj: &InMemDicomObject, tag: Tag) -> Result<&InMemDicomObject> {
+    get_items(obj, tag)?
+        .first()
+        .ok_or_else(|| Error::invalid_attribute(tag, "the sequence is empty"))
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::VR;
+
+    use super::*;
+    use crate::testing::strings;
+
+    #[test]
+    fn strings_are_read_without_padding_and_must_not_be_empty() {
+        let tag = dicom_tags::PHOTOMETRIC_INTERPRETATION;
+        let get = |value| {
+            get_str(
+                &InMemDicomObject::from_element_iter([strings(tag, VR::CS, &[value])]),
+                tag,
+            )
+        };
+        assert_eq!(get(" RGB \0").unwrap(), "RGB");
+        assert!(matches!(
+            get(" \0"),
+            Err(Error::InvalidAttribute { tag: t, .. }) if t == tag
+        ));
+        assert!(get_str(&InMemDicomObject::new_empty(), tag).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::InMemDicomObject;
+
+use crate::converter::{ConversionOptions, FocalPlaneSelection, OutputFlavor};
+use crate::error::{Error, Result, get_element_opt};
+use crate::slide::DicomPyramidSources;
+
+/// The focal planes of an image: the distinct Z offsets of its frames, and the plane of every
+/// frame.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FocalPlanes {
+    /// The Z offsets of the planes in the slide coordinate system, in millimeters, from the
+    /// lowest up
+    pub z_offsets: Vec<f64>,
+    /// The index of the plane of every frame
+    pub frame_planes: Vec<usize>,
+}
+
+impl FocalPlanes {
+    /// Reads the focal planes of the frames of an image from the Z offsets of their plane
+    /// positions. Frames without their own position are in the plane of the shared functional
+    /// groups, unless the image has several focal planes and is fully tiled with
+    /// `tiles_per_plane` tiles, whose frames are then ordered by plane after their position.
+    /// These planes are spaced by the Spacing Between Slices, or told apart by their order only.
+    pub fn read(
+        obj: &InMemDicomObject,
+        num_frames: usize,
+        tiles_per_plane: Option<usize>,
+    ) -> Result<Self> {
+        let per_frame_items =
+            get_element_opt(obj, dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)?
+                .and_then(|e| e.items())
+                .filter(|items| !items.is_empty() && items.len() == num_frames);
+        if let Some(items) = per_frame_items {
+            let frame_z_offsets = items
+                .iter()
+                .map(get_z_offset)
+                .collect::<Result<Option<Vec<_>>>>()?;
+            if let Some(frame_z_offsets) = frame_z_offsets {
+                let mut z_offsets = frame_z_offsets.clone();
+                z_offsets.sort_by(f64::total_cmp);
+                z_offsets.dedup();
+                let frame_planes = frame_z_offsets
+                    .iter()
+                    .map(|z| z_offsets.partition_point(|plane| plane < z))
+                    .collect();
+                return Ok(Self {
+                    z_offsets,
+                    frame_planes,
+                });
+            }
+        }
+
+        let shared_item = get_element_opt(obj, dicom_tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE)?
+            .and_then(|e| e.items())
+            .and_then(<[_]>::first);
+        let shared_z_offset = shared_item
+            .map(get_z_offset)
+            .transpose()?
+            .flatten()
+            .unwrap_or(0.0);
+        let num_planes = get_element_opt(obj, dicom_tags::TOTAL_PIXEL_MATRIX_FOCAL_PLANES)?
+            .map(|e| e.to_int::<usize>())
+            .transpose()
+            .map_err(|e| Error::invalid_attribute(dicom_tags::TOTAL_PIXEL_MATRIX_FOCAL_PLANES, e))?
+            .unwrap_or(1)
+            .max(1);
+        let tiles_per_plane = tiles_per_plane.filter(|tiles| *tiles > 0);
+        let (num_planes, spacing) = match tiles_per_plane {
+            Some(_) if num_planes > 1 => {
+                let spacing = shared_item
+                    .and_then(|item| item.element(dicom_tags::PIXEL_MEASURES_SEQUENCE).ok())
+                    .and_then(|e| e.items())
+                    .and_then(<[_]>::first)
+                    .and_then(|item| item.element(dicom_tags::SPACING_BETWEEN_SLICES).ok())
+                    .and_then(|e| e.to_float64().ok())
+                    .filter(|spacing| spacing.is_finite() && *spacing > 0.0);
+                (num_planes, spacing.unwrap_or(1.0))
+            }
+            _ => (1, 0.0),
+        };
+        Ok(Self {
+            z_offsets: (0..num_planes)
+                .map(|plane| shared_z_offset + plane as f64 * spacing)
+                .collect(),
+            frame_planes: (0..num_frames)
+                .map(|frame| match tiles_per_plane {
+                    Some(tiles) => frame / tiles % num_planes,
+                    None => 0,
+                })
+                .collect(),
+        })
+    }
+
+    /// The index of the plane nearest to a Z offset.
+    pub fn nearest(&self, z_offset: f64) -> usize {
+        nearest(&self.z_offsets, z_offset)
+    }
+}
+
+/// The Z offset of the plane position of a functional groups item, if it has one.
+fn get_z_offset(item: &InMemDicomObject) -> Result<Option<f64>> {
+    let Some(plane_position) = get_element_opt(item, dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE)?
+        .and_then(|e| e.items())
+        .and_then(<[_]>::first)
+    else {
+        return Ok(None);
+    };
+    get_element_opt(
+        plane_position,
+        dicom_tags::Z_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+    )?
+    .map(|e| {
+        e.to_float64().map_err(|e| {
+            Error::invalid_attribute(dicom_tags::Z_OFFSET_IN_SLIDE_COORDINATE_SYSTEM, e)
+        })
+    })
+    .transpose()
+}
+
+/// The index of the Z offset nearest to `z_offset`, the first of equally near ones.
+fn nearest(z_offsets: &[f64], z_offset: f64) -> usize {
+    z_offsets
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| (*a - z_offset).abs().total_cmp(&(*b - z_offset).abs()))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Maps every tile slot to the frame which holds it in the selected focal plane, given the slot
+/// of every frame, `None` for frames which are left out. Of several frames of a plane at the
+/// same slot, the first is kept. An extended depth of field takes the plane whose frame is the largest at
+/// every slot, which is usually the sharpest since sharp detail compresses less, and the nominal
+/// plane of equally large frames.
+pub(crate) fn select_tile_frames(
+    frame_slots: &[Option<usize>],
+    slots: usize,
+    planes: &FocalPlanes,
+    selection: FocalPlaneSelection,
+    frame_len: impl Fn(usize) -> u64,
+) -> Vec<Option<usize>> {
+    let num_planes = planes.z_offsets.len().max(1);
+    let nominal_plane = planes.nearest(0.0);
+    let selected_plane = match selection {
+        FocalPlaneSelection::Nominal | FocalPlaneSelection::All => Some(nominal_plane),
+        FocalPlaneSelection::Index(index) => Some(index.min(num_planes - 1)),
+        FocalPlaneSelection::ZOffset(z_offset) => Some(planes.nearest(z_offset)),
+        FocalPlaneSelection::ExtendedDepthOfField => None,
+    };
+
+    let mut tile_frames = vec![None; slots];
+    let mut is_taken = vec![false; slots * num_planes];
+    for (frame, &slot) in frame_slots.iter().enumerate() {
+        let Some(slot) = slot else {
+            continue;
+        };
+        let plane = planes.frame_planes.get(frame).copied().unwrap_or(0);
+        let taken = &mut is_taken[slot * num_planes + plane];
+        if *taken {
+            continue;
+        }
+        *taken = true;
+        let tile_frame = &mut tile_frames[slot];
+        match (selected_plane, *tile_frame) {
+            (Some(selected_plane), _) if plane != selected_plane => {}
+            (Some(_), _) | (None, None) => *tile_frame = Some(frame),
+            (None, Some(current)) => {
+                let (len, current_len) = (frame_len(frame), frame_len(current));
+                if len > current_len || (len == current_len && plane == nominal_plane) {
+                    *tile_frame = Some(frame);
+                }
+            }
+        }
+    }
+    tile_frames
+}
+
+/// The sources and options of every focal plane a conversion to a TIFF writes: the plane
+/// selected by the options, or every plane of level 0 in an OME-TIFF with
+/// [`FocalPlaneSelection::All`]. The selection is resolved to the Z offset of a plane of level
+/// 0, so that every level is converted from its plane nearest to it, whether the planes of the
+/// level are in one instance or in separate ones.
+pub(crate) fn select_focal_planes<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+) -> Result<Vec<(DicomPyramidSources<'a>, ConversionOptions)>> {
+    if options.focal_planes != FocalPlaneSelection::All || options.flavor != OutputFlavor::Ome {
+        return Ok(vec![select_focal_plane(sources, options)?]);
+    }
+    Ok(sources
+        .focal_planes()
+        .into_iter()
+        .map(|z_offset| select_z_offset(sources, options, z_offset))
+        .collect())
+}
+
+/// The sources and options of the focal plane selected by the options, for outputs which hold a
+/// single plane.
+pub(crate) fn select_focal_plane<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+) -> Result<(DicomPyramidSources<'a>, ConversionOptions)> {
+    let z_offsets = sources.focal_planes();
+    let z_offset = match options.focal_planes {
+        FocalPlaneSelection::Nominal => z_offsets.get(nearest(&z_offsets, 0.0)).copied(),
+        FocalPlaneSelection::Index(index) => {
+            if index >= z_offsets.len() {
+                return Err(Error::UnsupportedPixelData(format!(
+                    "level 0 has no focal plane {}, only {}",
+                    index,
+                    z_offsets.len()
+                )));
+            }
+            Some(z_offsets[index])
+        }
+        FocalPlaneSelection::ZOffset(z_offset) => Some(z_offset),
+        FocalPlaneSelection::ExtendedDepthOfField => {
+            if sources.has_split_focal_planes() {
+                return Err(Error::UnsupportedPixelData(
+                    "an extended depth of field needs the focal planes of every level in a \
+                     single instance"
+                        .to_string(),
+                ));
+            }
+            None
+        }
+        FocalPlaneSelection::All => {
+            return Err(Error::UnsupportedPixelData(
+                "all focal planes can only be written to an OME-TIFF".to_string(),
+            ));
+        }
+    };
+    Ok(match z_offset {
+        Some(z_offset) => select_z_offset(sources, options, z_offset),
+        None => (sources.clone(), options.clone()),
+    })
+}
+
+fn select_z_offset<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+    z_offset: f64,
+) -> (DicomPyramidSources<'a>, ConversionOptions) {
+    let options = options
+        .clone()
+        .focal_planes(FocalPlaneSelection::ZOffset(z_offset));
+    (sources.with_focal_plane(z_offset), options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::instance;
+
+    /// Three focal planes of two tiles each, the frames ordered by plane
+    fn three_planes() -> FocalPlanes {
+        FocalPlanes {
+            z_offsets: vec![-1.0, 0.5, 2.0],
+            frame_planes: vec![0, 0, 1, 1, 2, 2],
+        }
+    }
+
+    fn select(selection: FocalPlaneSelection) -> Vec<Option<usize>> {
+        let frame_slots = [Some(0), Some(1), Some(0), Some(1), Some(0), Some(1)];
+        select_tile_frames(&frame_slots, 2, &three_planes(), selection, |_| 0)
+    }
+
+    #[test]
+    fn the_frames_of_the_selected_plane_are_selected() {
+        // The nominal plane is the one nearest to Z offset 0
+        assert_eq!(select(FocalPlaneSelection::Nominal), [Some(2), Some(3)]);
+        assert_eq!(select(FocalPlaneSelection::All), [Some(2), Some(3)]);
+        assert_eq!(select(FocalPlaneSelection::Index(0)), [Some(0), Some(1)]);
+        assert_eq!(select(FocalPlaneSelection::Index(5)), [Some(4), Some(5)]);
+        assert_eq!(
+            select(FocalPlaneSelection::ZOffset(1.5)),
+            [Some(4), Some(5)]
+        );
+    }
+
+    #[test]
+    fn the_first_of_several_frames_of_a_plane_at_a_slot_is_kept() {
+        let planes = FocalPlanes {
+            z_offsets: vec![0.0],
+            frame_planes: vec![0; 4],
+        };
+        let frame_slots = [None, Some(1), Some(1), Some(0)];
+        let tile_frames = select_tile_frames(
+            &frame_slots,
+            3,
+            &planes,
+            FocalPlaneSelection::Nominal,
+            |_| 0,
+        );
+        assert_eq!(tile_frames, [Some(3), Some(1), None]);
+    }
+
+    #[test]
+    fn an_extended_depth_of_field_takes_the_largest_frame_of_every_slot() {
+        let frame_slots = [Some(0), Some(1), Some(0), Some(1), Some(0), Some(1)];
+        // At slot 0 plane 2 is the largest, at slot 1 planes 0 and 1 are equally large
+        let frame_lens = [10, 30, 20, 30, 40, 5];
+        let tile_frames = select_tile_frames(
+            &frame_slots,
+            2,
+            &three_planes(),
+            FocalPlaneSelection::ExtendedDepthOfField,
+            |frame| frame_lens[frame],
+        );
+        assert_eq!(tile_frames, [Some(4), Some(3)]);
+    }
+
+    /// Two levels whose focal planes are in an instance each, the nominal one (nearest to Z
+    /// offset 0) of each level as the level.
+    fn split_planes<'a>() -> DicomPyramidSources<'a> {
+        DicomPyramidSources {
+            levels: vec![instance(0, &[0.2], &[]), instance(1, &[0.2], &[])],
+            other_instances: vec![
+                vec![instance(2, &[-0.5], &[]), instance(3, &[1.0], &[])],
+                vec![instance(4, &[-0.4], &[])],
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn level_indices(sources: &DicomPyramidSources) -> Vec<usize> {
+        sources
+            .levels
+            .iter()
+            .map(|instance| instance.index)
+            .collect()
+    }
+
+    #[test]
+    fn every_level_is_converted_from_its_plane_nearest_to_the_selected_one() {
+        let sources = split_planes();
+        let select = |selection| {
+            let options = ConversionOptions::default().focal_planes(selection);
+            let (sources, options) = select_focal_plane(&sources, &options).unwrap();
+            assert!(!sources.has_split_focal_planes());
+            (level_indices(&sources), options.focal_planes)
+        };
+        assert_eq!(
+            select(FocalPlaneSelection::Nominal),
+            (vec![0, 1], FocalPlaneSelection::ZOffset(0.2))
+        );
+        assert_eq!(
+            select(FocalPlaneSelection::Index(0)),
+            (vec![2, 4], FocalPlaneSelection::ZOffset(-0.5))
+        );
+        assert_eq!(
+            select(FocalPlaneSelection::ZOffset(0.9)),
+            (vec![3, 1], FocalPlaneSelection::ZOffset(0.9))
+        );
+    }
+
+    #[test]
+    fn unavailable_focal_plane_selections_are_unsupported() {
+        let sources = split_planes();
+        for selection in [
+            FocalPlaneSelection::Index(3),
+            FocalPlaneSelection::ExtendedDepthOfField,
+            FocalPlaneSelection::All,
+        ] {
+            let options = ConversionOptions::default().focal_planes(selection);
+            assert!(matches!(
+                select_focal_plane(&sources, &options),
+                Err(Error::UnsupportedPixelData(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn an_ome_tiff_may_hold_every_focal_plane_of_level_0() {
+        let options = ConversionOptions::default()
+            .flavor(OutputFlavor::Ome)
+            .focal_planes(FocalPlaneSelection::All);
+        let planes = select_focal_planes(&split_planes(), &options).unwrap();
+        let planes: Vec<_> = planes
+            .iter()
+            .map(|(sources, options)| (level_indices(sources), options.focal_planes))
+            .collect();
+        assert_eq!(
+            planes,
+            [
+                (vec![2, 4], FocalPlaneSelection::ZOffset(-0.5)),
+                (vec![0, 1], FocalPlaneSelection::ZOffset(0.2)),
+                (vec![3, 1], FocalPlaneSelection::ZOffset(1.0)),
+            ]
+        );
+    }
+}
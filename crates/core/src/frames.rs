@@ -0,0 +1,318 @@
+use std::borrow::Cow;
+
+use crate::error::{Error, Result};
+use crate::pixel_data::PixelData;
+
+/// The memory layout of a native (uncompressed) frame.
+#[derive(Clone, Copy, Debug)]
+pub struct NativeLayout {
+    pub rows: u16,
+    pub columns: u16,
+    pub samples_per_pixel: u16,
+    pub bits_allocated: u16,
+    /// Whether the samples are stored color-by-plane (PlanarConfiguration 1)
+    pub planar: bool,
+    /// Whether the pixels are YBR_FULL_422, i.e. two luminance samples share a pair of
+    /// chrominance samples
+    pub subsampled_422: bool,
+}
+
+impl NativeLayout {
+    pub fn bytes_per_sample(&self) -> usize {
+        usize::from(self.bits_allocated / 8)
+    }
+
+    /// The number of bytes in one row of an interleaved frame.
+    pub fn row_len(&self) -> usize {
+        let samples_per_row = if self.subsampled_422 {
+            // Each pair of pixels is stored as Y Y Cb Cr
+            usize::from(self.columns) * 2
+        } else {
+            usize::from(self.columns) * usize::from(self.samples_per_pixel)
+        };
+        samples_per_row * self.bytes_per_sample()
+    }
+
+    pub fn frame_len(&self) -> usize {
+        self.row_len() * usize::from(self.rows)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.bits_allocated != 8 && self.bits_allocated != 16 {
+            return Err(Error::UnsupportedPixelData(format!(
+                "{} bits allocated in native pixel data",
+                self.bits_allocated
+            )));
+        }
+        if self.subsampled_422 && (self.planar || self.samples_per_pixel != 3) {
+            return Err(Error::UnsupportedPixelData(
+                "YBR_FULL_422 native pixel data must be 3 interleaved samples".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Converts a color-by-plane frame into a color-by-pixel frame, which is what TIFF tiles
+    /// with PlanarConfiguration 1 contain.
+    fn interleave(&self, frame: &[u8]) -> Vec<u8> {
+        let samples = usize::from(self.samples_per_pixel);
+        let bytes_per_sample = self.bytes_per_sample();
+        let plane_len = frame.len() / samples;
+        let mut interleaved = vec![0; frame.len()];
+        for (sample, plane) in frame.chunks_exact(plane_len).enumerate() {
+            for (pixel, value) in plane.chunks_exact(bytes_per_sample).enumerate() {
+                let start = (pixel * samples + sample) * bytes_per_sample;
+                interleaved[start..start + bytes_per_sample].copy_from_slice(value);
+            }
+        }
+        interleaved
+    }
+}
+
+/// The frames of the pixel data of a DICOM instance.
+pub enum Frames<'a> {
+    /// Encapsulated frames which are copied as-is.
+    Encapsulated(PixelData<'a>),
+    /// Native frames. 16 bit samples are little endian.
+    Native {
+        data: PixelData<'a>,
+        layout: NativeLayout,
+    },
+    /// RLE Lossless frames which are decoded into native frames.
+    Rle {
+        fragments: PixelData<'a>,
+        layout: NativeLayout,
+    },
+}
+
+impl Frames<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            Frames::Encapsulated(data)
+            | Frames::Native { data, .. }
+            | Frames::Rle {
+                fragments: data, ..
+            } => data.len(),
+        }
+    }
+
+    /// The number of bytes of the frame at `index` in the pixel data, compressed or not.
+    pub fn encoded_len(&self, index: usize) -> u64 {
+        match self {
+            Frames::Encapsulated(data)
+            | Frames::Native { data, .. }
+            | Frames::Rle {
+                fragments: data, ..
+            } => data.frame_len(index),
+        }
+    }
+
+    /// The frame at `index`. Native frames are always color-by-pixel, with 16 bit samples in
+    /// native byte order.
+    pub fn get(&self, index: usize) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Frames::Encapsulated(data) => data.frame(index),
+            Frames::Native { data, layout } => {
+                let mut frame = data.frame(index)?;
+                if layout.bits_allocated == 16 && cfg!(target_endian = "big") {
+                    for sample in frame.to_mut().chunks_exact_mut(2) {
+                        sample.swap(0, 1);
+                    }
+                }
+                if layout.planar && layout.samples_per_pixel > 1 {
+                    Ok(Cow::Owned(layout.interleave(&frame)))
+                } else {
+                    Ok(frame)
+                }
+            }
+            Frames::Rle { fragments, layout } => Ok(Cow::Owned(decode_rle_frame(
+                &fragments.frame(index)?,
+                layout,
+            )?)),
+        }
+    }
+}
+
+/// Decodes a DICOM RLE Lossless frame (PS3.5 Annex G) into a color-by-pixel native frame.
+fn decode_rle_frame(fragment: &[u8], layout: &NativeLayout) -> Result<Vec<u8>> {
+    if fragment.len() < 64 {
+        return Err(Error::InvalidPixelData(
+            "RLE frame is missing its header".to_string(),
+        ));
+    }
+    let read_u32 = |pos: usize| {
+        u32::from_le_bytes([
+            fragment[pos],
+            fragment[pos + 1],
+            fragment[pos + 2],
+            fragment[pos + 3],
+        ]) as usize
+    };
+    let num_segments = read_u32(0);
+    let bytes_per_sample = layout.bytes_per_sample();
+    let samples = usize::from(layout.samples_per_pixel);
+    if layout.subsampled_422 || num_segments != samples * bytes_per_sample {
+        return Err(Error::InvalidPixelData(format!(
+            "unexpected number of RLE segments: {}",
+            num_segments
+        )));
+    }
+
+    let num_pixels = usize::from(layout.rows) * usize::from(layout.columns);
+    let mut frame = vec![0; num_pixels * samples * bytes_per_sample];
+    let mut segment_data = Vec::with_capacity(num_pixels);
+    for segment in 0..num_segments {
+        let start = read_u32(4 + segment * 4);
+        let end = if segment + 1 < num_segments {
+            read_u32(4 + (segment + 1) * 4)
+        } else {
+            fragment.len()
+        };
+        if start > end || end > fragment.len() {
+            return Err(Error::InvalidPixelData(
+                "invalid RLE segment offsets".to_string(),
+            ));
+        }
+        decode_packbits(&fragment[start..end], &mut segment_data, num_pixels)?;
+
+        // Segments hold the bytes of each sample from most to least significant, whereas native
+        // samples are in native byte order.
+        let sample = segment / bytes_per_sample;
+        let significance = segment % bytes_per_sample;
+        let byte = if cfg!(target_endian = "little") {
+            bytes_per_sample - 1 - significance
+        } else {
+            significance
+        };
+        for (pixel, value) in segment_data.iter().enumerate() {
+            frame[(pixel * samples + sample) * bytes_per_sample + byte] = *value;
+        }
+    }
+    Ok(frame)
+}
+
+fn decode_packbits(data: &[u8], out: &mut Vec<u8>, len: usize) -> Result<()> {
+    out.clear();
+    let mut pos = 0;
+    while out.len() < len && pos < data.len() {
+        let header = data[pos] as i8;
+        pos += 1;
+        if header >= 0 {
+            let count = header as usize + 1;
+            let literal = data
+                .get(pos..pos + count)
+                .ok_or_else(|| Error::InvalidPixelData("truncated RLE literal run".to_string()))?;
+            out.extend_from_slice(literal);
+            pos += count;
+        } else if header != -128 {
+            let count = 1 - header as isize;
+            let value = *data.get(pos).ok_or_else(|| {
+                Error::InvalidPixelData("truncated RLE replicate run".to_string())
+            })?;
+            out.extend(std::iter::repeat_n(value, count as usize));
+            pos += 1;
+        }
+    }
+    if out.len() < len {
+        return Err(Error::InvalidPixelData(
+            "RLE segment is too short".to_string(),
+        ));
+    }
+    out.truncate(len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(
+        rows: u16,
+        columns: u16,
+        samples_per_pixel: u16,
+        bits_allocated: u16,
+    ) -> NativeLayout {
+        NativeLayout {
+            rows,
+            columns,
+            samples_per_pixel,
+            bits_allocated,
+            planar: false,
+            subsampled_422: false,
+        }
+    }
+
+    /// An RLE frame: the 64 byte header with the segment count and offsets, then the segments.
+    fn rle_frame(segments: &[&[u8]]) -> Vec<u8> {
+        let mut frame = vec![0; 64];
+        frame[..4].copy_from_slice(&(segments.len() as u32).to_le_bytes());
+        for (index, segment) in segments.iter().enumerate() {
+            let offset = frame.len() as u32;
+            frame[4 + index * 4..][..4].copy_from_slice(&offset.to_le_bytes());
+            frame.extend_from_slice(segment);
+        }
+        frame
+    }
+
+    #[test]
+    fn decodes_literal_and_replicate_runs() {
+        // 3 literal bytes, a no-op header, then 3 copies of 9
+        let frame = rle_frame(&[&[0x02, 1, 2, 3, 0x80, 0xFE, 9]]);
+        assert_eq!(
+            decode_rle_frame(&frame, &layout(2, 3, 1, 8)).unwrap(),
+            [1, 2, 3, 9, 9, 9]
+        );
+    }
+
+    #[test]
+    fn drops_bytes_past_the_end_of_the_segment() {
+        // A replicate run of 4 for a frame of 2 pixels, padded to an even length
+        let frame = rle_frame(&[&[0xFD, 7, 0x00]]);
+        assert_eq!(
+            decode_rle_frame(&frame, &layout(1, 2, 1, 8)).unwrap(),
+            [7, 7]
+        );
+    }
+
+    #[test]
+    fn interleaves_the_segments_of_samples() {
+        let frame = rle_frame(&[&[0x01, 10, 11], &[0xFF, 20], &[0x01, 30, 31]]);
+        assert_eq!(
+            decode_rle_frame(&frame, &layout(1, 2, 3, 8)).unwrap(),
+            [10, 20, 30, 11, 20, 31]
+        );
+    }
+
+    #[test]
+    fn decodes_16_bit_samples_to_native_byte_order() {
+        // The most significant bytes come first
+        let frame = rle_frame(&[&[0x01, 0x12, 0xAB], &[0x01, 0x34, 0xCD]]);
+        let expected: Vec<u8> = [0x1234u16, 0xABCD]
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            decode_rle_frame(&frame, &layout(1, 2, 1, 16)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let gray = layout(2, 2, 1, 8);
+        // The header is cut off
+        assert!(decode_rle_frame(&[1, 0, 0, 0], &gray).is_err());
+        // One segment for 3 samples
+        assert!(decode_rle_frame(&rle_frame(&[&[0xFD, 0]]), &layout(2, 2, 3, 8)).is_err());
+        // A segment with 3 of the 4 pixels
+        assert!(decode_rle_frame(&rle_frame(&[&[0xFE, 0]]), &gray).is_err());
+        // A literal run of 4 with 2 bytes left
+        assert!(decode_rle_frame(&rle_frame(&[&[0x03, 1, 2]]), &gray).is_err());
+        // A replicate run without its value
+        assert!(decode_rle_frame(&rle_frame(&[&[0xFD]]), &gray).is_err());
+        // A segment offset past the end of the frame
+        let mut frame = rle_frame(&[&[0xFD, 0]]);
+        frame[4] = 0xFF;
+        assert!(decode_rle_frame(&frame, &gray).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::{DefaultDicomObject, InMemDicomObject};
+use tiff::tags::{CompressionMethod, PhotometricInterpretation as TiffPhotometricInterpretation};
+
+use crate::compression::{self, Codec, NativeCompression, PixelEncoding};
+use crate::converter::{ChannelSelection, FocalPlaneSelection};
+use crate::error::{
+    Error, Result, get_element, get_element_opt, get_first_item, get_i64, get_items, get_opt_u16,
+    get_str, get_u16, get_u32,
+};
+use crate::focal_planes::{self, FocalPlanes};
+use crate::frames::{Frames, NativeLayout};
+use crate::optical_paths::OpticalPaths;
+use crate::pixel_data::PixelData;
+use crate::shared_read_seek::SharedReadSeek;
+use crate::tiff_writer::read_dicom_header;
+
+/// The image attributes of a DICOM WSI instance which are needed to write it as a TIFF image.
+pub struct DicomImage {
+    pub image_width: u32,
+    pub image_height: u32,
+    pub tile_width: u16,
+    pub tile_height: u16,
+    pub is_sparse: bool,
+    pub tiff_photometric_interpretation: TiffPhotometricInterpretation,
+    pub subsampling: Option<[u16; 2]>,
+    pub samples_per_pixel: u16,
+    pub bits_stored: u16,
+    pub is_signed: bool,
+    pub native_layout: NativeLayout,
+    pub pixel_encoding: PixelEncoding,
+}
+
+/// The frames of a DICOM image together with how they are stored as TIFF tiles or strips.
+pub struct TileData<'a> {
+    pub frames: Frames<'a>,
+    pub tiff_compression: CompressionMethod,
+    /// The compression applied to native frames, `None` if encapsulated frames are copied as-is
+    pub native_compression: Option<NativeCompression>,
+}
+
+impl DicomImage {
+    pub fn from_object(dcm_object: &dicom_object::DefaultDicomObject) -> Result<Self> {
+        let is_sparse = get_element_opt(dcm_object, dicom_tags::DIMENSION_ORGANIZATION_TYPE)?
+            .and_then(|e| e.to_str().ok())
+            .is_some_and(|s| s.trim() == "TILED_SPARSE");
+
+        let image_height = get_u32(dcm_object, dicom_tags::TOTAL_PIXEL_MATRIX_ROWS)?;
+        let image_width = get_u32(dcm_object, dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS)?;
+        let tile_height = get_u16(dcm_object, dicom_tags::ROWS)?;
+        let tile_width = get_u16(dcm_object, dicom_tags::COLUMNS)?;
+        // Tiles and frames are counted by dividing by the frame size
+        for (tag, value) in [
+            (dicom_tags::ROWS, tile_height),
+            (dicom_tags::COLUMNS, tile_width),
+        ] {
+            if value == 0 {
+                return Err(Error::invalid_attribute(tag, "the frames are empty"));
+            }
+        }
+        let dcm_photometric_interpretation =
+            get_str(dcm_object, dicom_tags::PHOTOMETRIC_INTERPRETATION)?;
+        let dcm_photometric_interpretation = dcm_photometric_interpretation.as_str();
+        let (tiff_photometric_interpretation, subsampling) =
+            dicom_photometric_interpretation_to_tiff(dcm_photometric_interpretation)?;
+        let samples_per_pixel = get_u16(dcm_object, dicom_tags::SAMPLES_PER_PIXEL)?;
+
+        let bits_stored = get_u16(dcm_object, dicom_tags::BITS_STORED)?;
+        let bits_allocated = get_u16(dcm_object, dicom_tags::BITS_ALLOCATED)?;
+        let is_signed = get_opt_u16(dcm_object, dicom_tags::PIXEL_REPRESENTATION)? == Some(1);
+        let planar_configuration =
+            get_opt_u16(dcm_object, dicom_tags::PLANAR_CONFIGURATION)?.unwrap_or(0);
+        let native_layout = NativeLayout {
+            rows: tile_height,
+            columns: tile_width,
+            samples_per_pixel,
+            bits_allocated,
+            planar: planar_configuration == 1,
+            subsampled_422: dcm_photometric_interpretation == "YBR_FULL_422",
+        };
+
+        // The transfer syntax describes how the pixel data is actually encoded, whereas the
+        // lossy image compression method may also describe an earlier compression.
+        let lossy_image_compression_method =
+            get_element_opt(dcm_object, dicom_tags::LOSSY_IMAGE_COMPRESSION_METHOD)?
+                .and_then(|e| e.to_multi_str().ok())
+                .and_then(|methods| methods.first().map(|m| m.trim().to_string()));
+        let pixel_encoding = compression::get_pixel_encoding(
+            dcm_object.meta().transfer_syntax(),
+            lossy_image_compression_method.as_deref(),
+        )?;
+
+        Ok(Self {
+            image_width,
+            image_height,
+            tile_width,
+            tile_height,
+            is_sparse,
+            tiff_photometric_interpretation,
+            subsampling,
+            samples_per_pixel,
+            bits_stored,
+            is_signed,
+            native_layout,
+            pixel_encoding,
+        })
+    }
+
+    pub fn tiles_across(&self) -> u32 {
+        self.image_width.div_ceil(u32::from(self.tile_width))
+    }
+
+    pub fn tiles_down(&self) -> u32 {
+        self.image_height.div_ceil(u32::from(self.tile_height))
+    }
+
+    /// Locates the frames of the pixel data in the source of `header` (the attributes before the
+    /// pixel data), followed by those of the further instances of a concatenation, and
+    /// determines how they are stored in the TIFF.
+    pub fn get_tile_data<'a>(
+        &self,
+        header: &DefaultDicomObject,
+        source: SharedReadSeek<'a>,
+        concatenation: &[SharedReadSeek<'a>],
+        native_compression: NativeCompression,
+    ) -> Result<TileData<'a>> {
+        let native_layout = self.native_layout;
+        if matches!(
+            self.pixel_encoding,
+            PixelEncoding::Native | PixelEncoding::Encapsulated(Codec::Rle)
+        ) {
+            native_layout.validate()?;
+        }
+        let locate = |header: &DefaultDicomObject, source| match self.pixel_encoding {
+            PixelEncoding::Encapsulated(_) => PixelData::encapsulated(header, source),
+            PixelEncoding::Native => PixelData::native(header, source, native_layout.frame_len()),
+        };
+        let pixel_data = if concatenation.is_empty() {
+            locate(header, source)?
+        } else {
+            // Every instance is located with its own header, which tells how many frames it has
+            let mut pixel_data = locate(&read_dicom_header(source.clone())?, source)?;
+            for source in concatenation {
+                pixel_data.append(locate(&read_dicom_header(source.clone())?, source.clone())?);
+            }
+            pixel_data
+        };
+        let tile_data = match self.pixel_encoding {
+            PixelEncoding::Encapsulated(Codec::Rle) => TileData {
+                frames: Frames::Rle {
+                    fragments: pixel_data,
+                    layout: native_layout,
+                },
+                tiff_compression: native_compression.tiff_compression(),
+                native_compression: Some(native_compression),
+            },
+            PixelEncoding::Encapsulated(codec) => TileData {
+                frames: Frames::Encapsulated(pixel_data),
+                tiff_compression: compression::get_tiff_compression(
+                    codec,
+                    self.tiff_photometric_interpretation,
+                    self.bits_stored,
+                )?,
+                native_compression: None,
+            },
+            PixelEncoding::Native => {
+                if pixel_data.len() == 0 {
+                    return Err(Error::invalid_attribute(
+                        dicom_tags::PIXEL_DATA,
+                        "smaller than one frame",
+                    ));
+                }
+                TileData {
+                    frames: Frames::Native {
+                        data: pixel_data,
+                        layout: native_layout,
+                    },
+                    tiff_compression: native_compression.tiff_compression(),
+                    native_compression: Some(native_compression),
+                }
+            }
+        };
+        Ok(tile_data)
+    }
+
+    /// The bits per sample of the TIFF image. Native tiles hold whole allocated samples, whereas
+    /// the bits of encapsulated frames are the bits stored.
+    pub fn bits_per_sample(&self, tile_data: &TileData) -> Vec<u16> {
+        let bits = if tile_data.native_compression.is_some() {
+            self.native_layout.bits_allocated
+        } else {
+            self.bits_stored
+        };
+        vec![bits; self.samples_per_pixel as usize]
+    }
+
+    /// Maps every tile slot (in row-major TIFF tile order) to the index of the frame that holds
+    /// its pixel data in the selected focal plane and channel. Slots without a frame are `None`.
+    /// An extended depth of field compares the frames by their encoded length, `frame_len`.
+    pub fn get_tile_frames(
+        &self,
+        dcm_object: &InMemDicomObject,
+        num_frames: usize,
+        focal_plane: FocalPlaneSelection,
+        channel: &ChannelSelection,
+        frame_len: impl Fn(usize) -> u64,
+    ) -> Result<Vec<Option<usize>>> {
+        let tiles = self.tiles_across() as usize * self.tiles_down() as usize;
+        if tiles == 0 {
+            return Err(Error::invalid_attribute(
+                dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS,
+                "the total pixel matrix is empty",
+            ));
+        }
+        let frame_slots = if self.is_sparse {
+            get_sparse_frame_slots(
+                dcm_object,
+                num_frames,
+                (self.tile_width, self.tile_height),
+                (self.tiles_across(), self.tiles_down()),
+            )?
+        } else {
+            (0..num_frames).map(|frame| frame % tiles).collect()
+        };
+        let planes = FocalPlanes::read(dcm_object, num_frames, (!self.is_sparse).then_some(tiles))?;
+        let frames_per_path = (!self.is_sparse).then_some(tiles * planes.z_offsets.len());
+        let paths = OpticalPaths::read(dcm_object, num_frames, frames_per_path)?;
+        let path = paths.select(channel)?;
+        // Frames of other optical paths are left out
+        let frame_slots: Vec<Option<usize>> = frame_slots
+            .into_iter()
+            .zip(&paths.frame_paths)
+            .map(|(slot, &frame_path)| (frame_path == path).then_some(slot))
+            .collect();
+        Ok(focal_planes::select_tile_frames(
+            &frame_slots,
+            tiles,
+            &planes,
+            focal_plane,
+            frame_len,
+        ))
+    }
+}
+
+fn dicom_photometric_interpretation_to_tiff(
+    dcm_photometric_interpretation: &str,
+) -> Result<(TiffPhotometricInterpretation, Option<[u16; 2]>)> {
+    match dcm_photometric_interpretation {
+        "MONOCHROME1" => Ok((TiffPhotometricInterpretation::WhiteIsZero, None)),
+        "MONOCHROME2" => Ok((TiffPhotometricInterpretation::BlackIsZero, None)),
+        "RGB" => Ok((TiffPhotometricInterpretation::RGB, None)),
+        "YBR_FULL" => Ok((TiffPhotometricInterpretation::YCbCr, Some([1, 1]))),
+        "YBR_FULL_422" => Ok((TiffPhotometricInterpretation::YCbCr, Some([2, 1]))),
+        "YBR_ICT" | "YBR_RCT" => Ok((TiffPhotometricInterpretation::YCbCr, None)),
+        _ => Err(Error::UnsupportedPhotometricInterpretation(
+            dcm_photometric_interpretation.to_string(),
+        )),
+    }
+}
+
+/// The tile slot (in row-major TIFF tile order) of every frame of a TILED_SPARSE image, from the
+/// per-frame plane positions.
+fn get_sparse_frame_slots(
+    dcm_object: &InMemDicomObject,
+    num_frames: usize,
+    (tile_width, tile_height): (u16, u16),
+    (tiles_across, tiles_down): (u32, u32),
+) -> Result<Vec<usize>> {
+    let per_frame_items = get_items(dcm_object, dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)?;
+    if per_frame_items.len() != num_frames {
+        return Err(Error::invalid_attribute(
+            dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            format!(
+                "{} items, but there are {} frames",
+                per_frame_items.len(),
+                num_frames
+            ),
+        ));
+    }
+
+    let mut frame_slots = Vec::with_capacity(num_frames);
+    for (frame_index, per_frame_item) in per_frame_items.iter().enumerate() {
+        let plane_position =
+            get_first_item(per_frame_item, dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE)?;
+        // Positions are 1-based and refer to the top left pixel of the frame
+        let column = get_i64(
+            plane_position,
+            dicom_tags::COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+        )?;
+        let row = get_i64(
+            plane_position,
+            dicom_tags::ROW_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+        )?;
+        if column < 1
+            || row < 1
+            || (column - 1) % i64::from(tile_width) != 0
+            || (row - 1) % i64::from(tile_height) != 0
+        {
+            return Err(Error::invalid_attribute(
+                dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE,
+                format!(
+                    "frame {} at column {}, row {} is not aligned to the tile grid",
+                    frame_index + 1,
+                    column,
+                    row
+                ),
+            ));
+        }
+        let tile_x = (column - 1) / i64::from(tile_width);
+        let tile_y = (row - 1) / i64::from(tile_height);
+        if tile_x >= i64::from(tiles_across) || tile_y >= i64::from(tiles_down) {
+            return Err(Error::invalid_attribute(
+                dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE,
+                format!(
+                    "frame {} at column {}, row {} is outside of the total pixel matrix",
+                    frame_index + 1,
+                    column,
+                    row
+                ),
+            ));
+        }
+        frame_slots.push((tile_y * i64::from(tiles_across) + tile_x) as usize);
+    }
+
+    Ok(frame_slots)
+}
+
+/// The pixel spacing (x, y) in millimeters, from the shared functional groups.
+pub fn get_pixel_spacing(dcm_object: &InMemDicomObject) -> Result<(f64, f64)> {
+    let first_shared_functional_group =
+        get_first_item(dcm_object, dicom_tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE)?;
+    let first_pixel_measures = get_first_item(
+        first_shared_functional_group,
+        dicom_tags::PIXEL_MEASURES_SEQUENCE,
+    )?;
+    let pixel_spacing_strs = get_element(first_pixel_measures, dicom_tags::PIXEL_SPACING)?
+        .strings()
+        .map_err(|e| Error::invalid_attribute(dicom_tags::PIXEL_SPACING, e))?;
+    let pixel_spacing = pixel_spacing_strs
+        .iter()
+        .map(|s| s.trim().parse::<f64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::invalid_attribute(dicom_tags::PIXEL_SPACING, e))?;
+    if pixel_spacing.len() != 2 {
+        return Err(Error::invalid_attribute(
+            dicom_tags::PIXEL_SPACING,
+            "expected 2 values",
+        ));
+    }
+    // PixelSpacing is the spacing between rows (y), then between columns (x)
+    Ok((pixel_spacing[1], pixel_spacing[0]))
+}
+
+/// The first ICC profile found in the optical path sequence, if any.
+pub fn get_icc_profile(dcm_object: &InMemDicomObject) -> Result<Option<Vec<u8>>> {
+    let optical_path_items = get_items(dcm_object, dicom_tags::OPTICAL_PATH_SEQUENCE)?;
+    if optical_path_items.is_empty() {
+        return Err(Error::invalid_attribute(
+            dicom_tags::OPTICAL_PATH_SEQUENCE,
+            "the sequence is empty",
+        ));
+    }
+    let icc_profile = optical_path_items
+        .iter()
+        .find_map(|item| item.element_opt(dicom_tags::ICC_PROFILE).ok().flatten())
+        .map(|elem| {
+            elem.to_bytes()
+                .map(|bytes| bytes.to_vec())
+                .map_err(|e| Error::invalid_attribute(dicom_tags::ICC_PROFILE, e))
+        })
+        .transpose()?;
+    Ok(icc_profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::VR;
+
+    use super::*;
+    use crate::testing::{self, plane_position, sequence, strings};
+
+    /// A TILED_SPARSE image of 3 x 2 tiles.
+    fn sparse_image() -> DicomImage {
+        DicomImage {
+            is_sparse: true,
+            ..testing::image((600, 400), 256)
+        }
+    }
+
+    fn tile_frames(per_frame_items: Vec<InMemDicomObject>) -> Result<Vec<Option<usize>>> {
+        let num_frames = per_frame_items.len();
+        let obj = InMemDicomObject::from_element_iter([sequence(
+            dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            per_frame_items,
+        )]);
+        sparse_image().get_tile_frames(
+            &obj,
+            num_frames,
+            FocalPlaneSelection::Nominal,
+            &ChannelSelection::First,
+            |_| 0,
+        )
+    }
+
+    #[test]
+    fn sparse_frames_are_placed_at_their_positions() {
+        let frames = tile_frames(vec![
+            plane_position(513, 257, None),
+            plane_position(1, 1, None),
+            plane_position(257, 257, None),
+        ])
+        .unwrap();
+
+        assert_eq!(frames, [Some(1), None, None, None, Some(2), Some(0)]);
+    }
+
+    #[test]
+    fn sparse_frames_off_the_tile_grid_are_invalid() {
+        for (column, row) in [(100, 1), (1, 300), (0, 1), (769, 1), (1, 513)] {
+            let result = tile_frames(vec![
+                plane_position(1, 1, None),
+                plane_position(column, row, None),
+            ]);
+            assert!(
+                matches!(
+                    result,
+                    Err(Error::InvalidAttribute { tag, .. })
+                        if tag == dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE
+                ),
+                "column {}, row {}",
+                column,
+                row
+            );
+        }
+    }
+
+    #[test]
+    fn sparse_frames_need_a_position_each() {
+        let obj = InMemDicomObject::from_element_iter([sequence(
+            dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE,
+            vec![plane_position(1, 1, None)],
+        )]);
+        let result = sparse_image().get_tile_frames(
+            &obj,
+            2,
+            FocalPlaneSelection::Nominal,
+            &ChannelSelection::First,
+            |_| 0,
+        );
+        assert!(matches!(result, Err(Error::InvalidAttribute { .. })));
+    }
+
+    #[test]
+    fn pixel_spacing_is_x_then_y() {
+        let pixel_measures = InMemDicomObject::from_element_iter([strings(
+            dicom_tags::PIXEL_SPACING,
+            VR::DS,
+            &["0.0005", "0.00025 "],
+        )]);
+        let shared_functional_groups = InMemDicomObject::from_element_iter([sequence(
+            dicom_tags::PIXEL_MEASURES_SEQUENCE,
+            vec![pixel_measures],
+        )]);
+        let obj = InMemDicomObject::from_element_iter([sequence(
+            dicom_tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE,
+            vec![shared_functional_groups],
+        )]);
+
+        assert_eq!(get_pixel_spacing(&obj).unwrap(), (0.00025, 0.0005));
+    }
+}
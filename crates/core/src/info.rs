@@ -0,0 +1,321 @@
+use std::fmt::Write as _;
+
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::{DefaultDicomObject, InMemDicomObject};
+
+use crate::converter::ConversionOptions;
+use crate::error::{Error, Result, get_items};
+use crate::image;
+use crate::metadata::{get_string, trim_padding};
+use crate::optical_paths::find_optical_path;
+use crate::slide::{DicomInstance, DicomPyramidSources, SlideId};
+use crate::validation::{Depth, Severity, check_slide};
+
+/// What the headers of the instances of a slide tell about it, and whether it can be converted.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct SlideInfo {
+    pub id: SlideId,
+    /// The pyramid levels, from level 0 (the largest) up
+    pub levels: Vec<ImageInfo>,
+    pub thumbnail: Option<ImageInfo>,
+    pub label: Option<ImageInfo>,
+    pub overview: Option<ImageInfo>,
+    /// The optical paths of level 0, which are the channels of fluorescence slides, whether
+    /// they are in one instance or in separate ones
+    pub optical_paths: Vec<OpticalPathInfo>,
+    /// The Z offsets of the focal planes of level 0 in micrometers, from the lowest up
+    pub focal_planes: Vec<f64>,
+    /// The errors which would fail the conversion of the slide to a TIFF with the options of
+    /// the probe, as far as the headers tell. [`Converter::validate`] checks the structure of
+    /// the pixel data too.
+    ///
+    /// [`Converter::validate`]: crate::Converter::validate
+    pub problems: Vec<Error>,
+}
+
+/// The attributes of a pyramid level or associated image.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct ImageInfo {
+    pub sop_instance_uid: Option<String>,
+    /// The width of the total pixel matrix, or of the frame if there is none
+    pub width: Option<u32>,
+    /// The height of the total pixel matrix, or of the frame if there is none
+    pub height: Option<u32>,
+    pub tile_width: Option<u16>,
+    pub tile_height: Option<u16>,
+    pub number_of_frames: Option<u32>,
+    pub transfer_syntax_uid: String,
+    pub photometric_interpretation: Option<String>,
+    /// TILED_FULL or TILED_SPARSE
+    pub dimension_organization_type: Option<String>,
+    /// The resolution (x, y) in micrometers per pixel
+    pub mpp: Option<(f64, f64)>,
+}
+
+/// An optical path of a slide: how it was illuminated and imaged.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct OpticalPathInfo {
+    pub identifier: Option<String>,
+    pub description: Option<String>,
+    /// The code meaning of the illumination color, e.g. "Full Spectrum"
+    pub illumination_color: Option<String>,
+    /// The illumination wavelength in nanometers
+    pub illumination_wavelength: Option<f64>,
+    /// The pass-through wavelength of the image path filter in nanometers, or the middle of
+    /// its pass band
+    pub emission_wavelength: Option<f64>,
+    pub objective_lens_power: Option<f64>,
+    pub has_icc_profile: bool,
+}
+
+impl SlideInfo {
+    /// Whether no problems were found which would fail the conversion.
+    pub fn is_convertible(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// The slide info as a JSON object, with camelCase keys and `null` for unknown values.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        let _ = write!(
+            json,
+            r#""studyInstanceUid":{},"seriesInstanceUid":{},"pyramidUid":{},"frameOfReferenceUid":{},"convertible":{},"levels":["#,
+            json_string(Some(&self.id.study_instance_uid)),
+            json_string(Some(&self.id.series_instance_uid)),
+            json_string(self.id.pyramid_uid.as_deref()),
+            json_string(self.id.frame_of_reference_uid.as_deref()),
+            self.is_convertible(),
+        );
+        for (index, level) in self.levels.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str(&level.to_json());
+        }
+        json.push(']');
+        for (key, image) in [
+            ("thumbnail", &self.thumbnail),
+            ("label", &self.label),
+            ("overview", &self.overview),
+        ] {
+            let image = image.as_ref().map(ImageInfo::to_json);
+            let _ = write!(json, r#","{}":{}"#, key, image.as_deref().unwrap_or("null"));
+        }
+        json.push_str(r#","opticalPaths":["#);
+        for (index, optical_path) in self.optical_paths.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str(&optical_path.to_json());
+        }
+        json.push_str(r#"],"focalPlanes":["#);
+        for (index, z_offset) in self.focal_planes.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "{}", z_offset);
+        }
+        json.push_str(r#"],"problems":["#);
+        for (index, problem) in self.problems.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            json.push_str(&json_string(Some(&problem.to_string())));
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+impl ImageInfo {
+    fn from_object(instance: &DicomInstance, obj: &DefaultDicomObject) -> Self {
+        let get_u32 = |tag| obj.element(tag).ok().and_then(|e| e.to_int::<u32>().ok());
+        let get_u16 = |tag| obj.element(tag).ok().and_then(|e| e.to_int::<u16>().ok());
+        let tile_width = get_u16(dicom_tags::COLUMNS);
+        let tile_height = get_u16(dicom_tags::ROWS);
+        Self {
+            sop_instance_uid: instance.sop_instance_uid.clone(),
+            width: get_u32(dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS).or(tile_width.map(u32::from)),
+            height: get_u32(dicom_tags::TOTAL_PIXEL_MATRIX_ROWS).or(tile_height.map(u32::from)),
+            tile_width,
+            tile_height,
+            number_of_frames: get_u32(dicom_tags::NUMBER_OF_FRAMES).or(Some(1)),
+            transfer_syntax_uid: trim_padding(obj.meta().transfer_syntax()).unwrap_or_default(),
+            photometric_interpretation: get_string(obj, dicom_tags::PHOTOMETRIC_INTERPRETATION),
+            dimension_organization_type: get_string(obj, dicom_tags::DIMENSION_ORGANIZATION_TYPE),
+            mpp: image::get_pixel_spacing(obj)
+                .ok()
+                .map(|(x, y)| (x * 1000.0, y * 1000.0)),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let number = |value: Option<f64>| match value {
+            Some(value) if value.is_finite() => value.to_string(),
+            _ => "null".to_string(),
+        };
+        format!(
+            r#"{{"sopInstanceUid":{},"width":{},"height":{},"tileWidth":{},"tileHeight":{},"numberOfFrames":{},"transferSyntaxUid":{},"photometricInterpretation":{},"dimensionOrganizationType":{},"mppX":{},"mppY":{}}}"#,
+            json_string(self.sop_instance_uid.as_deref()),
+            number(self.width.map(f64::from)),
+            number(self.height.map(f64::from)),
+            number(self.tile_width.map(f64::from)),
+            number(self.tile_height.map(f64::from)),
+            number(self.number_of_frames.map(f64::from)),
+            json_string(Some(&self.transfer_syntax_uid)),
+            json_string(self.photometric_interpretation.as_deref()),
+            json_string(self.dimension_organization_type.as_deref()),
+            number(self.mpp.map(|(x, _)| x)),
+            number(self.mpp.map(|(_, y)| y)),
+        )
+    }
+}
+
+impl OpticalPathInfo {
+    fn from_item(item: &InMemDicomObject) -> Self {
+        let get_f64 = |tag| {
+            item.element(tag)
+                .ok()
+                .and_then(|e| e.to_float64().ok())
+                .filter(|value| value.is_finite())
+        };
+        let illumination_color = get_items(item, dicom_tags::ILLUMINATION_COLOR_CODE_SEQUENCE)
+            .ok()
+            .and_then(<[_]>::first)
+            .and_then(|code| get_string(code, dicom_tags::CODE_MEANING));
+        Self {
+            identifier: get_string(item, dicom_tags::OPTICAL_PATH_IDENTIFIER),
+            description: get_string(item, dicom_tags::OPTICAL_PATH_DESCRIPTION),
+            illumination_color,
+            illumination_wavelength: get_f64(dicom_tags::ILLUMINATION_WAVE_LENGTH),
+            emission_wavelength: get_f64(dicom_tags::IMAGE_PATH_FILTER_PASS_THROUGH_WAVELENGTH)
+                .or_else(|| {
+                    let band = item
+                        .element(dicom_tags::IMAGE_PATH_FILTER_PASS_BAND)
+                        .ok()?
+                        .to_multi_float64()
+                        .ok()?;
+                    match band[..] {
+                        [low, high] if low.is_finite() && high.is_finite() => {
+                            Some((low + high) / 2.0)
+                        }
+                        _ => None,
+                    }
+                }),
+            objective_lens_power: get_f64(dicom_tags::OBJECTIVE_LENS_POWER),
+            has_icc_profile: item.element(dicom_tags::ICC_PROFILE).is_ok(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let number = |value: Option<f64>| value.map_or("null".to_string(), |v| v.to_string());
+        format!(
+            r#"{{"identifier":{},"description":{},"illuminationColor":{},"illuminationWavelength":{},"emissionWavelength":{},"objectiveLensPower":{},"hasIccProfile":{}}}"#,
+            json_string(self.identifier.as_deref()),
+            json_string(self.description.as_deref()),
+            json_string(self.illumination_color.as_deref()),
+            number(self.illumination_wavelength),
+            number(self.emission_wavelength),
+            number(self.objective_lens_power),
+            self.has_icc_profile,
+        )
+    }
+}
+
+/// Reads the headers of the instances of a slide, up to their pixel data, and checks them as
+/// the conversion to a TIFF with `options` would. Errors which would fail the conversion are
+/// collected as problems; only errors reading the sources are returned.
+pub(crate) fn probe_slide(
+    id: &SlideId,
+    dicom_pyramid_sources: &DicomPyramidSources,
+    options: &ConversionOptions,
+) -> Result<SlideInfo> {
+    let mut issues = Vec::new();
+    let headers = check_slide(dicom_pyramid_sources, options, Depth::Headers, &mut issues)?;
+    let image_info = |instance: &DicomInstance, header: &Option<DefaultDicomObject>| match header {
+        Some(header) => ImageInfo::from_object(instance, header),
+        None => ImageInfo {
+            sop_instance_uid: instance.sop_instance_uid.clone(),
+            ..Default::default()
+        },
+    };
+    let associated_info = |instance: &Option<DicomInstance>, header| {
+        instance
+            .as_ref()
+            .map(|instance| image_info(instance, header))
+    };
+    let mut optical_paths: Vec<OpticalPathInfo> = match headers.levels.first() {
+        Some(Some(header)) => get_items(header, dicom_tags::OPTICAL_PATH_SEQUENCE)
+            .unwrap_or_default()
+            .iter()
+            .map(OpticalPathInfo::from_item)
+            .collect(),
+        _ => Vec::new(),
+    };
+    // The channels in other instances of level 0 are described by their own headers
+    for identifier in dicom_pyramid_sources.optical_paths() {
+        if optical_paths
+            .iter()
+            .any(|path| path.identifier.as_ref() == Some(&identifier))
+        {
+            continue;
+        }
+        let sources = dicom_pyramid_sources.with_optical_path(&identifier);
+        let Some(instance) = sources.levels.first() else {
+            continue;
+        };
+        if let Ok(header) = instance.read_header()
+            && let Some(item) = find_optical_path(&header, Some(&identifier))
+        {
+            optical_paths.push(OpticalPathInfo::from_item(item));
+        }
+    }
+
+    Ok(SlideInfo {
+        id: id.clone(),
+        levels: dicom_pyramid_sources
+            .levels
+            .iter()
+            .zip(&headers.levels)
+            .map(|(instance, header)| image_info(instance, header))
+            .collect(),
+        thumbnail: associated_info(&dicom_pyramid_sources.thumbnail, &headers.thumbnail),
+        label: associated_info(&dicom_pyramid_sources.label, &headers.label),
+        overview: associated_info(&dicom_pyramid_sources.overview, &headers.overview),
+        optical_paths,
+        focal_planes: dicom_pyramid_sources
+            .focal_planes()
+            .into_iter()
+            .map(|z_offset| z_offset * 1000.0)
+            .collect(),
+        problems: issues
+            .into_iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .map(|issue| issue.error)
+            .collect(),
+    })
+}
+
+fn json_string(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", escape_json(value)),
+        None => "null".to_string(),
+    }
+}
+
+/// Escapes text for a JSON string.
+pub(crate) fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
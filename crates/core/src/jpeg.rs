@@ -0,0 +1,269 @@
+// JPEG marker codes, see ITU-T T.81 Table B.1
+const MARKER_SOI: u8 = 0xD8;
+const MARKER_EOI: u8 = 0xD9;
+const MARKER_SOS: u8 = 0xDA;
+const MARKER_DQT: u8 = 0xDB;
+const MARKER_DHT: u8 = 0xC4;
+pub const MARKER_SOF0: u8 = 0xC0;
+pub const MARKER_SOF1: u8 = 0xC1;
+#[cfg(feature = "tiles")]
+const MARKER_SOF2: u8 = 0xC2;
+pub const MARKER_SOF3: u8 = 0xC3;
+
+/// A JPEG interchange format stream split into its table-specification segments (DQT and DHT)
+/// and the remaining abbreviated image stream.
+pub struct SplitJpeg<'a> {
+    data: &'a [u8],
+    /// Byte ranges of the DQT and DHT segments (marker included), in stream order.
+    table_segments: Vec<(usize, usize)>,
+    /// The marker and sample precision of the start of frame segment, if any
+    start_of_frame: Option<(u8, u8)>,
+}
+
+impl<'a> SplitJpeg<'a> {
+    /// Parses the marker segments of a JPEG stream up to the first start of scan. Returns `None`
+    /// if the data does not look like a well-formed JPEG stream.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < 4 || data[0] != 0xFF || data[1] != MARKER_SOI {
+            return None;
+        }
+
+        let mut table_segments = Vec::new();
+        let mut start_of_frame = None;
+        let mut pos = 2;
+        loop {
+            // Markers may be preceded by any number of fill bytes (0xFF)
+            while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+                pos += 1;
+            }
+            if data.get(pos) != Some(&0xFF) {
+                return None;
+            }
+            let marker = *data.get(pos + 1)?;
+            if marker == MARKER_EOI {
+                return None;
+            }
+            let length = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+            if length < 2 {
+                return None;
+            }
+            let end = pos + 2 + length;
+            if end > data.len() {
+                return None;
+            }
+            if marker == MARKER_SOS {
+                break;
+            }
+            if marker == MARKER_DQT || marker == MARKER_DHT {
+                table_segments.push((pos, end));
+            }
+            // SOF0 to SOF15, except DHT, JPG and DAC
+            if matches!(marker, 0xC0..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF) && length > 2
+            {
+                start_of_frame = Some((marker, data[pos + 4]));
+            }
+            pos = end;
+        }
+
+        Some(Self {
+            data,
+            table_segments,
+            start_of_frame,
+        })
+    }
+
+    /// The start of frame marker (the coding process) and the sample precision, if the stream
+    /// has a frame header.
+    pub fn start_of_frame(&self) -> Option<(u8, u8)> {
+        self.start_of_frame
+    }
+
+    /// Whether the stream is a baseline, extended or progressive Huffman coded image with 8 bit
+    /// samples, which is what web browsers decode.
+    #[cfg(feature = "tiles")]
+    pub fn is_web_compatible(&self) -> bool {
+        matches!(
+            self.start_of_frame,
+            Some((MARKER_SOF0 | MARKER_SOF1 | MARKER_SOF2, 8))
+        )
+    }
+
+    /// The concatenated table-specification segments.
+    pub fn tables(&self) -> Vec<u8> {
+        self.table_segments
+            .iter()
+            .flat_map(|&(start, end)| &self.data[start..end])
+            .copied()
+            .collect()
+    }
+
+    /// The stream with all table-specification segments removed.
+    pub fn abbreviated(&self) -> Vec<u8> {
+        let mut abbreviated = Vec::with_capacity(self.data.len());
+        let mut pos = 0;
+        for &(start, end) in &self.table_segments {
+            abbreviated.extend_from_slice(&self.data[pos..start]);
+            pos = end;
+        }
+        abbreviated.extend_from_slice(&self.data[pos..]);
+        abbreviated
+    }
+}
+
+/// Returns the table-specification segments shared by all of the given JPEG streams, or `None`
+/// if any stream cannot be parsed, has no tables, or has tables that differ from the others.
+pub fn get_shared_tables<I>(streams: I) -> Option<Vec<u8>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut shared_tables: Option<Vec<u8>> = None;
+    for stream in streams {
+        let tables = SplitJpeg::parse(stream.as_ref())?.tables();
+        match &shared_tables {
+            Some(shared_tables) if *shared_tables != tables => return None,
+            Some(_) => {}
+            None if tables.is_empty() => return None,
+            None => shared_tables = Some(tables),
+        }
+    }
+    shared_tables
+}
+
+/// Wraps table-specification segments into an abbreviated table-specification stream, as
+/// expected by the TIFF JPEGTables tag.
+pub fn tables_stream(tables: &[u8]) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(tables.len() + 4);
+    stream.extend_from_slice(&[0xFF, MARKER_SOI]);
+    stream.extend_from_slice(tables);
+    stream.extend_from_slice(&[0xFF, MARKER_EOI]);
+    stream
+}
+
+/// Inserts the tables of an abbreviated table-specification stream (as in the TIFF JPEGTables
+/// tag) into an abbreviated image stream after its SOI marker, which makes it a self-contained
+/// interchange format stream. Returns `None` if either stream does not start with SOI.
+pub fn with_tables(tables_stream: &[u8], image_stream: &[u8]) -> Option<Vec<u8>> {
+    let soi = [0xFF, MARKER_SOI];
+    if !tables_stream.starts_with(&soi) || !image_stream.starts_with(&soi) {
+        return None;
+    }
+    let tables = tables_stream[2..]
+        .strip_suffix(&[0xFF, MARKER_EOI])
+        .unwrap_or(&tables_stream[2..]);
+    let mut stream = Vec::with_capacity(tables.len() + image_stream.len());
+    stream.extend_from_slice(&soi);
+    stream.extend_from_slice(tables);
+    stream.extend_from_slice(&image_stream[2..]);
+    Some(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A marker segment with the given payload, after its length.
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn dqt(value: u8) -> Vec<u8> {
+        segment(MARKER_DQT, &[0x00, value, value])
+    }
+
+    fn dht() -> Vec<u8> {
+        segment(MARKER_DHT, &[0x00, 0x01, 0x02])
+    }
+
+    fn sof0() -> Vec<u8> {
+        segment(
+            MARKER_SOF0,
+            &[8, 0x00, 0x10, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00],
+        )
+    }
+
+    /// A start of scan segment followed by entropy-coded data and the end of image.
+    fn scan() -> Vec<u8> {
+        let mut scan = segment(MARKER_SOS, &[0x01, 0x01, 0x00, 0x00, 0x3F, 0x00]);
+        scan.extend_from_slice(&[0x12, 0xFF, 0x00, 0x34, 0xFF, MARKER_EOI]);
+        scan
+    }
+
+    fn stream(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = vec![0xFF, MARKER_SOI];
+        for segment in segments {
+            stream.extend_from_slice(segment);
+        }
+        stream
+    }
+
+    #[test]
+    fn parse_splits_tables_from_the_image_stream() {
+        let data = stream(&[dqt(1), dht(), sof0(), scan()]);
+        let split = SplitJpeg::parse(&data).unwrap();
+
+        assert_eq!(split.start_of_frame(), Some((MARKER_SOF0, 8)));
+        assert_eq!(split.tables(), [dqt(1), dht()].concat());
+        assert_eq!(split.abbreviated(), stream(&[sof0(), scan()]));
+    }
+
+    #[test]
+    fn parse_skips_fill_bytes_before_markers() {
+        let mut filled_sof = vec![0xFF, 0xFF, 0xFF];
+        filled_sof.extend_from_slice(&sof0());
+        let data = stream(&[dqt(1), filled_sof.clone(), scan()]);
+        let split = SplitJpeg::parse(&data).unwrap();
+
+        assert_eq!(split.start_of_frame(), Some((MARKER_SOF0, 8)));
+        assert_eq!(split.tables(), dqt(1));
+        assert_eq!(split.abbreviated(), stream(&[filled_sof, scan()]));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_streams() {
+        let data = stream(&[dqt(1), sof0(), scan()]);
+        // A segment which runs past the end of the data
+        assert!(SplitJpeg::parse(&data[..8]).is_none());
+        // A length field cut off after its first byte
+        assert!(SplitJpeg::parse(&data[..5]).is_none());
+        // The end of image before any start of scan
+        assert!(SplitJpeg::parse(&stream(&[dqt(1), vec![0xFF, MARKER_EOI]])).is_none());
+        // A length shorter than the length field itself
+        assert!(SplitJpeg::parse(&stream(&[vec![0xFF, MARKER_DQT, 0x00, 0x01], scan()])).is_none());
+        // Data between segments which is not a marker
+        assert!(SplitJpeg::parse(&stream(&[dqt(1), vec![0x00], sof0(), scan()])).is_none());
+        // No start of image
+        assert!(SplitJpeg::parse(&data[2..]).is_none());
+    }
+
+    #[test]
+    fn shared_tables_must_be_the_same_in_every_stream() {
+        let first = stream(&[dqt(1), dht(), sof0(), scan()]);
+        let second = stream(&[dqt(1), dht(), sof0(), scan()]);
+        let different = stream(&[dqt(2), dht(), sof0(), scan()]);
+        let without_tables = stream(&[sof0(), scan()]);
+
+        assert_eq!(
+            get_shared_tables([&first, &second]),
+            Some([dqt(1), dht()].concat())
+        );
+        assert_eq!(get_shared_tables([&first, &different]), None);
+        assert_eq!(get_shared_tables([&without_tables, &without_tables]), None);
+        assert_eq!(get_shared_tables([&first[..], &first[..8]]), None);
+    }
+
+    #[test]
+    fn with_tables_restores_the_original_stream() {
+        let data = stream(&[dqt(1), dht(), sof0(), scan()]);
+        let split = SplitJpeg::parse(&data).unwrap();
+        let tables = tables_stream(&split.tables());
+
+        assert_eq!(tables, stream(&[dqt(1), dht(), vec![0xFF, MARKER_EOI]]));
+        let abbreviated = split.abbreviated();
+        assert_eq!(with_tables(&tables, &abbreviated[2..]), None);
+        assert_eq!(with_tables(&tables, &abbreviated), Some(data));
+    }
+}
@@ -0,0 +1,34 @@
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::InMemDicomObject;
+
+use crate::error::get_element_opt;
+
+/// A string value without its padding and surrounding whitespace, `None` if nothing is left.
+pub(crate) fn trim_padding(value: &str) -> Option<String> {
+    let value = value.trim_end_matches(['\0', ' ']).trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// A string attribute, if present and valid.
+pub(crate) fn get_string(dcm_object: &InMemDicomObject, tag: dicom_core::Tag) -> Option<String> {
+    let value = get_element_opt(dcm_object, tag).ok()??.to_str().ok()?;
+    trim_padding(&value)
+}
+
+/// The acquisition date (YYYYMMDD) and time (HHMMSS) of an instance.
+pub(crate) fn get_date_time(dcm_object: &InMemDicomObject) -> Option<(String, String)> {
+    let digits = |value: &str, len: usize| {
+        value
+            .get(..len)
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+            .map(str::to_string)
+    };
+    if let Some(date_time) = get_string(dcm_object, dicom_tags::ACQUISITION_DATE_TIME)
+        .and_then(|date_time| digits(&date_time, 14))
+    {
+        return Some((date_time[..8].to_string(), date_time[8..].to_string()));
+    }
+    let date = digits(&get_string(dcm_object, dicom_tags::CONTENT_DATE)?, 8)?;
+    let time = digits(&get_string(dcm_object, dicom_tags::CONTENT_TIME)?, 6)?;
+    Some((date, time))
+}
@@ -0,0 +1,369 @@
+use std::fmt::Write as _;
+
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::InMemDicomObject;
+
+use crate::converter::MetadataPolicy;
+use crate::image::DicomImage;
+use crate::metadata::{get_date_time, get_string};
+
+const OME_NAMESPACE: &str = "http://www.openmicroscopy.org/Schemas/OME/2016-06";
+/// "µm", as a character reference since TIFF ASCII values cannot hold other characters
+const MICROMETER: &str = "&#xB5;m";
+
+/// The channels and focal planes of an OME-TIFF, whose pages are the focal planes of every
+/// channel, channel by channel. The default is a single page.
+#[derive(Clone, Debug, Default)]
+pub struct OmeDimensions {
+    /// The item of the Optical Path Sequence of every channel, `None` if it is unknown
+    pub optical_paths: Vec<Option<InMemDicomObject>>,
+    /// The Z offsets of the focal planes in millimeters, empty for a single plane
+    pub z_offsets: Vec<f64>,
+}
+
+/// The OME-XML of a slide whose pyramid is stored in the first IFD of an OME-TIFF and its
+/// SubIFDs. It describes the pixels of level 0, its physical pixel size and its channels, and
+/// with [`MetadataPolicy::Full`] also the acquisition date and the scanner and objective lens.
+/// The pyramids of further channels and focal planes of the `dimensions` are stored in the IFDs
+/// following the first, one per page.
+pub fn ome_xml(
+    dcm_object: &InMemDicomObject,
+    image: &DicomImage,
+    bits_per_sample: u16,
+    pixel_spacing: Option<(f64, f64)>,
+    dimensions: &OmeDimensions,
+    metadata: MetadataPolicy,
+) -> String {
+    // A single channel which is not known otherwise is the first optical path of level 0
+    let first_optical_path = get_element_items(dcm_object, dicom_tags::OPTICAL_PATH_SEQUENCE)
+        .and_then(|items| items.first());
+    let channels: Vec<Option<&InMemDicomObject>> = match &dimensions.optical_paths[..] {
+        [] | [None] => vec![first_optical_path],
+        optical_paths => optical_paths.iter().map(Option::as_ref).collect(),
+    };
+    let optical_path = channels[0];
+    let num_planes = dimensions.z_offsets.len().max(1);
+    let full = metadata == MetadataPolicy::Full;
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    let _ = write!(
+        xml,
+        "<OME xmlns=\"{0}\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+         xsi:schemaLocation=\"{0} {0}/ome.xsd\" Creator=\"dicom2tiff {1}\">",
+        OME_NAMESPACE,
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let instrument = full
+        .then(|| instrument_xml(dcm_object, optical_path))
+        .flatten();
+    if let Some(instrument) = &instrument {
+        xml.push_str(instrument);
+    }
+
+    xml.push_str("<Image ID=\"Image:0\">");
+    if full && let Some((date, time)) = get_date_time(dcm_object) {
+        let _ = write!(
+            xml,
+            "<AcquisitionDate>{}-{}-{}T{}:{}:{}</AcquisitionDate>",
+            &date[0..4],
+            &date[4..6],
+            &date[6..8],
+            &time[0..2],
+            &time[2..4],
+            &time[4..6]
+        );
+    }
+    if instrument.is_some() {
+        xml.push_str("<InstrumentRef ID=\"Instrument:0\"/>");
+        if has_objective(optical_path) {
+            xml.push_str("<ObjectiveSettings ID=\"Objective:0:0\"/>");
+        }
+    }
+
+    let pixel_type = match (bits_per_sample, image.is_signed) {
+        (..=8, false) => "uint8",
+        (..=8, true) => "int8",
+        (_, false) => "uint16",
+        (_, true) => "int16",
+    };
+    let _ = write!(
+        xml,
+        "<Pixels ID=\"Pixels:0\" DimensionOrder=\"XYZCT\" Type=\"{}\" SizeX=\"{}\" SizeY=\"{}\" \
+         SizeC=\"{}\" SizeZ=\"{}\" SizeT=\"1\"",
+        pixel_type,
+        image.image_width,
+        image.image_height,
+        usize::from(image.samples_per_pixel) * channels.len(),
+        num_planes
+    );
+    if let Some((pixel_spacing_x, pixel_spacing_y)) = pixel_spacing {
+        // Pixel spacing is in mm
+        let _ = write!(
+            xml,
+            " PhysicalSizeX=\"{0}\" PhysicalSizeXUnit=\"{1}\" PhysicalSizeY=\"{2}\" \
+             PhysicalSizeYUnit=\"{1}\"",
+            pixel_spacing_x * 1000.0,
+            MICROMETER,
+            pixel_spacing_y * 1000.0
+        );
+    }
+    xml.push('>');
+
+    // The samples of an RGB image are a single channel
+    for (channel, optical_path) in channels.iter().enumerate() {
+        let _ = write!(
+            xml,
+            "<Channel ID=\"Channel:0:{}\" SamplesPerPixel=\"{}\"",
+            channel, image.samples_per_pixel
+        );
+        if let Some(optical_path) = optical_path {
+            xml.push_str(&channel_attributes(optical_path));
+        }
+        xml.push_str("/>");
+    }
+
+    if channels.len() * num_planes > 1 {
+        // The focal planes vary fastest, as in the XYZCT dimension order
+        for channel in 0..channels.len() {
+            for plane in 0..num_planes {
+                xml.push_str("<TiffData");
+                if channels.len() > 1 {
+                    let _ = write!(xml, " FirstC=\"{}\"", channel);
+                }
+                if num_planes > 1 {
+                    let _ = write!(xml, " FirstZ=\"{}\"", plane);
+                }
+                let _ = write!(
+                    xml,
+                    " IFD=\"{}\" PlaneCount=\"1\"/>",
+                    channel * num_planes + plane
+                );
+            }
+        }
+        for channel in 0..channels.len() {
+            for (plane, z_offset) in dimensions.z_offsets.iter().enumerate() {
+                let _ = write!(
+                    xml,
+                    "<Plane TheZ=\"{}\" TheT=\"0\" TheC=\"{}\" PositionZ=\"{}\" \
+                     PositionZUnit=\"{}\"/>",
+                    plane,
+                    channel,
+                    z_offset * 1000.0,
+                    MICROMETER
+                );
+            }
+        }
+    } else {
+        xml.push_str("<TiffData IFD=\"0\" PlaneCount=\"1\"/>");
+    }
+    xml.push_str("</Pixels></Image></OME>");
+    xml
+}
+
+/// The attributes of the channel of an optical path: its name, its excitation (illumination) and
+/// emission (image path filter) wavelengths, and the color of its illumination.
+fn channel_attributes(optical_path: &InMemDicomObject) -> String {
+    let mut attributes = String::new();
+    let name = get_string(optical_path, dicom_tags::OPTICAL_PATH_DESCRIPTION)
+        .or_else(|| get_string(optical_path, dicom_tags::OPTICAL_PATH_IDENTIFIER));
+    if let Some(name) = name {
+        let _ = write!(attributes, " Name=\"{}\"", escape(&name));
+    }
+    if let Some(wavelength) = get_number(optical_path, dicom_tags::ILLUMINATION_WAVE_LENGTH) {
+        let _ = write!(
+            attributes,
+            " ExcitationWavelength=\"{}\" ExcitationWavelengthUnit=\"nm\"",
+            wavelength
+        );
+    }
+    // The pass-through wavelength of the filter, or the middle of its pass band
+    let emission_wavelength = get_number(
+        optical_path,
+        dicom_tags::IMAGE_PATH_FILTER_PASS_THROUGH_WAVELENGTH,
+    )
+    .or_else(|| {
+        let band = get_string(optical_path, dicom_tags::IMAGE_PATH_FILTER_PASS_BAND)?;
+        let (low, high) = band.split_once('\\')?;
+        Some((low.trim().parse::<f64>().ok()? + high.trim().parse::<f64>().ok()?) / 2.0)
+    });
+    if let Some(wavelength) = emission_wavelength {
+        let _ = write!(
+            attributes,
+            " EmissionWavelength=\"{}\" EmissionWavelengthUnit=\"nm\"",
+            wavelength
+        );
+    }
+    if let Some(color) = get_illumination_color(optical_path) {
+        let _ = write!(attributes, " Color=\"{}\"", color);
+    }
+    attributes
+}
+
+/// The illumination color of an optical path as an OME color, a signed RGBA integer, for the
+/// colors with a name OME viewers show alike.
+fn get_illumination_color(optical_path: &InMemDicomObject) -> Option<i32> {
+    let code =
+        get_element_items(optical_path, dicom_tags::ILLUMINATION_COLOR_CODE_SEQUENCE)?.first()?;
+    let rgba: u32 = match get_string(code, dicom_tags::CODE_MEANING)?
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "red" => 0xFF00_00FF,
+        "green" => 0x00FF_00FF,
+        "blue" => 0x0000_FFFF,
+        "yellow" => 0xFFFF_00FF,
+        "cyan" => 0x00FF_FFFF,
+        "magenta" => 0xFF00_FFFF,
+        "white" | "full spectrum" => 0xFFFF_FFFF,
+        _ => return None,
+    };
+    Some(rgba as i32)
+}
+
+/// The scanner and its objective lens, if any of them is known.
+fn instrument_xml(
+    dcm_object: &InMemDicomObject,
+    optical_path: Option<&InMemDicomObject>,
+) -> Option<String> {
+    let mut microscope = String::new();
+    for (attribute, tag) in [
+        ("Manufacturer", dicom_tags::MANUFACTURER),
+        ("Model", dicom_tags::MANUFACTURER_MODEL_NAME),
+        ("SerialNumber", dicom_tags::DEVICE_SERIAL_NUMBER),
+    ] {
+        if let Some(value) = get_string(dcm_object, tag) {
+            let _ = write!(microscope, " {}=\"{}\"", attribute, escape(&value));
+        }
+    }
+
+    let mut objective = String::new();
+    if let Some(optical_path) = optical_path {
+        for (attribute, tag) in [
+            ("NominalMagnification", dicom_tags::OBJECTIVE_LENS_POWER),
+            ("LensNA", dicom_tags::OBJECTIVE_LENS_NUMERICAL_APERTURE),
+        ] {
+            if let Some(value) = get_number(optical_path, tag) {
+                let _ = write!(objective, " {}=\"{}\"", attribute, value);
+            }
+        }
+    }
+
+    if microscope.is_empty() && objective.is_empty() {
+        return None;
+    }
+    let mut xml = String::from("<Instrument ID=\"Instrument:0\">");
+    if !microscope.is_empty() {
+        let _ = write!(xml, "<Microscope{}/>", microscope);
+    }
+    if !objective.is_empty() {
+        let _ = write!(xml, "<Objective ID=\"Objective:0:0\"{}/>", objective);
+    }
+    xml.push_str("</Instrument>");
+    Some(xml)
+}
+
+fn has_objective(optical_path: Option<&InMemDicomObject>) -> bool {
+    optical_path.is_some_and(|optical_path| {
+        get_number(optical_path, dicom_tags::OBJECTIVE_LENS_POWER).is_some()
+            || get_number(optical_path, dicom_tags::OBJECTIVE_LENS_NUMERICAL_APERTURE).is_some()
+    })
+}
+
+fn get_element_items(
+    dcm_object: &InMemDicomObject,
+    tag: dicom_core::Tag,
+) -> Option<&[InMemDicomObject]> {
+    dcm_object.element_opt(tag).ok()??.items()
+}
+
+/// A decimal string or floating point attribute, if present and valid.
+fn get_number(dcm_object: &InMemDicomObject, tag: dicom_core::Tag) -> Option<f64> {
+    get_string(dcm_object, tag)?.parse().ok()
+}
+
+/// Escapes text for XML attribute values and character data, as ASCII.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters are not allowed in XML 1.0
+            c if c.is_control() => escaped.push(' '),
+            c if c.is_ascii() => escaped.push(c),
+            // TIFF ASCII values cannot hold other characters
+            c => {
+                let _ = write!(escaped, "&#x{:X};", u32::from(c));
+            }
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::{DataElement, PrimitiveValue, VR};
+
+    use super::*;
+    use crate::testing::image;
+
+    fn optical_path(identifier: &str) -> InMemDicomObject {
+        InMemDicomObject::from_element_iter([DataElement::new(
+            dicom_tags::OPTICAL_PATH_IDENTIFIER,
+            VR::SH,
+            PrimitiveValue::from(identifier),
+        )])
+    }
+
+    #[test]
+    fn pages_of_channels_and_focal_planes_are_in_xyzct_order() {
+        let dimensions = OmeDimensions {
+            optical_paths: vec![Some(optical_path("DAPI")), Some(optical_path("FITC"))],
+            z_offsets: vec![-0.001, 0.001],
+        };
+        let xml = ome_xml(
+            &InMemDicomObject::new_empty(),
+            &image((1000, 800), 256),
+            8,
+            Some((0.00025, 0.0005)),
+            &dimensions,
+            MetadataPolicy::Minimal,
+        );
+
+        assert!(xml.contains(
+            "<Pixels ID=\"Pixels:0\" DimensionOrder=\"XYZCT\" Type=\"uint8\" SizeX=\"1000\" \
+             SizeY=\"800\" SizeC=\"2\" SizeZ=\"2\" SizeT=\"1\" PhysicalSizeX=\"0.25\""
+        ));
+        assert!(xml.contains("PhysicalSizeY=\"0.5\""));
+        assert!(xml.contains("<Channel ID=\"Channel:0:0\" SamplesPerPixel=\"1\" Name=\"DAPI\"/>"));
+        assert!(xml.contains("<Channel ID=\"Channel:0:1\" SamplesPerPixel=\"1\" Name=\"FITC\"/>"));
+        assert!(xml.contains(
+            "<TiffData FirstC=\"0\" FirstZ=\"0\" IFD=\"0\" PlaneCount=\"1\"/>\
+             <TiffData FirstC=\"0\" FirstZ=\"1\" IFD=\"1\" PlaneCount=\"1\"/>\
+             <TiffData FirstC=\"1\" FirstZ=\"0\" IFD=\"2\" PlaneCount=\"1\"/>\
+             <TiffData FirstC=\"1\" FirstZ=\"1\" IFD=\"3\" PlaneCount=\"1\"/>"
+        ));
+        assert!(xml.contains("<Plane TheZ=\"1\" TheT=\"0\" TheC=\"0\" PositionZ=\"1\""));
+        assert!(!xml.contains("<Instrument"));
+    }
+
+    #[test]
+    fn a_single_page_has_a_single_tiff_data() {
+        let xml = ome_xml(
+            &InMemDicomObject::new_empty(),
+            &image((1000, 800), 256),
+            8,
+            None,
+            &OmeDimensions::default(),
+            MetadataPolicy::Full,
+        );
+
+        assert!(xml.contains("SizeC=\"1\" SizeZ=\"1\" SizeT=\"1\">"));
+        assert!(xml.contains("<TiffData IFD=\"0\" PlaneCount=\"1\"/></Pixels>"));
+        assert!(!xml.contains("PhysicalSizeX"));
+    }
+}
@@ -0,0 +1,381 @@
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::InMemDicomObject;
+
+use crate::converter::{ChannelSelection, ConversionOptions, OutputFlavor};
+use crate::error::{Error, Result, get_element_opt};
+use crate::focal_planes;
+use crate::metadata::trim_padding;
+use crate::slide::DicomPyramidSources;
+
+/// The optical paths of an image, which are the channels of fluorescence images: the
+/// identifiers of the optical paths of its frames, and the optical path of every frame.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct OpticalPaths {
+    /// The Optical Path Identifiers, in the order of the Optical Path Sequence. Empty if the
+    /// image does not identify its optical paths.
+    pub identifiers: Vec<String>,
+    /// The index of the optical path of every frame
+    pub frame_paths: Vec<usize>,
+}
+
+impl OpticalPaths {
+    /// Reads the optical paths of the frames of an image from the Optical Path Identification
+    /// of their functional groups. Frames without their own are of the optical path of the
+    /// shared functional groups, unless the image has several optical paths and is fully tiled
+    /// with `frames_per_path` frames (the tiles of all focal planes), whose frames are then
+    /// ordered by optical path after their plane.
+    pub fn read(
+        obj: &InMemDicomObject,
+        num_frames: usize,
+        frames_per_path: Option<usize>,
+    ) -> Result<Self> {
+        let mut identifiers = match get_element_opt(obj, dicom_tags::OPTICAL_PATH_SEQUENCE)?
+            .and_then(|e| e.items())
+        {
+            Some(items) => items
+                .iter()
+                .filter_map(|item| get_identifier(item).transpose())
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        let per_frame_items =
+            get_element_opt(obj, dicom_tags::PER_FRAME_FUNCTIONAL_GROUPS_SEQUENCE)?
+                .and_then(|e| e.items())
+                .filter(|items| !items.is_empty() && items.len() == num_frames);
+        if let Some(items) = per_frame_items {
+            let frame_identifiers = items
+                .iter()
+                .map(get_frame_identifier)
+                .collect::<Result<Option<Vec<_>>>>()?;
+            if let Some(frame_identifiers) = frame_identifiers {
+                for identifier in &frame_identifiers {
+                    if !identifiers.contains(identifier) {
+                        identifiers.push(identifier.clone());
+                    }
+                }
+                identifiers.retain(|identifier| frame_identifiers.contains(identifier));
+                let frame_paths = frame_identifiers
+                    .iter()
+                    .map(|identifier| {
+                        identifiers
+                            .iter()
+                            .position(|path| path == identifier)
+                            .unwrap_or_default()
+                    })
+                    .collect();
+                return Ok(Self {
+                    identifiers,
+                    frame_paths,
+                });
+            }
+        }
+
+        let shared_identifier =
+            get_element_opt(obj, dicom_tags::SHARED_FUNCTIONAL_GROUPS_SEQUENCE)?
+                .and_then(|e| e.items())
+                .and_then(<[_]>::first)
+                .map(get_frame_identifier)
+                .transpose()?
+                .flatten();
+        let num_paths = get_element_opt(obj, dicom_tags::NUMBER_OF_OPTICAL_PATHS)?
+            .map(|e| e.to_int::<usize>())
+            .transpose()
+            .map_err(|e| Error::invalid_attribute(dicom_tags::NUMBER_OF_OPTICAL_PATHS, e))?
+            .unwrap_or(identifiers.len());
+        match frames_per_path.filter(|frames| *frames > 0) {
+            Some(frames) if num_paths > 1 && identifiers.len() == num_paths => Ok(Self {
+                identifiers,
+                frame_paths: (0..num_frames)
+                    .map(|frame| frame / frames % num_paths)
+                    .collect(),
+            }),
+            _ => Ok(Self {
+                identifiers: shared_identifier
+                    .or_else(|| identifiers.into_iter().next())
+                    .into_iter()
+                    .collect(),
+                frame_paths: vec![0; num_frames],
+            }),
+        }
+    }
+
+    /// The index of the optical path of the selected channel. The first is taken if the
+    /// selection was not resolved to an identifier.
+    pub fn select(&self, channel: &ChannelSelection) -> Result<usize> {
+        match channel {
+            ChannelSelection::First | ChannelSelection::All => Ok(0),
+            ChannelSelection::Identifier(identifier) => self
+                .identifiers
+                .iter()
+                .position(|path| path == identifier)
+                .ok_or_else(|| {
+                    Error::invalid_attribute(
+                        dicom_tags::OPTICAL_PATH_IDENTIFICATION_SEQUENCE,
+                        format!("the image has no optical path {}", identifier),
+                    )
+                }),
+        }
+    }
+}
+
+/// The Optical Path Identifier of an item of the Optical Path Sequence, if it has one.
+fn get_identifier(item: &InMemDicomObject) -> Result<Option<String>> {
+    let identifier = get_element_opt(item, dicom_tags::OPTICAL_PATH_IDENTIFIER)?
+        .map(|e| e.to_str())
+        .transpose()
+        .map_err(|e| Error::invalid_attribute(dicom_tags::OPTICAL_PATH_IDENTIFIER, e))?;
+    Ok(identifier.and_then(|identifier| trim_padding(&identifier)))
+}
+
+/// The Optical Path Identifier of the Optical Path Identification of a functional groups item,
+/// if it has one.
+fn get_frame_identifier(item: &InMemDicomObject) -> Result<Option<String>> {
+    match get_element_opt(item, dicom_tags::OPTICAL_PATH_IDENTIFICATION_SEQUENCE)?
+        .and_then(|e| e.items())
+        .and_then(<[_]>::first)
+    {
+        Some(identification) => get_identifier(identification),
+        None => Ok(None),
+    }
+}
+
+/// The item of the Optical Path Sequence with the given identifier, or the first item if
+/// `identifier` is `None`.
+pub(crate) fn find_optical_path<'o>(
+    obj: &'o InMemDicomObject,
+    identifier: Option<&str>,
+) -> Option<&'o InMemDicomObject> {
+    let items = obj
+        .element_opt(dicom_tags::OPTICAL_PATH_SEQUENCE)
+        .ok()??
+        .items()?;
+    match identifier {
+        Some(identifier) => items
+            .iter()
+            .find(|item| get_identifier(item).ok().flatten().as_deref() == Some(identifier)),
+        None => items.first(),
+    }
+}
+
+/// The sources and options of every channel a conversion to a TIFF writes: the channel
+/// selected by the options, or every optical path of level 0 in an OME-TIFF with
+/// [`ChannelSelection::All`]. The selection is resolved to an identifier, so that every level
+/// is converted from the instance which holds its optical path, whether the channels of the
+/// level are in one instance or in separate ones.
+pub(crate) fn select_channels<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+) -> Result<Vec<(DicomPyramidSources<'a>, ConversionOptions)>> {
+    let identifiers = sources.optical_paths();
+    if options.channels != ChannelSelection::All
+        || options.flavor != OutputFlavor::Ome
+        || identifiers.is_empty()
+    {
+        return Ok(vec![select_channel(sources, options)?]);
+    }
+    Ok(identifiers
+        .iter()
+        .map(|identifier| select_identifier(sources, options, identifier))
+        .collect())
+}
+
+/// The sources and options of the channel selected by the options, for outputs which hold a
+/// single channel. Slides which do not identify their optical paths are left as they are.
+pub(crate) fn select_channel<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+) -> Result<(DicomPyramidSources<'a>, ConversionOptions)> {
+    let identifiers = sources.optical_paths();
+    let identifier = match &options.channels {
+        ChannelSelection::First => identifiers.first(),
+        ChannelSelection::Identifier(identifier) => {
+            if !identifiers.contains(identifier) {
+                return Err(Error::UnsupportedPixelData(format!(
+                    "level 0 has no optical path {}, only {}",
+                    identifier,
+                    identifiers.join(", ")
+                )));
+            }
+            Some(identifier)
+        }
+        ChannelSelection::All => {
+            return Err(Error::UnsupportedPixelData(
+                "all channels can only be written to an OME-TIFF".to_string(),
+            ));
+        }
+    };
+    Ok(match identifier {
+        Some(identifier) => select_identifier(sources, options, identifier),
+        None => (sources.clone(), options.clone()),
+    })
+}
+
+/// The sources and options of every page of a TIFF, by channel: the focal planes of every
+/// channel written. All channels must have as many focal planes, which are the Z planes of an
+/// OME-TIFF.
+pub(crate) fn select_pages<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+) -> Result<Vec<Vec<(DicomPyramidSources<'a>, ConversionOptions)>>> {
+    let pages = select_channels(sources, options)?
+        .iter()
+        .map(|(sources, options)| focal_planes::select_focal_planes(sources, options))
+        .collect::<Result<Vec<_>>>()?;
+    if pages.windows(2).any(|pair| pair[0].len() != pair[1].len()) {
+        return Err(Error::UnsupportedPixelData(
+            "the channels have different numbers of focal planes".to_string(),
+        ));
+    }
+    Ok(pages)
+}
+
+fn select_identifier<'a>(
+    sources: &DicomPyramidSources<'a>,
+    options: &ConversionOptions,
+    identifier: &str,
+) -> (DicomPyramidSources<'a>, ConversionOptions) {
+    let options = options
+        .clone()
+        .channels(ChannelSelection::Identifier(identifier.to_string()));
+    (sources.with_optical_path(identifier), options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::converter::FocalPlaneSelection;
+    use crate::testing::instance;
+
+    /// Two levels of channels 2 and 1: in an instance each at level 0, and in a single instance
+    /// at level 1.
+    fn channels<'a>() -> DicomPyramidSources<'a> {
+        DicomPyramidSources {
+            levels: vec![
+                instance(0, &[0.0], &["2"]),
+                instance(2, &[0.0], &["1", "2"]),
+            ],
+            other_instances: vec![vec![instance(1, &[0.0], &["1"])], Vec::new()],
+            ..Default::default()
+        }
+    }
+
+    fn level_indices(sources: &DicomPyramidSources) -> Vec<usize> {
+        sources
+            .levels
+            .iter()
+            .map(|instance| instance.index)
+            .collect()
+    }
+
+    fn select(channel: ChannelSelection) -> Result<(Vec<usize>, ChannelSelection)> {
+        let options = ConversionOptions::default().channels(channel);
+        let (sources, options) = select_channel(&channels(), &options)?;
+        Ok((level_indices(&sources), options.channels))
+    }
+
+    fn identifier(identifier: &str) -> ChannelSelection {
+        ChannelSelection::Identifier(identifier.to_string())
+    }
+
+    #[test]
+    fn every_level_is_converted_from_the_instance_of_the_selected_channel() {
+        assert_eq!(
+            select(ChannelSelection::First).unwrap(),
+            (vec![0, 2], identifier("2"))
+        );
+        assert_eq!(
+            select(identifier("1")).unwrap(),
+            (vec![1, 2], identifier("1"))
+        );
+        assert!(matches!(
+            select(identifier("3")),
+            Err(Error::UnsupportedPixelData(_))
+        ));
+        assert!(matches!(
+            select(ChannelSelection::All),
+            Err(Error::UnsupportedPixelData(_))
+        ));
+    }
+
+    #[test]
+    fn slides_without_identified_optical_paths_are_left_as_they_are() {
+        let sources = DicomPyramidSources {
+            levels: vec![instance(0, &[0.0], &[])],
+            ..Default::default()
+        };
+        let options = ConversionOptions::default();
+        let (sources, options) = select_channel(&sources, &options).unwrap();
+        assert_eq!(level_indices(&sources), [0]);
+        assert_eq!(options.channels, ChannelSelection::First);
+    }
+
+    #[test]
+    fn an_ome_tiff_may_hold_every_channel_of_level_0() {
+        let options = ConversionOptions::default()
+            .flavor(OutputFlavor::Ome)
+            .channels(ChannelSelection::All);
+        let channels: Vec<_> = select_channels(&channels(), &options)
+            .unwrap()
+            .iter()
+            .map(|(sources, options)| (level_indices(sources), options.channels.clone()))
+            .collect();
+        assert_eq!(
+            channels,
+            [(vec![0, 2], identifier("2")), (vec![1, 2], identifier("1"))]
+        );
+    }
+
+    #[test]
+    fn pages_are_the_focal_planes_of_every_channel() {
+        let sources = DicomPyramidSources {
+            levels: vec![instance(0, &[0.0, 1.0], &["1"])],
+            other_instances: vec![vec![instance(1, &[0.0, 1.0], &["2"])]],
+            ..Default::default()
+        };
+        let options = ConversionOptions::default()
+            .flavor(OutputFlavor::Ome)
+            .channels(ChannelSelection::All)
+            .focal_planes(FocalPlaneSelection::All);
+        let pages: Vec<Vec<_>> = select_pages(&sources, &options)
+            .unwrap()
+            .iter()
+            .map(|planes| {
+                planes
+                    .iter()
+                    .map(|(sources, options)| {
+                        (
+                            level_indices(sources),
+                            options.channels.clone(),
+                            options.focal_planes,
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+        let page = |index, channel, z_offset| {
+            (
+                vec![index],
+                identifier(channel),
+                FocalPlaneSelection::ZOffset(z_offset),
+            )
+        };
+        assert_eq!(
+            pages,
+            [
+                [page(0, "1", 0.0), page(0, "1", 1.0)],
+                [page(1, "2", 0.0), page(1, "2", 1.0)],
+            ]
+        );
+
+        let sources = DicomPyramidSources {
+            levels: vec![instance(0, &[0.0, 1.0], &["1"])],
+            other_instances: vec![vec![instance(1, &[0.0], &["2"])]],
+            ..Default::default()
+        };
+        assert!(matches!(
+            select_pages(&sources, &options),
+            Err(Error::UnsupportedPixelData(_))
+        ));
+    }
+}
@@ -0,0 +1,586 @@
+use std::borrow::Cow;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use dicom_core::value::{PrimitiveValue, Value as DicomValue};
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_encoding::{Codec, Endianness, TransferSyntaxIndex};
+use dicom_object::DefaultDicomObject;
+use dicom_parser::dataset::LazyDataToken;
+use dicom_parser::dataset::lazy_read::LazyDataSetReader;
+use dicom_transfer_syntax_registry::TransferSyntaxRegistry;
+
+use crate::error::{Error, Result, get_element_opt};
+use crate::shared_read_seek::SharedReadSeek;
+
+// Item and delimitation tags of encapsulated pixel data, see PS3.5 Section A.4
+const ITEM: (u16, u16) = (0xFFFE, 0xE000);
+const SEQUENCE_DELIMITATION_ITEM: (u16, u16) = (0xFFFE, 0xE0DD);
+
+/// Where the bytes of the pixel data are.
+enum Store<'a> {
+    /// The DICOM file, from which every frame is read when it is needed
+    Source(SharedReadSeek<'a>),
+    /// A buffer with all of the pixel data, for files which cannot be read at random, like those
+    /// with a deflated data set
+    Memory(Vec<u8>),
+}
+
+/// The pixel data of an image, split into frames which are read one at a time, so the memory
+/// needed does not grow with the size of the image.
+pub struct PixelData<'a> {
+    /// The stores of the pixel data, one per instance of a concatenation
+    stores: Vec<Store<'a>>,
+    /// The pieces (fragments) of all frames, in order
+    pieces: Vec<Piece>,
+    /// The range of `pieces` of every frame
+    frames: Vec<Range<usize>>,
+}
+
+/// A piece of a frame: a byte range of one of the stores.
+struct Piece {
+    store: usize,
+    range: Range<u64>,
+}
+
+impl Piece {
+    fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+}
+
+/// The pixel data element as found in the file.
+enum Location {
+    Native(Range<u64>),
+    Encapsulated {
+        basic_offset_table: Vec<u32>,
+        fragments: Vec<Range<u64>>,
+    },
+}
+
+impl<'a> PixelData<'a> {
+    /// Locates native pixel data with frames of `frame_len` bytes. 16 bit samples are little
+    /// endian.
+    pub fn native(
+        header: &DefaultDicomObject,
+        source: SharedReadSeek<'a>,
+        frame_len: usize,
+    ) -> Result<Self> {
+        let (store, location) = locate(header, source)?;
+        let Location::Native(range) = location else {
+            return Err(wrong_type());
+        };
+        let frame_len = frame_len as u64;
+        let num_frames = (range.end - range.start) / frame_len;
+        let pieces = (0..num_frames)
+            .map(|frame| {
+                let start = range.start + frame * frame_len;
+                Piece {
+                    store: 0,
+                    range: start..start + frame_len,
+                }
+            })
+            .collect::<Vec<_>>();
+        let frames = (0..pieces.len()).map(|piece| piece..piece + 1).collect();
+        Ok(Self {
+            stores: vec![store],
+            pieces,
+            frames,
+        })
+    }
+
+    /// Locates encapsulated pixel data and groups its fragments into frames, using the Extended
+    /// or Basic Offset Table if there are more fragments than frames.
+    pub fn encapsulated(header: &DefaultDicomObject, source: SharedReadSeek<'a>) -> Result<Self> {
+        let (store, location) = locate(header, source)?;
+        let Location::Encapsulated {
+            basic_offset_table,
+            fragments,
+        } = location
+        else {
+            return Err(wrong_type());
+        };
+        let frames = group_fragments(header, &basic_offset_table, &fragments)?;
+        Ok(Self {
+            stores: vec![store],
+            pieces: fragments
+                .into_iter()
+                .map(|range| Piece { store: 0, range })
+                .collect(),
+            frames,
+        })
+    }
+
+    /// Appends the frames of the next instance of a concatenation.
+    pub fn append(&mut self, other: PixelData<'a>) {
+        let (first_store, first_piece) = (self.stores.len(), self.pieces.len());
+        self.stores.extend(other.stores);
+        self.pieces
+            .extend(other.pieces.into_iter().map(|piece| Piece {
+                store: first_store + piece.store,
+                range: piece.range,
+            }));
+        self.frames.extend(
+            other
+                .frames
+                .into_iter()
+                .map(|frame| frame.start + first_piece..frame.end + first_piece),
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The number of bytes of the frame at `index`, without reading it.
+    pub fn frame_len(&self, index: usize) -> u64 {
+        self.pieces[self.frames[index].clone()]
+            .iter()
+            .map(Piece::len)
+            .sum()
+    }
+
+    /// The bytes of the frame at `index`.
+    pub fn frame(&self, index: usize) -> Result<Cow<'_, [u8]>> {
+        self.frame_prefix(index, usize::MAX)
+    }
+
+    /// Up to the first `max_len` bytes of the frame at `index`, which is enough to read the
+    /// headers of compressed frames without reading all of them.
+    pub fn frame_prefix(&self, index: usize, max_len: usize) -> Result<Cow<'_, [u8]>> {
+        let pieces = &self.pieces[self.frames[index].clone()];
+        if let [piece] = pieces
+            && let Store::Memory(data) = &self.stores[piece.store]
+        {
+            let range = &piece.range;
+            let end = range.end.min(range.start.saturating_add(max_len as u64));
+            return Ok(Cow::Borrowed(&data[range.start as usize..end as usize]));
+        }
+
+        let len = pieces.iter().map(Piece::len).sum::<u64>();
+        let mut frame = Vec::with_capacity(len.min(max_len as u64) as usize);
+        for piece in pieces {
+            let remaining = max_len - frame.len();
+            if remaining == 0 {
+                break;
+            }
+            let range = &piece.range;
+            let end = range.end.min(range.start.saturating_add(remaining as u64));
+            match &self.stores[piece.store] {
+                Store::Source(source) => {
+                    let mut source = source.clone();
+                    source.seek(SeekFrom::Start(range.start))?;
+                    source
+                        .take(end - range.start)
+                        .read_to_end(&mut frame)
+                        .map_err(truncated)?;
+                }
+                Store::Memory(data) => {
+                    frame.extend_from_slice(&data[range.start as usize..end as usize]);
+                }
+            }
+        }
+        if frame.len() as u64 != len.min(max_len as u64) {
+            return Err(Error::InvalidPixelData(
+                "the pixel data is truncated".to_string(),
+            ));
+        }
+        Ok(Cow::Owned(frame))
+    }
+}
+
+fn wrong_type() -> Error {
+    Error::invalid_attribute(dicom_tags::PIXEL_DATA, "wrong type")
+}
+
+fn truncated(error: std::io::Error) -> Error {
+    if error.kind() == std::io::ErrorKind::UnexpectedEof {
+        Error::InvalidPixelData("the pixel data is truncated".to_string())
+    } else {
+        Error::Io(error)
+    }
+}
+
+/// Finds the pixel data in the source without reading it. Files which cannot be read at random
+/// (deflated or big endian) are read into memory instead.
+fn locate<'a>(
+    header: &DefaultDicomObject,
+    mut source: SharedReadSeek<'a>,
+) -> Result<(Store<'a>, Location)> {
+    let transfer_syntax_uid = header.meta().transfer_syntax();
+    let transfer_syntax = TransferSyntaxRegistry
+        .get(transfer_syntax_uid)
+        .ok_or_else(|| Error::UnsupportedTransferSyntax(transfer_syntax_uid.to_string()))?;
+    let seekable = matches!(
+        transfer_syntax.codec(),
+        Codec::None | Codec::EncapsulatedPixelData(..)
+    ) && transfer_syntax.endianness() == Endianness::Little;
+    if !seekable {
+        return read_into_memory(source);
+    }
+    let Some(data_set_start) = find_data_set(&mut source)? else {
+        return read_into_memory(source);
+    };
+    let file_len = source.seek(SeekFrom::End(0))?;
+    source.seek(SeekFrom::Start(data_set_start))?;
+
+    let parse_error = |e: &dyn std::fmt::Display| {
+        Error::InvalidPixelData(format!("cannot read the data set before it: {}", e))
+    };
+    let mut reader = LazyDataSetReader::new_with_ts(&mut source, transfer_syntax)
+        .map_err(|e| parse_error(&e))?;
+    // Pixel data may also be nested in sequences (e.g. of icon images), which are skipped
+    let mut depth = 0usize;
+    let mut native_len = None;
+    loop {
+        let token = match reader.advance() {
+            Some(token) => token.map_err(|e| parse_error(&e))?,
+            None => {
+                return Err(Error::MissingAttribute {
+                    tag: dicom_tags::PIXEL_DATA,
+                });
+            }
+        };
+        match token {
+            LazyDataToken::SequenceStart { .. } => depth += 1,
+            LazyDataToken::PixelSequenceStart if depth == 0 => break,
+            LazyDataToken::PixelSequenceStart => depth += 1,
+            LazyDataToken::SequenceEnd => depth = depth.saturating_sub(1),
+            LazyDataToken::ElementHeader(header)
+                if depth == 0 && header.tag == dicom_tags::PIXEL_DATA =>
+            {
+                native_len = Some(header.len);
+                break;
+            }
+            token @ (LazyDataToken::LazyValue { .. } | LazyDataToken::LazyItemValue { .. }) => {
+                token.skip().map_err(|e| parse_error(&e))?;
+            }
+            _ => {}
+        }
+    }
+    drop(reader);
+
+    let start = source.stream_position()?;
+    if let Some(len) = native_len {
+        let len = len.get().ok_or_else(wrong_type)?;
+        let range = start..start + u64::from(len);
+        if range.end > file_len {
+            return Err(truncated(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        return Ok((Store::Source(source), Location::Native(range)));
+    }
+
+    let mut basic_offset_table = None;
+    let mut fragments = Vec::new();
+    loop {
+        let mut item_header = [0; 8];
+        source.read_exact(&mut item_header).map_err(truncated)?;
+        let tag = (
+            u16::from_le_bytes([item_header[0], item_header[1]]),
+            u16::from_le_bytes([item_header[2], item_header[3]]),
+        );
+        let len = u32::from_le_bytes([
+            item_header[4],
+            item_header[5],
+            item_header[6],
+            item_header[7],
+        ]);
+        match tag {
+            ITEM => {
+                let start = source.stream_position()?;
+                let range = start..start + u64::from(len);
+                if range.end > file_len {
+                    return Err(truncated(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                // The first item is the Basic Offset Table, which may be empty
+                if basic_offset_table.is_none() {
+                    let mut table = vec![0; len as usize];
+                    source.read_exact(&mut table)?;
+                    basic_offset_table = Some(
+                        table
+                            .chunks_exact(4)
+                            .map(|offset| {
+                                u32::from_le_bytes([offset[0], offset[1], offset[2], offset[3]])
+                            })
+                            .collect(),
+                    );
+                } else {
+                    fragments.push(range.clone());
+                    source.seek(SeekFrom::Start(range.end))?;
+                }
+            }
+            SEQUENCE_DELIMITATION_ITEM => break,
+            _ => {
+                return Err(Error::InvalidPixelData(format!(
+                    "unexpected element ({:04X},{:04X}) in encapsulated pixel data",
+                    tag.0, tag.1
+                )));
+            }
+        }
+    }
+
+    Ok((
+        Store::Source(source),
+        Location::Encapsulated {
+            basic_offset_table: basic_offset_table.unwrap_or_default(),
+            fragments,
+        },
+    ))
+}
+
+/// The position of the data set after the preamble and the file meta group, or `None` if the
+/// file meta group does not start with its group length.
+fn find_data_set(source: &mut SharedReadSeek) -> Result<Option<u64>> {
+    // The preamble is optional
+    let mut preamble = [0; 132];
+    source.rewind()?;
+    let magic_code_start = match source.read_exact(&mut preamble) {
+        Ok(()) if &preamble[128..] == b"DICM" => 132,
+        _ if &preamble[..4] == b"DICM" => 4,
+        _ => return Ok(None),
+    };
+    source.seek(SeekFrom::Start(magic_code_start))?;
+
+    // (0002,0000) UL, explicit VR little endian
+    let mut group_length = [0; 12];
+    source.read_exact(&mut group_length)?;
+    if group_length[..6] != [0x02, 0x00, 0x00, 0x00, b'U', b'L'] {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes([
+        group_length[8],
+        group_length[9],
+        group_length[10],
+        group_length[11],
+    ]);
+    Ok(Some(magic_code_start + 12 + u64::from(len)))
+}
+
+/// Reads the whole file to get its pixel data.
+fn read_into_memory(mut source: SharedReadSeek) -> Result<(Store, Location)> {
+    source.rewind()?;
+    let mut obj = dicom_object::from_reader(source)?;
+    let pixel_data =
+        obj.take_element(dicom_tags::PIXEL_DATA)
+            .map_err(|_| Error::MissingAttribute {
+                tag: dicom_tags::PIXEL_DATA,
+            })?;
+    match pixel_data.into_value() {
+        DicomValue::Primitive(value) => {
+            let mut data = value.to_bytes().into_owned();
+            // Word (OW) values are in native byte order
+            if cfg!(target_endian = "big") && matches!(value, PrimitiveValue::U16(_)) {
+                for sample in data.chunks_exact_mut(2) {
+                    sample.swap(0, 1);
+                }
+            }
+            let range = 0..data.len() as u64;
+            Ok((Store::Memory(data), Location::Native(range)))
+        }
+        DicomValue::PixelSequence(sequence) => {
+            let (basic_offset_table, fragments) = sequence.into_parts();
+            let mut data = Vec::new();
+            let fragments = fragments
+                .into_iter()
+                .map(|fragment| {
+                    let start = data.len() as u64;
+                    data.extend_from_slice(&fragment);
+                    start..data.len() as u64
+                })
+                .collect();
+            Ok((
+                Store::Memory(data),
+                Location::Encapsulated {
+                    basic_offset_table: basic_offset_table.into_vec(),
+                    fragments,
+                },
+            ))
+        }
+        DicomValue::Sequence(_) => Err(wrong_type()),
+    }
+}
+
+/// Groups the fragments of encapsulated pixel data into frames. Every fragment is a frame unless
+/// there are more fragments than frames, in which case an offset table tells where each frame
+/// starts.
+fn group_fragments(
+    header: &DefaultDicomObject,
+    basic_offset_table: &[u32],
+    fragments: &[Range<u64>],
+) -> Result<Vec<Range<usize>>> {
+    let num_frames = match get_element_opt(header, dicom_tags::NUMBER_OF_FRAMES)? {
+        Some(element) => element
+            .to_int::<usize>()
+            .map_err(|e| Error::invalid_attribute(dicom_tags::NUMBER_OF_FRAMES, e))?,
+        None => fragments.len(),
+    };
+    if fragments.len() <= num_frames {
+        return Ok((0..fragments.len()).map(|i| i..i + 1).collect());
+    }
+    if num_frames == 1 {
+        return Ok(std::iter::once(0..fragments.len()).collect());
+    }
+
+    let extended_offset_table = get_element_opt(header, dicom_tags::EXTENDED_OFFSET_TABLE)?
+        .map(|element| {
+            element
+                .to_multi_int::<u64>()
+                .map_err(|e| Error::invalid_attribute(dicom_tags::EXTENDED_OFFSET_TABLE, e))
+        })
+        .transpose()?;
+    let frame_offsets = match extended_offset_table {
+        Some(offsets) if !offsets.is_empty() => offsets,
+        _ => basic_offset_table.iter().copied().map(u64::from).collect(),
+    };
+    if frame_offsets.len() != num_frames {
+        return Err(Error::InvalidPixelData(format!(
+            "{} fragments cannot be grouped into {} frames without an offset table",
+            fragments.len(),
+            num_frames
+        )));
+    }
+
+    // Offsets are from the first byte of the item of the first fragment to the first byte of the
+    // item of the first fragment of each frame. Items have 8 byte headers.
+    let item_offsets = fragments
+        .iter()
+        .scan(0, |offset, fragment| {
+            let item_offset = *offset;
+            *offset += 8 + fragment.end - fragment.start;
+            Some(item_offset)
+        })
+        .collect::<Vec<_>>();
+    let frame_starts = frame_offsets
+        .iter()
+        .map(|offset| {
+            item_offsets
+                .iter()
+                .position(|item_offset| item_offset == offset)
+                .ok_or_else(|| {
+                    Error::InvalidPixelData(format!(
+                        "offset table entry {} is not the start of a fragment",
+                        offset
+                    ))
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut frames = Vec::with_capacity(num_frames);
+    for (i, &start) in frame_starts.iter().enumerate() {
+        let end = frame_starts.get(i + 1).copied().unwrap_or(fragments.len());
+        if end <= start {
+            return Err(Error::InvalidPixelData(
+                "offset table entries are not in ascending order".to_string(),
+            ));
+        }
+        frames.push(start..end);
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::{DataElement, PrimitiveValue, VR};
+    use dicom_dictionary_std::uids;
+    use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+
+    use super::*;
+
+    fn header(number_of_frames: Option<u32>, extended_offset_table: &[u64]) -> DefaultDicomObject {
+        let mut dataset = InMemDicomObject::new_empty();
+        if let Some(number_of_frames) = number_of_frames {
+            dataset.put(DataElement::new(
+                dicom_tags::NUMBER_OF_FRAMES,
+                VR::IS,
+                PrimitiveValue::from(number_of_frames.to_string()),
+            ));
+        }
+        if !extended_offset_table.is_empty() {
+            dataset.put(DataElement::new(
+                dicom_tags::EXTENDED_OFFSET_TABLE,
+                VR::OV,
+                PrimitiveValue::U64(extended_offset_table.into()),
+            ));
+        }
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid("1.2.3")
+            .transfer_syntax(uids::JPEG_BASELINE8_BIT)
+            .build()
+            .unwrap();
+        dataset.with_exact_meta(meta)
+    }
+
+    /// Consecutive fragments of the given lengths.
+    fn fragments(lengths: &[u64]) -> Vec<Range<u64>> {
+        let mut start = 0;
+        lengths
+            .iter()
+            .map(|length| {
+                start += length;
+                start - length..start
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_fragment_is_a_frame_without_more_fragments_than_frames() {
+        let fragments = fragments(&[10, 6, 4]);
+        assert_eq!(
+            group_fragments(&header(Some(3), &[]), &[], &fragments).unwrap(),
+            [0..1, 1..2, 2..3]
+        );
+        assert_eq!(
+            group_fragments(&header(None, &[]), &[], &fragments).unwrap(),
+            [0..1, 1..2, 2..3]
+        );
+    }
+
+    #[test]
+    fn a_single_frame_has_all_fragments() {
+        assert_eq!(
+            group_fragments(&header(Some(1), &[]), &[], &fragments(&[10, 6, 4])).unwrap(),
+            vec![Range { start: 0, end: 3 }]
+        );
+    }
+
+    #[test]
+    fn groups_fragments_by_the_basic_offset_table() {
+        // The items start at 0, 18, 32 and 44
+        let fragments = fragments(&[10, 6, 4, 2]);
+        assert_eq!(
+            group_fragments(&header(Some(2), &[]), &[0, 32], &fragments).unwrap(),
+            [0..2, 2..4]
+        );
+        assert_eq!(
+            group_fragments(&header(Some(3), &[]), &[0, 18, 44], &fragments).unwrap(),
+            [0..1, 1..3, 3..4]
+        );
+    }
+
+    #[test]
+    fn groups_fragments_by_the_extended_offset_table() {
+        let fragments = fragments(&[10, 6, 4, 2]);
+        assert_eq!(
+            group_fragments(&header(Some(2), &[0, 18]), &[], &fragments).unwrap(),
+            [0..1, 1..4]
+        );
+        // The extended offset table takes precedence over the basic offset table
+        assert_eq!(
+            group_fragments(&header(Some(2), &[0, 18]), &[0, 32], &fragments).unwrap(),
+            [0..1, 1..4]
+        );
+    }
+
+    #[test]
+    fn rejects_offset_tables_which_do_not_match_the_fragments() {
+        let fragments = fragments(&[10, 6, 4, 2]);
+        // No offset table
+        assert!(group_fragments(&header(Some(2), &[]), &[], &fragments).is_err());
+        // An entry for each fragment rather than each frame
+        assert!(group_fragments(&header(Some(2), &[]), &[0, 18, 32, 44], &fragments).is_err());
+        // An entry inside a fragment
+        assert!(group_fragments(&header(Some(2), &[]), &[0, 20], &fragments).is_err());
+        // Entries out of order
+        assert!(group_fragments(&header(Some(2), &[]), &[32, 0], &fragments).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// What happened when progress is reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Writing a pyramid level started
+    LevelStarted,
+    /// Tiles of the current pyramid level were written
+    TilesWritten,
+    /// A pyramid level was written completely
+    LevelFinished,
+    /// All images, including the associated images, were written
+    Finished,
+}
+
+/// The progress of a conversion. Tile counts only include the tiles of the selected pyramid
+/// levels and the levels synthesized below them, which make up nearly all of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub event: ProgressEvent,
+    /// The pyramid level being written, 0 being the largest. Levels which are made together,
+    /// like reduced levels which are averaged from the same level, are reported as the first of
+    /// them, with the tiles of all of them.
+    pub level: usize,
+    /// The number of pyramid levels written so far
+    pub levels_written: usize,
+    /// The number of pyramid levels which are written in total
+    pub levels_total: usize,
+    /// The number of tiles of the current level written so far
+    pub level_tiles_written: u64,
+    pub level_tiles_total: u64,
+    /// The number of tiles of all levels written so far
+    pub tiles_written: u64,
+    pub tiles_total: u64,
+    /// The number of bytes of tiles written so far
+    pub bytes_written: u64,
+}
+
+impl Progress {
+    /// The fraction of tiles written, from 0.0 to 1.0.
+    pub fn fraction(&self) -> f64 {
+        if self.tiles_total == 0 {
+            return if self.event == ProgressEvent::Finished {
+                1.0
+            } else {
+                0.0
+            };
+        }
+        self.tiles_written as f64 / self.tiles_total as f64
+    }
+}
+
+/// A callback which is called with the progress of a conversion, when a pyramid level starts and
+/// finishes and every few tiles in between.
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub(crate) fn new(callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Counts the written tiles and bytes and reports them to the callback, if there is one.
+pub(crate) struct ProgressTracker<'a> {
+    callback: Option<&'a ProgressCallback>,
+    interval: u64,
+    progress: Progress,
+    /// The number of levels which are written together as the current level
+    current_levels: usize,
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub fn new(
+        callback: Option<&'a ProgressCallback>,
+        interval: u64,
+        levels_total: usize,
+        tiles_total: u64,
+    ) -> Self {
+        Self {
+            callback,
+            interval: interval.max(1),
+            progress: Progress {
+                event: ProgressEvent::LevelStarted,
+                level: 0,
+                levels_written: 0,
+                levels_total,
+                level_tiles_written: 0,
+                level_tiles_total: 0,
+                tiles_written: 0,
+                tiles_total,
+                bytes_written: 0,
+            },
+            current_levels: 1,
+        }
+    }
+
+    fn report(&mut self, event: ProgressEvent) {
+        if let Some(callback) = self.callback {
+            self.progress.event = event;
+            (callback.0)(&self.progress);
+        }
+    }
+
+    pub fn start_level(&mut self, level: usize, tiles: u64) {
+        self.start_levels(level, 1, tiles);
+    }
+
+    /// Starts `count` levels from `level` on which are written together, with `tiles` tiles in
+    /// all. They are reported as `level` until they are finished.
+    pub fn start_levels(&mut self, level: usize, count: usize, tiles: u64) {
+        self.current_levels = count;
+        self.progress.level = level;
+        self.progress.level_tiles_written = 0;
+        self.progress.level_tiles_total = tiles;
+        self.report(ProgressEvent::LevelStarted);
+    }
+
+    pub fn tile_written(&mut self, bytes: u64) {
+        self.progress.level_tiles_written += 1;
+        self.progress.tiles_written += 1;
+        self.progress.bytes_written += bytes;
+        if self
+            .progress
+            .level_tiles_written
+            .is_multiple_of(self.interval)
+            && self.progress.level_tiles_written < self.progress.level_tiles_total
+        {
+            self.report(ProgressEvent::TilesWritten);
+        }
+    }
+
+    /// Finishes the current level, or levels. The tiles of a level skipped in lenient mode are
+    /// counted as written, so the totals still add up.
+    pub fn finish_level(&mut self) {
+        let remaining = self
+            .progress
+            .level_tiles_total
+            .saturating_sub(self.progress.level_tiles_written);
+        self.progress.level_tiles_written += remaining;
+        self.progress.tiles_written += remaining;
+        self.progress.levels_written += self.current_levels;
+        self.report(ProgressEvent::LevelFinished);
+    }
+
+    pub fn finish(&mut self) {
+        self.report(ProgressEvent::Finished);
+    }
+}
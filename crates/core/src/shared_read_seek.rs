@@ -1,38 +1,82 @@
-use std::cell::RefCell;
-use std::io::{Read, Result, Seek, SeekFrom};
-use std::rc::Rc;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, PoisonError};
 
-pub trait ReadSeek: Read + Seek {}
-impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+pub trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send + ?Sized> ReadSeek for T {}
+
+/// A reader together with its position, `None` if unknown (e.g. after a failed read).
+struct Positioned<R: ?Sized> {
+    position: Option<u64>,
+    reader: R,
+}
 
 // Type alias for convenience
-type SharedReader<'a> = Rc<RefCell<dyn ReadSeek + 'a>>;
+type SharedReader<'a> = Arc<Mutex<Positioned<dyn ReadSeek + 'a>>>;
 
+/// A reader shared by several handles, which can be sent to other threads. Each handle has its
+/// own position, so clones can read from different parts of the source at the same time. Reads
+/// are serialized, and the reader only seeks when a handle reads from another position than the
+/// last read ended at, which keeps sequential reads through a `BufReader` buffered.
 #[derive(Clone)]
 pub struct SharedReadSeek<'a> {
     inner: SharedReader<'a>,
+    position: u64,
 }
 
 impl<'a> SharedReadSeek<'a> {
     fn new(inner: SharedReader<'a>) -> Self {
-        Self { inner }
+        Self { inner, position: 0 }
     }
 
     pub fn from_read_seek<R: ReadSeek + 'a>(reader: R) -> Self {
-        // Wrap the reader in a RefCell and Rc to allow shared ownership and mutable access
-        let reader = Rc::new(RefCell::new(reader));
+        let reader = Arc::new(Mutex::new(Positioned {
+            position: None,
+            reader,
+        }));
         Self::new(reader)
     }
 }
 
 impl Read for SharedReadSeek<'_> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.inner.borrow_mut().read(buf)
+        // A panic while reading leaves the position unknown, so the reader is still usable
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let inner = &mut *inner;
+        if inner.position != Some(self.position) {
+            inner.position = None;
+            inner.reader.seek(SeekFrom::Start(self.position))?;
+        }
+        inner.position = None;
+        let bytes_read = inner.reader.read(buf)?;
+        self.position += bytes_read as u64;
+        inner.position = Some(self.position);
+        Ok(bytes_read)
     }
 }
 
 impl Seek for SharedReadSeek<'_> {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        self.inner.borrow_mut().seek(pos)
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => {
+                let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+                inner.position = None;
+                let position = inner.reader.seek(pos)?;
+                inner.position = Some(position);
+                Some(position)
+            }
+        };
+        self.position = position.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        Ok(self.position)
     }
 }
@@ -0,0 +1,1006 @@
+use std::fmt;
+use std::io::{Read, Seek, Write};
+
+use dicom_dictionary_std::{tags as dicom_tags, uids};
+use dicom_object::mem::InMemElement;
+use dicom_object::{DefaultDicomObject, InMemDicomObject};
+
+use crate::concatenation::{self, ConcatenationPart};
+use crate::converter::{ConversionOptions, Converter};
+use crate::error::{Error, ImageKind, Result, get_element_opt};
+use crate::focal_planes::FocalPlanes;
+use crate::info::SlideInfo;
+use crate::metadata::trim_padding;
+use crate::optical_paths::OpticalPaths;
+use crate::shared_read_seek::SharedReadSeek;
+use crate::validation::{Severity, ValidationIssue};
+
+/// The kinds of non-pyramid images of a slide which are included as associated images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AssociatedImageKind {
+    Thumbnail,
+    Label,
+    Overview,
+}
+
+impl AssociatedImageKind {
+    fn from_image_type_value_3(value: &str) -> Option<Self> {
+        match value.to_ascii_uppercase().as_str() {
+            "THUMBNAIL" => Some(AssociatedImageKind::Thumbnail),
+            "LABEL" => Some(AssociatedImageKind::Label),
+            "OVERVIEW" => Some(AssociatedImageKind::Overview),
+            _ => None,
+        }
+    }
+}
+
+impl From<AssociatedImageKind> for ImageKind {
+    fn from(kind: AssociatedImageKind) -> Self {
+        match kind {
+            AssociatedImageKind::Thumbnail => ImageKind::Thumbnail,
+            AssociatedImageKind::Label => ImageKind::Label,
+            AssociatedImageKind::Overview => ImageKind::Overview,
+        }
+    }
+}
+
+/// What an instance is, as far as telling pyramid levels and associated images apart goes: the
+/// values of its Image Type and its SOP Class.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct InstanceType {
+    /// The values of the Image Type, without padding, empty if it is missing
+    pub image_type: Vec<String>,
+    /// The SOP Class UID of the data set, or else of the file meta information
+    pub sop_class_uid: Option<String>,
+}
+
+impl InstanceType {
+    fn read(obj: &DefaultDicomObject) -> Result<Self> {
+        let image_type = get_element_opt(obj, dicom_tags::IMAGE_TYPE)?
+            .map(|e| e.to_multi_str())
+            .transpose()
+            .map_err(|e| Error::invalid_attribute(dicom_tags::IMAGE_TYPE, e))?
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|value| value.trim_matches(['\0', ' ']).to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let sop_class_uid = get_uid(obj, dicom_tags::SOP_CLASS_UID)?
+            .or_else(|| trim_padding(obj.meta().media_storage_sop_class_uid()));
+        Ok(Self {
+            image_type,
+            sop_class_uid,
+        })
+    }
+
+    /// Whether the instance is a pyramid level by default: a whole slide image whose Image Type
+    /// value 3 is VOLUME, in any case. The other values are not checked, since scanners fill
+    /// them in loosely, e.g. with an empty or LOCALIZER value 4, or none at all.
+    pub fn is_pyramid_level(&self) -> bool {
+        self.value_3()
+            .is_some_and(|value| value.eq_ignore_ascii_case("VOLUME"))
+            && self.is_whole_slide_image()
+    }
+
+    /// Whether the SOP Class is VL Whole Slide Microscopy Image Storage, or is not given.
+    pub fn is_whole_slide_image(&self) -> bool {
+        self.sop_class_uid
+            .as_deref()
+            .is_none_or(|uid| uid == uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE)
+    }
+
+    fn value_3(&self) -> Option<&str> {
+        self.image_type.get(2).map(String::as_str)
+    }
+
+    fn associated_image_kind(&self) -> Option<AssociatedImageKind> {
+        self.value_3()
+            .and_then(AssociatedImageKind::from_image_type_value_3)
+            .filter(|_| self.is_whole_slide_image())
+    }
+
+    /// Why an instance which is neither a pyramid level nor an associated image is left out.
+    fn skip_reason(&self) -> Error {
+        if self.image_type.is_empty() {
+            return Error::MissingAttribute {
+                tag: dicom_tags::IMAGE_TYPE,
+            };
+        }
+        let image_type = self.image_type.join("\\");
+        if self.is_pyramid_level() {
+            // Only a predicate of the options leaves these out
+            Error::invalid_attribute(
+                dicom_tags::IMAGE_TYPE,
+                format!("{} is left out by the pyramid level predicate", image_type),
+            )
+        } else if !self.is_whole_slide_image()
+            && self.value_3().is_some_and(|value| {
+                value.eq_ignore_ascii_case("VOLUME")
+                    || AssociatedImageKind::from_image_type_value_3(value).is_some()
+            })
+        {
+            Error::invalid_attribute(
+                dicom_tags::SOP_CLASS_UID,
+                format!(
+                    "{} is not VL Whole Slide Microscopy Image Storage",
+                    self.sop_class_uid.as_deref().unwrap_or_default()
+                ),
+            )
+        } else {
+            Error::invalid_attribute(
+                dicom_tags::IMAGE_TYPE,
+                format!(
+                    "{} is neither a pyramid level nor an associated image",
+                    image_type
+                ),
+            )
+        }
+    }
+}
+
+/// A DICOM instance of the sources.
+#[derive(Clone)]
+pub(crate) struct DicomInstance<'a> {
+    /// The index of the instance in the sources
+    pub index: usize,
+    pub sop_instance_uid: Option<String>,
+    /// The number of tiles of the total pixel matrix, 0 if unknown
+    pub tiles: u64,
+    /// The Z offsets of the focal planes of the frames, in millimeters, from the lowest up
+    pub z_offsets: Vec<f64>,
+    /// The identifiers of the optical paths of the frames, empty if they are not identified
+    pub optical_paths: Vec<String>,
+    pub source: SharedReadSeek<'a>,
+    /// The sources of the further instances of a concatenation, whose frames follow those of
+    /// the instance, in order
+    pub concatenation: Vec<SharedReadSeek<'a>>,
+}
+
+impl<'a> DicomInstance<'a> {
+    /// Reads the attributes before the pixel data, merged with those of the further instances
+    /// of a concatenation.
+    pub fn read_header(&self) -> Result<DefaultDicomObject> {
+        concatenation::read_header(self.source.clone(), &self.concatenation)
+    }
+
+    /// Appends the next instance of a concatenation, and the focal planes and optical paths of
+    /// its frames.
+    pub fn join(&mut self, next: DicomInstance<'a>) {
+        for z_offset in next.z_offsets {
+            if !self.z_offsets.contains(&z_offset) {
+                self.z_offsets.push(z_offset);
+            }
+        }
+        self.z_offsets.sort_by(f64::total_cmp);
+        for identifier in next.optical_paths {
+            if !self.optical_paths.contains(&identifier) {
+                self.optical_paths.push(identifier);
+            }
+        }
+        self.concatenation.push(next.source);
+        self.concatenation.extend(next.concatenation);
+    }
+
+    /// Adds the instance and the image it is to an error, unless the conversion was cancelled.
+    pub fn error(&self, image: ImageKind, error: Error) -> Error {
+        if let Error::Cancelled = error {
+            return error;
+        }
+        Error::Instance {
+            index: self.index,
+            sop_instance_uid: self.sop_instance_uid.clone(),
+            image: Some(image),
+            source: Box::new(error),
+        }
+    }
+
+    /// The distance from `z_offset` to the nearest focal plane of the instance.
+    fn focal_plane_distance(&self, z_offset: f64) -> f64 {
+        self.z_offsets
+            .iter()
+            .map(|z| (z - z_offset).abs())
+            .min_by(f64::total_cmp)
+            .unwrap_or(f64::INFINITY)
+    }
+
+    /// Whether the instance holds other frames than `other`: of other focal planes or of other
+    /// optical paths.
+    fn is_disjoint(&self, other: &DicomInstance) -> bool {
+        are_disjoint(&self.z_offsets, &other.z_offsets)
+            || (!self.optical_paths.is_empty()
+                && !other.optical_paths.is_empty()
+                && are_disjoint(&self.optical_paths, &other.optical_paths))
+    }
+}
+
+fn are_disjoint<T: PartialEq>(a: &[T], b: &[T]) -> bool {
+    a.iter().all(|value| !b.contains(value))
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct DicomPyramidSources<'a> {
+    /// Pyramid levels in order from level 0 (largest) up
+    pub levels: Vec<DicomInstance<'a>>,
+    /// The instances of the other focal planes or optical paths of every level whose planes
+    /// or paths are in separate instances, by the index of the level
+    pub other_instances: Vec<Vec<DicomInstance<'a>>>,
+    pub thumbnail: Option<DicomInstance<'a>>,
+    pub label: Option<DicomInstance<'a>>,
+    pub overview: Option<DicomInstance<'a>>,
+}
+
+impl<'a> DicomPyramidSources<'a> {
+    /// The instances of a level, of all its focal planes and optical paths.
+    fn level_instances(&self, level: usize) -> impl Iterator<Item = &DicomInstance<'a>> {
+        self.levels
+            .get(level)
+            .into_iter()
+            .chain(self.other_instances.get(level).into_iter().flatten())
+    }
+
+    /// The identifiers of the optical paths of level 0, in the order of its instances.
+    pub fn optical_paths(&self) -> Vec<String> {
+        let mut identifiers: Vec<String> = Vec::new();
+        for identifier in self
+            .level_instances(0)
+            .flat_map(|instance| &instance.optical_paths)
+        {
+            if !identifiers.contains(identifier) {
+                identifiers.push(identifier.clone());
+            }
+        }
+        identifiers
+    }
+
+    /// The Z offsets of the focal planes of level 0, from the lowest up.
+    pub fn focal_planes(&self) -> Vec<f64> {
+        let mut z_offsets: Vec<f64> = self
+            .level_instances(0)
+            .flat_map(|instance| instance.z_offsets.iter().copied())
+            .collect();
+        z_offsets.sort_by(f64::total_cmp);
+        z_offsets.dedup();
+        z_offsets
+    }
+
+    /// Whether the focal planes of a level are in separate instances, once an optical path is
+    /// selected.
+    pub fn has_split_focal_planes(&self) -> bool {
+        self.other_instances
+            .iter()
+            .any(|instances| !instances.is_empty())
+    }
+
+    /// The sources with the instance of every level which holds its focal plane nearest to
+    /// `z_offset`.
+    pub fn with_focal_plane(&self, z_offset: f64) -> Self {
+        let distance = |instance: &DicomInstance| instance.focal_plane_distance(z_offset);
+        let levels = (0..self.levels.len())
+            .map(|level| {
+                self.level_instances(level)
+                    .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+                    .expect("every level has an instance")
+                    .clone()
+            })
+            .collect();
+        Self {
+            levels,
+            other_instances: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// The sources with only the instances of every level which hold the optical path
+    /// `identifier`, the one with the plane nearest to Z offset 0 as the level. Levels without
+    /// the optical path are left as they are.
+    pub fn with_optical_path(&self, identifier: &str) -> Self {
+        let mut sources = Self {
+            levels: Vec::new(),
+            other_instances: Vec::new(),
+            ..self.clone()
+        };
+        for level in 0..self.levels.len() {
+            let instances: Vec<DicomInstance<'a>> = self.level_instances(level).cloned().collect();
+            let (mut instances, others): (Vec<_>, Vec<_>) = instances
+                .into_iter()
+                .partition(|instance| instance.optical_paths.iter().any(|path| path == identifier));
+            if instances.is_empty() {
+                instances = others;
+            }
+            let level = take_nominal(&mut instances);
+            sources.levels.push(level);
+            sources.other_instances.push(instances);
+        }
+        sources
+    }
+}
+
+/// Removes the instance with the focal plane nearest to Z offset 0 from the instances of a
+/// level, which must not be empty.
+fn take_nominal<'a>(instances: &mut Vec<DicomInstance<'a>>) -> DicomInstance<'a> {
+    let distance = |instance: &DicomInstance| instance.focal_plane_distance(0.0);
+    let nominal = (0..instances.len())
+        .min_by(|&a, &b| distance(&instances[a]).total_cmp(&distance(&instances[b])))
+        .unwrap_or(0);
+    instances.remove(nominal)
+}
+
+/// The UIDs which identify the pyramid of a slide. Instances are grouped into slides by these.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SlideId {
+    pub study_instance_uid: String,
+    pub series_instance_uid: String,
+    pub pyramid_uid: Option<String>,
+    pub frame_of_reference_uid: Option<String>,
+}
+
+impl SlideId {
+    fn from_object(obj: &InMemDicomObject) -> Result<Self> {
+        Ok(Self {
+            study_instance_uid: get_uid(obj, dicom_tags::STUDY_INSTANCE_UID)?.unwrap_or_default(),
+            series_instance_uid: get_uid(obj, dicom_tags::SERIES_INSTANCE_UID)?.unwrap_or_default(),
+            pyramid_uid: get_uid(obj, dicom_tags::PYRAMID_UID)?,
+            frame_of_reference_uid: get_uid(obj, dicom_tags::FRAME_OF_REFERENCE_UID)?,
+        })
+    }
+}
+
+impl fmt::Display for SlideId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "study {}, series {}",
+            self.study_instance_uid, self.series_instance_uid
+        )?;
+        if let Some(pyramid_uid) = &self.pyramid_uid {
+            write!(f, ", pyramid {}", pyramid_uid)?;
+        }
+        Ok(())
+    }
+}
+
+/// A slide found in the input: the pyramid levels and associated images of one pyramid.
+pub struct Slide<'a> {
+    id: SlideId,
+    sources: DicomPyramidSources<'a>,
+}
+
+impl<'a> Slide<'a> {
+    pub fn id(&self) -> &SlideId {
+        &self.id
+    }
+
+    /// The UID which best identifies the slide: the Pyramid UID if present, otherwise the
+    /// Series Instance UID.
+    pub fn uid(&self) -> &str {
+        self.id
+            .pyramid_uid
+            .as_deref()
+            .unwrap_or(&self.id.series_instance_uid)
+    }
+
+    /// Whether the slide is identified by `uid`, which may be its Pyramid UID or its Series
+    /// Instance UID.
+    pub fn matches_uid(&self, uid: &str) -> bool {
+        self.id.pyramid_uid.as_deref() == Some(uid) || self.id.series_instance_uid == uid
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.sources.levels.len()
+    }
+
+    /// The Optical Path Identifiers of the channels of level 0, which select a channel with
+    /// [`ChannelSelection::Identifier`]. Empty if the instances do not identify their optical
+    /// paths.
+    ///
+    /// [`ChannelSelection::Identifier`]: crate::ChannelSelection::Identifier
+    pub fn optical_paths(&self) -> Vec<String> {
+        self.sources.optical_paths()
+    }
+
+    /// Converts the slide to a pyramidal TIFF with the default [`ConversionOptions`]. Use
+    /// [`Converter::convert_slide`] to change the options.
+    ///
+    /// [`ConversionOptions`]: crate::ConversionOptions
+    /// [`Converter::convert_slide`]: crate::Converter::convert_slide
+    pub fn convert<W: Write + Seek>(&self, output: W) -> Result<()> {
+        Converter::default().convert_slide(self, output)
+    }
+
+    /// Describes the slide from the headers of its instances, with the problems which would
+    /// fail its conversion with the default [`ConversionOptions`]. Use
+    /// [`Converter::probe_slide`] to check against other options.
+    ///
+    /// [`ConversionOptions`]: crate::ConversionOptions
+    /// [`Converter::probe_slide`]: crate::Converter::probe_slide
+    pub fn info(&self) -> Result<SlideInfo> {
+        Converter::default().probe_slide(self)
+    }
+
+    pub(crate) fn sources(&self) -> &DicomPyramidSources<'a> {
+        &self.sources
+    }
+}
+
+/// Reads the headers of the given DICOM instances and groups them into slides, by Study Instance
+/// UID, Series Instance UID, Pyramid UID and Frame of Reference UID. Instances which are neither
+/// pyramid levels nor associated images are ignored; use [`Converter::discover_slides`] to learn
+/// which, or to choose the pyramid levels with [`ConversionOptions::pyramid_levels_by`].
+///
+/// Associated images (thumbnail, label and overview) belong to the slide of the same series. If
+/// there is no such slide but only one slide in their study, they belong to that slide.
+///
+/// [`ConversionOptions::pyramid_levels_by`]: crate::ConversionOptions::pyramid_levels_by
+pub fn discover_slides<'a, R: Read + Seek + Send + 'a>(
+    dicom_sources: Vec<R>,
+) -> Result<Vec<Slide<'a>>> {
+    Ok(discover(dicom_sources, &ConversionOptions::default())?.slides)
+}
+
+/// The slides found in the input, and the instances which are left out of them.
+#[non_exhaustive]
+pub struct SlideDiscovery<'a> {
+    pub slides: Vec<Slide<'a>>,
+    /// Why every instance which is neither a pyramid level nor an associated image of a slide
+    /// is left out, as [`Error::Instance`]
+    pub skipped: Vec<Error>,
+}
+
+/// Discovers the slides of the given sources like [`discover_slides`], with the pyramid levels
+/// of `options`, failing on the first instance which cannot be read or grouped.
+pub(crate) fn discover<'a, R: Read + Seek + Send + 'a>(
+    dicom_sources: Vec<R>,
+    options: &ConversionOptions,
+) -> Result<SlideDiscovery<'a>> {
+    let mut skipped = Vec::new();
+    let slides = discover_slides_reporting(dicom_sources, options, |issue| match issue.severity {
+        Severity::Error => Err(issue.error),
+        Severity::Warning => {
+            skipped.push(issue.error);
+            Ok(())
+        }
+    })?;
+    Ok(SlideDiscovery { slides, skipped })
+}
+
+/// Discovers the slides of the given sources like [`discover`], passing the instances which
+/// cannot be read or grouped to `report` as errors, and the ignored ones as warnings. Instances
+/// with errors are left out if `report` returns `Ok`.
+pub(crate) fn discover_slides_reporting<'a, R: Read + Seek + Send + 'a>(
+    dicom_sources: Vec<R>,
+    options: &ConversionOptions,
+    mut report: impl FnMut(ValidationIssue) -> Result<()>,
+) -> Result<Vec<Slide<'a>>> {
+    let mut slides: SlideLevels<'a> = Vec::new();
+    let mut associated_images = Vec::new();
+    let mut concatenation_parts = Vec::new();
+    for (index, source) in dicom_sources.into_iter().enumerate() {
+        let source = SharedReadSeek::from_read_seek(source);
+        let obj = match dicom_object::OpenFileOptions::new()
+            .read_until(dicom_tags::PIXEL_DATA)
+            .from_reader(source.clone())
+        {
+            Ok(obj) => obj,
+            Err(e) => {
+                let error = Error::Instance {
+                    index,
+                    sop_instance_uid: None,
+                    image: None,
+                    source: Box::new(e.into()),
+                };
+                report(ValidationIssue::new(Severity::Error, error))?;
+                continue;
+            }
+        };
+        let sop_instance_uid = get_uid(&obj, dicom_tags::SOP_INSTANCE_UID).unwrap_or_default();
+        let mut report_instance = |severity, e| {
+            let error = Error::Instance {
+                index,
+                sop_instance_uid: sop_instance_uid.clone(),
+                image: None,
+                source: Box::new(e),
+            };
+            report(ValidationIssue::new(severity, error))
+        };
+        let instance_type = match InstanceType::read(&obj) {
+            Ok(instance_type) => instance_type,
+            Err(e) => {
+                report_instance(Severity::Error, e)?;
+                continue;
+            }
+        };
+        let id = match SlideId::from_object(&obj) {
+            Ok(id) => id,
+            Err(e) => {
+                report_instance(Severity::Error, e)?;
+                continue;
+            }
+        };
+        let (z_offsets, optical_paths) = get_frame_dimensions(&obj);
+        let instance = DicomInstance {
+            index,
+            sop_instance_uid: sop_instance_uid.clone(),
+            tiles: count_tiles(&obj),
+            z_offsets,
+            optical_paths,
+            source,
+            concatenation: Vec::new(),
+        };
+        if options.is_pyramid_level(&instance_type) {
+            let get_size = |tag| {
+                obj.element(tag)
+                    .ok()
+                    .and_then(|e| e.uint32().ok())
+                    .unwrap_or(0)
+            };
+            let size = (
+                get_size(dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS),
+                get_size(dicom_tags::TOTAL_PIXEL_MATRIX_ROWS),
+            );
+            match ConcatenationPart::read(&obj) {
+                Ok(Some(part)) => concatenation_parts.push(((id, size), part, instance)),
+                Ok(None) => push_level(&mut slides, id, size, instance),
+                Err(e) => report_instance(Severity::Error, e)?,
+            }
+        } else if let Some(kind) = instance_type.associated_image_kind() {
+            associated_images.push((id, kind, instance));
+        } else {
+            report_instance(Severity::Warning, instance_type.skip_reason())?;
+        }
+    }
+
+    // The instances of a concatenation are a single level
+    for ((id, size), instance) in
+        concatenation::join_concatenations(concatenation_parts, &mut report)?
+    {
+        push_level(&mut slides, id, size, instance);
+    }
+
+    let mut slides: Vec<Slide> = slides
+        .into_iter()
+        .map(|(id, mut instances)| {
+            // Sort descending by TOTAL_PIXEL_MATRIX_COLUMNS, so pyramid levels are in order from
+            // 0 up.
+            instances.sort_by_key(|((cols, _), _)| std::cmp::Reverse(*cols));
+            Slide {
+                id,
+                sources: group_level_instances(instances),
+            }
+        })
+        .collect();
+    slides.sort_by(|a, b| a.id.cmp(&b.id));
+
+    for (id, kind, instance) in associated_images {
+        let same_series = |slide: &&mut Slide| {
+            slide.id.study_instance_uid == id.study_instance_uid
+                && slide.id.series_instance_uid == id.series_instance_uid
+                && (id.pyramid_uid.is_none() || slide.id.pyramid_uid == id.pyramid_uid)
+        };
+        let same_study = |slide: &&mut Slide| slide.id.study_instance_uid == id.study_instance_uid;
+        let mut owners: Vec<&mut Slide> = slides.iter_mut().filter(same_series).collect();
+        if owners.is_empty() {
+            owners = slides.iter_mut().filter(same_study).collect();
+            if owners.len() > 1 {
+                owners.clear();
+            }
+        }
+        let has_owners = !owners.is_empty();
+        let mut is_included = false;
+        for slide in owners {
+            // Only the first image of each kind is included
+            let associated_source = match kind {
+                AssociatedImageKind::Thumbnail => &mut slide.sources.thumbnail,
+                AssociatedImageKind::Label => &mut slide.sources.label,
+                AssociatedImageKind::Overview => &mut slide.sources.overview,
+            };
+            if associated_source.is_none() {
+                *associated_source = Some(instance.clone());
+                is_included = true;
+            }
+        }
+        if !is_included {
+            let error = if has_owners {
+                Error::invalid_attribute(
+                    dicom_tags::IMAGE_TYPE,
+                    format!(
+                        "the slide already has a {} image",
+                        ImageKind::from(kind).to_string().to_lowercase()
+                    ),
+                )
+            } else {
+                Error::invalid_attribute(
+                    dicom_tags::SERIES_INSTANCE_UID,
+                    "no slide of the series, nor a single slide of the study",
+                )
+            };
+            report(ValidationIssue::new(
+                Severity::Warning,
+                instance.error(ImageKind::from(kind), error),
+            ))?;
+        }
+    }
+
+    Ok(slides)
+}
+
+/// The (columns, rows) of the total pixel matrix of an instance.
+type MatrixSize = (u32, u32);
+
+/// The pyramid level instances of every slide, with the size of their total pixel matrix.
+type SlideLevels<'a> = Vec<(SlideId, Vec<(MatrixSize, DicomInstance<'a>)>)>;
+
+fn push_level<'a>(
+    slides: &mut SlideLevels<'a>,
+    id: SlideId,
+    size: MatrixSize,
+    instance: DicomInstance<'a>,
+) {
+    match slides.iter_mut().find(|(slide_id, _)| *slide_id == id) {
+        Some((_, levels)) => levels.push((size, instance)),
+        None => slides.push((id, vec![(size, instance)])),
+    }
+}
+
+/// Groups the pyramid level instances of a slide, sorted by size, into levels. Instances of the
+/// same size at other Z offsets or with other optical paths are of the same level; the instance
+/// with the plane nearest to Z offset 0 is the level, and the others its other instances.
+fn group_level_instances<'a>(
+    instances: Vec<(MatrixSize, DicomInstance<'a>)>,
+) -> DicomPyramidSources<'a> {
+    let mut levels: Vec<(MatrixSize, Vec<DicomInstance<'a>>)> = Vec::new();
+    for (size, instance) in instances {
+        match levels.last_mut() {
+            Some((level_size, level_instances))
+                if *level_size == size
+                    && level_instances
+                        .iter()
+                        .all(|other| instance.is_disjoint(other)) =>
+            {
+                level_instances.push(instance);
+            }
+            _ => levels.push((size, vec![instance])),
+        }
+    }
+
+    let mut sources = DicomPyramidSources::default();
+    for (_, mut instances) in levels {
+        // In the order of their UIDs, so that the channels of a slide do not depend on the
+        // order in which its instances were found
+        instances.sort_by(|a, b| a.sop_instance_uid.cmp(&b.sop_instance_uid));
+        sources.levels.push(take_nominal(&mut instances));
+        sources.other_instances.push(instances);
+    }
+    sources
+}
+
+/// The Z offsets of the focal planes and the identifiers of the optical paths of the frames of
+/// an instance, or of Z offset 0 and no identifiers if they cannot be read. Errors are left for
+/// the conversion to report.
+fn get_frame_dimensions(obj: &InMemDicomObject) -> (Vec<f64>, Vec<String>) {
+    let num_frames = obj
+        .element(dicom_tags::NUMBER_OF_FRAMES)
+        .ok()
+        .and_then(|e| e.to_int::<usize>().ok())
+        .unwrap_or(1);
+    let is_sparse = obj
+        .element(dicom_tags::DIMENSION_ORGANIZATION_TYPE)
+        .ok()
+        .and_then(|e| e.to_str().ok())
+        .is_some_and(|s| s.trim() == "TILED_SPARSE");
+    let tiles_per_plane = (!is_sparse).then(|| count_tiles(obj) as usize);
+    let z_offsets = FocalPlanes::read(obj, num_frames, tiles_per_plane)
+        .map_or_else(|_| vec![0.0], |planes| planes.z_offsets);
+    let frames_per_path = tiles_per_plane.map(|tiles| tiles * z_offsets.len());
+    let optical_paths = OpticalPaths::read(obj, num_frames, frames_per_path)
+        .map(|paths| paths.identifiers)
+        .unwrap_or_default();
+    (z_offsets, optical_paths)
+}
+
+/// The number of tiles of an image, from the size of its total pixel matrix and of its frames.
+fn count_tiles(obj: &InMemDicomObject) -> u64 {
+    let get = |tag| {
+        obj.element(tag)
+            .ok()
+            .and_then(|e| e.to_int::<u64>().ok())
+            .filter(|value| *value > 0)
+    };
+    let tiles_along = |total, frame| Some(get(total)?.div_ceil(get(frame)?));
+    let across = tiles_along(dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS, dicom_tags::COLUMNS);
+    let down = tiles_along(dicom_tags::TOTAL_PIXEL_MATRIX_ROWS, dicom_tags::ROWS);
+    across.zip(down).map_or(0, |(across, down)| across * down)
+}
+
+pub(crate) fn get_uid(obj: &InMemDicomObject, tag: dicom_core::Tag) -> Result<Option<String>> {
+    let uid = get_element_opt(obj, tag)?
+        .map(InMemElement::to_str)
+        .transpose()
+        .map_err(|e| Error::invalid_attribute(tag, e))?
+        .and_then(|uid| trim_padding(&uid));
+    Ok(uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom_core::{Tag, VR};
+    use dicom_object::FileMetaTableBuilder;
+
+    use super::*;
+    use crate::testing::{file, strings, wsi_object};
+
+    const VOLUME: &[&str] = &["ORIGINAL", "PRIMARY", "VOLUME", "NONE"];
+
+    fn slide_id(
+        study_instance_uid: &str,
+        series_instance_uid: &str,
+        pyramid_uid: Option<&str>,
+        frame_of_reference_uid: Option<&str>,
+    ) -> SlideId {
+        SlideId {
+            study_instance_uid: study_instance_uid.to_string(),
+            series_instance_uid: series_instance_uid.to_string(),
+            pyramid_uid: pyramid_uid.map(str::to_string),
+            frame_of_reference_uid: frame_of_reference_uid.map(str::to_string),
+        }
+    }
+
+    /// Discovers the slides of instances of the given slides, image types and sizes, whose SOP
+    /// Instance UIDs are their index in the sources.
+    fn discover_instances(
+        instances: &[(&SlideId, &[&str], (u32, u32))],
+    ) -> SlideDiscovery<'static> {
+        let sources = instances
+            .iter()
+            .enumerate()
+            .map(|(index, (id, image_type, size))| {
+                let obj = wsi_object(id, &index.to_string(), image_type, *size);
+                file(obj, uids::JPEG_BASELINE8_BIT)
+            })
+            .collect();
+        discover(sources, &ConversionOptions::default()).unwrap()
+    }
+
+    fn level_indices(slide: &Slide) -> Vec<usize> {
+        slide
+            .sources
+            .levels
+            .iter()
+            .map(|instance| instance.index)
+            .collect()
+    }
+
+    fn skipped_indices(discovery: &SlideDiscovery) -> Vec<usize> {
+        discovery
+            .skipped
+            .iter()
+            .map(|error| match error {
+                Error::Instance { index, .. } => *index,
+                error => panic!("unexpected {:?}", error),
+            })
+            .collect()
+    }
+
+    fn instance_type(image_type: &[&str], sop_class_uid: Option<&str>) -> InstanceType {
+        InstanceType {
+            image_type: image_type.iter().map(|value| value.to_string()).collect(),
+            sop_class_uid: sop_class_uid.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn instances_are_classified_by_image_type_value_3_and_sop_class() {
+        let wsi = Some(uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE);
+        let other = Some(uids::VL_MICROSCOPIC_IMAGE_STORAGE);
+        // The Image Type, the SOP Class, and whether it is a pyramid level or an associated image
+        type Case<'a> = (
+            &'a [&'a str],
+            Option<&'a str>,
+            bool,
+            Option<AssociatedImageKind>,
+        );
+        let cases: [Case; 11] = [
+            (&["ORIGINAL", "PRIMARY", "VOLUME", "NONE"], wsi, true, None),
+            (
+                &["DERIVED", "PRIMARY", "VOLUME", "RESAMPLED"],
+                wsi,
+                true,
+                None,
+            ),
+            // An empty or LOCALIZER value 4, or none at all
+            (&["ORIGINAL", "PRIMARY", "VOLUME", ""], wsi, true, None),
+            (
+                &["ORIGINAL", "PRIMARY", "VOLUME", "LOCALIZER"],
+                wsi,
+                true,
+                None,
+            ),
+            (&["ORIGINAL", "PRIMARY", "VOLUME"], wsi, true, None),
+            (&["original", "primary", "volume", "none"], None, true, None),
+            (
+                &["ORIGINAL", "PRIMARY", "VOLUME", "NONE"],
+                other,
+                false,
+                None,
+            ),
+            (
+                &["ORIGINAL", "PRIMARY", "label", "NONE"],
+                wsi,
+                false,
+                Some(AssociatedImageKind::Label),
+            ),
+            (
+                &["DERIVED", "PRIMARY", "THUMBNAIL", "RESAMPLED"],
+                wsi,
+                false,
+                Some(AssociatedImageKind::Thumbnail),
+            ),
+            (
+                &["ORIGINAL", "PRIMARY", "OVERVIEW", "NONE"],
+                other,
+                false,
+                None,
+            ),
+            (&["ORIGINAL", "PRIMARY"], wsi, false, None),
+        ];
+        for (image_type, sop_class_uid, is_pyramid_level, associated_image_kind) in cases {
+            let instance_type = instance_type(image_type, sop_class_uid);
+            assert_eq!(
+                instance_type.is_pyramid_level(),
+                is_pyramid_level,
+                "{:?}",
+                instance_type
+            );
+            assert_eq!(
+                instance_type.associated_image_kind(),
+                associated_image_kind,
+                "{:?}",
+                instance_type
+            );
+        }
+    }
+
+    #[test]
+    fn skip_reasons_name_the_attribute_at_fault() {
+        let other = Some(uids::VL_MICROSCOPIC_IMAGE_STORAGE);
+        let cases: [(&[&str], Option<&str>, Tag); 5] = [
+            (&[], None, dicom_tags::IMAGE_TYPE),
+            (
+                &["ORIGINAL", "PRIMARY", "VOLUME", "NONE"],
+                other,
+                dicom_tags::SOP_CLASS_UID,
+            ),
+            (
+                &["ORIGINAL", "PRIMARY", "Overview", "NONE"],
+                other,
+                dicom_tags::SOP_CLASS_UID,
+            ),
+            (
+                &["DERIVED", "PRIMARY", "OTHER"],
+                None,
+                dicom_tags::IMAGE_TYPE,
+            ),
+            // Left out by a predicate of the options
+            (
+                &["ORIGINAL", "PRIMARY", "VOLUME"],
+                None,
+                dicom_tags::IMAGE_TYPE,
+            ),
+        ];
+        for (image_type, sop_class_uid, expected_tag) in cases {
+            let instance_type = instance_type(image_type, sop_class_uid);
+            let tag = match instance_type.skip_reason() {
+                Error::MissingAttribute { tag } | Error::InvalidAttribute { tag, .. } => tag,
+                error => panic!("unexpected {:?}", error),
+            };
+            assert_eq!(tag, expected_tag, "{:?}", instance_type);
+        }
+    }
+
+    #[test]
+    fn instance_type_is_read_without_padding() {
+        let obj = InMemDicomObject::from_element_iter([strings(
+            dicom_tags::IMAGE_TYPE,
+            VR::CS,
+            &["ORIGINAL", "PRIMARY", "VOLUME ", ""],
+        )]);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid("1.2.3")
+            .transfer_syntax(uids::JPEG_BASELINE8_BIT)
+            .build()
+            .unwrap();
+
+        let instance_type = InstanceType::read(&obj.with_exact_meta(meta)).unwrap();
+        assert_eq!(
+            instance_type.image_type,
+            ["ORIGINAL", "PRIMARY", "VOLUME", ""]
+        );
+        // From the file meta information
+        assert_eq!(
+            instance_type.sop_class_uid.as_deref(),
+            Some(uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE)
+        );
+        assert!(instance_type.is_pyramid_level());
+    }
+
+    #[test]
+    fn interleaved_instances_are_grouped_into_slides_by_their_uids() {
+        let a = slide_id("1", "1.1", Some("1.1.1"), Some("1.9"));
+        let b = slide_id("1", "1.1", Some("1.1.2"), Some("1.9"));
+        let c = slide_id("1", "1.1", Some("1.1.2"), Some("1.8"));
+        let d = slide_id("1", "1.2", None, None);
+        let e = slide_id("2", "1.1", None, None);
+        let discovery = discover_instances(&[
+            (&a, VOLUME, (512, 512)),
+            (&b, VOLUME, (1024, 1024)),
+            (&c, VOLUME, (1024, 1024)),
+            (&a, VOLUME, (1024, 1024)),
+            (&d, VOLUME, (1024, 1024)),
+            (&b, VOLUME, (512, 512)),
+            (&e, VOLUME, (1024, 1024)),
+            (&a, &["DERIVED", "PRIMARY", "OTHER"], (1024, 1024)),
+        ]);
+
+        let slides: Vec<(&SlideId, Vec<usize>)> = discovery
+            .slides
+            .iter()
+            .map(|slide| (slide.id(), level_indices(slide)))
+            .collect();
+        assert_eq!(
+            slides,
+            [
+                (&a, vec![3, 0]),
+                (&c, vec![2]),
+                (&b, vec![1, 5]),
+                (&d, vec![4]),
+                (&e, vec![6]),
+            ]
+        );
+        assert_eq!(skipped_indices(&discovery), [7]);
+    }
+
+    #[test]
+    fn associated_images_belong_to_the_slide_of_their_series_or_else_of_their_study() {
+        let a = slide_id("1", "1.1", None, None);
+        let b = slide_id("1", "1.2", None, None);
+        let c = slide_id("2", "2.1", None, None);
+        let label = &["ORIGINAL", "PRIMARY", "LABEL", "NONE"][..];
+        let thumbnail = &["DERIVED", "PRIMARY", "THUMBNAIL", "RESAMPLED"][..];
+        let overview = &["ORIGINAL", "PRIMARY", "OVERVIEW", "NONE"][..];
+        let discovery = discover_instances(&[
+            (&a, label, (300, 100)),
+            (&a, VOLUME, (1024, 1024)),
+            (&b, VOLUME, (1024, 1024)),
+            (&c, VOLUME, (1024, 1024)),
+            // Of another series of a study with a single slide
+            (&slide_id("2", "2.9", None, None), thumbnail, (200, 200)),
+            // Of another series of a study with two slides
+            (&slide_id("1", "1.9", None, None), overview, (400, 200)),
+            // A second label of the series
+            (&a, label, (300, 100)),
+        ]);
+
+        let associated_images = |slide: &Slide| {
+            let index = |instance: &Option<DicomInstance>| instance.as_ref().map(|i| i.index);
+            let sources = &slide.sources;
+            (
+                index(&sources.thumbnail),
+                index(&sources.label),
+                index(&sources.overview),
+            )
+        };
+        let slides: Vec<_> = discovery.slides.iter().map(associated_images).collect();
+        assert_eq!(
+            slides,
+            [
+                (None, Some(0), None),
+                (None, None, None),
+                (Some(4), None, None)
+            ]
+        );
+        assert_eq!(skipped_indices(&discovery), [5, 6]);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+use std::io::{self, Seek, Write};
+use std::path::PathBuf;
+
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Where the files of an output made of many files are stored, such as the metadata documents
+/// and chunks of a Zarr hierarchy or the tiles of a Deep Zoom image. Keys are `/`-separated
+/// paths relative to the root of the output.
+pub trait OutputStore {
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()>;
+}
+
+impl<S: OutputStore + ?Sized> OutputStore for &mut S {
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        (**self).set(key, value)
+    }
+}
+
+/// Stores every key as a file below a root directory, which is created if it does not exist.
+/// This is the layout of `.zarr` directories and of static tiles served by a web server.
+#[derive(Clone, Debug)]
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl OutputStore for DirectoryStore {
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, value)
+    }
+}
+
+/// Stores every key as an uncompressed entry of a ZIP archive, which Zarr readers open like a
+/// directory. [`ZipStore::finish`] must be called to write the central directory.
+pub struct ZipStore<W: Write + Seek> {
+    zip: ZipWriter<W>,
+}
+
+impl<W: Write + Seek> ZipStore<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            zip: ZipWriter::new(output),
+        }
+    }
+
+    /// Finishes the archive and returns the output.
+    pub fn finish(self) -> io::Result<W> {
+        self.zip.finish().map_err(io::Error::other)
+    }
+}
+
+impl<W: Write + Seek> OutputStore for ZipStore<W> {
+    fn set(&mut self, key: &str, value: &[u8]) -> io::Result<()> {
+        // Chunks and tiles are already compressed, and readers can then read them without
+        // inflating
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(value.len() as u64 >= u64::from(u32::MAX));
+        self.zip
+            .start_file(key, options)
+            .map_err(io::Error::other)?;
+        self.zip.write_all(value)
+    }
+}
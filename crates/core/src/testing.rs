@@ -0,0 +1,166 @@
+//! Data sets and images of whole slide images for the unit tests.
+
+use std::io::Cursor;
+
+use dicom_core::value::DataSetSequence;
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_dictionary_std::{tags as dicom_tags, uids};
+use dicom_object::mem::InMemElement;
+use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+use tiff::tags::PhotometricInterpretation;
+
+use crate::compression::{Codec, PixelEncoding};
+use crate::frames::NativeLayout;
+use crate::image::DicomImage;
+use crate::shared_read_seek::SharedReadSeek;
+use crate::slide::{DicomInstance, SlideId, get_uid};
+
+/// An element of one or more string values.
+pub(crate) fn strings(tag: Tag, vr: VR, values: &[&str]) -> InMemElement {
+    let values = values.iter().map(|value| value.to_string()).collect();
+    DataElement::new(tag, vr, PrimitiveValue::Strs(values))
+}
+
+pub(crate) fn sequence(tag: Tag, items: Vec<InMemDicomObject>) -> InMemElement {
+    DataElement::new(tag, VR::SQ, DataSetSequence::from(items))
+}
+
+/// A functional groups item with the 1-based position of a frame in the total pixel matrix,
+/// and its Z offset if given.
+pub(crate) fn plane_position(column: i32, row: i32, z_offset: Option<f64>) -> InMemDicomObject {
+    let mut position = InMemDicomObject::from_element_iter([
+        DataElement::new(
+            dicom_tags::COLUMN_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+            VR::SL,
+            PrimitiveValue::from(column),
+        ),
+        DataElement::new(
+            dicom_tags::ROW_POSITION_IN_TOTAL_IMAGE_PIXEL_MATRIX,
+            VR::SL,
+            PrimitiveValue::from(row),
+        ),
+    ]);
+    if let Some(z_offset) = z_offset {
+        position.put(strings(
+            dicom_tags::Z_OFFSET_IN_SLIDE_COORDINATE_SYSTEM,
+            VR::DS,
+            &[&z_offset.to_string()],
+        ));
+    }
+    InMemDicomObject::from_element_iter([sequence(
+        dicom_tags::PLANE_POSITION_SLIDE_SEQUENCE,
+        vec![position],
+    )])
+}
+
+/// An 8 bit monochrome image of `size` with square JPEG frames of `tile_size`.
+pub(crate) fn image((image_width, image_height): (u32, u32), tile_size: u16) -> DicomImage {
+    DicomImage {
+        image_width,
+        image_height,
+        tile_width: tile_size,
+        tile_height: tile_size,
+        is_sparse: false,
+        tiff_photometric_interpretation: PhotometricInterpretation::BlackIsZero,
+        subsampling: None,
+        samples_per_pixel: 1,
+        bits_stored: 8,
+        is_signed: false,
+        native_layout: NativeLayout {
+            rows: tile_size,
+            columns: tile_size,
+            samples_per_pixel: 1,
+            bits_allocated: 8,
+            planar: false,
+            subsampled_422: false,
+        },
+        pixel_encoding: PixelEncoding::Encapsulated(Codec::Jpeg),
+    }
+}
+
+/// An instance of the sources holding the given focal planes and optical paths, whose data set
+/// is empty.
+pub(crate) fn instance<'a>(
+    index: usize,
+    z_offsets: &[f64],
+    optical_paths: &[&str],
+) -> DicomInstance<'a> {
+    DicomInstance {
+        index,
+        sop_instance_uid: None,
+        tiles: 0,
+        z_offsets: z_offsets.to_vec(),
+        optical_paths: optical_paths.iter().map(|path| path.to_string()).collect(),
+        source: SharedReadSeek::from_read_seek(Cursor::new(Vec::new())),
+        concatenation: Vec::new(),
+    }
+}
+
+/// A DICOM file of a VL Whole Slide Microscopy Image data set, with the given transfer syntax.
+pub(crate) fn file(obj: InMemDicomObject, transfer_syntax: &str) -> Cursor<Vec<u8>> {
+    let sop_instance_uid = get_uid(&obj, dicom_tags::SOP_INSTANCE_UID)
+        .unwrap()
+        .unwrap_or_else(|| "1.2.3".to_string());
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .transfer_syntax(transfer_syntax)
+        .build()
+        .unwrap();
+    let mut data = Vec::new();
+    obj.with_exact_meta(meta).write_all(&mut data).unwrap();
+    Cursor::new(data)
+}
+
+/// The header of a whole slide image of the slide `id`, of `size` with 256 x 256 tiles.
+pub(crate) fn wsi_object(
+    id: &SlideId,
+    sop_instance_uid: &str,
+    image_type: &[&str],
+    (width, height): (u32, u32),
+) -> InMemDicomObject {
+    let tiles = width.div_ceil(256) * height.div_ceil(256);
+    let mut obj = InMemDicomObject::from_element_iter([
+        strings(
+            dicom_tags::SOP_CLASS_UID,
+            VR::UI,
+            &[uids::VL_WHOLE_SLIDE_MICROSCOPY_IMAGE_STORAGE],
+        ),
+        strings(dicom_tags::SOP_INSTANCE_UID, VR::UI, &[sop_instance_uid]),
+        strings(
+            dicom_tags::STUDY_INSTANCE_UID,
+            VR::UI,
+            &[&id.study_instance_uid],
+        ),
+        strings(
+            dicom_tags::SERIES_INSTANCE_UID,
+            VR::UI,
+            &[&id.series_instance_uid],
+        ),
+        strings(dicom_tags::IMAGE_TYPE, VR::CS, image_type),
+        DataElement::new(
+            dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS,
+            VR::UL,
+            PrimitiveValue::from(width),
+        ),
+        DataElement::new(
+            dicom_tags::TOTAL_PIXEL_MATRIX_ROWS,
+            VR::UL,
+            PrimitiveValue::from(height),
+        ),
+        DataElement::new(dicom_tags::COLUMNS, VR::US, PrimitiveValue::from(256_u16)),
+        DataElement::new(dicom_tags::ROWS, VR::US, PrimitiveValue::from(256_u16)),
+        strings(dicom_tags::NUMBER_OF_FRAMES, VR::IS, &[&tiles.to_string()]),
+    ]);
+    if let Some(pyramid_uid) = &id.pyramid_uid {
+        obj.put(strings(dicom_tags::PYRAMID_UID, VR::UI, &[pyramid_uid]));
+    }
+    if let Some(frame_of_reference_uid) = &id.frame_of_reference_uid {
+        obj.put(strings(
+            dicom_tags::FRAME_OF_REFERENCE_UID,
+            VR::UI,
+            &[frame_of_reference_uid],
+        ));
+    }
+    obj
+}
@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use tiff::tags::Tag as TiffTag;
+
+use crate::error::{Error, Result};
+
+/// The values of a TIFF tag, by their representation.
+enum TagValues {
+    /// BYTE, SBYTE, ASCII and UNDEFINED values
+    Bytes(Vec<u8>),
+    /// SHORT, LONG, LONG8, IFD and IFD8 values
+    Unsigned(Vec<u64>),
+    /// RATIONAL, SRATIONAL, FLOAT, DOUBLE and signed integer values
+    Float(Vec<f64>),
+}
+
+/// An image file directory, with the values of all its tags.
+pub struct Ifd {
+    entries: HashMap<u16, TagValues>,
+    /// The offset of the next IFD of the chain, 0 for the last one
+    pub next: u64,
+}
+
+impl Ifd {
+    /// The first value of an unsigned integer tag.
+    pub fn unsigned(&self, tag: TiffTag) -> Option<u64> {
+        self.unsigned_vec(tag)?.first().copied()
+    }
+
+    pub fn unsigned_vec(&self, tag: TiffTag) -> Option<&[u64]> {
+        match self.entries.get(&tag.to_u16())? {
+            TagValues::Unsigned(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// The first value of a rational or floating point tag.
+    pub fn float(&self, tag: TiffTag) -> Option<f64> {
+        match self.entries.get(&tag.to_u16())? {
+            TagValues::Float(values) => values.first().copied(),
+            TagValues::Unsigned(values) => values.first().map(|&value| value as f64),
+            TagValues::Bytes(_) => None,
+        }
+    }
+
+    pub fn bytes(&self, tag: TiffTag) -> Option<&[u8]> {
+        match self.entries.get(&tag.to_u16())? {
+            TagValues::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The value of an ASCII tag, without its terminating null characters. Invalid characters
+    /// are replaced.
+    pub fn ascii(&self, tag: TiffTag) -> Option<String> {
+        let bytes = self.bytes(tag)?;
+        let value = String::from_utf8_lossy(bytes);
+        Some(value.trim_end_matches('\0').to_string())
+    }
+}
+
+/// A reader of the image file directories of a TIFF or BigTIFF, which leaves reading the image
+/// data to the caller.
+pub struct TiffReader<R> {
+    reader: R,
+    big_endian: bool,
+    bigtiff: bool,
+    /// The offset of the first IFD
+    pub first_ifd: u64,
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    /// Reads the header of a TIFF.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 4];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header)?;
+        let big_endian = match &header[..2] {
+            b"II" => false,
+            b"MM" => true,
+            _ => return Err(Error::InvalidTiff("not a TIFF file".to_string())),
+        };
+        let mut tiff = Self {
+            reader,
+            big_endian,
+            bigtiff: false,
+            first_ifd: 0,
+        };
+        match tiff.u16_from(&header[2..]) {
+            42 => tiff.first_ifd = tiff.read_u32()?.into(),
+            43 => {
+                // The byte size of offsets, always 8, and a reserved 0
+                let mut sizes = [0; 4];
+                tiff.reader.read_exact(&mut sizes)?;
+                if tiff.u16_from(&sizes[..2]) != 8 {
+                    return Err(Error::InvalidTiff(
+                        "BigTIFF offsets are not 8 bytes long".to_string(),
+                    ));
+                }
+                tiff.bigtiff = true;
+                tiff.first_ifd = tiff.read_u64()?;
+            }
+            version => {
+                return Err(Error::InvalidTiff(format!(
+                    "unknown TIFF version {}",
+                    version
+                )));
+            }
+        }
+        Ok(tiff)
+    }
+
+    /// The underlying reader, e.g. to read tiles.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Reads the IFD at the given offset, skipping tags of unknown types.
+    pub fn read_ifd(&mut self, offset: u64) -> Result<Ifd> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let entry_count = if self.bigtiff {
+            self.read_u64()?
+        } else {
+            self.read_u16()?.into()
+        };
+        let entry_len = if self.bigtiff { 20 } else { 12 };
+        let Some(entries_len) = entry_count.checked_mul(entry_len) else {
+            return Err(Error::InvalidTiff(format!(
+                "the IFD at offset {} has too many entries",
+                offset
+            )));
+        };
+        let mut raw_entries = Vec::new();
+        (&mut self.reader)
+            .take(entries_len)
+            .read_to_end(&mut raw_entries)?;
+        if raw_entries.len() as u64 != entries_len {
+            return Err(Error::InvalidTiff(format!(
+                "the IFD at offset {} is truncated",
+                offset
+            )));
+        }
+        let next = if self.bigtiff {
+            self.read_u64()?
+        } else {
+            self.read_u32()?.into()
+        };
+
+        let mut entries = HashMap::new();
+        for raw_entry in raw_entries.chunks_exact(entry_len as usize) {
+            let tag = self.u16_from(&raw_entry[0..2]);
+            let field_type = self.u16_from(&raw_entry[2..4]);
+            let (count, value_field) = if self.bigtiff {
+                (self.u64_from(&raw_entry[4..12]), &raw_entry[12..20])
+            } else {
+                (self.u32_from(&raw_entry[4..8]).into(), &raw_entry[8..12])
+            };
+            let Some(value_size) = type_size(field_type) else {
+                continue;
+            };
+            let Some(len) = count.checked_mul(value_size) else {
+                return Err(Error::InvalidTiff(format!("tag {} is too long", tag)));
+            };
+            // Values which fit into the value field are stored in it, others at its offset
+            let data = if len <= value_field.len() as u64 {
+                value_field[..len as usize].to_vec()
+            } else {
+                let value_offset = if self.bigtiff {
+                    self.u64_from(value_field)
+                } else {
+                    self.u32_from(value_field).into()
+                };
+                self.reader.seek(SeekFrom::Start(value_offset))?;
+                let mut data = Vec::new();
+                (&mut self.reader).take(len).read_to_end(&mut data)?;
+                if data.len() as u64 != len {
+                    return Err(Error::InvalidTiff(format!(
+                        "the values of tag {} are truncated",
+                        tag
+                    )));
+                }
+                data
+            };
+            entries.insert(tag, self.tag_values(field_type, &data, value_size));
+        }
+
+        Ok(Ifd { entries, next })
+    }
+
+    fn tag_values(&self, field_type: u16, data: &[u8], value_size: u64) -> TagValues {
+        let values = data.chunks_exact(value_size as usize);
+        match field_type {
+            // SHORT, LONG, IFD, LONG8 and IFD8
+            3 => TagValues::Unsigned(values.map(|v| self.u16_from(v).into()).collect()),
+            4 | 13 => TagValues::Unsigned(values.map(|v| self.u32_from(v).into()).collect()),
+            16 | 18 => TagValues::Unsigned(values.map(|v| self.u64_from(v)).collect()),
+            // SSHORT, SLONG and SLONG8
+            8 => TagValues::Float(values.map(|v| self.u16_from(v) as i16 as f64).collect()),
+            9 => TagValues::Float(values.map(|v| self.u32_from(v) as i32 as f64).collect()),
+            17 => TagValues::Float(values.map(|v| self.u64_from(v) as i64 as f64).collect()),
+            // RATIONAL and SRATIONAL
+            5 => TagValues::Float(
+                values
+                    .map(|v| f64::from(self.u32_from(&v[..4])) / f64::from(self.u32_from(&v[4..])))
+                    .collect(),
+            ),
+            10 => TagValues::Float(
+                values
+                    .map(|v| {
+                        f64::from(self.u32_from(&v[..4]) as i32)
+                            / f64::from(self.u32_from(&v[4..]) as i32)
+                    })
+                    .collect(),
+            ),
+            // FLOAT and DOUBLE
+            11 => TagValues::Float(
+                values
+                    .map(|v| f64::from(f32::from_bits(self.u32_from(v))))
+                    .collect(),
+            ),
+            12 => TagValues::Float(values.map(|v| f64::from_bits(self.u64_from(v))).collect()),
+            // BYTE, ASCII, SBYTE and UNDEFINED
+            _ => TagValues::Bytes(data.to_vec()),
+        }
+    }
+
+    fn read_u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(self.u16_from(&bytes))
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(self.u32_from(&bytes))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(self.u64_from(&bytes))
+    }
+
+    fn u16_from(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32_from(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn u64_from(&self, bytes: &[u8]) -> u64 {
+        let bytes: [u8; 8] = bytes[..8].try_into().unwrap_or_default();
+        if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }
+    }
+}
+
+/// The size in bytes of a value of a TIFF field type, `None` for unknown types.
+fn type_size(field_type: u16) -> Option<u64> {
+    match field_type {
+        // BYTE, ASCII, SBYTE and UNDEFINED
+        1 | 2 | 6 | 7 => Some(1),
+        // SHORT and SSHORT
+        3 | 8 => Some(2),
+        // LONG, SLONG, FLOAT and IFD
+        4 | 9 | 11 | 13 => Some(4),
+        // RATIONAL, SRATIONAL, DOUBLE, LONG8, SLONG8 and IFD8
+        5 | 10 | 12 | 16..=18 => Some(8),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Writes integers in the byte order of a TIFF.
+    struct Writer {
+        data: Vec<u8>,
+        big_endian: bool,
+    }
+
+    impl Writer {
+        fn new(big_endian: bool) -> Self {
+            Self {
+                data: Vec::new(),
+                big_endian,
+            }
+        }
+
+        fn u16(&mut self, value: u16) -> &mut Self {
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+            self
+        }
+
+        fn u32(&mut self, value: u32) -> &mut Self {
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+            self
+        }
+
+        fn u64(&mut self, value: u64) -> &mut Self {
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+            self
+        }
+    }
+
+    const NEXT_IFD: u64 = 1234;
+
+    /// A TIFF with a single IFD, whose ImageDescription, XResolution and StripOffsets values are
+    /// at an offset in a classic TIFF but in the value field in a BigTIFF.
+    fn tiff(big_endian: bool, bigtiff: bool) -> Vec<u8> {
+        let encode = |write: &dyn Fn(&mut Writer)| {
+            let mut writer = Writer::new(big_endian);
+            write(&mut writer);
+            writer.data
+        };
+        let entries: [(u16, u16, u64, Vec<u8>); 5] = [
+            (256, 3, 1, encode(&|w| _ = w.u16(1000))),
+            (270, 2, 6, b"slide\0".to_vec()),
+            (282, 5, 1, encode(&|w| _ = w.u32(5).u32(2))),
+            (273, 4, 2, encode(&|w| _ = w.u32(10).u32(70_000))),
+            // A tag of an unknown type, which is skipped
+            (999, 99, 1, vec![0; 4]),
+        ];
+
+        let mut writer = Writer::new(big_endian);
+        writer
+            .data
+            .extend_from_slice(if big_endian { b"MM" } else { b"II" });
+        let (ifd_offset, entry_len, field_len) = if bigtiff {
+            writer.u16(43).u16(8).u16(0).u64(16);
+            (16, 20, 8)
+        } else {
+            writer.u16(42).u32(8);
+            (8, 12, 4)
+        };
+        let count_len = if bigtiff { 8 } else { 2 };
+        let mut value_offset =
+            ifd_offset + count_len + entries.len() as u64 * entry_len + field_len;
+        let mut values = Vec::new();
+
+        if bigtiff {
+            writer.u64(entries.len() as u64);
+        } else {
+            writer.u16(entries.len() as u16);
+        }
+        for (tag, field_type, count, data) in &entries {
+            writer.u16(*tag).u16(*field_type);
+            if bigtiff {
+                writer.u64(*count);
+            } else {
+                writer.u32(*count as u32);
+            }
+            if data.len() as u64 <= field_len {
+                let mut field = data.clone();
+                field.resize(field_len as usize, 0);
+                writer.data.extend_from_slice(&field);
+            } else {
+                if bigtiff {
+                    writer.u64(value_offset);
+                } else {
+                    writer.u32(value_offset as u32);
+                }
+                value_offset += data.len() as u64;
+                values.extend_from_slice(data);
+            }
+        }
+        if bigtiff {
+            writer.u64(NEXT_IFD);
+        } else {
+            writer.u32(NEXT_IFD as u32);
+        }
+        writer.data.extend_from_slice(&values);
+        writer.data
+    }
+
+    #[test]
+    fn reads_tiffs_and_bigtiffs_in_both_byte_orders() {
+        for big_endian in [false, true] {
+            for bigtiff in [false, true] {
+                let mut reader = TiffReader::new(Cursor::new(tiff(big_endian, bigtiff))).unwrap();
+                assert_eq!(reader.first_ifd, if bigtiff { 16 } else { 8 });
+
+                let ifd = reader.read_ifd(reader.first_ifd).unwrap();
+                assert_eq!(ifd.next, NEXT_IFD);
+                assert_eq!(ifd.unsigned(TiffTag::ImageWidth), Some(1000));
+                assert_eq!(
+                    ifd.ascii(TiffTag::ImageDescription).as_deref(),
+                    Some("slide")
+                );
+                assert_eq!(ifd.float(TiffTag::XResolution), Some(2.5));
+                assert_eq!(
+                    ifd.unsigned_vec(TiffTag::StripOffsets),
+                    Some(&[10, 70_000][..])
+                );
+                assert!(ifd.bytes(TiffTag::Unknown(999)).is_none());
+                assert!(ifd.unsigned(TiffTag::ImageLength).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let invalid_headers: [&[u8]; 4] = [
+            b"XX\x2a\x00\x08\x00\x00\x00",
+            // Version 44
+            b"II\x2c\x00\x08\x00\x00\x00",
+            // BigTIFF offsets of 4 bytes
+            b"II\x2b\x00\x04\x00\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00",
+            b"MM\x00\x2a\x00",
+        ];
+        for header in invalid_headers {
+            assert!(TiffReader::new(Cursor::new(header)).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_ifds() {
+        for bigtiff in [false, true] {
+            let data = tiff(false, bigtiff);
+            let first_ifd = if bigtiff { 16 } else { 8 };
+            // Cut within the entries
+            let mut reader = TiffReader::new(Cursor::new(&data[..first_ifd + 20])).unwrap();
+            assert!(matches!(
+                reader.read_ifd(first_ifd as u64),
+                Err(Error::InvalidTiff(_))
+            ));
+            // Cut within the values after the IFD, or the offset of the next IFD of a BigTIFF
+            let mut reader = TiffReader::new(Cursor::new(&data[..data.len() - 1])).unwrap();
+            assert!(reader.read_ifd(first_ifd as u64).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_a_bigtiff_entry_count_which_overflows() {
+        let mut writer = Writer::new(false);
+        writer.data.extend_from_slice(b"II");
+        writer.u16(43).u16(8).u16(0).u64(16).u64(u64::MAX);
+        let mut reader = TiffReader::new(Cursor::new(writer.data)).unwrap();
+        assert!(matches!(reader.read_ifd(16), Err(Error::InvalidTiff(_))));
+    }
+}
@@ -0,0 +1,1138 @@
+use std::borrow::Cow;
+use std::io::{Seek, Write};
+
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::{DefaultDicomObject, InMemDicomObject};
+use tiff::encoder::{DirectoryEncoder, TiffEncoder, TiffKind};
+use tiff::tags::{CompressionMethod, Predictor, SampleFormat, Tag as TiffTag};
+
+use crate::cancellation::CancellationToken;
+use crate::converter::{
+    AssociatedImages, ChannelSelection, ConversionOptions, FocalPlaneSelection, IccProfilePolicy,
+    MetadataPolicy, OutputFlavor,
+};
+#[cfg(feature = "downsample")]
+use crate::decode;
+#[cfg(feature = "downsample")]
+use crate::downsample::{self, TileFormat};
+use crate::error::{Error, ImageKind, Result, get_element_opt};
+use crate::frames::{Frames, NativeLayout};
+use crate::image::{self, DicomImage, TileData};
+use crate::jpeg;
+use crate::metadata::{get_date_time, get_string};
+use crate::ome::{self, OmeDimensions};
+use crate::optical_paths;
+use crate::pixel_data::PixelData;
+use crate::progress::{ProgressCallback, ProgressTracker};
+use crate::shared_read_seek::SharedReadSeek;
+use crate::slide::{AssociatedImageKind, DicomInstance, DicomPyramidSources};
+use crate::workers::Workers;
+
+pub(crate) fn write_pyramid<W: Write + Seek>(
+    dicom_pyramid_sources: &DicomPyramidSources,
+    output: W,
+    options: &ConversionOptions,
+    progress_callback: Option<&ProgressCallback>,
+    progress_interval: u64,
+    workers: &Workers,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    if dicom_pyramid_sources.levels.is_empty() {
+        return Err(Error::NoPyramidLevels);
+    }
+    cancellation.check()?;
+
+    let pages = optical_paths::select_pages(dicom_pyramid_sources, options)?;
+    let selected_levels = || {
+        pages.iter().flatten().flat_map(|(page_sources, _)| {
+            page_sources
+                .levels
+                .iter()
+                .enumerate()
+                .filter(|(level, _)| options.levels.includes(*level))
+        })
+    };
+    let (levels_total, tiles_total) = (
+        selected_levels().count(),
+        selected_levels()
+            .map(|(_, instance)| instance.tiles)
+            .sum::<u64>(),
+    );
+    #[cfg(feature = "downsample")]
+    let (levels_total, tiles_total) = {
+        let (levels, tiles) = count_synthesized_levels(&pages, options);
+        (levels_total + levels, tiles_total + tiles)
+    };
+    let mut progress = ProgressTracker::new(
+        progress_callback,
+        progress_interval,
+        levels_total,
+        tiles_total,
+    );
+
+    if options.bigtiff {
+        write_images(
+            &mut TiffEncoder::new_big(output)?,
+            dicom_pyramid_sources,
+            &pages,
+            options,
+            workers,
+            &mut progress,
+            cancellation,
+        )
+    } else {
+        write_images(
+            &mut TiffEncoder::new(output)?,
+            dicom_pyramid_sources,
+            &pages,
+            options,
+            workers,
+            &mut progress,
+            cancellation,
+        )
+    }
+}
+
+/// Writes the pyramid of every page in `pages` (the focal planes of every channel), and the
+/// associated images of `dicom_pyramid_sources`.
+fn write_images<W: Write + Seek, K: TiffKind>(
+    tiff: &mut TiffEncoder<W, K>,
+    dicom_pyramid_sources: &DicomPyramidSources,
+    pages: &[Vec<(DicomPyramidSources, ConversionOptions)>],
+    options: &ConversionOptions,
+    workers: &Workers,
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let associated_images = match options.flavor {
+        OutputFlavor::Aperio => options.associated_images,
+        OutputFlavor::Generic | OutputFlavor::Ome => AssociatedImages::none(),
+    };
+    let write_associated = |tiff: &mut TiffEncoder<W, K>,
+                            instance: &Option<DicomInstance>,
+                            kind: AssociatedImageKind,
+                            enabled: bool,
+                            level_0_size: (u32, u32)|
+     -> Result<()> {
+        cancellation.check()?;
+        match instance {
+            Some(instance) if enabled => {
+                write_associated_image(tiff, instance.source.clone(), kind, level_0_size, options)
+                    .map_err(|e| instance.error(ImageKind::from(kind), e))
+            }
+            _ => Ok(()),
+        }
+    };
+
+    // Writes a level, or only reports its progress if it was skipped in lenient mode
+    let write_level = |tiff: &mut TiffEncoder<W, K>,
+                       progress: &mut ProgressTracker,
+                       (level, instance): (usize, &DicomInstance),
+                       prepared_level: Option<PyramidLevel>,
+                       ifd: LevelIfd|
+     -> Result<Option<((u32, u32), u64)>> {
+        progress.start_level(level, instance.tiles);
+        let written = prepared_level
+            .map(|prepared_level| {
+                write_pyramid_level(
+                    tiff,
+                    &prepared_level,
+                    ifd,
+                    options,
+                    workers,
+                    progress,
+                    cancellation,
+                )
+            })
+            .transpose()
+            .map_err(|e| instance.error(ImageKind::Level(level), e))?;
+        progress.finish_level();
+        Ok(written)
+    };
+
+    // Images are written in the order of Aperio SVS files, which is what OpenSlide expects:
+    // level 0, the thumbnail, the remaining levels, then the label and the overview (macro).
+    // In OME-TIFFs, level 0 is written after the other levels, since its SubIFDs tag refers to
+    // them, and the pyramid of every further focal plane and channel follows in the next IFD.
+    // Levels are prepared in batches by the workers and written in order.
+    let dimensions = match options.flavor {
+        OutputFlavor::Ome => get_ome_dimensions(pages),
+        OutputFlavor::Aperio | OutputFlavor::Generic => OmeDimensions::default(),
+    };
+    let mut level_0_size = None;
+    for (page, (page_sources, page_options)) in pages.iter().flatten().enumerate() {
+        let selected_levels = page_sources
+            .levels
+            .iter()
+            .enumerate()
+            .filter(|(level, _)| options.levels.includes(*level))
+            .collect::<Vec<_>>();
+        let mut ome_level_0 = None;
+        let mut sub_ifds = Vec::new();
+        for batch in selected_levels.chunks(workers.batch_len()) {
+            cancellation.check()?;
+            let prepared_levels = workers.map(batch, |&(level, instance)| {
+                prepare_pyramid_level(instance, page_options, workers)
+                    .map_err(|e| instance.error(ImageKind::Level(level), e))
+            });
+            for (&(level, instance), prepared_level) in batch.iter().zip(prepared_levels) {
+                cancellation.check()?;
+                let prepared_level = prepared_level?;
+                if options.flavor == OutputFlavor::Ome && ome_level_0.is_none() {
+                    if let Some(prepared_level) = prepared_level {
+                        ome_level_0 = Some(((level, instance), prepared_level));
+                    } else {
+                        write_level(tiff, progress, (level, instance), None, LevelIfd::Sub)?;
+                    }
+                    continue;
+                }
+                let ifd = match options.flavor {
+                    OutputFlavor::Ome => LevelIfd::Sub,
+                    _ => LevelIfd::Main {
+                        is_first: level_0_size.is_none(),
+                        sub_ifds: &[],
+                        dimensions: &dimensions,
+                    },
+                };
+                let Some((size, offset)) =
+                    write_level(tiff, progress, (level, instance), prepared_level, ifd)?
+                else {
+                    continue;
+                };
+                if options.flavor == OutputFlavor::Ome {
+                    sub_ifds.push(offset);
+                } else if level_0_size.is_none() {
+                    level_0_size = Some(size);
+                    write_associated(
+                        tiff,
+                        &dicom_pyramid_sources.thumbnail,
+                        AssociatedImageKind::Thumbnail,
+                        associated_images.thumbnail,
+                        size,
+                    )?;
+                }
+            }
+        }
+        #[cfg(feature = "downsample")]
+        if options.synthesize_levels
+            && let Some(&(level, instance)) = selected_levels.last()
+        {
+            sub_ifds.extend(write_synthesized_levels(
+                tiff,
+                (level, instance),
+                page_options,
+                workers,
+                progress,
+                cancellation,
+            )?);
+        }
+        if let Some((level, prepared_level)) = ome_level_0 {
+            let ifd = LevelIfd::Main {
+                is_first: page == 0,
+                sub_ifds: &sub_ifds,
+                dimensions: &dimensions,
+            };
+            let size = write_level(tiff, progress, level, Some(prepared_level), ifd)?
+                .map(|(size, _)| size);
+            level_0_size = level_0_size.or(size);
+        } else if options.flavor == OutputFlavor::Ome && page > 0 {
+            // The OME-XML refers to the focal planes and channels by the index of their IFD
+            return Err(Error::NoPyramidLevels);
+        }
+    }
+    let level_0_size = level_0_size.ok_or(Error::NoPyramidLevels)?;
+    write_associated(
+        tiff,
+        &dicom_pyramid_sources.label,
+        AssociatedImageKind::Label,
+        associated_images.label,
+        level_0_size,
+    )?;
+    write_associated(
+        tiff,
+        &dicom_pyramid_sources.overview,
+        AssociatedImageKind::Overview,
+        associated_images.overview,
+        level_0_size,
+    )?;
+    progress.finish();
+
+    Ok(())
+}
+
+/// The channels and focal planes of the pages of an OME-TIFF. The optical path of every
+/// channel is read from the header of its level 0.
+fn get_ome_dimensions(pages: &[Vec<(DicomPyramidSources, ConversionOptions)>]) -> OmeDimensions {
+    let optical_paths = pages
+        .iter()
+        .filter_map(|planes| planes.first())
+        .map(|(sources, options)| {
+            let ChannelSelection::Identifier(identifier) = &options.channels else {
+                return None;
+            };
+            let header = sources.levels.first()?.read_header().ok()?;
+            optical_paths::find_optical_path(&header, Some(identifier)).cloned()
+        })
+        .collect();
+    let z_offsets = match pages.first() {
+        Some(planes) if planes.len() > 1 => planes
+            .iter()
+            .filter_map(|(_, options)| match options.focal_planes {
+                FocalPlaneSelection::ZOffset(z_offset) => Some(z_offset),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    OmeDimensions {
+        optical_paths,
+        z_offsets,
+    }
+}
+
+/// The NewSubfileType of reduced-resolution versions of another image in the TIFF.
+const SUBFILE_REDUCED_RESOLUTION: u32 = 1;
+
+/// The number of bytes read from the start of each JPEG frame to find its tables.
+const JPEG_HEADER_LEN: usize = 64 * 1024;
+
+/// The JPEG quality of the tiles of synthesized levels.
+#[cfg(feature = "downsample")]
+const SYNTHESIZED_LEVEL_QUALITY: u8 = 90;
+
+/// Reads the attributes before the pixel data. The frames are read from the source as they are
+/// written.
+pub(crate) fn read_dicom_header(mut dcm_source: SharedReadSeek) -> Result<DefaultDicomObject> {
+    dcm_source.rewind()?;
+    Ok(dicom_object::OpenFileOptions::new()
+        .read_until(dicom_tags::PIXEL_DATA)
+        .from_reader(dcm_source)?)
+}
+
+/// Reads what is needed to write the frames of an image, and of the further instances of its
+/// concatenation.
+pub(crate) fn prepare_image<'a>(
+    dcm_object: &DefaultDicomObject,
+    dcm_source: SharedReadSeek<'a>,
+    concatenation: &[SharedReadSeek<'a>],
+    options: &ConversionOptions,
+) -> Result<(DicomImage, TileData<'a>, Vec<Option<usize>>)> {
+    let image = DicomImage::from_object(dcm_object)?;
+    let tile_data = image.get_tile_data(
+        dcm_object,
+        dcm_source,
+        concatenation,
+        options.native_compression,
+    )?;
+    let tile_frames = image.get_tile_frames(
+        dcm_object,
+        tile_data.frames.len(),
+        options.focal_planes,
+        &options.channels,
+        |frame| tile_data.frames.encoded_len(frame),
+    )?;
+    Ok((image, tile_data, tile_frames))
+}
+
+/// A pyramid level whose pixel data has been located, ready to be written.
+struct PyramidLevel<'a> {
+    dcm_object: DefaultDicomObject,
+    image: DicomImage,
+    tile_data: TileData<'a>,
+    tile_frames: Vec<Option<usize>>,
+    pixel_spacing: Option<(f64, f64)>,
+    icc_profile: Option<Vec<u8>>,
+    /// The JPEG tables shared by all tiles, which are then stored abbreviated
+    jpeg_tables: Option<Vec<u8>>,
+}
+
+/// Reads the header of a pyramid level and locates its frames, or returns `None` if the level
+/// is skipped in lenient mode.
+fn prepare_pyramid_level<'a>(
+    instance: &DicomInstance<'a>,
+    options: &ConversionOptions,
+    workers: &Workers,
+) -> Result<Option<PyramidLevel<'a>>> {
+    let Some(dcm_object) = options.skip_error(instance.read_header())? else {
+        return Ok(None);
+    };
+    let source = instance.source.clone();
+    let Some((image, tile_data, tile_frames)) = options.skip_error(
+        prepare_image(&dcm_object, source, &instance.concatenation, options).and_then(|prepared| {
+            check_flavor_compression(options.flavor, prepared.1.tiff_compression)?;
+            Ok(prepared)
+        }),
+    )?
+    else {
+        return Ok(None);
+    };
+    let pixel_spacing = options.skip_error(image::get_pixel_spacing(&dcm_object))?;
+    let icc_profile = match options.icc_profile {
+        IccProfilePolicy::Preserve => options
+            .skip_error(image::get_icc_profile(&dcm_object))?
+            .flatten(),
+        IccProfilePolicy::Omit => None,
+    };
+
+    // If all JPEG tiles of the level share the same quantization and Huffman tables, write
+    // them once in the JPEGTables tag and store abbreviated tiles, like Aperio SVS files do.
+    // Otherwise every tile keeps its own tables.
+    let jpeg_tables = match &tile_data.frames {
+        Frames::Encapsulated(fragments)
+            if tile_data.tiff_compression == CompressionMethod::ModernJPEG =>
+        {
+            get_shared_jpeg_tables(fragments, workers)?
+        }
+        _ => None,
+    };
+
+    Ok(Some(PyramidLevel {
+        dcm_object,
+        image,
+        tile_data,
+        tile_frames,
+        pixel_spacing,
+        icc_profile,
+        jpeg_tables,
+    }))
+}
+
+/// Where a pyramid level is written in the TIFF.
+#[derive(Clone, Copy)]
+enum LevelIfd<'s> {
+    /// An image of the main IFD chain. `is_first` is whether it is the first written level,
+    /// which carries the slide metadata, `sub_ifds` are the offsets of the IFDs of the reduced
+    /// levels in an OME-TIFF, and `dimensions` its channels and focal planes.
+    Main {
+        is_first: bool,
+        sub_ifds: &'s [u64],
+        dimensions: &'s OmeDimensions,
+    },
+    /// A reduced-resolution level in a SubIFD of level 0 of an OME-TIFF
+    Sub,
+}
+
+/// Writes a pyramid level as a tiled TIFF image and returns its (width, height) and the offset
+/// of its IFD.
+fn write_pyramid_level<W: Write + Seek, K: TiffKind>(
+    tiff: &mut TiffEncoder<W, K>,
+    level: &PyramidLevel,
+    ifd: LevelIfd,
+    options: &ConversionOptions,
+    workers: &Workers,
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<((u32, u32), u64)> {
+    let PyramidLevel {
+        dcm_object,
+        image,
+        tile_data,
+        tile_frames,
+        pixel_spacing,
+        icc_profile,
+        jpeg_tables,
+    } = level;
+
+    let is_first = matches!(ifd, LevelIfd::Main { is_first: true, .. });
+    let mut dir = match ifd {
+        LevelIfd::Main { .. } => tiff.image_directory()?,
+        LevelIfd::Sub => tiff.extra_directory()?,
+    };
+
+    match options.flavor {
+        // Fake Aperio SVS
+        OutputFlavor::Aperio => {
+            let mut image_description = aperio_level_description(*pixel_spacing);
+            if is_first && options.metadata_policy() == MetadataPolicy::Full {
+                for (key, value) in get_aperio_metadata(dcm_object) {
+                    image_description.push_str(&format!("|{} = {}", key, value));
+                }
+                write_metadata_tags(&mut dir, dcm_object)?;
+            }
+            dir.write_tag(TiffTag::ImageDescription, image_description.as_str())?;
+        }
+        OutputFlavor::Generic => {
+            if is_first && options.metadata_policy() == MetadataPolicy::Full {
+                write_metadata_tags(&mut dir, dcm_object)?;
+            }
+            if !is_first {
+                dir.write_tag(TiffTag::NewSubfileType, SUBFILE_REDUCED_RESOLUTION)?;
+            }
+        }
+        OutputFlavor::Ome => {
+            if let LevelIfd::Main {
+                is_first: true,
+                dimensions,
+                ..
+            } = ifd
+            {
+                let ome_xml = ome::ome_xml(
+                    dcm_object,
+                    image,
+                    image.bits_per_sample(tile_data)[0],
+                    *pixel_spacing,
+                    dimensions,
+                    options.metadata_policy(),
+                );
+                dir.write_tag(TiffTag::ImageDescription, ome_xml.as_str())?;
+                if options.metadata_policy() == MetadataPolicy::Full {
+                    write_metadata_tags(&mut dir, dcm_object)?;
+                }
+            } else if matches!(ifd, LevelIfd::Sub) {
+                dir.write_tag(TiffTag::NewSubfileType, SUBFILE_REDUCED_RESOLUTION)?;
+            }
+            if let LevelIfd::Main { sub_ifds, .. } = ifd
+                && !sub_ifds.is_empty()
+            {
+                let sub_ifds = sub_ifds
+                    .iter()
+                    .map(|&offset| K::convert_offset(offset))
+                    .collect::<tiff::TiffResult<Vec<_>>>()?;
+                dir.write_tag(TiffTag::SubIfd, K::convert_slice(&sub_ifds))?;
+            }
+        }
+    }
+
+    // Dimensions
+    dir.write_tag(TiffTag::ImageWidth, image.image_width)?;
+    dir.write_tag(TiffTag::ImageLength, image.image_height)?;
+    dir.write_tag(TiffTag::TileWidth, image.tile_width)?;
+    dir.write_tag(TiffTag::TileLength, image.tile_height)?;
+    // Resolution (MPP)
+    if let Some(pixel_spacing) = *pixel_spacing {
+        write_resolution_tags(&mut dir, pixel_spacing)?;
+    }
+    write_image_tags(&mut dir, image, tile_data, icc_profile.as_deref())?;
+
+    if let Some(jpeg_tables) = jpeg_tables {
+        dir.write_tag(TiffTag::JPEGTables, &jpeg::tables_stream(jpeg_tables)[..])?;
+    }
+
+    // Image Data. Tiles are prepared in batches by the workers and written in order.
+    let mut offsets = Vec::with_capacity(tile_frames.len());
+    let mut byte_counts = Vec::with_capacity(tile_frames.len());
+    for batch in tile_frames.chunks(workers.batch_len()) {
+        cancellation.check()?;
+        let tiles = workers.map(batch, |frame_index| {
+            frame_index
+                .map(|frame_index| prepare_tile(level, frame_index))
+                .transpose()
+        });
+        for tile in tiles {
+            // Tiles without a frame (only possible when sparsely tiled) are written as
+            // zero-length entries, which readers like OpenSlide treat as missing tiles.
+            let Some(tile) = tile? else {
+                offsets.push(K::convert_offset(0)?);
+                byte_counts.push(K::convert_offset(0)?);
+                progress.tile_written(0);
+                continue;
+            };
+            let byte_count = tile.len() as u64;
+            let offset = dir.write_data(&tile[..])?;
+            offsets.push(K::convert_offset(offset)?);
+            byte_counts.push(K::convert_offset(byte_count)?);
+            progress.tile_written(byte_count);
+        }
+    }
+    dir.write_tag(TiffTag::TileOffsets, K::convert_slice(&offsets))?;
+    dir.write_tag(TiffTag::TileByteCounts, K::convert_slice(&byte_counts))?;
+
+    let offset = dir.finish_with_offsets()?.pointer.0;
+
+    Ok(((image.image_width, image.image_height), offset))
+}
+
+/// The ImageDescription of a pyramid level of an Aperio SVS file, with its microns per pixel.
+fn aperio_level_description(pixel_spacing: Option<(f64, f64)>) -> String {
+    let mut image_description = String::from("Aperio\n");
+    if let Some((pixel_spacing_x, _pixel_spacing_y)) = pixel_spacing {
+        let mpp_x = pixel_spacing_x * 1000.0;
+        image_description.push_str(&format!("|MPP = {}", mpp_x));
+    }
+    image_description
+}
+
+/// The number of levels and tiles synthesized below the smallest selected level of every page,
+/// if levels are synthesized.
+#[cfg(feature = "downsample")]
+fn count_synthesized_levels(
+    pages: &[Vec<(DicomPyramidSources, ConversionOptions)>],
+    options: &ConversionOptions,
+) -> (usize, u64) {
+    if !options.synthesize_levels {
+        return (0, 0);
+    }
+    let smallest_levels = pages.iter().flatten().filter_map(|(page_sources, _)| {
+        page_sources
+            .levels
+            .iter()
+            .enumerate()
+            .rfind(|(level, _)| options.levels.includes(*level))
+    });
+    let (mut levels, mut tiles) = (0, 0);
+    for (_, instance) in smallest_levels {
+        let (sizes, tile_size) = synthesized_sizes(instance);
+        levels += sizes.len();
+        tiles += sizes
+            .iter()
+            .map(|&size| downsample::count_tiles(size, tile_size))
+            .sum::<u64>();
+    }
+    (levels, tiles)
+}
+
+/// The (width, height) of the levels synthesized below a pyramid level, and their tile size,
+/// which is that of the level. There are none if its header cannot be read, which fails its
+/// conversion anyway.
+#[cfg(feature = "downsample")]
+fn synthesized_sizes(instance: &DicomInstance) -> (Vec<(u32, u32)>, (u32, u32)) {
+    let image = instance
+        .read_header()
+        .ok()
+        .and_then(|header| DicomImage::from_object(&header).ok());
+    match image {
+        Some(image) => {
+            let tile_size = (u32::from(image.tile_width), u32::from(image.tile_height));
+            (
+                downsample::reduced_sizes((image.image_width, image.image_height), tile_size),
+                tile_size,
+            )
+        }
+        None => (Vec::new(), (1, 1)),
+    }
+}
+
+/// Synthesizes the reduced-resolution levels below a pyramid level (see
+/// [`ConversionOptions::synthesize_levels`]) and writes them after it, numbered after it, as
+/// tiled JPEG images: reduced-resolution images of the main IFD chain, or SubIFDs of level 0 of
+/// an OME-TIFF. Returns the offsets of their IFDs. The levels are made at the same time, and
+/// their tiles are written as soon as they are made. Nothing is written if the level cannot be
+/// decoded in lenient mode.
+#[cfg(feature = "downsample")]
+fn write_synthesized_levels<W: Write + Seek, K: TiffKind>(
+    tiff: &mut TiffEncoder<W, K>,
+    (level, instance): (usize, &DicomInstance),
+    options: &ConversionOptions,
+    workers: &Workers,
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<Vec<u64>> {
+    let prepared = instance.read_header().and_then(|dcm_object| {
+        let (image, tile_data, tile_frames) = prepare_image(
+            &dcm_object,
+            instance.source.clone(),
+            &instance.concatenation,
+            options,
+        )?;
+        decode::check_jpeg_encodable(&image)?;
+        Ok((dcm_object, image, tile_data, tile_frames))
+    });
+    let tile_size =
+        |image: &DicomImage| (u32::from(image.tile_width), u32::from(image.tile_height));
+    let Some((dcm_object, image, tile_data, tile_frames)) = options
+        .skip_error(prepared)
+        .map_err(|e| instance.error(ImageKind::Level(level), e))?
+    else {
+        // Like skipped pyramid levels, the levels only report their progress
+        let (sizes, tile_size) = synthesized_sizes(instance);
+        if !sizes.is_empty() {
+            progress.start_levels(
+                level + 1,
+                sizes.len(),
+                sizes
+                    .iter()
+                    .map(|&size| downsample::count_tiles(size, tile_size))
+                    .sum(),
+            );
+            progress.finish_level();
+        }
+        return Ok(Vec::new());
+    };
+    let sizes =
+        downsample::reduced_sizes((image.image_width, image.image_height), tile_size(&image));
+    if sizes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let pixel_spacing = image::get_pixel_spacing(&dcm_object).ok();
+    let icc_profile = match options.icc_profile {
+        IccProfilePolicy::Preserve => image::get_icc_profile(&dcm_object).ok().flatten(),
+        IccProfilePolicy::Omit => None,
+    };
+    // The tiles of all levels are written as they are made, while the image of the first level
+    // is open, and the images of the further levels follow with their offsets
+    progress.start_levels(
+        level + 1,
+        sizes.len(),
+        sizes
+            .iter()
+            .map(|&size| downsample::count_tiles(size, tile_size(&image)))
+            .sum(),
+    );
+    let mut dir = synthesized_level_directory(
+        tiff,
+        &image,
+        sizes[0],
+        pixel_spacing,
+        icc_profile.as_deref(),
+        options,
+    )?;
+    let mut offsets: Vec<Vec<_>> = sizes.iter().map(|_| Vec::new()).collect();
+    let mut byte_counts: Vec<Vec<_>> = sizes.iter().map(|_| Vec::new()).collect();
+    let format = TileFormat {
+        tile_size: tile_size(&image),
+        crop: false,
+        quality: SYNTHESIZED_LEVEL_QUALITY,
+        subsample_chroma: true,
+    };
+    downsample::downsample_image(
+        (&image, &tile_data, &tile_frames),
+        &sizes,
+        &format,
+        workers,
+        cancellation,
+        |index, tiles| {
+            for tile in tiles {
+                // Like missing tiles of sparse levels, tiles without pixel data are empty
+                let Some(tile) = tile else {
+                    offsets[index].push(K::convert_offset(0)?);
+                    byte_counts[index].push(K::convert_offset(0)?);
+                    progress.tile_written(0);
+                    continue;
+                };
+                let byte_count = tile.len() as u64;
+                let offset = dir.write_data(&tile[..])?;
+                offsets[index].push(K::convert_offset(offset)?);
+                byte_counts[index].push(K::convert_offset(byte_count)?);
+                progress.tile_written(byte_count);
+            }
+            Ok(())
+        },
+    )
+    .map_err(|e| instance.error(ImageKind::Level(level), e))?;
+    dir.write_tag(TiffTag::TileOffsets, K::convert_slice(&offsets[0]))?;
+    dir.write_tag(TiffTag::TileByteCounts, K::convert_slice(&byte_counts[0]))?;
+    let mut ifd_offsets = vec![dir.finish_with_offsets()?.pointer.0];
+
+    for (index, &size) in sizes.iter().enumerate().skip(1) {
+        let mut dir = synthesized_level_directory(
+            tiff,
+            &image,
+            size,
+            pixel_spacing,
+            icc_profile.as_deref(),
+            options,
+        )?;
+        dir.write_tag(TiffTag::TileOffsets, K::convert_slice(&offsets[index]))?;
+        dir.write_tag(
+            TiffTag::TileByteCounts,
+            K::convert_slice(&byte_counts[index]),
+        )?;
+        ifd_offsets.push(dir.finish_with_offsets()?.pointer.0);
+    }
+    progress.finish_level();
+    Ok(ifd_offsets)
+}
+
+/// Starts the image of a synthesized level of `size`, reduced from `image`, and writes its tags
+/// but for the tile offsets and byte counts.
+#[cfg(feature = "downsample")]
+fn synthesized_level_directory<'t, W: Write + Seek, K: TiffKind>(
+    tiff: &'t mut TiffEncoder<W, K>,
+    image: &DicomImage,
+    (width, height): (u32, u32),
+    pixel_spacing: Option<(f64, f64)>,
+    icc_profile: Option<&[u8]>,
+    options: &ConversionOptions,
+) -> Result<DirectoryEncoder<'t, W, K>> {
+    // The pixel spacing grows with the downsampling, which is about a power of two
+    let pixel_spacing = pixel_spacing.map(|(x, y)| {
+        (
+            x * f64::from(image.image_width) / f64::from(width),
+            y * f64::from(image.image_height) / f64::from(height),
+        )
+    });
+    let mut dir = match options.flavor {
+        OutputFlavor::Ome => tiff.extra_directory()?,
+        OutputFlavor::Aperio | OutputFlavor::Generic => tiff.image_directory()?,
+    };
+    match options.flavor {
+        OutputFlavor::Aperio => {
+            let image_description = aperio_level_description(pixel_spacing);
+            dir.write_tag(TiffTag::ImageDescription, image_description.as_str())?;
+        }
+        OutputFlavor::Generic | OutputFlavor::Ome => {
+            dir.write_tag(TiffTag::NewSubfileType, SUBFILE_REDUCED_RESOLUTION)?;
+        }
+    }
+
+    dir.write_tag(TiffTag::ImageWidth, width)?;
+    dir.write_tag(TiffTag::ImageLength, height)?;
+    dir.write_tag(TiffTag::TileWidth, image.tile_width)?;
+    dir.write_tag(TiffTag::TileLength, image.tile_height)?;
+    if let Some(pixel_spacing) = pixel_spacing {
+        write_resolution_tags(&mut dir, pixel_spacing)?;
+    }
+
+    // Color tiles are YCbCr JPEG images with 2x2 chroma subsampling
+    if image.samples_per_pixel == 1 {
+        dir.write_tag(
+            TiffTag::PhotometricInterpretation,
+            tiff::tags::PhotometricInterpretation::BlackIsZero.to_u16(),
+        )?;
+    } else {
+        dir.write_tag(
+            TiffTag::PhotometricInterpretation,
+            tiff::tags::PhotometricInterpretation::YCbCr.to_u16(),
+        )?;
+        // Tag: YCbCrSubSampling
+        dir.write_tag(TiffTag::Unknown(530), &[2u16, 2][..])?;
+    }
+    dir.write_tag(TiffTag::SamplesPerPixel, image.samples_per_pixel)?;
+    dir.write_tag(
+        TiffTag::BitsPerSample,
+        &vec![8u16; usize::from(image.samples_per_pixel)][..],
+    )?;
+    dir.write_tag(TiffTag::Compression, CompressionMethod::ModernJPEG.to_u16())?;
+    if let Some(icc_profile) = icc_profile {
+        dir.write_tag(TiffTag::IccProfile, icc_profile)?;
+    }
+    Ok(dir)
+}
+
+/// Fails if tiles with the given compression cannot be stored in the flavor of TIFF.
+pub(crate) fn check_flavor_compression(
+    flavor: OutputFlavor,
+    compression: CompressionMethod,
+) -> Result<()> {
+    match (flavor, compression) {
+        // The Aperio JPEG 2000 compression codes are only known to readers of Aperio SVS files
+        (OutputFlavor::Generic | OutputFlavor::Ome, CompressionMethod::Unknown(_)) => {
+            Err(Error::UnsupportedPixelData(format!(
+                "JPEG 2000 tiles cannot be stored in {}, use the Aperio flavor",
+                match flavor {
+                    OutputFlavor::Ome => "an OME-TIFF",
+                    _ => "a generic TIFF",
+                }
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reads a frame and turns it into the bytes of its tile: compressed if it is native, and
+/// without its tables if the level has shared JPEG tables.
+fn prepare_tile<'l>(level: &'l PyramidLevel, frame_index: usize) -> Result<Cow<'l, [u8]>> {
+    let tile_data = &level.tile_data;
+    let frame = tile_data.frames.get(frame_index)?;
+    let tile = if let Some(native_compression) = tile_data.native_compression {
+        Cow::Owned(native_compression.compress(&frame, &level.image.native_layout)?)
+    } else if level.jpeg_tables.is_some() {
+        Cow::Owned(
+            jpeg::SplitJpeg::parse(&frame)
+                .ok_or_else(|| Error::InvalidPixelData("invalid JPEG frame".to_string()))?
+                .abbreviated(),
+        )
+    } else {
+        frame
+    };
+    Ok(tile)
+}
+
+/// Returns the JPEG tables shared by all frames (see [`jpeg::get_shared_tables`]). Only the start
+/// of each frame is read, unless its tables do not fit in it.
+fn get_shared_jpeg_tables(frames: &PixelData, workers: &Workers) -> Result<Option<Vec<u8>>> {
+    let read_header = |&index: &usize| {
+        frames
+            .frame_prefix(index, JPEG_HEADER_LEN)
+            .and_then(|prefix| match jpeg::SplitJpeg::parse(&prefix) {
+                Some(_) => Ok(prefix),
+                None => frames.frame(index),
+            })
+    };
+    let indices = (0..frames.len()).collect::<Vec<_>>();
+    let mut error = None;
+    let headers = indices
+        .chunks(workers.batch_len())
+        .flat_map(|batch| workers.map(batch, read_header))
+        .map_while(|header| header.map_err(|err| error = Some(err)).ok());
+    let shared_tables = jpeg::get_shared_tables(headers);
+    match error {
+        Some(err) => Err(err),
+        None => Ok(shared_tables),
+    }
+}
+
+/// Writes a thumbnail, label or overview image as a stripped TIFF image, which is how Aperio SVS
+/// files store associated images. Images which cannot be stored as strips without decoding them
+/// (multiple compressed frames) or which OpenSlide cannot decode as associated images (JPEG 2000)
+/// are skipped.
+fn write_associated_image<W: Write + Seek, K: TiffKind>(
+    tiff: &mut TiffEncoder<W, K>,
+    dcm_source: SharedReadSeek,
+    kind: AssociatedImageKind,
+    (level_0_width, level_0_height): (u32, u32),
+    options: &ConversionOptions,
+) -> Result<()> {
+    let Some(dcm_object) = options.skip_error(read_dicom_header(dcm_source.clone()))? else {
+        return Ok(());
+    };
+    let Some((image, tile_data, tile_frames)) =
+        options.skip_error(prepare_image(&dcm_object, dcm_source, &[], options))?
+    else {
+        return Ok(());
+    };
+    let icc_profile = match options.icc_profile {
+        IccProfilePolicy::Preserve => image::get_icc_profile(&dcm_object).ok().flatten(),
+        IccProfilePolicy::Omit => None,
+    };
+
+    let Some((width, height, rows_per_strip, strips)) =
+        get_strips(&image, &tile_data, &tile_frames)?
+    else {
+        return Ok(());
+    };
+
+    let mut dir = tiff.image_directory()?;
+
+    let image_description = match kind {
+        AssociatedImageKind::Thumbnail => format!(
+            "Aperio\n{}x{} -> {}x{}",
+            level_0_width, level_0_height, width, height
+        ),
+        AssociatedImageKind::Label => format!("Aperio\nlabel {}x{}", width, height),
+        AssociatedImageKind::Overview => format!("Aperio\nmacro {}x{}", width, height),
+    };
+    dir.write_tag(TiffTag::ImageDescription, image_description.as_str())?;
+
+    dir.write_tag(TiffTag::ImageWidth, width)?;
+    dir.write_tag(TiffTag::ImageLength, height)?;
+    dir.write_tag(TiffTag::RowsPerStrip, rows_per_strip)?;
+    if let Ok(pixel_spacing) = image::get_pixel_spacing(&dcm_object) {
+        write_resolution_tags(&mut dir, pixel_spacing)?;
+    }
+    write_image_tags(&mut dir, &image, &tile_data, icc_profile.as_deref())?;
+
+    let mut offsets = Vec::with_capacity(strips.len());
+    let mut byte_counts = Vec::with_capacity(strips.len());
+    for strip in strips {
+        let byte_count = strip.len() as u64;
+        let offset = dir.write_data(&strip[..])?;
+        offsets.push(K::convert_offset(offset)?);
+        byte_counts.push(K::convert_offset(byte_count)?);
+    }
+    dir.write_tag(TiffTag::StripOffsets, K::convert_slice(&offsets))?;
+    dir.write_tag(TiffTag::StripByteCounts, K::convert_slice(&byte_counts))?;
+
+    dir.finish()?;
+
+    Ok(())
+}
+
+/// Whether the frames of an image can be stored as TIFF strips: a single JPEG frame, or native
+/// frames which are not 4:2:2 subsampled, in rows of at most 65535 pixels.
+pub(crate) fn can_store_as_strips(
+    image: &DicomImage,
+    tile_data: &TileData,
+    tile_frames: &[Option<usize>],
+) -> bool {
+    match tile_data.native_compression {
+        None => {
+            matches!(tile_frames, [Some(_)])
+                && tile_data.tiff_compression == CompressionMethod::ModernJPEG
+        }
+        Some(_) => u16::try_from(image.image_width).is_ok() && !image.native_layout.subsampled_422,
+    }
+}
+
+/// Arranges the frames of an image into TIFF strips. Returns the (width, height, rows per
+/// strip, strips) of the stripped image, or `None` if the frames cannot be stored as strips.
+#[allow(clippy::type_complexity)]
+fn get_strips<'a>(
+    image: &DicomImage,
+    tile_data: &'a TileData,
+    tile_frames: &[Option<usize>],
+) -> Result<Option<(u32, u32, u32, Vec<Cow<'a, [u8]>>)>> {
+    if !can_store_as_strips(image, tile_data, tile_frames) {
+        return Ok(None);
+    }
+    match (tile_data.native_compression, tile_frames) {
+        // A single encapsulated frame is a strip as is
+        (None, [Some(frame_index)]) => {
+            let frame = tile_data.frames.get(*frame_index)?;
+            let (width, height) = (u32::from(image.tile_width), u32::from(image.tile_height));
+            Ok(Some((width, height, height, vec![frame])))
+        }
+        (None, _) => Ok(None),
+        // Native frames are stitched into one strip per row of tiles
+        (Some(native_compression), _) => {
+            let layout = image.native_layout;
+            let columns = image.image_width as u16;
+            let tile_row_len = layout.row_len();
+            let tiles_across = image.tiles_across() as usize;
+            let mut strips = Vec::with_capacity(image.tiles_down() as usize);
+            for (tile_y, tile_row) in tile_frames.chunks(tiles_across).enumerate() {
+                let rows = (image.image_height - tile_y as u32 * u32::from(image.tile_height))
+                    .min(u32::from(image.tile_height)) as u16;
+                let strip_layout = NativeLayout {
+                    rows,
+                    columns,
+                    planar: false,
+                    ..layout
+                };
+                let mut strip = vec![0; strip_layout.frame_len()];
+                for (tile_x, frame_index) in tile_row.iter().enumerate() {
+                    let Some(frame_index) = frame_index else {
+                        continue;
+                    };
+                    let frame = tile_data.frames.get(*frame_index)?;
+                    let start = tile_x * tile_row_len;
+                    let len = (strip_layout.row_len() - start).min(tile_row_len);
+                    for row in 0..usize::from(rows) {
+                        let strip_start = row * strip_layout.row_len() + start;
+                        strip[strip_start..strip_start + len]
+                            .copy_from_slice(&frame[row * tile_row_len..row * tile_row_len + len]);
+                    }
+                }
+                strips.push(Cow::Owned(
+                    native_compression.compress(&strip, &strip_layout)?,
+                ));
+            }
+            Ok(Some((
+                image.image_width,
+                image.image_height,
+                u32::from(image.tile_height),
+                strips,
+            )))
+        }
+    }
+}
+
+/// Writes the tags describing the pixels of an image.
+fn write_image_tags<W: Write + Seek, K: TiffKind>(
+    dir: &mut DirectoryEncoder<W, K>,
+    image: &DicomImage,
+    tile_data: &TileData,
+    icc_profile: Option<&[u8]>,
+) -> Result<()> {
+    dir.write_tag(
+        TiffTag::PhotometricInterpretation,
+        image.tiff_photometric_interpretation.to_u16(),
+    )?;
+    // Tag: YCbCrSubSampling
+    let ycbcr_subsampling_tag = TiffTag::Unknown(530);
+    if let Some(subsampling) = image.subsampling {
+        dir.write_tag(ycbcr_subsampling_tag, &subsampling[..])?;
+    }
+    dir.write_tag(TiffTag::SamplesPerPixel, image.samples_per_pixel)?;
+    dir.write_tag(
+        TiffTag::BitsPerSample,
+        &image.bits_per_sample(tile_data)[..],
+    )?;
+
+    dir.write_tag(TiffTag::Compression, tile_data.tiff_compression.to_u16())?;
+    if image.is_signed && tile_data.native_compression.is_some() {
+        dir.write_tag(
+            TiffTag::SampleFormat,
+            &vec![SampleFormat::Int.to_u16(); image.samples_per_pixel as usize][..],
+        )?;
+    }
+    if tile_data
+        .native_compression
+        .is_some_and(|c| c.uses_predictor(&image.native_layout))
+    {
+        dir.write_tag(TiffTag::Predictor, Predictor::Horizontal.to_u16())?;
+    }
+
+    if let Some(icc_profile) = icc_profile {
+        dir.write_tag(TiffTag::IccProfile, icc_profile)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the resolution in pixels per centimeter, from the pixel spacing in millimeters.
+fn write_resolution_tags<W: Write + Seek, K: TiffKind>(
+    dir: &mut DirectoryEncoder<W, K>,
+    (pixel_spacing_x, pixel_spacing_y): (f64, f64),
+) -> Result<()> {
+    let mpp_x = pixel_spacing_x * 1000.0;
+    let mpp_y = pixel_spacing_y * 1000.0;
+    // Centimeters
+    let x_resolution = 10000.0 / mpp_x;
+    let y_resolution = 10000.0 / mpp_y;
+    dir.write_tag(
+        TiffTag::ResolutionUnit,
+        tiff::tags::ResolutionUnit::Centimeter.to_u16(),
+    )?;
+    dir.write_tag(TiffTag::XResolution, x_resolution)?;
+    dir.write_tag(TiffTag::YResolution, y_resolution)?;
+    Ok(())
+}
+
+/// Slide metadata as the key-value pairs of an Aperio ImageDescription.
+fn get_aperio_metadata(dcm_object: &InMemDicomObject) -> Vec<(&'static str, String)> {
+    let mut metadata = Vec::new();
+    let objective_lens_power = get_element_opt(dcm_object, dicom_tags::OPTICAL_PATH_SEQUENCE)
+        .ok()
+        .flatten()
+        .and_then(|e| e.items())
+        .and_then(|items| items.first())
+        .and_then(|item| get_string(item, dicom_tags::OBJECTIVE_LENS_POWER));
+    if let Some(objective_lens_power) = objective_lens_power {
+        metadata.push(("AppMag", objective_lens_power));
+    }
+    if let Some((date, time)) = get_date_time(dcm_object) {
+        // Aperio dates are MM/DD/YY
+        metadata.push((
+            "Date",
+            format!("{}/{}/{}", &date[4..6], &date[6..8], &date[2..4]),
+        ));
+        metadata.push((
+            "Time",
+            format!("{}:{}:{}", &time[0..2], &time[2..4], &time[4..6]),
+        ));
+    }
+    if let Some(serial_number) = get_string(dcm_object, dicom_tags::DEVICE_SERIAL_NUMBER) {
+        metadata.push(("ScanScope ID", serial_number));
+    }
+    if let Some(manufacturer) = get_string(dcm_object, dicom_tags::MANUFACTURER) {
+        metadata.push(("Manufacturer", manufacturer));
+    }
+    if let Some(model) = get_string(dcm_object, dicom_tags::MANUFACTURER_MODEL_NAME) {
+        metadata.push(("Model", model));
+    }
+    // Values must not contain the field separator
+    metadata
+        .into_iter()
+        .map(|(key, value)| (key, value.replace('|', "/")))
+        .collect()
+}
+
+/// Writes the DateTime, Make, Model and Software tags.
+fn write_metadata_tags<W: Write + Seek, K: TiffKind>(
+    dir: &mut DirectoryEncoder<W, K>,
+    dcm_object: &InMemDicomObject,
+) -> Result<()> {
+    if let Some((date, time)) = get_date_time(dcm_object) {
+        let date_time = format!(
+            "{}:{}:{} {}:{}:{}",
+            &date[0..4],
+            &date[4..6],
+            &date[6..8],
+            &time[0..2],
+            &time[2..4],
+            &time[4..6]
+        );
+        dir.write_tag(TiffTag::DateTime, date_time.as_str())?;
+    }
+    if let Some(manufacturer) = get_string(dcm_object, dicom_tags::MANUFACTURER) {
+        dir.write_tag(TiffTag::Make, manufacturer.as_str())?;
+    }
+    if let Some(model) = get_string(dcm_object, dicom_tags::MANUFACTURER_MODEL_NAME) {
+        dir.write_tag(TiffTag::Model, model.as_str())?;
+    }
+    if let Some(software) = get_string(dcm_object, dicom_tags::SOFTWARE_VERSIONS) {
+        dir.write_tag(TiffTag::Software, software.as_str())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,427 @@
+use tiff::tags::PhotometricInterpretation as TiffPhotometricInterpretation;
+
+use crate::cancellation::CancellationToken;
+use crate::compression::{Codec, PixelEncoding};
+use crate::converter::{ConversionOptions, TileLayout};
+use crate::decode::{self, BACKGROUND};
+use crate::downsample::{self, TileFormat};
+use crate::error::{Error, ImageKind, Result};
+use crate::focal_planes;
+use crate::image::{DicomImage, TileData};
+use crate::info::escape_json;
+use crate::jpeg;
+use crate::optical_paths;
+use crate::progress::{ProgressCallback, ProgressTracker};
+use crate::slide::{DicomInstance, DicomPyramidSources};
+use crate::store::OutputStore;
+use crate::tiff_writer::prepare_image;
+use crate::workers::Workers;
+
+/// Writes the pyramid levels of a slide as a static tile tree in the given layout: JPEG tiles of
+/// a fixed size at every power of two downsampling of the largest level. Output levels with the
+/// size of a DICOM level are cut from it, or copied from its frames if they are JPEG frames of
+/// the tile size. The output levels down to the next one with the size of a DICOM level are
+/// made from it by averaging 2x2 pixels of the level above, all while it is read row by row.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_tiles<S: OutputStore>(
+    dicom_pyramid_sources: &DicomPyramidSources,
+    store: &mut S,
+    layout: &TileLayout,
+    options: &ConversionOptions,
+    progress_callback: Option<&ProgressCallback>,
+    progress_interval: u64,
+    workers: &Workers,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    if dicom_pyramid_sources.levels.is_empty() {
+        return Err(Error::NoPyramidLevels);
+    }
+    cancellation.check()?;
+
+    // A tile tree holds a single channel and focal plane
+    let (dicom_pyramid_sources, options) =
+        &optical_paths::select_channel(dicom_pyramid_sources, options)?;
+    let (dicom_pyramid_sources, options) =
+        &focal_planes::select_focal_plane(dicom_pyramid_sources, options)?;
+
+    // All source levels are prepared first, to find the output levels of their size
+    let selected_levels = dicom_pyramid_sources
+        .levels
+        .iter()
+        .enumerate()
+        .filter(|(level, _)| options.levels.includes(*level))
+        .collect::<Vec<_>>();
+    let mut source_levels = Vec::new();
+    for batch in selected_levels.chunks(workers.batch_len()) {
+        cancellation.check()?;
+        let prepared_levels = workers.map(batch, |&(level, instance)| {
+            prepare_source_level(instance, options)
+                .map_err(|e| instance.error(ImageKind::Level(level), e))
+        });
+        for (&(level, _), prepared_level) in batch.iter().zip(prepared_levels) {
+            if let Some(prepared_level) = prepared_level? {
+                source_levels.push((level, prepared_level));
+            }
+        }
+    }
+    let Some((_, largest)) = source_levels.first() else {
+        return Err(Error::NoPyramidLevels);
+    };
+    let size = (largest.image.image_width, largest.image.image_height);
+    let tile_size = options
+        .static_tile_size
+        .unwrap_or(u32::from(largest.image.tile_width))
+        .clamp(1, u32::from(u16::MAX));
+    let format = TileFormat {
+        tile_size: (tile_size, tile_size),
+        crop: true,
+        quality: options.static_tile_quality,
+        subsample_chroma: false,
+    };
+
+    let output_levels = output_levels(size, tile_size, layout);
+    let tiles_of = |level_size| downsample::count_tiles(level_size, format.tile_size);
+    let mut progress = ProgressTracker::new(
+        progress_callback,
+        progress_interval,
+        output_levels.len(),
+        output_levels
+            .iter()
+            .map(|&level_size| tiles_of(level_size))
+            .sum(),
+    );
+    let source_of = |level_size: (u32, u32)| {
+        source_levels
+            .iter()
+            .find(|(_, source)| (source.image.image_width, source.image.image_height) == level_size)
+    };
+
+    // The largest output level has the size of the largest source level
+    let mut scale = 0;
+    while scale < output_levels.len() {
+        cancellation.check()?;
+        let (source_index, source) =
+            source_of(output_levels[scale]).expect("the level has the size of a source level");
+        let source_error = |e| {
+            dicom_pyramid_sources.levels[*source_index].error(ImageKind::Level(*source_index), e)
+        };
+        let output_level = OutputLevel {
+            size: output_levels[scale],
+            tile_size,
+        };
+        let key_of = |output_level: &OutputLevel, scale, position| {
+            tile_key(
+                layout,
+                size,
+                output_level,
+                output_levels.len(),
+                scale,
+                position,
+            )
+        };
+
+        // Tiles are prepared in batches by the workers and written in order
+        progress.start_level(scale, tiles_of(output_level.size));
+        let positions = (0..output_level.size.1.div_ceil(tile_size))
+            .flat_map(|row| {
+                (0..output_level.size.0.div_ceil(tile_size)).map(move |column| (column, row))
+            })
+            .collect::<Vec<_>>();
+        for batch in positions.chunks(workers.batch_len()) {
+            cancellation.check()?;
+            let prepared_tiles = workers.map(batch, |&position| {
+                prepare_tile(source, &output_level, &format, position)
+            });
+            for (&position, tile) in batch.iter().zip(prepared_tiles) {
+                let Some(tile) = tile.map_err(source_error)? else {
+                    progress.tile_written(0);
+                    continue;
+                };
+                store.set(&key_of(&output_level, scale, position), &tile)?;
+                progress.tile_written(tile.len() as u64);
+            }
+        }
+        progress.finish_level();
+
+        // The smaller levels without a source level of their size
+        let reduced_sizes = output_levels[scale + 1..]
+            .iter()
+            .copied()
+            .take_while(|&level_size| source_of(level_size).is_none())
+            .collect::<Vec<_>>();
+        if !reduced_sizes.is_empty() {
+            progress.start_levels(
+                scale + 1,
+                reduced_sizes.len(),
+                reduced_sizes
+                    .iter()
+                    .map(|&level_size| tiles_of(level_size))
+                    .sum(),
+            );
+            let mut rows_written = vec![0; reduced_sizes.len()];
+            downsample::downsample_image(
+                (&source.image, &source.tile_data, &source.tile_frames),
+                &reduced_sizes,
+                &format,
+                workers,
+                cancellation,
+                |index, tiles| {
+                    let output_level = OutputLevel {
+                        size: reduced_sizes[index],
+                        tile_size,
+                    };
+                    let row = rows_written[index];
+                    rows_written[index] += 1;
+                    for (column, tile) in (0..).zip(tiles) {
+                        let Some(tile) = tile else {
+                            progress.tile_written(0);
+                            continue;
+                        };
+                        let key = key_of(&output_level, scale + 1 + index, (column, row));
+                        store.set(&key, &tile)?;
+                        progress.tile_written(tile.len() as u64);
+                    }
+                    Ok(())
+                },
+            )
+            .map_err(source_error)?;
+            progress.finish_level();
+        }
+        scale += 1 + reduced_sizes.len();
+    }
+
+    match layout {
+        TileLayout::Dzi { name } => {
+            let dzi = format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"jpeg\" \
+                 Overlap=\"0\" TileSize=\"{}\"><Size Width=\"{}\" Height=\"{}\"/></Image>",
+                tile_size, size.0, size.1
+            );
+            store.set(&format!("{}.dzi", name), dzi.as_bytes())?;
+        }
+        TileLayout::Iiif { id } => {
+            let scale_factors = (0..output_levels.len())
+                .map(|scale| (1u64 << scale).to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            let info = format!(
+                r#"{{"@context":"http://iiif.io/api/image/3/context.json","id":"{}","type":"ImageService3","protocol":"http://iiif.io/api/image","profile":"level0","width":{},"height":{},"tiles":[{{"width":{},"height":{},"scaleFactors":[{}]}}]}}"#,
+                escape_json(id),
+                size.0,
+                size.1,
+                tile_size,
+                tile_size,
+                scale_factors
+            );
+            store.set("info.json", info.as_bytes())?;
+        }
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+/// A DICOM pyramid level from which tiles are copied or cut, and smaller levels are made.
+struct SourceLevel<'a> {
+    image: DicomImage,
+    tile_data: TileData<'a>,
+    tile_frames: Vec<Option<usize>>,
+    /// Whether the JPEG frames can be copied as tiles, if web browsers can decode them
+    copy_frames: bool,
+}
+
+/// A level of the output, 2^scale times smaller than the largest level.
+struct OutputLevel {
+    /// (width, height)
+    size: (u32, u32),
+    tile_size: u32,
+}
+
+/// The (width, height) of every output level, from the largest one down to a single pixel for
+/// Deep Zoom, or down to a single tile for IIIF.
+fn output_levels(
+    (width, height): (u32, u32),
+    tile_size: u32,
+    layout: &TileLayout,
+) -> Vec<(u32, u32)> {
+    let mut levels = vec![(width, height)];
+    loop {
+        let (width, height) = *levels.last().unwrap();
+        let last = match layout {
+            TileLayout::Dzi { .. } => width <= 1 && height <= 1,
+            TileLayout::Iiif { .. } => width <= tile_size && height <= tile_size,
+        };
+        if last {
+            return levels;
+        }
+        levels.push((width.div_ceil(2), height.div_ceil(2)));
+    }
+}
+
+/// The key of a tile: `{name}_files/{level}/{column}_{row}.jpeg` with Deep Zoom levels counted
+/// from the smallest, or `{region}/{size}/0/default.jpg` with the canonical region and size of
+/// the IIIF Image API as requested by OpenSeadragon.
+fn tile_key(
+    layout: &TileLayout,
+    (width, height): (u32, u32),
+    level: &OutputLevel,
+    levels: usize,
+    scale: usize,
+    (column, row): (u32, u32),
+) -> String {
+    let (x, y, tile_width, tile_height) = level.tile_rect((column, row));
+    match layout {
+        TileLayout::Dzi { name } => {
+            format!(
+                "{}_files/{}/{}_{}.jpeg",
+                name,
+                levels - 1 - scale,
+                column,
+                row
+            )
+        }
+        TileLayout::Iiif { .. } => {
+            let factor = 1u64 << scale;
+            let region_x = u64::from(x) * factor;
+            let region_y = u64::from(y) * factor;
+            let region_width = (u64::from(tile_width) * factor).min(u64::from(width) - region_x);
+            let region_height = (u64::from(tile_height) * factor).min(u64::from(height) - region_y);
+            let region = if (region_x, region_y) == (0, 0)
+                && (region_width, region_height) == (u64::from(width), u64::from(height))
+            {
+                "full".to_string()
+            } else {
+                format!(
+                    "{},{},{},{}",
+                    region_x, region_y, region_width, region_height
+                )
+            };
+            let size = if (tile_width, tile_height) == (width, height) {
+                "max".to_string()
+            } else {
+                format!("{},{}", tile_width, tile_height)
+            };
+            format!("{}/{}/0/default.jpg", region, size)
+        }
+    }
+}
+
+impl OutputLevel {
+    /// The (x, y, width, height) of a tile in the level. Tiles at the right and bottom edges are
+    /// cropped to the level.
+    fn tile_rect(&self, (column, row): (u32, u32)) -> (u32, u32, u32, u32) {
+        let x = column * self.tile_size;
+        let y = row * self.tile_size;
+        (
+            x,
+            y,
+            self.tile_size.min(self.size.0 - x),
+            self.tile_size.min(self.size.1 - y),
+        )
+    }
+}
+
+/// Reads the header of a pyramid level and locates its frames, or returns `None` if the level
+/// is skipped in lenient mode.
+fn prepare_source_level<'a>(
+    instance: &DicomInstance<'a>,
+    options: &ConversionOptions,
+) -> Result<Option<SourceLevel<'a>>> {
+    let Some(dcm_object) = options.skip_error(instance.read_header())? else {
+        return Ok(None);
+    };
+    let source = instance.source.clone();
+    let Some((image, tile_data, tile_frames)) = options.skip_error(
+        prepare_image(&dcm_object, source, &instance.concatenation, options).and_then(|prepared| {
+            decode::check_jpeg_encodable(&prepared.0)?;
+            Ok(prepared)
+        }),
+    )?
+    else {
+        return Ok(None);
+    };
+    // Browsers would take the components of RGB frames for YCbCr
+    let copy_frames = image.pixel_encoding == PixelEncoding::Encapsulated(Codec::Jpeg)
+        && (image.samples_per_pixel == 1
+            || image.tiff_photometric_interpretation == TiffPhotometricInterpretation::YCbCr);
+
+    Ok(Some(SourceLevel {
+        image,
+        tile_data,
+        tile_frames,
+        copy_frames,
+    }))
+}
+
+/// Makes the JPEG tile at a position of an output level of the size of the source, or returns
+/// `None` if there is no pixel data for it (only possible when the source is sparsely tiled).
+fn prepare_tile(
+    source: &SourceLevel,
+    level: &OutputLevel,
+    format: &TileFormat,
+    position: (u32, u32),
+) -> Result<Option<Vec<u8>>> {
+    let image = &source.image;
+    let (x, y, width, height) = level.tile_rect(position);
+
+    // Frames which are exactly the tile are copied, if browsers can decode them
+    let aligned = source.copy_frames
+        && (u32::from(image.tile_width), u32::from(image.tile_height))
+            == (level.tile_size, level.tile_size)
+        && (width, height) == (level.tile_size, level.tile_size);
+    if aligned {
+        let slot = (position.1 * image.tiles_across() + position.0) as usize;
+        let Some(frame_index) = source.tile_frames[slot] else {
+            return Ok(None);
+        };
+        let frame = source.tile_data.frames.get(frame_index)?;
+        if jpeg::SplitJpeg::parse(&frame).is_some_and(|jpeg| jpeg.is_web_compatible()) {
+            return Ok(Some(frame.into_owned()));
+        }
+    }
+
+    let Some(pixels) = read_region(source, (x, x + width), (y, y + height))? else {
+        return Ok(None);
+    };
+    let samples = usize::from(image.samples_per_pixel);
+    downsample::encode_tile(&pixels, (width, height), samples, format).map(Some)
+}
+
+/// Reads the pixels of a region of the source, as 8 bit color-by-pixel samples where black is
+/// 0, or returns `None` if no frame covers it.
+fn read_region(
+    source: &SourceLevel,
+    (x0, x1): (u32, u32),
+    (y0, y1): (u32, u32),
+) -> Result<Option<Vec<u8>>> {
+    let image = &source.image;
+    let samples = usize::from(image.samples_per_pixel);
+    let (tile_width, tile_height) = (u32::from(image.tile_width), u32::from(image.tile_height));
+
+    let region_width = (x1 - x0) as usize;
+    let mut region = vec![BACKGROUND; region_width * (y1 - y0) as usize * samples];
+    let mut covered = false;
+    for tile_y in y0 / tile_height..y1.div_ceil(tile_height) {
+        for tile_x in x0 / tile_width..x1.div_ceil(tile_width) {
+            let slot = (tile_y * image.tiles_across() + tile_x) as usize;
+            let Some(frame_index) = source.tile_frames.get(slot).copied().flatten() else {
+                continue;
+            };
+            covered = true;
+            let frame = decode::decode_frame_8_bit(image, &source.tile_data, frame_index)?;
+            let frame_row_len = tile_width as usize * samples;
+            let columns = (tile_x * tile_width).max(x0)..((tile_x + 1) * tile_width).min(x1);
+            let frame_start = (columns.start - tile_x * tile_width) as usize * samples;
+            let len = columns.len() * samples;
+            for y in (tile_y * tile_height).max(y0)..((tile_y + 1) * tile_height).min(y1) {
+                let frame_row = (y - tile_y * tile_height) as usize * frame_row_len;
+                let region_start =
+                    ((y - y0) as usize * region_width + (columns.start - x0) as usize) * samples;
+                region[region_start..region_start + len]
+                    .copy_from_slice(&frame[frame_row + frame_start..][..len]);
+            }
+        }
+    }
+    Ok(covered.then_some(region))
+}
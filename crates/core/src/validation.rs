@@ -0,0 +1,502 @@
+use std::fmt;
+use std::io::{Read, Seek};
+
+use dicom_core::Tag;
+use dicom_dictionary_std::tags as dicom_tags;
+use dicom_object::DefaultDicomObject;
+
+use crate::compression::{self, Codec, PixelEncoding};
+use crate::converter::{
+    ChannelSelection, ConversionOptions, FocalPlaneSelection, IccProfilePolicy, OutputFlavor,
+    Strictness,
+};
+use crate::error::{Error, ImageKind, Result, get_number_of_frames, get_str, get_u16, get_u32};
+use crate::image::{self, DicomImage, TileData};
+use crate::optical_paths;
+use crate::slide::{self, AssociatedImageKind, DicomInstance, DicomPyramidSources, SlideId};
+use crate::tiff_writer::{can_store_as_strips, check_flavor_compression};
+#[cfg(feature = "downsample")]
+use crate::{decode, downsample};
+
+/// How serious a problem found by a validation is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The conversion goes on, but leaves out the instance, image or attribute concerned.
+    Warning,
+    /// The conversion fails, or writes a TIFF which readers cannot open.
+    Error,
+}
+
+/// A problem found by a validation. Problems of an instance are [`Error::Instance`] errors,
+/// which tell the index of the instance in the sources.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ValidationIssue {
+    pub severity: Severity,
+    pub error: Error,
+}
+
+/// What a validation found: the slides in the sources, and every problem their conversion
+/// would run into, in the order the conversion would.
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    pub slides: Vec<SlideId>,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl ValidationIssue {
+    pub(crate) fn new(severity: Severity, error: Error) -> Self {
+        Self { severity, error }
+    }
+
+    /// The index in the sources of the instance the problem is in, if any.
+    pub fn instance_index(&self) -> Option<usize> {
+        match &self.error {
+            Error::Instance { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+
+    /// The attribute the problem is in, if any.
+    pub fn tag(&self) -> Option<Tag> {
+        match self.error.root() {
+            Error::MissingAttribute { tag } | Error::InvalidAttribute { tag, .. } => Some(*tag),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.error)
+    }
+}
+
+impl ValidationReport {
+    /// Whether the conversion would succeed: no errors were found, only warnings if any.
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Error> {
+        self.with_severity(Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Error> {
+        self.with_severity(Severity::Warning)
+    }
+
+    fn with_severity(&self, severity: Severity) -> impl Iterator<Item = &Error> {
+        self.issues
+            .iter()
+            .filter(move |issue| issue.severity == severity)
+            .map(|issue| &issue.error)
+    }
+}
+
+/// How far the images are read by a check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Depth {
+    /// Only the attributes before the pixel data
+    Headers,
+    /// The structure of the pixel data too, to locate the frames, without decoding them
+    PixelData,
+}
+
+/// The headers of the images of a slide, `None` where they could not be read.
+#[derive(Default)]
+pub(crate) struct SlideHeaders {
+    pub levels: Vec<Option<DefaultDicomObject>>,
+    pub thumbnail: Option<DefaultDicomObject>,
+    pub label: Option<DefaultDicomObject>,
+    pub overview: Option<DefaultDicomObject>,
+}
+
+/// Walks the given sources as the conversion of their slide to a TIFF with `options` would,
+/// without writing anything, and reports every problem instead of stopping at the first. Only
+/// errors reading the sources are returned.
+pub(crate) fn validate<R: Read + Seek + Send>(
+    dicom_sources: Vec<R>,
+    options: &ConversionOptions,
+) -> Result<ValidationReport> {
+    let mut issues = Vec::new();
+    let slides = slide::discover_slides_reporting(dicom_sources, options, |issue| {
+        issues.push(issue);
+        Ok(())
+    })?;
+    let slide_ids: Vec<SlideId> = slides.iter().map(|slide| slide.id().clone()).collect();
+    match slide_ids.len() {
+        0 => issues.push(ValidationIssue::new(
+            Severity::Error,
+            Error::NoPyramidLevels,
+        )),
+        1 => {}
+        _ => issues.push(ValidationIssue::new(
+            Severity::Error,
+            Error::MultipleSlides(slide_ids.clone()),
+        )),
+    }
+    for slide in &slides {
+        check_slide(slide.sources(), options, Depth::PixelData, &mut issues)?;
+    }
+    Ok(ValidationReport {
+        slides: slide_ids,
+        issues,
+    })
+}
+
+/// Checks the images of a slide as its conversion to a TIFF with `options` would, recording the
+/// problems of the images it writes in `issues`, and returns the headers read.
+pub(crate) fn check_slide(
+    dicom_pyramid_sources: &DicomPyramidSources,
+    options: &ConversionOptions,
+    depth: Depth,
+    issues: &mut Vec<ValidationIssue>,
+) -> Result<SlideHeaders> {
+    // The focal planes of every channel, all of which a conversion may write
+    let pages = match optical_paths::select_pages(dicom_pyramid_sources, options) {
+        Ok(pages) => pages.into_iter().flatten().collect(),
+        Err(e) => {
+            // The images are still checked, as those of the first channel and nominal plane
+            issues.push(ValidationIssue::new(Severity::Error, e));
+            let options = options
+                .clone()
+                .channels(ChannelSelection::First)
+                .focal_planes(FocalPlaneSelection::Nominal);
+            vec![(dicom_pyramid_sources.clone(), options)]
+        }
+    };
+    let mut headers = SlideHeaders::default();
+    let mut written_levels = 0;
+    for (page, (page_sources, page_options)) in pages.iter().enumerate() {
+        for (level, instance) in page_sources.levels.iter().enumerate() {
+            let is_selected = options.levels.includes(level);
+            let mut checks = Checks {
+                issues: is_selected.then_some(&mut *issues),
+                options: page_options,
+                instance,
+                kind: ImageKind::Level(level),
+            };
+            let header = checks.check(instance.read_header())?;
+            if let Some(header) = &header
+                && check_level(&mut checks, header, depth)?
+                && is_selected
+                && page == 0
+            {
+                written_levels += 1;
+            }
+            if page == 0 {
+                headers.levels.push(header);
+            }
+        }
+        // The levels synthesized below the smallest level are decoded from its frames
+        #[cfg(feature = "downsample")]
+        if options.synthesize_levels
+            && let Some((level, instance)) = page_sources
+                .levels
+                .iter()
+                .enumerate()
+                .rfind(|(level, _)| options.levels.includes(*level))
+            && let Ok(image) = instance
+                .read_header()
+                .and_then(|header| DicomImage::from_object(&header))
+            && !downsample::reduced_sizes(
+                (image.image_width, image.image_height),
+                (u32::from(image.tile_width), u32::from(image.tile_height)),
+            )
+            .is_empty()
+        {
+            let mut checks = Checks {
+                issues: Some(&mut *issues),
+                options: page_options,
+                instance,
+                kind: ImageKind::Level(level),
+            };
+            checks.check(decode::check_jpeg_encodable(&image))?;
+        }
+    }
+    if written_levels == 0 {
+        issues.push(ValidationIssue::new(
+            Severity::Error,
+            Error::NoPyramidLevels,
+        ));
+    }
+
+    let mut check_associated = |instance: &Option<DicomInstance>, kind: AssociatedImageKind| {
+        let Some(instance) = instance else {
+            return Ok(None);
+        };
+        let is_written = options.flavor == OutputFlavor::Aperio
+            && match kind {
+                AssociatedImageKind::Thumbnail => options.associated_images.thumbnail,
+                AssociatedImageKind::Label => options.associated_images.label,
+                AssociatedImageKind::Overview => options.associated_images.overview,
+            };
+        let mut checks = Checks {
+            issues: is_written.then_some(&mut *issues),
+            options,
+            instance,
+            kind: ImageKind::from(kind),
+        };
+        let Some(header) = checks.check(instance.read_header())? else {
+            return Ok(None);
+        };
+        check_associated_image(&mut checks, &header, depth)?;
+        Ok::<_, Error>(Some(header))
+    };
+    headers.thumbnail = check_associated(
+        &dicom_pyramid_sources.thumbnail,
+        AssociatedImageKind::Thumbnail,
+    )?;
+    headers.label = check_associated(&dicom_pyramid_sources.label, AssociatedImageKind::Label)?;
+    headers.overview = check_associated(
+        &dicom_pyramid_sources.overview,
+        AssociatedImageKind::Overview,
+    )?;
+    Ok(headers)
+}
+
+/// The checks of an image, which record the problems its conversion would run into.
+struct Checks<'p, 'a> {
+    /// Where the problems are recorded, `None` if the image is not converted
+    issues: Option<&'p mut Vec<ValidationIssue>>,
+    options: &'p ConversionOptions,
+    instance: &'p DicomInstance<'a>,
+    kind: ImageKind,
+}
+
+impl Checks<'_, '_> {
+    /// Returns the value of a check, or `None` if it failed. The error is recorded as a warning
+    /// if the conversion would skip it in lenient mode, and as an error otherwise. Reading
+    /// errors are returned.
+    fn check<T>(&mut self, result: Result<T>) -> Result<Option<T>> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(e @ Error::Io(_)) => Err(e),
+            Err(e) => {
+                let severity = match self.options.strictness {
+                    Strictness::Lenient => Severity::Warning,
+                    Strictness::Strict => Severity::Error,
+                };
+                self.report(severity, e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Records a problem which the conversion does not fail on.
+    fn report(&mut self, severity: Severity, error: Error) {
+        if let Some(issues) = &mut self.issues {
+            issues.push(ValidationIssue::new(
+                severity,
+                self.instance.error(self.kind, error),
+            ));
+        }
+    }
+}
+
+/// Checks a pyramid level and returns whether it would be written.
+fn check_level(checks: &mut Checks, header: &DefaultDicomObject, depth: Depth) -> Result<bool> {
+    let options = checks.options;
+    let is_written = match check_image(checks, header, Some(options.flavor))? {
+        Some(image) => check_frames(checks, &image, header, depth)?.is_some(),
+        None => false,
+    };
+    checks.check(image::get_pixel_spacing(header))?;
+    if options.icc_profile == IccProfilePolicy::Preserve {
+        checks.check(image::get_icc_profile(header))?;
+    }
+    Ok(is_written)
+}
+
+/// Checks an associated image, which is left out of the TIFF if its frames cannot be stored as
+/// strips.
+fn check_associated_image(
+    checks: &mut Checks,
+    header: &DefaultDicomObject,
+    depth: Depth,
+) -> Result<()> {
+    let Some(image) = check_image(checks, header, None)? else {
+        return Ok(());
+    };
+    if let Some(LocatedFrames {
+        tile_data: Some(tile_data),
+        tile_frames,
+    }) = check_frames(checks, &image, header, depth)?
+        && !can_store_as_strips(&image, &tile_data, &tile_frames)
+    {
+        checks.report(
+            Severity::Warning,
+            Error::UnsupportedPixelData(
+                "the frames cannot be stored as strips, so the image is left out".to_string(),
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Checks what the conversion of an image checks before locating its frames: its image
+/// attributes, transfer syntax and photometric interpretation, and, for pyramid levels written
+/// in a TIFF of the given flavor, whether the flavor can store its compression. Returns the
+/// image if they can be converted.
+fn check_image(
+    checks: &mut Checks,
+    header: &DefaultDicomObject,
+    flavor: Option<OutputFlavor>,
+) -> Result<Option<DicomImage>> {
+    // The image stops at the first missing attribute, so they are checked one by one first
+    let mut has_attributes = true;
+    for tag in [
+        dicom_tags::TOTAL_PIXEL_MATRIX_ROWS,
+        dicom_tags::TOTAL_PIXEL_MATRIX_COLUMNS,
+    ] {
+        has_attributes &= checks.check(get_u32(header, tag))?.is_some();
+    }
+    for tag in [
+        dicom_tags::ROWS,
+        dicom_tags::COLUMNS,
+        dicom_tags::SAMPLES_PER_PIXEL,
+        dicom_tags::BITS_STORED,
+        dicom_tags::BITS_ALLOCATED,
+    ] {
+        has_attributes &= checks.check(get_u16(header, tag))?.is_some();
+    }
+    has_attributes &= checks
+        .check(get_str(header, dicom_tags::PHOTOMETRIC_INTERPRETATION))?
+        .is_some();
+    if !has_attributes {
+        return Ok(None);
+    }
+
+    let Some(image) = checks.check(DicomImage::from_object(header))? else {
+        return Ok(None);
+    };
+    let is_mapped = checks
+        .check(check_pixel_encoding(&image, flavor))?
+        .is_some();
+    Ok(is_mapped.then_some(image))
+}
+
+/// Checks that the frames of an image can be stored in a TIFF of the given flavor, as they are
+/// or compressed.
+fn check_pixel_encoding(image: &DicomImage, flavor: Option<OutputFlavor>) -> Result<()> {
+    match image.pixel_encoding {
+        PixelEncoding::Native | PixelEncoding::Encapsulated(Codec::Rle) => {
+            image.native_layout.validate()?;
+        }
+        PixelEncoding::Encapsulated(codec) => {
+            let tiff_compression = compression::get_tiff_compression(
+                codec,
+                image.tiff_photometric_interpretation,
+                image.bits_stored,
+            )?;
+            if let Some(flavor) = flavor {
+                check_flavor_compression(flavor, tiff_compression)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The frames of an image and the tile slots they fill.
+struct LocatedFrames<'a> {
+    /// The frames, `None` if the pixel data was not read
+    tile_data: Option<TileData<'a>>,
+    tile_frames: Vec<Option<usize>>,
+}
+
+/// Checks the frames of an image against its tile grid: the positions of sparse tiles in the
+/// functional groups, and the number of frames of fully tiled images. Without reading the pixel
+/// data, the Number of Frames is taken as the number of frames.
+fn check_frames<'a>(
+    checks: &mut Checks<'_, 'a>,
+    image: &DicomImage,
+    header: &DefaultDicomObject,
+    depth: Depth,
+) -> Result<Option<LocatedFrames<'a>>> {
+    let number_of_frames = get_number_of_frames(header);
+    let (tile_data, num_frames) = match depth {
+        Depth::Headers => {
+            let Some(number_of_frames) = checks.check(number_of_frames)? else {
+                return Ok(None);
+            };
+            (None, number_of_frames)
+        }
+        Depth::PixelData => {
+            let instance = checks.instance;
+            let native_compression = checks.options.native_compression;
+            let Some(tile_data) = checks.check(image.get_tile_data(
+                header,
+                instance.source.clone(),
+                &instance.concatenation,
+                native_compression,
+            ))?
+            else {
+                return Ok(None);
+            };
+            let num_frames = tile_data.frames.len();
+            if let Ok(number_of_frames) = number_of_frames
+                && number_of_frames != num_frames
+            {
+                checks.report(
+                    Severity::Warning,
+                    Error::invalid_attribute(
+                        dicom_tags::NUMBER_OF_FRAMES,
+                        format!(
+                            "{} frames, but the pixel data holds {}",
+                            number_of_frames, num_frames
+                        ),
+                    ),
+                );
+            }
+            (Some(tile_data), num_frames)
+        }
+    };
+
+    let frame_len = |frame| {
+        tile_data
+            .as_ref()
+            .map_or(0, |tile_data| tile_data.frames.encoded_len(frame))
+    };
+    let Some(tile_frames) = checks.check(image.get_tile_frames(
+        header,
+        num_frames,
+        checks.options.focal_planes,
+        &checks.options.channels,
+        frame_len,
+    ))?
+    else {
+        return Ok(None);
+    };
+    let missing = tile_frames.iter().filter(|frame| frame.is_none()).count();
+    if !image.is_sparse && missing > 0 {
+        // Further frames are of other focal planes or optical paths, but missing ones leave
+        // holes in the tile grid
+        checks.report(
+            Severity::Warning,
+            Error::invalid_attribute(
+                dicom_tags::NUMBER_OF_FRAMES,
+                format!(
+                    "{} of the {}x{} tiles have no frame and are written empty",
+                    missing,
+                    image.tiles_across(),
+                    image.tiles_down()
+                ),
+            ),
+        );
+    }
+    Ok(Some(LocatedFrames {
+        tile_data,
+        tile_frames,
+    }))
+}
@@ -0,0 +1,78 @@
+use crate::error::Result;
+
+/// How many threads prepare the levels and tiles of a conversion.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Parallelism {
+    /// Everything happens on the converting thread
+    #[default]
+    Sequential,
+    /// Levels and tiles are prepared on a thread pool of the given size, or on the global rayon
+    /// pool if it is 0
+    #[cfg(feature = "parallel")]
+    Threads(usize),
+}
+
+impl Parallelism {
+    pub(crate) fn workers(self) -> Result<Workers> {
+        match self {
+            Parallelism::Sequential => Ok(Workers::Sequential),
+            #[cfg(feature = "parallel")]
+            Parallelism::Threads(0) => Ok(Workers::Global),
+            #[cfg(feature = "parallel")]
+            Parallelism::Threads(threads) => {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|index| format!("dicom2tiff-{}", index))
+                    .build()
+                    .map_err(std::io::Error::other)?;
+                Ok(Workers::Pool(pool))
+            }
+        }
+    }
+}
+
+/// Maps batches of items, such as the tiles of a level, on the threads of a conversion. Results
+/// are returned in the order of the items, so that a single writer can commit them in order and
+/// the output is the same however many threads there are.
+pub(crate) enum Workers {
+    Sequential,
+    #[cfg(feature = "parallel")]
+    Global,
+    #[cfg(feature = "parallel")]
+    Pool(rayon::ThreadPool),
+}
+
+impl Workers {
+    /// The number of items which are mapped at once. Sequentially, this is one item, so that
+    /// nothing is read before it is needed.
+    pub(crate) fn batch_len(&self) -> usize {
+        match self {
+            Workers::Sequential => 1,
+            #[cfg(feature = "parallel")]
+            Workers::Global => rayon::current_num_threads() * 4,
+            #[cfg(feature = "parallel")]
+            Workers::Pool(pool) => pool.current_num_threads() * 4,
+        }
+    }
+
+    pub(crate) fn map<T, U, F>(&self, items: &[T], f: F) -> Vec<U>
+    where
+        T: Sync,
+        U: Send,
+        F: Fn(&T) -> U + Send + Sync,
+    {
+        match self {
+            Workers::Sequential => items.iter().map(f).collect(),
+            #[cfg(feature = "parallel")]
+            Workers::Global => {
+                use rayon::prelude::*;
+                items.par_iter().map(f).collect()
+            }
+            #[cfg(feature = "parallel")]
+            Workers::Pool(pool) => {
+                use rayon::prelude::*;
+                pool.install(|| items.par_iter().map(f).collect())
+            }
+        }
+    }
+}
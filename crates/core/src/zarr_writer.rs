@@ -0,0 +1,295 @@
+use std::fmt::Write as _;
+
+use crate::cancellation::CancellationToken;
+use crate::compression::ChunkCompression;
+use crate::converter::ConversionOptions;
+use crate::decode;
+use crate::error::{Error, ImageKind, Result};
+use crate::focal_planes;
+use crate::image::{self, DicomImage, TileData};
+use crate::optical_paths;
+use crate::progress::{ProgressCallback, ProgressTracker};
+use crate::slide::{DicomInstance, DicomPyramidSources};
+use crate::store::OutputStore;
+use crate::tiff_writer::prepare_image;
+use crate::workers::Workers;
+
+/// The metadata of the root group of a Zarr v2 hierarchy.
+const ZGROUP: &str = r#"{"zarr_format":2}"#;
+
+/// Writes the pyramid levels of a slide as an OME-Zarr (NGFF 0.4) multiscale image: one Zarr v2
+/// array per level, named by its index among the written levels, and the multiscales metadata
+/// in the attributes of the root group. Every tile of a level is a chunk of its array.
+pub(crate) fn write_multiscales<S: OutputStore>(
+    dicom_pyramid_sources: &DicomPyramidSources,
+    store: &mut S,
+    options: &ConversionOptions,
+    progress_callback: Option<&ProgressCallback>,
+    progress_interval: u64,
+    workers: &Workers,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    if dicom_pyramid_sources.levels.is_empty() {
+        return Err(Error::NoPyramidLevels);
+    }
+    cancellation.check()?;
+
+    // A multiscale image holds a single channel and focal plane
+    let (dicom_pyramid_sources, options) =
+        &optical_paths::select_channel(dicom_pyramid_sources, options)?;
+    let (dicom_pyramid_sources, options) =
+        &focal_planes::select_focal_plane(dicom_pyramid_sources, options)?;
+
+    let selected_levels = dicom_pyramid_sources
+        .levels
+        .iter()
+        .enumerate()
+        .filter(|(level, _)| options.levels.includes(*level))
+        .collect::<Vec<_>>();
+    let mut progress = ProgressTracker::new(
+        progress_callback,
+        progress_interval,
+        selected_levels.len(),
+        selected_levels
+            .iter()
+            .map(|(_, instance)| instance.tiles)
+            .sum(),
+    );
+
+    store.set(".zgroup", ZGROUP.as_bytes())?;
+
+    // Levels are prepared in batches by the workers and written in order
+    let mut datasets = Vec::new();
+    for batch in selected_levels.chunks(workers.batch_len()) {
+        cancellation.check()?;
+        let prepared_levels = workers.map(batch, |&(level, instance)| {
+            prepare_zarr_level(instance, options)
+                .map_err(|e| instance.error(ImageKind::Level(level), e))
+        });
+        for (&(level, instance), prepared_level) in batch.iter().zip(prepared_levels) {
+            cancellation.check()?;
+            let prepared_level = prepared_level?;
+            progress.start_level(level, instance.tiles);
+            if let Some(prepared_level) = prepared_level {
+                let path = datasets.len().to_string();
+                write_array(
+                    store,
+                    &path,
+                    &prepared_level,
+                    options.chunk_compression,
+                    workers,
+                    &mut progress,
+                    cancellation,
+                )
+                .map_err(|e| instance.error(ImageKind::Level(level), e))?;
+                datasets.push(Dataset {
+                    path,
+                    size: (
+                        prepared_level.image.image_width,
+                        prepared_level.image.image_height,
+                    ),
+                    pixel_spacing: prepared_level.pixel_spacing,
+                });
+            }
+            progress.finish_level();
+        }
+    }
+    if datasets.is_empty() {
+        return Err(Error::NoPyramidLevels);
+    }
+
+    store.set(".zattrs", multiscales_json(&datasets).as_bytes())?;
+    progress.finish();
+
+    Ok(())
+}
+
+/// A pyramid level whose pixel data has been located, ready to be written as an array.
+struct ZarrLevel<'a> {
+    image: DicomImage,
+    tile_data: TileData<'a>,
+    tile_frames: Vec<Option<usize>>,
+    pixel_spacing: Option<(f64, f64)>,
+    /// The bytes of each decoded sample, 1 or 2
+    bytes_per_sample: usize,
+}
+
+/// A written array, as described in the multiscales metadata.
+struct Dataset {
+    path: String,
+    /// (width, height)
+    size: (u32, u32),
+    /// (x, y) in millimeters
+    pixel_spacing: Option<(f64, f64)>,
+}
+
+/// Reads the header of a pyramid level and locates its frames, or returns `None` if the level
+/// is skipped in lenient mode.
+fn prepare_zarr_level<'a>(
+    instance: &DicomInstance<'a>,
+    options: &ConversionOptions,
+) -> Result<Option<ZarrLevel<'a>>> {
+    let Some(dcm_object) = options.skip_error(instance.read_header())? else {
+        return Ok(None);
+    };
+    let source = instance.source.clone();
+    let Some((image, tile_data, tile_frames)) = options.skip_error(
+        prepare_image(&dcm_object, source, &instance.concatenation, options).and_then(|prepared| {
+            decode::check_decodable(&prepared.0)?;
+            Ok(prepared)
+        }),
+    )?
+    else {
+        return Ok(None);
+    };
+    // Pixel spacings which are not positive cannot be scales
+    let pixel_spacing = options
+        .skip_error(image::get_pixel_spacing(&dcm_object))?
+        .filter(|&(x, y)| x > 0.0 && y > 0.0 && x.is_finite() && y.is_finite());
+    let bytes_per_sample = decode::bytes_per_sample(&image, &tile_data);
+
+    Ok(Some(ZarrLevel {
+        image,
+        tile_data,
+        tile_frames,
+        pixel_spacing,
+        bytes_per_sample,
+    }))
+}
+
+/// Writes a pyramid level as an array of shape (c, y, x), chunked by tile. Chunks of tiles
+/// without a frame (only possible when sparsely tiled) are not written, so readers fill them
+/// with the fill value.
+fn write_array<S: OutputStore>(
+    store: &mut S,
+    path: &str,
+    level: &ZarrLevel,
+    compression: ChunkCompression,
+    workers: &Workers,
+    progress: &mut ProgressTracker,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let image = &level.image;
+    let dtype = match (level.bytes_per_sample, image.is_signed) {
+        (1, false) => "|u1",
+        (1, true) => "|i1",
+        (_, false) => "<u2",
+        (_, true) => "<i2",
+    };
+    let zarray = format!(
+        r#"{{"zarr_format":2,"shape":[{0},{1},{2}],"chunks":[{0},{3},{4}],"dtype":"{5}","compressor":{6},"fill_value":0,"order":"C","filters":null,"dimension_separator":"/"}}"#,
+        image.samples_per_pixel,
+        image.image_height,
+        image.image_width,
+        image.tile_height,
+        image.tile_width,
+        dtype,
+        compression.compressor_json()
+    );
+    store.set(&format!("{}/.zarray", path), zarray.as_bytes())?;
+
+    // Chunks are prepared in batches by the workers and written in order
+    let tiles_across = image.tiles_across() as usize;
+    let slots = level.tile_frames.iter().enumerate().collect::<Vec<_>>();
+    for batch in slots.chunks(workers.batch_len()) {
+        cancellation.check()?;
+        let chunks = workers.map(batch, |(_, frame_index)| {
+            frame_index
+                .map(|frame_index| prepare_chunk(level, frame_index, compression))
+                .transpose()
+        });
+        for (&(slot, _), chunk) in batch.iter().zip(chunks) {
+            let Some(chunk) = chunk? else {
+                progress.tile_written(0);
+                continue;
+            };
+            let key = format!("{}/0/{}/{}", path, slot / tiles_across, slot % tiles_across);
+            store.set(&key, &chunk)?;
+            progress.tile_written(chunk.len() as u64);
+        }
+    }
+
+    Ok(())
+}
+
+/// Decodes a frame and turns it into the bytes of its chunk: color-by-plane, with little endian
+/// samples, and compressed.
+fn prepare_chunk(
+    level: &ZarrLevel,
+    frame_index: usize,
+    compression: ChunkCompression,
+) -> Result<Vec<u8>> {
+    let image = &level.image;
+    let tile = decode::decode_frame(image, &level.tile_data, frame_index)?;
+    let chunk = deinterleave(
+        &tile,
+        usize::from(image.samples_per_pixel),
+        level.bytes_per_sample,
+    );
+    compression.compress(chunk)
+}
+
+/// Converts a color-by-pixel tile into a color-by-plane chunk with little endian samples.
+fn deinterleave(tile: &[u8], samples: usize, bytes_per_sample: usize) -> Vec<u8> {
+    let plane_len = tile.len() / samples;
+    let mut chunk = vec![0; tile.len()];
+    for (pixel, values) in tile.chunks_exact(samples * bytes_per_sample).enumerate() {
+        for (sample, value) in values.chunks_exact(bytes_per_sample).enumerate() {
+            let start = sample * plane_len + pixel * bytes_per_sample;
+            let target = &mut chunk[start..start + bytes_per_sample];
+            target.copy_from_slice(value);
+            if cfg!(target_endian = "big") {
+                target.reverse();
+            }
+        }
+    }
+    chunk
+}
+
+/// The attributes of the root group, with the multiscales metadata of the arrays. Their scales
+/// are the physical pixel size in micrometers if all levels have a pixel spacing, or otherwise
+/// the downsampling factors from the first level.
+fn multiscales_json(datasets: &[Dataset]) -> String {
+    let physical = datasets
+        .iter()
+        .all(|dataset| dataset.pixel_spacing.is_some());
+    let space_axis = |name: &str| {
+        if physical {
+            format!(
+                r#"{{"name":"{}","type":"space","unit":"micrometer"}}"#,
+                name
+            )
+        } else {
+            format!(r#"{{"name":"{}","type":"space"}}"#, name)
+        }
+    };
+
+    let mut json = format!(
+        r#"{{"multiscales":[{{"version":"0.4","axes":[{{"name":"c","type":"channel"}},{},{}],"datasets":["#,
+        space_axis("y"),
+        space_axis("x")
+    );
+    let (level_0_width, level_0_height) = datasets[0].size;
+    for (index, dataset) in datasets.iter().enumerate() {
+        let (scale_x, scale_y) = match dataset.pixel_spacing {
+            // Pixel spacing is in mm
+            Some((pixel_spacing_x, pixel_spacing_y)) if physical => {
+                (pixel_spacing_x * 1000.0, pixel_spacing_y * 1000.0)
+            }
+            _ => (
+                f64::from(level_0_width) / f64::from(dataset.size.0),
+                f64::from(level_0_height) / f64::from(dataset.size.1),
+            ),
+        };
+        if index > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            r#"{{"path":"{}","coordinateTransformations":[{{"type":"scale","scale":[1,{},{}]}}]}}"#,
+            dataset.path, scale_y, scale_x
+        );
+    }
+    json.push_str("]}]}");
+    json
+}
@@ -9,6 +9,10 @@ struct FileSystemSyncAccessHandleWrapper {
     handle: web_sys::FileSystemSyncAccessHandle,
 }
 
+// SAFETY: the module is built without threads, so the handle is only ever used on the thread of
+// the worker which opened it.
+unsafe impl Send for FileSystemSyncAccessHandleWrapper {}
+
 impl From<web_sys::FileSystemSyncAccessHandle> for FileSystemSyncAccessHandleWrapper {
     fn from(handle: web_sys::FileSystemSyncAccessHandle) -> Self {
         Self { pos: 0, handle }
@@ -122,6 +126,141 @@ fn is_dicom_file<R: Read + Seek>(reader: &mut R) -> bool {
     result
 }
 
+/// Converts an error into a JS `Error` whose `name` is the kind of error (e.g.
+/// "UnsupportedTransferSyntax"), with the failing instance and attribute as extra properties.
+fn to_js_error(error: &dicom2tiff::Error) -> JsValue {
+    let js_error = js_sys::Error::new(&error.to_string());
+    let root = error.root();
+    js_error.set_name(match root {
+        dicom2tiff::Error::MissingAttribute { .. } => "MissingAttribute",
+        dicom2tiff::Error::InvalidAttribute { .. } => "InvalidAttribute",
+        dicom2tiff::Error::UnsupportedPhotometricInterpretation(_) => {
+            "UnsupportedPhotometricInterpretation"
+        }
+        dicom2tiff::Error::UnsupportedTransferSyntax(_) => "UnsupportedTransferSyntax",
+        dicom2tiff::Error::UnsupportedPixelData(_) => "UnsupportedPixelData",
+        dicom2tiff::Error::InvalidPixelData(_) => "InvalidPixelData",
+        dicom2tiff::Error::NoPyramidLevels => "NoPyramidLevels",
+        dicom2tiff::Error::MultipleSlides(_) => "MultipleSlides",
+        dicom2tiff::Error::Cancelled => "Cancelled",
+        dicom2tiff::Error::Dicom(_) => "DicomError",
+        dicom2tiff::Error::Io(_) => "IoError",
+        dicom2tiff::Error::Tiff(_) => "TiffError",
+        _ => "Error",
+    });
+
+    let set = |key: &str, value: JsValue| {
+        js_sys::Reflect::set(&js_error, &JsValue::from_str(key), &value).ok();
+    };
+    if let dicom2tiff::Error::Instance {
+        index,
+        sop_instance_uid,
+        image,
+        ..
+    } = error
+    {
+        set("instanceIndex", JsValue::from(*index as u32));
+        if let Some(sop_instance_uid) = sop_instance_uid {
+            set("sopInstanceUid", JsValue::from_str(sop_instance_uid));
+        }
+        if let Some(image) = image {
+            set("image", JsValue::from_str(&image.to_string()));
+            if let dicom2tiff::ImageKind::Level(level) = image {
+                set("level", JsValue::from(*level as u32));
+            }
+        }
+    }
+    if let dicom2tiff::Error::MissingAttribute { tag }
+    | dicom2tiff::Error::InvalidAttribute { tag, .. } = root
+    {
+        set("tag", JsValue::from_str(&tag.to_string()));
+    }
+
+    js_error.into()
+}
+
+/// A handle to abort a conversion, which then throws an `Error` named "Cancelled". The conversion
+/// blocks the worker running it, so `abort` must be called from the `onProgress` callback, e.g.
+/// when the main thread has set a flag in a `SharedArrayBuffer`.
+///
+/// Passing a handle to a conversion consumes it, so pass a clone (`abortHandle.clone()`) and keep
+/// the original to call `abort` on. Clones share the same state.
+#[wasm_bindgen]
+#[derive(Clone, Default)]
+pub struct AbortHandle {
+    token: dicom2tiff::CancellationToken,
+}
+
+#[wasm_bindgen]
+impl AbortHandle {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[wasm_bindgen(js_name = "clone")]
+    pub fn clone_handle(&self) -> AbortHandle {
+        self.clone()
+    }
+
+    pub fn abort(&self) {
+        self.token.cancel();
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn aborted(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+/// A JS function which is called with the progress of a conversion.
+struct JsProgressCallback(js_sys::Function);
+
+// SAFETY: the module is built without threads, so the function is only ever called on the thread
+// of the worker which passed it in.
+unsafe impl Send for JsProgressCallback {}
+unsafe impl Sync for JsProgressCallback {}
+
+impl JsProgressCallback {
+    /// Calls the function with an object like `{ event: "tilesWritten", level: 0, tilesWritten:
+    /// 64, tilesTotal: 1024, ... }`. Errors thrown by the function are ignored.
+    fn call(&self, progress: &dicom2tiff::Progress) {
+        let object = js_sys::Object::new();
+        let set = |key: &str, value: JsValue| {
+            js_sys::Reflect::set(&object, &JsValue::from_str(key), &value).ok();
+        };
+        set(
+            "event",
+            JsValue::from_str(match progress.event {
+                dicom2tiff::ProgressEvent::LevelStarted => "levelStarted",
+                dicom2tiff::ProgressEvent::TilesWritten => "tilesWritten",
+                dicom2tiff::ProgressEvent::LevelFinished => "levelFinished",
+                dicom2tiff::ProgressEvent::Finished => "finished",
+            }),
+        );
+        set("level", JsValue::from(progress.level as u32));
+        set(
+            "levelsWritten",
+            JsValue::from(progress.levels_written as u32),
+        );
+        set("levelsTotal", JsValue::from(progress.levels_total as u32));
+        set(
+            "levelTilesWritten",
+            JsValue::from(progress.level_tiles_written as f64),
+        );
+        set(
+            "levelTilesTotal",
+            JsValue::from(progress.level_tiles_total as f64),
+        );
+        set("tilesWritten", JsValue::from(progress.tiles_written as f64));
+        set("tilesTotal", JsValue::from(progress.tiles_total as f64));
+        set("bytesWritten", JsValue::from(progress.bytes_written as f64));
+        self.0.call1(&JsValue::NULL, &object).ok();
+    }
+}
+
+/// Converts the DICOM files of the input handles to a TIFF written to the output handle. If given,
+/// `onProgress` is called with the progress of the conversion, and `abortHandle` aborts it.
 #[wasm_bindgen(js_name = "convertViaSyncAccessHandles")]
 pub fn convert_via_sync_access_handles(
     #[wasm_bindgen(js_name = "inputSyncAccessHandles")] input_sync_access_handles: Vec<
@@ -129,6 +268,8 @@ pub fn convert_via_sync_access_handles(
     >,
     #[wasm_bindgen(js_name = "outputSyncAccessHandle")]
     output_sync_access_handle: web_sys::FileSystemSyncAccessHandle,
+    #[wasm_bindgen(js_name = "onProgress")] on_progress: Option<js_sys::Function>,
+    #[wasm_bindgen(js_name = "abortHandle")] abort_handle: Option<AbortHandle>,
 ) -> Result<(), JsValue> {
     crate::panic_hook::set_panic_hook();
 
@@ -143,8 +284,48 @@ pub fn convert_via_sync_access_handles(
 
     let writer = FileSystemSyncAccessHandleWrapper::from(output_sync_access_handle);
 
-    dicom2tiff::convert_dicom_sources(readers, writer)
-        .map_err(|e| JsValue::from(JsError::new(&e.to_string())))?;
+    let mut converter = dicom2tiff::Converter::default();
+    if let Some(on_progress) = on_progress {
+        let callback = JsProgressCallback(on_progress);
+        converter = converter.on_progress(move |progress| callback.call(progress));
+    }
+    if let Some(abort_handle) = abort_handle {
+        converter = converter.cancellation_token(abort_handle.token);
+    }
+    converter
+        .convert(readers, writer)
+        .map_err(|e| to_js_error(&e))?;
 
     Ok(())
 }
+
+/// Describes the slides of the DICOM files of the input handles from their headers, without
+/// converting them. Returns an array with an object per slide: its UIDs, `levels`, `thumbnail`,
+/// `label` and `overview` (with their size, tile size, frame count, transfer syntax,
+/// photometric interpretation and MPP), `opticalPaths`, and the `problems` which would fail
+/// its conversion, `convertible` being whether there are none.
+#[wasm_bindgen(js_name = "probeViaSyncAccessHandles")]
+pub fn probe_via_sync_access_handles(
+    #[wasm_bindgen(js_name = "inputSyncAccessHandles")] input_sync_access_handles: Vec<
+        web_sys::FileSystemSyncAccessHandle,
+    >,
+) -> Result<JsValue, JsValue> {
+    crate::panic_hook::set_panic_hook();
+
+    let mut readers = input_sync_access_handles
+        .into_iter()
+        .map(FileSystemSyncAccessHandleWrapper::from)
+        .map(BufReader::new)
+        .collect::<Vec<_>>();
+
+    // Remove any files that do not appear to be DICOM files
+    readers.retain_mut(is_dicom_file);
+
+    let slides = dicom2tiff::probe_dicom_sources(readers).map_err(|e| to_js_error(&e))?;
+    let json = slides
+        .iter()
+        .map(dicom2tiff::SlideInfo::to_json)
+        .collect::<Vec<_>>()
+        .join(",");
+    js_sys::JSON::parse(&format!("[{}]", json))
+}
@@ -1,5 +1,5 @@
 // Web Worker for handling DICOM conversion
-import init, { convertViaSyncAccessHandles } from '../../crates/wasm/pkg/dicom2tiff.js';
+import init, { AbortHandle, convertViaSyncAccessHandles } from '../../crates/wasm/pkg/dicom2tiff.js';
 
 let wasmInitialized = false;
 
@@ -29,7 +29,7 @@ self.addEventListener('message', async (event) => {
 
   if (type === 'convertViaOpfs') {
     console.log("Converting via OPFS...");
-    const { inputFileHandles, outputFileHandle } = event.data;
+    const { inputFileHandles, outputFileHandle, cancelFlag } = event.data;
 
     if (!inputFileHandles) {
       self.postMessage({ type: 'error', error: 'No input file handles provided', fileId });
@@ -47,8 +47,17 @@ self.addEventListener('message', async (event) => {
       const inputSyncAccessHandles = await Promise.all(inputFileHandles.map(handle => handle.createSyncAccessHandle()));
       const outputSyncAccessHandle = await outputFileHandle.createSyncAccessHandle();
 
-      // Convert using the OPFS API
-      convertViaSyncAccessHandles(inputSyncAccessHandles, outputSyncAccessHandle);
+      // The conversion blocks this worker, so the main thread cancels it by setting a flag in
+      // shared memory, which is checked whenever progress is reported
+      const abortHandle = new AbortHandle();
+
+      // Convert using the OPFS API, forwarding progress to the main thread
+      convertViaSyncAccessHandles(inputSyncAccessHandles, outputSyncAccessHandle, (progress) => {
+        if (cancelFlag && Atomics.load(cancelFlag, 0) === 1) {
+          abortHandle.abort();
+        }
+        self.postMessage({ type: 'progress', progress, fileId });
+      }, abortHandle.clone());
 
       // Signal completion - files are already in the output directory
       self.postMessage({ 
@@ -57,6 +66,10 @@ self.addEventListener('message', async (event) => {
       });
 
     } catch (error) {
+      if (error.name === 'Cancelled') {
+        self.postMessage({ type: 'cancelled', fileId });
+        return;
+      }
       console.error('Conversion with Full OPFS API failed:', error);
       self.postMessage({ type: 'error', error: error.message || 'Conversion failed', fileId });
     }